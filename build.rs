@@ -0,0 +1,4 @@
+// the migrations are compiled in, this rebuilds when they change
+fn main() {
+    println!("cargo:rerun-if-changed=migrations");
+}
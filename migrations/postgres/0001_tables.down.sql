@@ -0,0 +1,11 @@
+DROP TABLE analyzed_files;
+DROP TABLE images;
+DROP TABLE markers;
+DROP TABLE lists;
+DROP TABLE plays;
+DROP TABLE users;
+DROP TABLE syncs;
+DROP TABLE missing_files;
+DROP TABLE tags;
+DROP TABLE episodes;
+DROP TABLE series;
@@ -0,0 +1,12 @@
+-- A table for each kind of thing, a row per thing by its key with it as JSON.
+CREATE TABLE IF NOT EXISTS series (key text PRIMARY KEY, value text NOT NULL);
+CREATE TABLE IF NOT EXISTS episodes (key text PRIMARY KEY, value text NOT NULL);
+CREATE TABLE IF NOT EXISTS tags (key text PRIMARY KEY, value text NOT NULL);
+CREATE TABLE IF NOT EXISTS missing_files (key text PRIMARY KEY, value text NOT NULL);
+CREATE TABLE IF NOT EXISTS syncs (key text PRIMARY KEY, value text NOT NULL);
+CREATE TABLE IF NOT EXISTS users (key text PRIMARY KEY, value text NOT NULL);
+CREATE TABLE IF NOT EXISTS plays (key text PRIMARY KEY, value text NOT NULL);
+CREATE TABLE IF NOT EXISTS lists (key text PRIMARY KEY, value text NOT NULL);
+CREATE TABLE IF NOT EXISTS markers (key text PRIMARY KEY, value text NOT NULL);
+CREATE TABLE IF NOT EXISTS images (key text PRIMARY KEY, value text NOT NULL);
+CREATE TABLE IF NOT EXISTS analyzed_files (key text PRIMARY KEY, value text NOT NULL);
@@ -0,0 +1,2 @@
+-- The job queue, a row per job by its id.
+CREATE TABLE IF NOT EXISTS jobs (key TEXT PRIMARY KEY NOT NULL, value TEXT NOT NULL);
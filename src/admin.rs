@@ -0,0 +1,448 @@
+use std::collections::{BTreeSet, HashMap};
+
+use axum::{
+    body::Bytes,
+    extract::{Path, Query},
+    http::{header, Request, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post, put},
+    Json, Router,
+};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    auth,
+    backup::{self, Restored},
+    cache::CacheStats,
+    circuit_breaker, config, edges,
+    errors::ApiError,
+    ffmpeg, lidarr, migrations, playback, port_forwarding,
+    probe::{self, Probe},
+    prowlarr, radarr, readarr,
+    scheduler::{self, Task},
+    sonarr, stream_stats, telemetry,
+    upstream::Upstream,
+    users::{self, Device, Role},
+};
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/status", get(get_status))
+        .route("/config", get(get_config))
+        .route("/reload", post(reload))
+        .route("/backup", post(create_backup))
+        .route("/restore", post(restore_backup))
+        .route("/cache", get(get_cache).delete(purge_cache))
+        .route("/circuit-breaker", get(get_circuit_breaker))
+        .route("/log-level", get(get_log_level).put(set_log_level))
+        .route("/users", get(get_users))
+        .route("/users/:name", put(put_user).delete(delete_user))
+        .route("/devices", get(get_devices))
+        .route("/devices/:id", delete(revoke_device))
+        .route("/stream-stats", get(get_stream_stats))
+        .route("/playback-sessions", get(get_playback_sessions))
+        .route("/edges", get(get_edges))
+        .route("/tasks", get(get_tasks))
+        .route("/tasks/:name/run", post(run_task))
+        .route("/test/:service", post(test_service))
+        .route(
+            "/sonarr/commands",
+            get(get_sonarr_commands).post(run_sonarr_command),
+        )
+        .route("/sonarr/commands/:id", get(get_sonarr_command))
+        .route_layer(middleware::from_fn(authenticate))
+}
+
+/// Compares without bailing out at the first different byte, so the token
+/// can't be guessed one character at a time from response timings.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Lets in requests with the admin token, or an admin's access token when
+/// logging in is required.
+async fn authenticate<B>(req: Request<B>, next: Next<B>) -> Response {
+    let config = config::get();
+    if config.admin_token.is_none() && !config.require_auth {
+        return ApiError::empty(404, None).into_response();
+    }
+
+    let admin_token = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .zip(config.admin_token.as_ref())
+        .filter(|(given, token)| constant_time_eq(given.as_bytes(), token.as_bytes()))
+        .is_some();
+
+    if !admin_token {
+        if !config.require_auth {
+            return ApiError::empty(401, None).into_response();
+        }
+
+        let allowed = auth::authenticate(req.headers())
+            .await
+            .and_then(|user| user.require(Role::Admin));
+        if let Err(e) = allowed {
+            return e.into_response();
+        }
+    }
+
+    next.run(req).await
+}
+
+async fn get_config() -> Json<Value> {
+    Json(json!(*config::get()))
+}
+
+async fn reload() -> Result<Json<Value>, ApiError> {
+    config::reload()
+        .map(|config| Json(json!(*config)))
+        .map_err(|e| ApiError::new(400, e.to_string()))
+}
+
+async fn create_backup() -> Result<Response, ApiError> {
+    let archive = backup::create()
+        .await
+        .map_err(|e| ApiError::empty(500, Some(e)))?;
+    let name = format!("centarr-{}.zip", Utc::now().format("%Y-%m-%d"));
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/zip".to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", name),
+            ),
+        ],
+        archive,
+    )
+        .into_response())
+}
+
+#[derive(Deserialize)]
+struct RestoreQuery {
+    config: Option<bool>,
+}
+
+/// Restores a backup over the running centarr's state, which is loaded
+/// again from it.
+async fn restore_backup(
+    Query(query): Query<RestoreQuery>,
+    archive: Bytes,
+) -> Result<Json<Restored>, ApiError> {
+    let restored = backup::restore(&archive, query.config.unwrap_or(true))
+        .await
+        .map_err(|e| ApiError::new(400, e))?;
+    crate::load_state().await;
+    if restored.config {
+        config::reload().map_err(|e| {
+            ApiError::new(
+                422,
+                format!("The backup was restored, but its config isn't valid: {}", e),
+            )
+        })?;
+    }
+
+    Ok(Json(restored))
+}
+
+fn upstreams() -> [&'static Upstream; 5] {
+    [
+        &sonarr::UPSTREAM,
+        &lidarr::UPSTREAM,
+        &readarr::UPSTREAM,
+        &radarr::UPSTREAM,
+        &prowlarr::UPSTREAM,
+    ]
+}
+
+async fn get_cache() -> Json<HashMap<&'static str, CacheStats>> {
+    let mut stats = HashMap::new();
+    for upstream in upstreams() {
+        stats.insert(upstream.name, upstream.cache.stats().await);
+    }
+
+    Json(stats)
+}
+
+async fn purge_cache() -> Json<Value> {
+    let mut purged = 0;
+    for upstream in upstreams() {
+        purged += upstream.cache.purge().await;
+    }
+
+    Json(json!({ "purged": purged }))
+}
+
+async fn get_status() -> Json<Value> {
+    Json(json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "schema": migrations::status().await.ok(),
+        "remoteAccess": port_forwarding::status(),
+        "skipped": sonarr::skipped(),
+        "ffmpeg": ffmpeg::status(),
+    }))
+}
+
+async fn get_circuit_breaker() -> Json<HashMap<&'static str, circuit_breaker::Status>> {
+    Json(
+        upstreams()
+            .into_iter()
+            .map(|upstream| (upstream.name, upstream.breaker.status()))
+            .collect(),
+    )
+}
+
+async fn get_stream_stats() -> Json<stream_stats::Summary> {
+    Json(stream_stats::summary())
+}
+
+async fn get_playback_sessions() -> Json<Vec<playback::SessionStatus>> {
+    Json(playback::sessions())
+}
+
+async fn get_edges() -> Json<Vec<edges::Status>> {
+    Json(edges::list())
+}
+
+#[derive(Serialize, Deserialize)]
+struct LogLevel {
+    level: String,
+}
+
+async fn get_log_level() -> Json<LogLevel> {
+    Json(LogLevel {
+        level: telemetry::log_level(),
+    })
+}
+
+async fn set_log_level(Json(body): Json<LogLevel>) -> Result<Json<LogLevel>, ApiError> {
+    telemetry::set_log_level(&body.level).map_err(|e| ApiError::new(400, e))?;
+    tracing::info!("Log level changed to {:?}", body.level);
+
+    Ok(Json(body))
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TaskSummary {
+    name: &'static str,
+    schedule: String,
+    /// When it runs next, `None` when its schedule is off.
+    next_run: Option<String>,
+    #[serde(flatten)]
+    status: scheduler::Status,
+}
+
+async fn get_tasks() -> Json<Vec<TaskSummary>> {
+    let config = config::get();
+
+    Json(
+        Task::ALL
+            .into_iter()
+            .map(|task| TaskSummary {
+                name: task.name(),
+                schedule: task.expression(&config).to_string(),
+                next_run: scheduler::next_run(task, &config),
+                status: scheduler::status(task),
+            })
+            .collect(),
+    )
+}
+
+/// Queues a task to run now, answering with the job's id.
+async fn run_task(Path(name): Path<String>) -> Result<(StatusCode, Json<Value>), ApiError> {
+    let task = Task::from_name(&name)
+        .ok_or_else(|| ApiError::new(404, format!("There's no task {:?}", name)))?;
+    let id = scheduler::start(task)
+        .await
+        .ok_or_else(|| ApiError::new(409, format!("{} is already queued or running", name)))?;
+    tracing::info!("Queued {} from the admin API", name);
+
+    Ok((StatusCode::ACCEPTED, Json(json!({ "jobId": id }))))
+}
+
+async fn test_service(Path(service): Path<String>) -> Result<Json<Probe>, ApiError> {
+    probe::probe(&service).await.map(Json)
+}
+
+/// Sonarr commands that can be run through the admin API, ones that only
+/// look for new releases or go over what Sonarr already has.
+const SONARR_COMMANDS: &[&str] = &["RssSync", "RefreshSeries", "RescanSeries"];
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SonarrCommand {
+    name: String,
+    /// The show to refresh or rescan, all of them without.
+    series_id: Option<i32>,
+}
+
+/// Queues one of [`SONARR_COMMANDS`] in Sonarr, answering with Sonarr's
+/// command, whose `id` it can be followed by.
+async fn run_sonarr_command(
+    Json(command): Json<SonarrCommand>,
+) -> Result<(StatusCode, Json<Value>), ApiError> {
+    let name = SONARR_COMMANDS
+        .iter()
+        .find(|name| name.eq_ignore_ascii_case(&command.name))
+        .ok_or_else(|| {
+            ApiError::new(
+                422,
+                format!(
+                    "{:?} isn't one of the commands that can be run, {}",
+                    command.name,
+                    SONARR_COMMANDS.join(", ")
+                ),
+            )
+        })?;
+    let body = match (*name, command.series_id) {
+        ("RssSync", Some(_)) => {
+            return Err(ApiError::new(
+                422,
+                "RssSync is for all shows at once".into(),
+            ));
+        }
+        (name, Some(series_id)) => json!({ "name": name, "seriesId": series_id }),
+        (name, None) => json!({ "name": name }),
+    };
+
+    let answer = sonarr::post("/command", &body).await?;
+    let answer = serde_json::from_str::<Value>(&answer)
+        .map_err(|e| ApiError::empty(502, Some(e.to_string())))?;
+    tracing::info!("Queued Sonarr's {} from the admin API", name);
+
+    Ok((StatusCode::ACCEPTED, Json(answer)))
+}
+
+/// Sonarr's queued, running and recently finished commands, straight from
+/// Sonarr as they change by the second.
+async fn get_sonarr_commands() -> Result<Json<Value>, ApiError> {
+    let body = sonarr::fetch("/command").await?;
+
+    serde_json::from_str(&body)
+        .map(Json)
+        .map_err(|e| ApiError::empty(502, Some(e.to_string())))
+}
+
+async fn get_sonarr_command(Path(id): Path<i64>) -> Result<Json<Value>, ApiError> {
+    let body = sonarr::fetch(&format!("/command/{}", id)).await?;
+
+    serde_json::from_str(&body)
+        .map(Json)
+        .map_err(|e| ApiError::empty(502, Some(e.to_string())))
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct UserSummary {
+    name: String,
+    role: Role,
+    allowed_tags: BTreeSet<String>,
+}
+
+async fn get_users() -> Json<Vec<UserSummary>> {
+    Json(
+        users::all()
+            .into_iter()
+            .map(|user| UserSummary {
+                name: user.name,
+                role: user.role,
+                allowed_tags: user.allowed_tags,
+            })
+            .collect(),
+    )
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PutUser {
+    password: Option<String>,
+    role: Option<Role>,
+    /// Restricted tags they may see anyway.
+    allowed_tags: Option<Vec<String>>,
+}
+
+/// Adds the user, or changes their password, role and allowed tags.
+async fn put_user(
+    Path(name): Path<String>,
+    Json(body): Json<PutUser>,
+) -> Result<StatusCode, ApiError> {
+    if name.trim().is_empty() {
+        return Err(ApiError::new(400, "A user needs a name".into()));
+    }
+    let password = body.password.filter(|password| !password.is_empty());
+    if password.is_none() && !users::exists(&name) {
+        return Err(ApiError::new(400, "A new user needs a password".into()));
+    }
+
+    if let Some(password) = password {
+        users::set_password(&name, &password).await;
+        tracing::info!("Set the password of {}", name);
+    }
+    if let Some(role) = body.role {
+        users::set_role(&name, role).await;
+        tracing::info!("{} is now {:?}", name, role);
+    }
+    if let Some(tags) = body.allowed_tags {
+        let tags = tags
+            .iter()
+            .map(|tag| tag.trim().to_lowercase())
+            .filter(|tag| !tag.is_empty())
+            .collect::<BTreeSet<_>>();
+        tracing::info!("{} may see {:?}", name, tags);
+        users::set_allowed_tags(&name, tags).await;
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn delete_user(Path(name): Path<String>) -> Result<StatusCode, ApiError> {
+    if !users::remove(&name).await {
+        return Err(ApiError::new(404, format!("There's no user {:?}", name)));
+    }
+
+    tracing::info!("Removed user {}", name);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Serialize)]
+struct DeviceSummary {
+    user: String,
+    #[serde(flatten)]
+    device: Device,
+}
+
+/// Every user's devices, most recently seen first.
+async fn get_devices() -> Json<Vec<DeviceSummary>> {
+    let mut devices = users::all()
+        .into_iter()
+        .flat_map(|user| {
+            let name = user.name;
+            user.devices.into_iter().map(move |device| DeviceSummary {
+                user: name.clone(),
+                device,
+            })
+        })
+        .collect::<Vec<_>>();
+    devices.sort_by_key(|summary| std::cmp::Reverse(summary.device.last_seen));
+
+    Json(devices)
+}
+
+/// Forgets the device, logging it out. Its access token stops working
+/// right away.
+async fn revoke_device(Path(id): Path<String>) -> Result<StatusCode, ApiError> {
+    if !users::revoke_device(&id).await {
+        return Err(ApiError::new(404, format!("There's no device {:?}", id)));
+    }
+    auth::revoke(&id).await;
+
+    tracing::info!("Revoked device {}", id);
+    Ok(StatusCode::NO_CONTENT)
+}
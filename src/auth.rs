@@ -0,0 +1,590 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use axum::{
+    extract::{FromRequest, RequestParts},
+    http::{header, HeaderMap, Request},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use once_cell::sync::Lazy;
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::{
+    config,
+    errors::ApiError,
+    redis,
+    users::{self, Device, Preferences, Role},
+};
+
+const ACCESS_TOKEN_LIFETIME: Duration = Duration::from_secs(15 * 60);
+/// `{"alg":"HS256","typ":"JWT"}`, the only kind of token we hand out.
+const JWT_HEADER: &str = "eyJhbGciOiJIUzI1NiIsInR5cCI6IkpXVCJ9";
+
+/// Devices logged out in the last [`ACCESS_TOKEN_LIFETIME`], with when, as
+/// the access tokens they had are otherwise good until they expire.
+static REVOKED: Lazy<Mutex<HashMap<String, u64>>> = Lazy::new(Default::default);
+/// Signs access tokens unless `jwt_secret` is configured.
+static SIGNING_KEY: Lazy<String> = Lazy::new(|| {
+    let path = config::get().signing_key_path.clone();
+    read_or_create_key(&path).unwrap_or_else(|e| {
+        tracing::warn!(
+            "Can't keep a signing key in {:?}, access tokens won't survive a restart: {}",
+            path,
+            e
+        );
+        users::random_hex(32)
+    })
+});
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/auth/login", post(login))
+        .route("/auth/refresh", post(refresh))
+        .route("/auth/logout", post(logout))
+        .route("/auth/me", get(me))
+        .route(
+            "/users/me/preferences",
+            get(get_preferences).patch(patch_preferences),
+        )
+}
+
+#[derive(Serialize, Deserialize)]
+struct Claims {
+    sub: String,
+    #[serde(default)]
+    role: Role,
+    /// The device it was issued to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    did: Option<String>,
+    iat: u64,
+    exp: u64,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Reads the key kept at `path`, or generates one and writes it there
+/// readable only by us.
+fn read_or_create_key(path: &Path) -> io::Result<String> {
+    match fs::read_to_string(path) {
+        Ok(key) if key.trim().is_empty() => {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "it's empty"))
+        }
+        Ok(key) => {
+            let permissions = fs::metadata(path)?.permissions();
+            if permissions.mode() & 0o077 != 0 {
+                fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+            }
+            return Ok(key.trim().to_string());
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let key = users::random_hex(32);
+    let created = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path);
+    match created {
+        Ok(mut file) => file.write_all(key.as_bytes())?,
+        // another centarr sharing it got there first
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => return read_or_create_key(path),
+        Err(e) => return Err(e),
+    }
+    tracing::info!("Generated a signing key in {:?}", path);
+
+    Ok(key)
+}
+
+fn key() -> hmac::Key {
+    let secret = match config::get().jwt_secret.clone() {
+        Some(secret) => secret,
+        None => SIGNING_KEY.clone(),
+    };
+
+    hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes())
+}
+
+/// `claims` as a token signed with `key`.
+fn sign(claims: &Claims, key: &hmac::Key) -> String {
+    let payload =
+        base64::encode_config(serde_json::to_vec(claims).unwrap(), base64::URL_SAFE_NO_PAD);
+
+    let signed = format!("{}.{}", JWT_HEADER, payload);
+    let signature = hmac::sign(key, signed.as_bytes());
+
+    format!(
+        "{}.{}",
+        signed,
+        base64::encode_config(signature, base64::URL_SAFE_NO_PAD)
+    )
+}
+
+/// The claims of `token` when it was signed with `key` and hasn't
+/// expired.
+fn decode(token: &str, key: &hmac::Key) -> Option<Claims> {
+    let (signed, signature) = token.rsplit_once('.')?;
+    let (header, payload) = signed.split_once('.')?;
+    // anything else, like "alg": "none", is rejected outright
+    if header != JWT_HEADER {
+        return None;
+    }
+
+    let signature = base64::decode_config(signature, base64::URL_SAFE_NO_PAD).ok()?;
+    hmac::verify(key, signed.as_bytes(), &signature).ok()?;
+
+    let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD).ok()?;
+    let claims = serde_json::from_slice::<Claims>(&payload).ok()?;
+
+    (claims.exp > now()).then_some(claims)
+}
+
+/// A signed access token for `name` on `device`, carrying their current
+/// role.
+async fn issue(name: &str, device: Option<&str>) -> String {
+    let now = now();
+    let claims = Claims {
+        sub: name.to_string(),
+        role: users::role(name).unwrap_or_default(),
+        did: device.map(String::from),
+        iat: now,
+        exp: now + ACCESS_TOKEN_LIFETIME.as_secs(),
+    };
+
+    sign(&claims, &key())
+}
+
+/// Who `token` was issued to, when it's ours, unexpired and they and
+/// their device still exist.
+pub async fn verify(token: &str) -> Option<AuthUser> {
+    let claims = decode(token, &key())?;
+
+    let known = match &claims.did {
+        Some(device) => {
+            users::device(&claims.sub, device).is_some() && !is_revoked(device, claims.iat).await
+        }
+        None => users::exists(&claims.sub),
+    };
+
+    known.then_some(AuthUser {
+        name: claims.sub,
+        role: claims.role,
+        device: claims.did,
+    })
+}
+
+/// Refuses the access tokens handed to `device` so far, on every centarr
+/// sharing a Redis server.
+pub async fn revoke(device: &str) {
+    let now = now();
+    {
+        let mut revoked = REVOKED.lock().unwrap();
+        revoked.retain(|_, at| *at + ACCESS_TOKEN_LIFETIME.as_secs() > now);
+        revoked.insert(device.to_string(), now);
+    }
+
+    if let Some(redis) = redis::shared() {
+        let key = format!("revoked:{}", device);
+        if let Err(e) = redis
+            .set(&key, &now.to_string(), ACCESS_TOKEN_LIFETIME)
+            .await
+        {
+            tracing::warn!("Can't share that device {} logged out: {}", device, e);
+        }
+    }
+}
+
+/// Whether `device` logged out since a token was issued to it at
+/// `issued_at`.
+async fn is_revoked(device: &str, issued_at: u64) -> bool {
+    let revoked_at = REVOKED.lock().unwrap().get(device).copied();
+    let revoked_at = match (revoked_at, redis::shared()) {
+        (Some(at), _) => Some(at),
+        (None, Some(redis)) => match redis.get(&format!("revoked:{}", device)).await {
+            Ok(at) => at.and_then(|at| String::from_utf8_lossy(&at).parse().ok()),
+            Err(e) => {
+                tracing::warn!("Can't check whether device {} logged out: {}", device, e);
+                None
+            }
+        },
+        (None, None) => None,
+    };
+
+    revoked_at.is_some_and(|at| issued_at <= at)
+}
+
+/// Why a request was turned away.
+#[derive(Debug)]
+pub enum AuthError {
+    /// No access token was sent.
+    MissingToken,
+    /// The access token isn't ours, expired, or its user is gone.
+    InvalidToken,
+    /// Logged in, but not allowed to do this.
+    Forbidden { required: Role },
+}
+
+impl IntoResponse for AuthError {
+    fn into_response(self) -> Response {
+        let (status, message) = match self {
+            AuthError::MissingToken => (401, "Log in first".into()),
+            AuthError::InvalidToken => (401, "The access token is invalid or expired".into()),
+            AuthError::Forbidden { required } => {
+                (403, format!("Only {:?} users can do this", required))
+            }
+        };
+
+        ApiError::new(status, message).into_response()
+    }
+}
+
+pub async fn authenticate(headers: &HeaderMap) -> Result<AuthUser, AuthError> {
+    let token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or(AuthError::MissingToken)?;
+
+    verify(token).await.ok_or(AuthError::InvalidToken)
+}
+
+/// The logged in user making the request, rejects the request with a 401
+/// when there isn't one.
+#[derive(Debug)]
+pub struct AuthUser {
+    pub name: String,
+    /// As it was when the access token was issued.
+    pub role: Role,
+    /// Id of the device they're using.
+    pub device: Option<String>,
+}
+
+impl AuthUser {
+    pub fn require(self, role: Role) -> Result<Self, AuthError> {
+        if self.role < role {
+            return Err(AuthError::Forbidden { required: role });
+        }
+
+        Ok(self)
+    }
+}
+
+#[async_trait]
+impl<B: Send> FromRequest<B> for AuthUser {
+    type Rejection = AuthError;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        authenticate(req.headers()).await
+    }
+}
+
+async fn check<B>(role: Role, req: Request<B>, next: Next<B>) -> Response {
+    if config::get().require_auth {
+        let allowed = authenticate(req.headers())
+            .await
+            .and_then(|user| user.require(role));
+        if let Err(e) = allowed {
+            return e.into_response();
+        }
+    }
+
+    next.run(req).await
+}
+
+/// Turns away requests without a valid access token when
+/// `require_auth` is on.
+pub async fn require<B>(req: Request<B>, next: Next<B>) -> Response {
+    check(Role::Viewer, req, next).await
+}
+
+/// Like [`require`], but for routes only admins may use.
+pub async fn require_admin<B>(req: Request<B>, next: Next<B>) -> Response {
+    check(Role::Admin, req, next).await
+}
+
+#[derive(Deserialize)]
+struct Login {
+    username: String,
+    password: String,
+    #[serde(default)]
+    device: Option<DeviceInfo>,
+}
+
+/// What a client tells about itself when logging in.
+#[derive(Deserialize)]
+pub struct DeviceInfo {
+    /// The `deviceId` it got from an earlier login, to be recognized.
+    #[serde(default)]
+    id: Option<String>,
+    name: String,
+    #[serde(rename = "type", default = "default_device_type")]
+    kind: String,
+}
+
+fn default_device_type() -> String {
+    "unknown".into()
+}
+
+/// Registers the device `name` is logging in on, going by its user agent
+/// when it doesn't say.
+pub async fn register_device(
+    name: &str,
+    device: Option<DeviceInfo>,
+    headers: &HeaderMap,
+) -> String {
+    let device = device.unwrap_or_else(|| DeviceInfo {
+        id: None,
+        name: headers
+            .get(header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("Unknown device")
+            .to_string(),
+        kind: default_device_type(),
+    });
+
+    users::register_device(name, device.id.as_deref(), &device.name, &device.kind).await
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RefreshTokenBody {
+    refresh_token: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Tokens {
+    access_token: String,
+    token_type: &'static str,
+    /// In seconds.
+    expires_in: u64,
+    refresh_token: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_id: Option<String>,
+}
+
+/// A fresh access and refresh token for `name` on `device`.
+pub async fn tokens(name: &str, device: Option<String>) -> Json<Tokens> {
+    Json(Tokens {
+        access_token: issue(name, device.as_deref()).await,
+        token_type: "Bearer",
+        expires_in: ACCESS_TOKEN_LIFETIME.as_secs(),
+        refresh_token: users::issue_refresh_token(name, device.as_deref()).await,
+        device_id: device,
+    })
+}
+
+async fn login(headers: HeaderMap, Json(login): Json<Login>) -> Result<Json<Tokens>, ApiError> {
+    let user = users::authenticate(&login.username, &login.password)
+        .ok_or_else(|| ApiError::new(401, "Wrong username or password".into()))?;
+
+    let device = register_device(&user.name, login.device, &headers).await;
+    tracing::info!("{} logged in", user.name);
+
+    Ok(tokens(&user.name, Some(device)).await)
+}
+
+/// Trades a refresh token for a new access token, and a new refresh token
+/// since each can only be used once.
+async fn refresh(Json(body): Json<RefreshTokenBody>) -> Result<Json<Tokens>, ApiError> {
+    let (name, device) = users::take_refresh_token(&body.refresh_token)
+        .await
+        .ok_or_else(|| ApiError::new(401, "The refresh token is invalid or expired".into()))?;
+
+    if let Some(device) = &device {
+        users::touch_device(&name, device).await;
+    }
+
+    Ok(tokens(&name, device).await)
+}
+
+async fn logout(Json(body): Json<RefreshTokenBody>) -> axum::http::StatusCode {
+    if let Some((_, Some(device))) = users::take_refresh_token(&body.refresh_token).await {
+        revoke(&device).await;
+    }
+    axum::http::StatusCode::NO_CONTENT
+}
+
+#[derive(Serialize)]
+struct Me {
+    name: String,
+    role: Role,
+    device: Option<Device>,
+}
+
+async fn me(user: AuthUser) -> Json<Me> {
+    let device = user
+        .device
+        .and_then(|device| users::device(&user.name, &device));
+
+    Json(Me {
+        name: user.name,
+        role: user.role,
+        device,
+    })
+}
+
+async fn get_preferences(user: AuthUser) -> Json<Preferences> {
+    Json(users::preferences(&user.name))
+}
+
+/// Changes the preferences sent, leaving the others as they were.
+async fn patch_preferences(
+    user: AuthUser,
+    Json(changes): Json<Map<String, Value>>,
+) -> Result<Json<Preferences>, ApiError> {
+    let mut preferences = serde_json::to_value(users::preferences(&user.name))
+        .map_err(|e| ApiError::empty(500, Some(e.to_string())))?;
+    if let Some(unknown) = changes
+        .keys()
+        .find(|key| preferences.get(key.as_str()).is_none())
+    {
+        return Err(ApiError::new(
+            422,
+            format!("{:?} is not a preference", unknown),
+        ));
+    }
+    preferences.as_object_mut().unwrap().extend(changes);
+    let preferences = serde_json::from_value::<Preferences>(preferences)
+        .map_err(|e| ApiError::new(422, e.to_string()))?;
+    if preferences
+        .watched_threshold
+        .is_some_and(|threshold| !(1..=100).contains(&threshold))
+    {
+        return Err(ApiError::new(
+            422,
+            "watchedThreshold should be a percentage between 1 and 100".into(),
+        ));
+    }
+
+    if !users::set_preferences(&user.name, preferences.clone()).await {
+        return Err(ApiError::new(404, format!("There's no user {}", user.name)));
+    }
+    Ok(Json(preferences))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claims(exp: u64) -> Claims {
+        Claims {
+            sub: "alice".into(),
+            role: Role::Viewer,
+            did: None,
+            iat: now(),
+            exp,
+        }
+    }
+
+    fn test_key(secret: &str) -> hmac::Key {
+        hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes())
+    }
+
+    #[test]
+    fn signed_tokens_only_verify_with_their_key() {
+        let token = sign(&claims(now() + 60), &test_key("one"));
+
+        let decoded = decode(&token, &test_key("one")).unwrap();
+        assert_eq!(decoded.sub, "alice");
+        assert!(decode(&token, &test_key("two")).is_none());
+
+        // a payload changed after signing
+        let (signed, signature) = token.rsplit_once('.').unwrap();
+        let forged = base64::encode_config(
+            r#"{"sub":"alice","role":"admin","iat":0,"exp":99999999999}"#,
+            base64::URL_SAFE_NO_PAD,
+        );
+        let forged = format!("{}.{}.{}", JWT_HEADER, forged, signature);
+        assert!(decode(&forged, &test_key("one")).is_none());
+        assert!(decode(signed, &test_key("one")).is_none());
+    }
+
+    #[test]
+    fn expired_tokens_are_rejected() {
+        let key = test_key("one");
+
+        assert!(decode(&sign(&claims(now() - 1), &key), &key).is_none());
+        assert!(decode(&sign(&claims(now() + 1), &key), &key).is_some());
+    }
+
+    #[test]
+    fn other_headers_are_rejected() {
+        let key = test_key("one");
+        let token = sign(&claims(now() + 60), &key);
+        let (_, rest) = token.split_once('.').unwrap();
+
+        for header in [
+            r#"{"alg":"none","typ":"JWT"}"#,
+            r#"{"alg":"HS512","typ":"JWT"}"#,
+            r#"{"typ":"JWT","alg":"HS256"}"#,
+        ] {
+            let header = base64::encode_config(header, base64::URL_SAFE_NO_PAD);
+            assert!(decode(&format!("{}.{}", header, rest), &key).is_none());
+        }
+        let (unsigned, _) = token.rsplit_once('.').unwrap();
+        assert!(decode(&format!("{}.", unsigned), &key).is_none());
+    }
+
+    #[tokio::test]
+    async fn tokens_of_revoked_devices_are_rejected() {
+        config::init_for_tests();
+        users::set_password("auth-test", "secret").await;
+        let device = users::register_device("auth-test", None, "Phone", "ios").await;
+        let token = issue("auth-test", Some(&device)).await;
+        assert_eq!(verify(&token).await.unwrap().name, "auth-test");
+
+        revoke(&device).await;
+        assert!(verify(&token).await.is_none());
+        // tokens issued after they logged in again still work
+        assert!(!is_revoked(&device, now() + 1).await);
+
+        let other = users::register_device("auth-test", None, "TV", "tv").await;
+        let token = issue("auth-test", Some(&other)).await;
+        assert!(verify(&token).await.is_some());
+        users::remove("auth-test").await;
+        assert!(verify(&token).await.is_none());
+    }
+
+    #[test]
+    fn the_signing_key_is_kept_private() {
+        let dir = std::env::temp_dir().join(format!("centarr-key-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let path = dir.join("keys/signing.key");
+
+        let key = read_or_create_key(&path).unwrap();
+        assert_eq!(key.len(), 64);
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(read_or_create_key(&path).unwrap(), key);
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        assert_eq!(read_or_create_key(&path).unwrap(), key);
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        fs::write(&path, "").unwrap();
+        assert!(read_or_create_key(&path).is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,153 @@
+//! Backups of the state centarr keeps in its database, like users, watch
+//! history and lists, together with the config file, for moving centarr or
+//! rebuilding its server without starting over.
+
+use std::io;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use serde::Serialize;
+
+use crate::{config, migrations, store, users, zip};
+
+/// Where the config file goes in the archive, and the tables, an SQLite
+/// database of them as they were at one moment.
+const CONFIG_ENTRY: &str = "config.json";
+const DATABASE_ENTRY: &str = "data/centarr.db";
+/// Left out as the artwork they index isn't backed up, and jobs are queued
+/// again by what made them.
+const LEFT_OUT: &[&str] = &["images", "jobs"];
+
+/// What a backup restored.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Restored {
+    /// The tables.
+    pub files: Vec<String>,
+    pub config: bool,
+}
+
+/// A file in the data dir for a snapshot of the tables, removed again when
+/// it's dropped.
+struct Snapshot(PathBuf);
+
+impl Snapshot {
+    async fn new() -> io::Result<Self> {
+        let data_dir = &config::get().data_dir;
+        tokio::fs::create_dir_all(data_dir).await?;
+
+        Ok(Snapshot(
+            data_dir.join(format!(".backup-{}.db", users::random_hex(8))),
+        ))
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// A zip of a snapshot of the tables and the config file, when there is
+/// one.
+pub async fn create() -> Result<Vec<u8>, String> {
+    let snapshot = Snapshot::new()
+        .await
+        .map_err(|e| format!("Can't make room for the snapshot: {}", e))?;
+    store::snapshot(&snapshot.0, LEFT_OUT)
+        .await
+        .map_err(|e| format!("Can't snapshot the tables: {}", e))?;
+    let database = tokio::fs::read(&snapshot.0)
+        .await
+        .map_err(|e| format!("Can't read the snapshot: {}", e))?;
+    let mut files = vec![(DATABASE_ENTRY.to_string(), database)];
+
+    let config_path = config::path();
+    match tokio::fs::read(&config_path).await {
+        Ok(contents) => files.push((CONFIG_ENTRY.to_string(), contents)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(format!("Can't read {:?}: {}", config_path, e)),
+    }
+
+    tracing::info!("Backed up the tables");
+    zip::archive(&files, SystemTime::now()).map_err(|e| format!("Can't zip the backup: {}", e))
+}
+
+/// The rows of each table in the snapshot `database`, refusing one of a
+/// newer version.
+async fn read_database(database: &[u8]) -> Result<store::Tables, String> {
+    let snapshot = Snapshot::new()
+        .await
+        .map_err(|e| format!("Can't make room for the snapshot: {}", e))?;
+    tokio::fs::write(&snapshot.0, database)
+        .await
+        .map_err(|e| format!("Can't write the snapshot: {}", e))?;
+    let (version, tables) = store::read_snapshot(&snapshot.0)
+        .await
+        .map_err(|e| format!("The backup's {} can't be read: {}", DATABASE_ENTRY, e))?;
+
+    if version > migrations::latest() {
+        return Err(format!(
+            "The backup is of version {}, newer than the {} this centarr knows",
+            version,
+            migrations::latest()
+        ));
+    }
+    Ok(tables)
+}
+
+/// Puts the tables of a backup in place, over what's there, refusing ones
+/// of a newer version. The config file is only replaced with
+/// `with_config`.
+pub async fn restore(archive: &[u8], with_config: bool) -> Result<Restored, String> {
+    let files = zip::read(archive).map_err(|e| format!("That's not a backup: {}", e))?;
+
+    let mut restored = Restored {
+        files: Vec::new(),
+        config: false,
+    };
+    let mut config_file = None;
+    let mut database = None;
+    for (name, contents) in files {
+        match name.as_str() {
+            // folders, from zipping a backup again with something else
+            _ if name.ends_with('/') => {}
+            DATABASE_ENTRY => database = Some(contents),
+            CONFIG_ENTRY => config_file = Some(contents),
+            _ => return Err(format!("{} doesn't belong in a backup", name)),
+        }
+    }
+
+    let mut tables = match database {
+        Some(database) => read_database(&database).await?,
+        None => return Err("The backup has none of the tables".into()),
+    };
+    tables.retain(|table, _| !LEFT_OUT.contains(table));
+    store::replace(&tables)
+        .await
+        .map_err(|e| format!("Can't restore the tables: {}", e))?;
+    restored.files = tables.keys().map(|table| table.to_string()).collect();
+
+    if let Some(contents) = config_file.filter(|_| with_config) {
+        let path = config::path();
+        let mut temp = path.clone().into_os_string();
+        temp.push(".tmp");
+        let written = match tokio::fs::write(&temp, &contents).await {
+            Ok(()) => tokio::fs::rename(&temp, &path).await,
+            Err(e) => Err(e),
+        };
+        written.map_err(|e| format!("Can't restore the config to {:?}: {}", path, e))?;
+        restored.config = true;
+    }
+
+    tracing::info!(
+        "Restored {} tables{}",
+        restored.files.len(),
+        if restored.config {
+            " and the config"
+        } else {
+            ""
+        }
+    );
+    Ok(restored)
+}
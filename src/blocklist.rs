@@ -0,0 +1,168 @@
+//! Sonarr's blocklist, the releases that failed to download or import and
+//! won't be grabbed again until they're taken off it.
+
+use axum::{
+    extract::{Path, Query},
+    http::StatusCode,
+    middleware,
+    routing::{delete, get},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    auth,
+    errors::ApiError,
+    sonarr::{self, ApiVersion},
+};
+
+const DEFAULT_PAGE_SIZE: u32 = 20;
+const MAX_PAGE_SIZE: u32 = 100;
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/blocklist", get(list))
+        .route("/blocklist/:id", delete(remove))
+        .route_layer(middleware::from_fn(auth::require_admin))
+}
+
+/// Where the blocklist is, which Sonarr v2 calls the blacklist.
+fn base() -> &'static str {
+    match sonarr::detected() {
+        Some(detected) if detected.api == ApiVersion::Legacy => "/blacklist",
+        _ => "/blocklist",
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BlocklistQuery {
+    #[serde(default = "first_page")]
+    page: u32,
+    #[serde(default = "default_page_size")]
+    page_size: u32,
+}
+
+fn first_page() -> u32 {
+    1
+}
+
+fn default_page_size() -> u32 {
+    DEFAULT_PAGE_SIZE
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SonarrPage {
+    page: u32,
+    page_size: u32,
+    total_records: u64,
+    records: Vec<SonarrBlocked>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SonarrBlocked {
+    id: i32,
+    series_id: i32,
+    #[serde(default)]
+    episode_ids: Vec<i32>,
+    source_title: String,
+    quality: Option<Value>,
+    date: String,
+    protocol: Option<String>,
+    indexer: Option<String>,
+    message: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Page {
+    page: u32,
+    page_size: u32,
+    total_records: u64,
+    /// Most recently blocked first.
+    records: Vec<Blocked>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Blocked {
+    id: i32,
+    series_id: i32,
+    series_title: Option<String>,
+    episode_ids: Vec<i32>,
+    /// The release's name.
+    source_title: String,
+    /// Like `WEBDL-1080p`.
+    quality: Option<String>,
+    date: String,
+    protocol: Option<String>,
+    indexer: Option<String>,
+    /// Why it failed, when Sonarr knows.
+    message: Option<String>,
+}
+
+/// A page of the blocklist, straight from Sonarr as it changes whenever a
+/// download fails.
+async fn list(Query(query): Query<BlocklistQuery>) -> Result<Json<Page>, ApiError> {
+    if query.page == 0 {
+        return Err(ApiError::new(400, "page starts at 1".into()));
+    }
+    if !(1..=MAX_PAGE_SIZE).contains(&query.page_size) {
+        return Err(ApiError::new(
+            400,
+            format!("pageSize should be between 1 and {}", MAX_PAGE_SIZE),
+        ));
+    }
+
+    // v2 sorts by sortDir, v3 by sortDirection
+    let body = sonarr::fetch(&format!(
+        "{}?page={}&pageSize={}&sortKey=date&sortDir=desc&sortDirection=descending",
+        base(),
+        query.page,
+        query.page_size
+    ))
+    .await?;
+    let page = serde_json::from_str::<SonarrPage>(&body)
+        .map_err(|e| ApiError::empty(500, Some(e.to_string())))?;
+    let series = sonarr::series_by_id().await?;
+
+    Ok(Json(Page {
+        page: page.page,
+        page_size: page.page_size,
+        total_records: page.total_records,
+        records: page
+            .records
+            .into_iter()
+            .map(|blocked| Blocked {
+                id: blocked.id,
+                series_id: blocked.series_id,
+                series_title: series
+                    .get(&blocked.series_id.into())
+                    .and_then(|series| series["title"].as_str())
+                    .map(String::from),
+                episode_ids: blocked.episode_ids,
+                source_title: blocked.source_title,
+                quality: blocked
+                    .quality
+                    .as_ref()
+                    .and_then(|quality| quality["quality"]["name"].as_str())
+                    .map(String::from),
+                date: blocked.date,
+                protocol: blocked.protocol,
+                indexer: blocked.indexer,
+                message: blocked.message,
+            })
+            .collect(),
+    }))
+}
+
+/// Takes a release off the blocklist, so Sonarr may grab it again.
+async fn remove(Path(id): Path<i32>) -> Result<StatusCode, ApiError> {
+    sonarr::delete(&format!("{}/{}", base(), id)).await?;
+    tracing::info!("Took {} off Sonarr's blocklist", id);
+
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,96 @@
+use std::f32::consts::PI;
+
+use image::RgbImage;
+
+const CHARACTERS: &[u8; 83] =
+    b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz#$%*+,-.:;=?@[]^_{|}~";
+
+fn base83(value: u32, length: u32, hash: &mut String) {
+    for i in 1..=length {
+        let digit = value / 83u32.pow(length - i) % 83;
+        hash.push(CHARACTERS[digit as usize] as char);
+    }
+}
+
+fn to_linear(value: u8) -> f32 {
+    let value = f32::from(value) / 255.0;
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn to_srgb(value: f32) -> u32 {
+    let value = value.clamp(0.0, 1.0);
+    if value <= 0.003_130_8 {
+        (value * 12.92 * 255.0 + 0.5) as u32
+    } else {
+        ((1.055 * value.powf(1.0 / 2.4) - 0.055) * 255.0 + 0.5) as u32
+    }
+}
+
+fn sign_pow(value: f32, exponent: f32) -> f32 {
+    value.abs().powf(exponent).copysign(value)
+}
+
+/// The blurhash of `image` with `x` by `y` components, each 1 to 9. It's
+/// slow for large images, which hardly look different scaled down first.
+pub fn encode(image: &RgbImage, x: u32, y: u32) -> String {
+    let (width, height) = image.dimensions();
+    let linear = image
+        .pixels()
+        .map(|pixel| pixel.0.map(to_linear))
+        .collect::<Vec<_>>();
+
+    let mut factors = Vec::with_capacity((x * y) as usize);
+    for j in 0..y {
+        for i in 0..x {
+            let normalisation = if i == 0 && j == 0 { 1.0 } else { 2.0 };
+            let mut factor = [0.0; 3];
+            for (index, pixel) in linear.iter().enumerate() {
+                let (px, py) = (index as u32 % width, index as u32 / width);
+                let basis = (PI * i as f32 * px as f32 / width as f32).cos()
+                    * (PI * j as f32 * py as f32 / height as f32).cos();
+                for channel in 0..3 {
+                    factor[channel] += basis * pixel[channel];
+                }
+            }
+            let scale = normalisation / (width * height) as f32;
+            factors.push(factor.map(|value| value * scale));
+        }
+    }
+
+    let (dc, ac) = factors.split_first().unwrap();
+    let mut hash = String::new();
+    base83((x - 1) + (y - 1) * 9, 1, &mut hash);
+
+    let maximum = if ac.is_empty() {
+        base83(0, 1, &mut hash);
+        1.0
+    } else {
+        let actual = ac
+            .iter()
+            .flatten()
+            .fold(0.0f32, |maximum, value| maximum.max(value.abs()));
+        let quantised = (actual * 166.0 - 0.5).floor().clamp(0.0, 82.0) as u32;
+        base83(quantised, 1, &mut hash);
+        (quantised + 1) as f32 / 166.0
+    };
+
+    base83(
+        (to_srgb(dc[0]) << 16) + (to_srgb(dc[1]) << 8) + to_srgb(dc[2]),
+        4,
+        &mut hash,
+    );
+    for factor in ac {
+        let [r, g, b] = factor.map(|value| {
+            (sign_pow(value / maximum, 0.5) * 9.0 + 9.5)
+                .floor()
+                .clamp(0.0, 18.0) as u32
+        });
+        base83(r * 19 * 19 + g * 19 + b, 2, &mut hash);
+    }
+
+    hash
+}
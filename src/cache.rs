@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::redis;
+
+/// Cache of upstream response bodies with a per-entry TTL, kept in memory
+/// or shared over Redis when there's a server.
+pub struct Cache {
+    /// Keys in Redis start with it.
+    name: &'static str,
+    entries: Mutex<HashMap<String, Entry>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+struct Entry {
+    body: String,
+    expires: Instant,
+}
+
+#[derive(Serialize)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl Cache {
+    pub fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            entries: Mutex::default(),
+            hits: AtomicU64::default(),
+            misses: AtomicU64::default(),
+        }
+    }
+
+    fn shared_key(&self, key: &str) -> String {
+        format!("cache:{}:{}", self.name, key)
+    }
+
+    pub async fn get(&self, key: &str) -> Option<String> {
+        if let Some(redis) = redis::shared() {
+            let body = match redis.get(&self.shared_key(key)).await {
+                Ok(body) => body.and_then(|body| String::from_utf8(body).ok()),
+                Err(e) => {
+                    tracing::warn!("Can't get {} from the shared cache: {}", key, e);
+                    None
+                }
+            };
+            let counter = match body {
+                Some(_) => &self.hits,
+                None => &self.misses,
+            };
+            counter.fetch_add(1, Ordering::Relaxed);
+            return body;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+
+        match entries.get(key) {
+            Some(entry) if entry.expires > Instant::now() => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Some(entry.body.clone())
+            }
+            Some(_) => {
+                entries.remove(key);
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    pub async fn insert(&self, key: String, body: String, ttl: Duration) {
+        if let Some(redis) = redis::shared() {
+            if let Err(e) = redis.set(&self.shared_key(&key), &body, ttl).await {
+                tracing::warn!("Can't add {} to the shared cache: {}", key, e);
+            }
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        let now = Instant::now();
+
+        entries.retain(|_, entry| entry.expires > now);
+        entries.insert(
+            key,
+            Entry {
+                body,
+                expires: now + ttl,
+            },
+        );
+    }
+
+    /// Drops every entry, returning how many there were.
+    pub async fn purge(&self) -> usize {
+        if let Some(redis) = redis::shared() {
+            let pattern = self.shared_key("*");
+            let purged = match redis.keys(&pattern).await {
+                Ok(keys) => redis.delete(&keys).await,
+                Err(e) => Err(e),
+            };
+            return purged
+                .map_err(|e| tracing::warn!("Can't purge the shared cache: {}", e))
+                .unwrap_or(0);
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        let purged = entries.len();
+        entries.clear();
+        purged
+    }
+
+    pub async fn stats(&self) -> CacheStats {
+        let entries = match redis::shared() {
+            Some(redis) => redis
+                .keys(&self.shared_key("*"))
+                .await
+                .map_err(|e| tracing::warn!("Can't count the shared cache: {}", e))
+                .map_or(0, |keys| keys.len()),
+            None => self.entries.lock().unwrap().len(),
+        };
+
+        CacheStats {
+            entries,
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -0,0 +1,189 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use once_cell::sync::OnceCell;
+use reqwest::StatusCode;
+use ring::digest;
+use serde::{Deserialize, Serialize};
+
+use crate::{errors::ApiError, users};
+
+/// Set by `--mock`, for developing and testing without the real upstreams.
+static MOCK: OnceCell<Mock> = OnceCell::new();
+/// The recorded responses when replaying, read once when first needed.
+static CASSETTES: OnceCell<HashMap<Key, Cassette>> = OnceCell::new();
+
+/// Upstream, method, path and request body.
+type Key = (String, String, String, Option<String>);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Mode {
+    /// Upstream responses are saved as they come in.
+    Record,
+    /// Upstreams aren't called, saved responses are answered with instead.
+    Replay,
+}
+
+#[derive(Debug)]
+pub struct Mock {
+    pub mode: Mode,
+    /// Where the responses are kept, a folder per upstream.
+    pub dir: PathBuf,
+}
+
+/// A response saved for a request. They're matched by what's in them, not
+/// by their file names, so they can be written by hand too.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct Cassette {
+    method: String,
+    path: String,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    request_body: Option<String>,
+    status: u16,
+    body: String,
+}
+
+/// Records or replays upstream responses from now on.
+pub fn init(mock: Mock) {
+    tracing::info!(
+        "{} upstream responses in {:?}",
+        match mock.mode {
+            Mode::Record => "Recording",
+            Mode::Replay => "Replaying",
+        },
+        mock.dir
+    );
+    MOCK.set(mock).expect("mocking should only be set up once");
+}
+
+pub fn mode() -> Option<Mode> {
+    MOCK.get().map(|mock| mock.mode)
+}
+
+fn key(service: &str, method: &str, path: &str, body: Option<&[u8]>) -> Key {
+    (
+        service.into(),
+        method.into(),
+        path.into(),
+        body.map(|body| String::from_utf8_lossy(body).into_owned()),
+    )
+}
+
+/// Every cassette in the folders of `dir`, each named after its upstream.
+fn load(dir: &Path) -> HashMap<Key, Cassette> {
+    let mut cassettes = HashMap::new();
+    let folders = std::fs::read_dir(dir).into_iter().flatten().flatten();
+
+    for folder in folders {
+        let service = folder.file_name().to_string_lossy().into_owned();
+        let files = std::fs::read_dir(folder.path())
+            .into_iter()
+            .flatten()
+            .flatten();
+        for file in files.map(|file| file.path()) {
+            let cassette = std::fs::read(&file)
+                .map_err(|e| e.to_string())
+                .and_then(|contents| {
+                    serde_json::from_slice::<Cassette>(&contents).map_err(|e| e.to_string())
+                });
+            match cassette {
+                Ok(cassette) => {
+                    let key = key(
+                        &service,
+                        &cassette.method,
+                        &cassette.path,
+                        cassette.request_body.as_deref().map(str::as_bytes),
+                    );
+                    cassettes.insert(key, cassette);
+                }
+                Err(e) => tracing::warn!("Skipping the cassette {:?}: {}", file, e),
+            }
+        }
+    }
+
+    tracing::debug!("Loaded {} cassettes", cassettes.len());
+    cassettes
+}
+
+/// The saved response to `method` `path`, a 503 when there's none.
+pub fn replay(
+    service: &str,
+    method: &str,
+    path: &str,
+    body: Option<&[u8]>,
+) -> Result<(StatusCode, String), ApiError> {
+    let mock = MOCK.get().expect("replaying needs --mock");
+    let cassettes = CASSETTES.get_or_init(|| load(&mock.dir));
+
+    cassettes
+        .get(&key(service, method, path, body))
+        .and_then(|cassette| {
+            let status = StatusCode::from_u16(cassette.status).ok()?;
+            Some((status, cassette.body.clone()))
+        })
+        .ok_or_else(|| {
+            ApiError::empty(
+                503,
+                Some(format!(
+                    "No {} response was recorded for {} {}",
+                    service, method, path
+                )),
+            )
+        })
+}
+
+/// Where a recording of `method` `path` goes, named so they're easy to tell
+/// apart.
+fn file(mock: &Mock, service: &str, method: &str, path: &str, body: Option<&[u8]>) -> PathBuf {
+    let mut key = format!("{} {}", method, path).into_bytes();
+    if let Some(body) = body {
+        key.push(b'\n');
+        key.extend_from_slice(body);
+    }
+    let hash = digest::digest(&digest::SHA256, &key);
+    let slug = path
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .take(60)
+        .collect::<String>();
+
+    mock.dir.join(service).join(format!(
+        "{}{}-{}.json",
+        method.to_lowercase(),
+        slug.trim_end_matches('-'),
+        users::hex(&hash.as_ref()[..4])
+    ))
+}
+
+/// Saves the response to `method` `path` when recording.
+pub async fn record(
+    service: &str,
+    method: &str,
+    path: &str,
+    body: Option<&[u8]>,
+    response: &(StatusCode, String),
+) {
+    let mock = match MOCK.get() {
+        Some(mock) if mock.mode == Mode::Record => mock,
+        _ => return,
+    };
+    let file = file(mock, service, method, path, body);
+    let cassette = Cassette {
+        method: method.into(),
+        path: path.into(),
+        request_body: body.map(|body| String::from_utf8_lossy(body).into_owned()),
+        status: response.0.as_u16(),
+        body: response.1.clone(),
+    };
+
+    let saved = async {
+        if let Some(folder) = file.parent() {
+            tokio::fs::create_dir_all(folder).await?;
+        }
+        tokio::fs::write(&file, serde_json::to_vec_pretty(&cassette)?).await
+    };
+    if let Err(e) = saved.await {
+        tracing::warn!("Failed to record {} {} to {:?}: {}", method, path, file, e);
+    }
+}
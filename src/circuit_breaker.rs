@@ -0,0 +1,112 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+/// Stops calling an upstream that keeps failing, letting a single trial
+/// request through once `cooldown` has passed.
+pub struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    consecutive_failures: u32,
+    open_until: Option<Instant>,
+    trial_in_flight: bool,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum State {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+#[derive(Serialize)]
+pub struct Status {
+    pub state: State,
+    pub consecutive_failures: u32,
+    pub retry_in_secs: Option<u64>,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            threshold,
+            cooldown,
+            inner: Mutex::new(Inner {
+                consecutive_failures: 0,
+                open_until: None,
+                trial_in_flight: false,
+            }),
+        }
+    }
+
+    /// Whether a request may be sent right now.
+    pub fn allow(&self) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+
+        match inner.open_until {
+            None => true,
+            Some(until) if until > Instant::now() => false,
+            Some(_) if inner.trial_in_flight => false,
+            Some(_) => {
+                inner.trial_in_flight = true;
+                true
+            }
+        }
+    }
+
+    pub fn record_success(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.consecutive_failures = 0;
+        inner.open_until = None;
+        inner.trial_in_flight = false;
+    }
+
+    /// Returns whether this failure opened the breaker.
+    pub fn record_failure(&self) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        inner.consecutive_failures += 1;
+        inner.trial_in_flight = false;
+
+        if inner.consecutive_failures < self.threshold {
+            return false;
+        }
+
+        let opened = inner.open_until.is_none();
+        if opened {
+            tracing::warn!(
+                "Upstream failed {} times in a row, pausing requests for {:?}",
+                inner.consecutive_failures,
+                self.cooldown
+            );
+        }
+        inner.open_until = Some(Instant::now() + self.cooldown);
+
+        opened
+    }
+
+    pub fn status(&self) -> Status {
+        let inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+
+        let state = match inner.open_until {
+            None => State::Closed,
+            Some(until) if until > now => State::Open,
+            Some(_) => State::HalfOpen,
+        };
+
+        Status {
+            state,
+            consecutive_failures: inner.consecutive_failures,
+            retry_in_secs: inner
+                .open_until
+                .filter(|until| *until > now)
+                .map(|until| (until - now).as_secs()),
+        }
+    }
+}
@@ -0,0 +1,368 @@
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use axum::http::StatusCode;
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, Subcommand};
+
+use crate::{backup, cassettes::Mode, config, ffmpeg, importer::Source, listen, migrations, store};
+
+#[derive(Parser, Debug)]
+#[command(version)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug, PartialEq, Eq)]
+pub enum Command {
+    /// Start the API and streaming servers (default)
+    Serve {
+        /// Save what upstreams answer, or answer with that instead of calling them
+        #[arg(long, value_enum)]
+        mock: Option<Mode>,
+        /// Where those answers are kept, <data dir>/cassettes by default
+        #[arg(long, value_name = "DIR", requires = "mock")]
+        cassettes: Option<PathBuf>,
+    },
+    /// Validate the configuration and Sonarr connectivity
+    Check,
+    /// Run diagnostics on path mappings, ffmpeg and ports
+    Doctor,
+    /// Copy what was watched on Jellyfin or Plex, while centarr isn't running
+    Import {
+        /// Where the watch state comes from
+        #[arg(long, value_enum)]
+        from: Source,
+        /// The server's url
+        #[arg(long)]
+        url: String,
+        /// A Jellyfin API key, or the X-Plex-Token of the Plex user
+        #[arg(long, value_name = "KEY")]
+        api_key: String,
+        /// Only this Jellyfin user's watch state, everyone's by default
+        #[arg(long, value_name = "NAME")]
+        user: Option<String>,
+        /// The centarr user it becomes the watch state of, by default the one of who streams without logging in
+        #[arg(long = "as", value_name = "NAME")]
+        viewer: Option<String>,
+    },
+    /// Bring the database's tables to a version, while centarr isn't running
+    Migrate {
+        /// The version to migrate to, back to an older one to downgrade, the latest by default
+        #[arg(long, value_name = "VERSION")]
+        to: Option<i64>,
+        /// Only list the migrations that would run
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Put a backup from POST /admin/backup in place, while centarr isn't running
+    Restore {
+        /// The backup
+        file: PathBuf,
+        /// Keep the current config file instead of the backup's
+        #[arg(long)]
+        without_config: bool,
+    },
+}
+
+/// The command in `args`, the first being the program's name, a bare
+/// `centarr` serving.
+pub fn parse<I, T>(args: I) -> Result<Command, clap::Error>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<OsString> + Clone,
+{
+    let command = Cli::try_parse_from(args)?
+        .command
+        .unwrap_or(Command::Serve {
+            mock: None,
+            cassettes: None,
+        });
+
+    if let Command::Import {
+        from: Source::Plex,
+        user: Some(_),
+        ..
+    } = command
+    {
+        return Err(Cli::command().error(
+            ErrorKind::ArgumentConflict,
+            "--user only goes with Jellyfin, Plex tokens are per user",
+        ));
+    }
+
+    Ok(command)
+}
+
+fn report(ok: bool, message: impl AsRef<str>) -> bool {
+    println!(
+        "[{}] {}",
+        if ok { " ok " } else { "fail" },
+        message.as_ref()
+    );
+    ok
+}
+
+async fn check_sonarr() -> bool {
+    match crate::sonarr::detect(&crate::config::get()).await {
+        Ok(detected) => report(
+            true,
+            format!(
+                "Sonarr {} is reachable, using its {:?} API",
+                detected.version, detected.api
+            ),
+        ),
+        Err(e) if e.status_code() == StatusCode::UNAUTHORIZED => report(
+            false,
+            "Sonarr responded with 401 Unauthorized, is SONARR_API_KEY correct?",
+        ),
+        Err(e) => report(
+            false,
+            format!(
+                "Sonarr can't be reached at SONARR_URL ({})",
+                e.status_code()
+            ),
+        ),
+    }
+}
+
+pub async fn migrate(to: Option<i64>, dry_run: bool) -> ExitCode {
+    let status = match migrations::status().await {
+        Ok(status) => status,
+        Err(e) => {
+            report(false, e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let to = to.unwrap_or(status.latest);
+    report(
+        true,
+        format!(
+            "the tables are at version {}, the latest is {}",
+            status.version, status.latest
+        ),
+    );
+
+    let migrated = migrations::migrate(to, dry_run, |step| {
+        println!(
+            "       {}{} {}: {}",
+            if dry_run { "would " } else { "" },
+            if step.undo { "undo" } else { "apply" },
+            step.migration.version,
+            step.migration.description
+        );
+    })
+    .await;
+
+    let message = match migrated {
+        Ok(steps) if steps.is_empty() => format!("already at version {}", to),
+        Ok(_) if dry_run => format!(
+            "nothing changed, run without --dry-run to migrate to {}",
+            to
+        ),
+        Ok(_) => format!("migrated to version {}", to),
+        Err(e) => {
+            report(false, e);
+            return ExitCode::FAILURE;
+        }
+    };
+    report(true, message);
+
+    ExitCode::SUCCESS
+}
+
+pub async fn restore(file: &Path, with_config: bool) -> ExitCode {
+    let archive = match tokio::fs::read(file).await {
+        Ok(archive) => archive,
+        Err(e) => {
+            report(false, format!("{:?} can't be read: {}", file, e));
+            return ExitCode::FAILURE;
+        }
+    };
+
+    if let Err(e) = store::open().await {
+        report(false, e);
+        return ExitCode::FAILURE;
+    }
+    match backup::restore(&archive, with_config).await {
+        Ok(restored) => {
+            report(
+                true,
+                format!(
+                    "restored {} to {:?}",
+                    restored.files.join(", "),
+                    config::get().data_dir
+                ),
+            );
+            if restored.config {
+                report(true, format!("restored the config to {:?}", config::path()));
+            }
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            report(false, e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+pub async fn check() -> ExitCode {
+    report(true, "configuration is valid");
+
+    if check_sonarr().await {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+pub async fn doctor() -> ExitCode {
+    let config = config::get();
+    let mut healthy = report(true, "configuration is valid");
+    healthy &= check_sonarr().await;
+
+    for mapping in &config.path_mappings {
+        healthy &= report(
+            mapping.local.is_dir(),
+            format!(
+                "path mapping {:?} -> {:?} resolves to a directory",
+                mapping.remote, mapping.local
+            ),
+        );
+    }
+
+    let probe = config.data_dir.join(".doctor");
+    let writable = std::fs::create_dir_all(&config.data_dir)
+        .and_then(|_| std::fs::write(&probe, b""))
+        .and_then(|_| std::fs::remove_file(&probe));
+    healthy &= match writable {
+        Ok(()) => report(true, format!("data dir {:?} is writable", config.data_dir)),
+        Err(e) => report(
+            false,
+            format!("data dir {:?} is not writable: {}", config.data_dir, e),
+        ),
+    };
+
+    let detected = ffmpeg::detect(&config).await;
+    for (name, path, tool) in [
+        ("ffmpeg", &config.ffmpeg_path, &detected.ffmpeg),
+        ("ffprobe", &config.ffprobe_path, &detected.ffprobe),
+    ] {
+        healthy &= match (&tool.version, &tool.error) {
+            (Some(version), _) => report(true, version),
+            (_, Some(e)) => report(false, format!("{} at {:?} {}", name, path, e)),
+            _ => report(true, format!("{} is present", name)),
+        };
+    }
+    for missing in &detected.missing {
+        healthy &= report(false, format!("ffmpeg is built with {}", missing));
+    }
+
+    for (name, addrs) in [
+        ("api", &config.api_addr),
+        ("streaming", &config.stream_addr),
+    ] {
+        let listed = addrs
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(", ");
+        healthy &= match listen::bind(name, addrs, config.socket_mode).await {
+            Ok(_) => report(true, format!("{} {} can be listened on", name, listed)),
+            Err(e) => report(false, e),
+        };
+    }
+
+    if healthy {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_args(args: &str) -> Result<Command, ErrorKind> {
+        parse(std::iter::once("centarr").chain(args.split_whitespace())).map_err(|e| e.kind())
+    }
+
+    #[test]
+    fn the_commands_are_consistent() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn arguments_are_parsed_into_commands() {
+        let serve = Command::Serve {
+            mock: None,
+            cassettes: None,
+        };
+        assert_eq!(parse_args(""), Ok(serve));
+        assert_eq!(
+            parse_args("serve --mock replay --cassettes /tmp/c"),
+            Ok(Command::Serve {
+                mock: Some(Mode::Replay),
+                cassettes: Some("/tmp/c".into()),
+            })
+        );
+        assert_eq!(
+            parse_args("migrate --to 1 --dry-run"),
+            Ok(Command::Migrate {
+                to: Some(1),
+                dry_run: true,
+            })
+        );
+        assert_eq!(
+            parse_args("restore backup.zip --without-config"),
+            Ok(Command::Restore {
+                file: "backup.zip".into(),
+                without_config: true,
+            })
+        );
+        assert_eq!(
+            parse_args("import --from jellyfin --url http://jf --api-key k --user bob --as amy"),
+            Ok(Command::Import {
+                from: Source::Jellyfin,
+                url: "http://jf".into(),
+                api_key: "k".into(),
+                user: Some("bob".into()),
+                viewer: Some("amy".into()),
+            })
+        );
+    }
+
+    #[test]
+    fn wrong_arguments_are_refused() {
+        assert_eq!(parse_args("--help"), Err(ErrorKind::DisplayHelp));
+        assert_eq!(parse_args("serve --mock"), Err(ErrorKind::InvalidValue));
+        assert_eq!(
+            parse_args("serve --mock rewind"),
+            Err(ErrorKind::InvalidValue)
+        );
+        assert_eq!(
+            parse_args("serve --cassettes /tmp/c"),
+            Err(ErrorKind::MissingRequiredArgument)
+        );
+        assert_eq!(
+            parse_args("migrate --to latest"),
+            Err(ErrorKind::ValueValidation)
+        );
+        assert_eq!(
+            parse_args("import --from plex --url http://plex --api-key k --user bob"),
+            Err(ErrorKind::ArgumentConflict)
+        );
+        assert_eq!(
+            parse_args("import --from plex"),
+            Err(ErrorKind::MissingRequiredArgument)
+        );
+        assert_eq!(
+            parse_args("restore"),
+            Err(ErrorKind::MissingRequiredArgument)
+        );
+        assert_eq!(parse_args("rewind"), Err(ErrorKind::InvalidSubcommand));
+    }
+}
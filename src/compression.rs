@@ -0,0 +1,92 @@
+use axum::{
+    http::{header, Extensions, HeaderMap, HeaderValue, Request, StatusCode, Version},
+    middleware::Next,
+    response::Response,
+};
+use tower_http::compression::{
+    predicate::{DefaultPredicate, NotForContentType, Predicate},
+    CompressionLayer,
+};
+
+use crate::config;
+
+/// Compressing part of a file gives a range of the compressed file, which
+/// isn't what was asked for.
+fn not_partial(status: StatusCode, _: Version, headers: &HeaderMap, _: &Extensions) -> bool {
+    status != StatusCode::PARTIAL_CONTENT && !headers.contains_key(header::CONTENT_RANGE)
+}
+
+/// Zstd, gzip, brotli or deflate compression, whichever the client prefers, of
+/// responses that aren't media. Media is compressed already, and event
+/// streams have to reach clients as each event happens rather than when
+/// the compressor's buffer fills.
+pub fn layer() -> CompressionLayer<impl Predicate> {
+    let predicate = DefaultPredicate::new()
+        .and(not_partial as fn(_, _, &_, &_) -> _)
+        .and(NotForContentType::const_new("video/"))
+        .and(NotForContentType::const_new("audio/"))
+        .and(NotForContentType::const_new("application/zip"))
+        .and(NotForContentType::const_new("application/octet-stream"))
+        .and(NotForContentType::const_new("text/event-stream"));
+
+    CompressionLayer::new().compress_when(predicate)
+}
+
+/// Hides `Accept-Encoding` from the compression layer when compression is
+/// off or the path is excluded, so the response is sent as it is. Goes on
+/// top of [`layer`], and checks the config per request so it's reloaded.
+pub async fn exclude<B>(mut req: Request<B>, next: Next<B>) -> Response {
+    let config = config::get();
+    let excluded = !config.compression
+        || config
+            .uncompressed_paths
+            .iter()
+            .any(|prefix| req.uri().path().starts_with(prefix.as_str()));
+
+    if excluded {
+        req.headers_mut().remove(header::ACCEPT_ENCODING);
+        return next.run(req).await;
+    }
+
+    let mut res = next.run(req).await;
+    let headers = res.headers_mut();
+    headers.append(header::VARY, HeaderValue::from_static("accept-encoding"));
+
+    // the compressed body isn't byte for byte what the tag was made of
+    if headers.contains_key(header::CONTENT_ENCODING) {
+        let weak = headers
+            .get(header::ETAG)
+            .and_then(|etag| etag.to_str().ok())
+            .filter(|etag| !etag.starts_with("W/"))
+            .and_then(|etag| HeaderValue::from_str(&format!("W/{}", etag)).ok());
+        if let Some(weak) = weak {
+            headers.insert(header::ETAG, weak);
+        }
+    }
+
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, routing::get, Router};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn zstd_is_offered() {
+        config::init_for_tests();
+        let app = Router::new()
+            .route("/shows", get(|| async { "[]".repeat(1000) }))
+            .layer(layer())
+            .layer(axum::middleware::from_fn(exclude));
+
+        let req = Request::get("/shows")
+            .header(header::ACCEPT_ENCODING, "zstd")
+            .body(Body::empty())
+            .unwrap();
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(res.headers()[header::CONTENT_ENCODING], "zstd");
+    }
+}
@@ -0,0 +1,1114 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use arc_swap::ArcSwap;
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize, Serializer};
+
+use crate::{
+    events::Event,
+    postgres, redis,
+    scheduler::{Cron, Task},
+    storage, upstream,
+};
+
+/// Swapped whole on reloads, so reading it never waits on one.
+static CONFIG: OnceCell<ArcSwap<Config>> = OnceCell::new();
+
+const DEFAULT_CONFIG_PATH: &str = "/etc/centarr/config.json";
+const DEFAULT_DATA_DIR: &str = "/var/lib/centarr";
+/// A gigabyte, some thousands of posters and fanart.
+const DEFAULT_IMAGE_CACHE_SIZE: u64 = 1_000_000_000;
+const DEFAULT_STREAM_CHUNK_SIZE: u64 = 1_048_576;
+/// Owner and group can connect, like the reverse proxy's user when it's
+/// in centarr's group.
+const DEFAULT_SOCKET_MODE: u32 = 0o660;
+const DEFAULT_LOG_LEVEL: &str = "centarr=debug,tower_http=debug";
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Config {
+    pub sonarr: UpstreamConfig,
+    pub lidarr: Option<UpstreamConfig>,
+    pub readarr: Option<UpstreamConfig>,
+    pub radarr: Option<UpstreamConfig>,
+    pub prowlarr: Option<UpstreamConfig>,
+    pub qbittorrent: Option<QbittorrentConfig>,
+    pub sabnzbd: Option<UpstreamConfig>,
+    /// How long successful upstream responses are reused.
+    #[serde(serialize_with = "as_secs")]
+    pub cache_ttl: Duration,
+    /// How long a call to Sonarr, the other *arrs or a download client may
+    /// take before it's given up on.
+    #[serde(serialize_with = "as_secs")]
+    pub upstream_timeout: Duration,
+    /// How long the API may take to answer a request.
+    #[serde(serialize_with = "as_secs")]
+    pub request_timeout: Duration,
+    /// Both of the above for indexer searches, which wait on every indexer.
+    #[serde(serialize_with = "as_secs")]
+    pub search_timeout: Duration,
+    /// How many calls to all the *arrs together are made at a time, the
+    /// rest waiting their turn.
+    pub upstream_concurrency: Option<usize>,
+    /// The same for each of them on its own, by name like `sonarr`.
+    pub upstream_limits: BTreeMap<String, usize>,
+    /// Whether shows and episodes Sonarr sends in a shape that can't be
+    /// read are left out of listings, instead of failing them.
+    pub skip_unreadable: bool,
+    pub path_mappings: Vec<PathMapping>,
+    /// Every address the API listens on, `[::]` listens on IPv4 too
+    /// unless an IPv4 address with the same port is listed.
+    pub api_addr: Vec<ListenAddr>,
+    pub stream_addr: Vec<ListenAddr>,
+    /// Permissions of the Unix sockets listened on.
+    #[serde(serialize_with = "as_octal")]
+    pub socket_mode: u32,
+    pub web_root: Option<PathBuf>,
+    pub ffmpeg_path: PathBuf,
+    pub ffprobe_path: PathBuf,
+    /// Whether a static ffmpeg build is downloaded into the data dir on
+    /// startup when it isn't there yet, and used unless the paths are set.
+    pub download_ffmpeg: bool,
+    /// Where it's downloaded from, a `.tar.xz` with `ffmpeg` and `ffprobe`
+    /// in it.
+    pub ffmpeg_download_url: String,
+    pub log_level: String,
+    /// Upper bound on how fast a single stream is sent, in bytes per second.
+    pub max_stream_rate: Option<u64>,
+    /// Bytes of a stream sent at full speed, for the player to buffer,
+    /// before the rest is paced to a little over the file's bitrate.
+    pub stream_burst: Option<u64>,
+    /// Bytes handed to the kernel at a time when streaming.
+    pub stream_chunk_size: u64,
+    /// Percent of an episode played, or streamed, past which it counts as
+    /// watched.
+    pub watched_threshold: u64,
+    /// Whether streams start with small chunks after a seek and send
+    /// bigger ones the longer they read on.
+    pub adaptive_chunks: bool,
+    /// Whether `TCP_NODELAY` is set on streaming connections.
+    pub tcp_nodelay: bool,
+    /// `SO_SNDBUF` of streaming connections, the kernel's default when
+    /// unset.
+    pub send_buffer_size: Option<u64>,
+    /// Idle time before keepalive probes are sent on streaming
+    /// connections, none are when unset.
+    #[serde(serialize_with = "as_optional_secs")]
+    pub tcp_keepalive: Option<Duration>,
+    /// Upper bound on how fast a single download is sent, in bytes per
+    /// second.
+    pub max_download_rate: Option<u64>,
+    /// Whether whole seasons can be downloaded as a zip.
+    pub zip_downloads: bool,
+    /// Whether API responses are compressed for clients that accept it.
+    pub compression: bool,
+    /// API paths starting with any of these are never compressed.
+    pub uncompressed_paths: Vec<String>,
+    pub notifications: Vec<NotificationConfig>,
+    pub webhooks: Vec<WebhookConfig>,
+    /// Folders watched for media files appearing and disappearing.
+    pub media_roots: Vec<PathBuf>,
+    /// Where centarr keeps its own state, like the synced library.
+    pub data_dir: PathBuf,
+    /// A `postgres://` url of the database the state goes in instead of
+    /// the data dir, caches like artwork and thumbnails staying there.
+    #[serde(serialize_with = "redact_optional")]
+    pub database_url: Option<String>,
+    /// A `redis://` or `rediss://` url of the server the response cache, logged out
+    /// devices and streaming sessions are shared over, for running more
+    /// than one centarr behind a load balancer.
+    #[serde(serialize_with = "redact_optional")]
+    pub redis_url: Option<String>,
+    /// How often the library is fully synced from Sonarr, zero turns
+    /// syncing off and proxies every request instead.
+    #[serde(serialize_with = "as_secs")]
+    pub sync_interval: Duration,
+    /// Required as `?token=` on `/webhooks/sonarr` when set.
+    #[serde(serialize_with = "redact_optional")]
+    pub webhook_token: Option<String>,
+    /// Bearer token guarding `/admin`, which is disabled without one.
+    #[serde(serialize_with = "redact_optional")]
+    pub admin_token: Option<String>,
+    /// Whether the API and stream server only answer logged in users.
+    pub require_auth: bool,
+    /// Signs access tokens, one is generated and kept in
+    /// `signing_key_path` when unset.
+    #[serde(serialize_with = "redact_optional")]
+    pub jwt_secret: Option<String>,
+    /// Where the generated signing key is kept, readable only by centarr.
+    /// It's outside the data dir and media roots, so it can't be streamed.
+    pub signing_key_path: PathBuf,
+    pub oidc: Option<OidcConfig>,
+    pub trakt: Option<TraktConfig>,
+    pub upstream_tls: UpstreamTlsConfig,
+    /// Lowercased labels of Sonarr and Radarr tags whose shows and movies
+    /// are hidden from users not allowed them.
+    pub restricted_tags: Vec<String>,
+    /// Whether intros are looked for in the audio of synced episodes.
+    pub detect_intros: bool,
+    /// Widths of the seek bar thumbnails made for synced episodes, none
+    /// are made when empty.
+    pub trickplay_widths: Vec<u32>,
+    /// Whether the artwork of every show is fetched ahead of time.
+    pub prefetch_images: bool,
+    /// Bytes of artwork kept in the data dir, the least recently used goes
+    /// first when there's more.
+    pub image_cache_size: u64,
+    /// Cron expressions of scheduled tasks by name, or `"off"`, replacing
+    /// their defaults.
+    pub schedules: BTreeMap<String, String>,
+    /// How many queued jobs run at a time.
+    pub job_workers: usize,
+    /// Whether the API is advertised on the local network over mDNS.
+    pub mdns: bool,
+    /// What clients finding centarr on the network show it as, the host
+    /// name when unset.
+    pub server_name: Option<String>,
+    /// Whether the router is asked to forward the API and streaming ports
+    /// over NAT-PMP or UPnP.
+    pub port_forwarding: bool,
+    /// The API of the centarr this one streams for as an edge, checking in
+    /// with it instead of being the one clients browse.
+    pub primary_url: Option<String>,
+    /// Where clients reach this edge's streaming server.
+    pub edge_url: Option<String>,
+    /// Shared by the primary and its edges, which check in with it and
+    /// verify the watch urls it signs with it.
+    #[serde(serialize_with = "redact_optional")]
+    pub edge_token: Option<String>,
+}
+
+/// A TCP address, or the path of a Unix socket written as `unix:/path`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl std::str::FromStr for ListenAddr {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s.strip_prefix("unix:") {
+            Some(path) if Path::new(path).is_absolute() => Ok(ListenAddr::Unix(path.into())),
+            Some(_) => Err(()),
+            None => s.parse().map(ListenAddr::Tcp).map_err(|_| ()),
+        }
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => addr.fmt(f),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl Serialize for ListenAddr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Where to reach one of the *arr services.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct UpstreamConfig {
+    pub url: String,
+    #[serde(serialize_with = "redact")]
+    pub api_key: String,
+}
+
+/// qBittorrent's WebUI, which logs in with a username and password.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct QbittorrentConfig {
+    pub url: String,
+    pub username: String,
+    #[serde(serialize_with = "redact")]
+    pub password: String,
+}
+
+/// How HTTPS connections to upstream services are verified, for ones
+/// behind self-signed certificates or requiring client certificates.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpstreamTlsConfig {
+    /// PEM file of CA certificates trusted besides the usual ones.
+    pub ca_cert: Option<PathBuf>,
+    /// Skips verifying certificates altogether.
+    pub accept_invalid_certs: bool,
+    /// PEM file of the certificate presented to upstreams, and its key
+    /// unless `client_key` is set.
+    pub client_cert: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+}
+
+/// An OpenID Connect provider, like Authelia or Keycloak, people can log
+/// in with instead of a password.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct OidcConfig {
+    /// Where `/.well-known/openid-configuration` is found.
+    pub issuer: String,
+    pub client_id: String,
+    #[serde(serialize_with = "redact")]
+    pub client_secret: String,
+    /// Registered with the provider, `<scheme>://<host>/auth/oidc/callback`
+    /// of the request when unset.
+    #[serde(default)]
+    pub redirect_url: Option<String>,
+    /// The ID token claim holding the centarr user name.
+    #[serde(default = "default_username_claim")]
+    pub username_claim: String,
+}
+
+fn default_username_claim() -> String {
+    "preferred_username".into()
+}
+
+/// A Trakt API app, which users link their Trakt account to so what they
+/// play is scrobbled there.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TraktConfig {
+    pub client_id: String,
+    #[serde(serialize_with = "redact")]
+    pub client_secret: String,
+    /// `https://api.trakt.tv` unless set, like to the staging API's.
+    #[serde(default = "default_trakt_url")]
+    pub url: String,
+}
+
+fn default_trakt_url() -> String {
+    "https://api.trakt.tv".into()
+}
+
+/// Where to send notifications to, and for which events.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct NotificationConfig {
+    #[serde(flatten)]
+    pub notifier: Notifier,
+    /// Event kinds to send, all of them when empty.
+    #[serde(default)]
+    pub events: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Notifier {
+    Discord {
+        #[serde(serialize_with = "redact")]
+        webhook_url: String,
+    },
+    Pushover {
+        #[serde(serialize_with = "redact")]
+        token: String,
+        #[serde(serialize_with = "redact")]
+        user: String,
+    },
+    Ntfy {
+        /// The topic, like `https://ntfy.sh/centarr`.
+        url: String,
+        #[serde(default, serialize_with = "redact_optional")]
+        token: Option<String>,
+    },
+    Webhook {
+        url: String,
+    },
+}
+
+/// A url every event is POSTed to as JSON.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct WebhookConfig {
+    pub url: String,
+    /// Signs each body with HMAC-SHA256, sent as `X-Centarr-Signature`.
+    #[serde(default, serialize_with = "redact_optional")]
+    pub secret: Option<String>,
+    /// Event kinds to send, all of them when empty.
+    #[serde(default)]
+    pub events: Vec<String>,
+}
+
+/// Rewrites paths as Sonarr sees them to where the same files live on this machine.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PathMapping {
+    pub remote: PathBuf,
+    pub local: PathBuf,
+}
+
+/// The optional JSON config file, every value in it can be overridden by
+/// the matching environment variable.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    sonarr_url: Option<String>,
+    sonarr_api_key: Option<String>,
+    lidarr: Option<UpstreamConfig>,
+    readarr: Option<UpstreamConfig>,
+    radarr: Option<UpstreamConfig>,
+    prowlarr: Option<UpstreamConfig>,
+    qbittorrent: Option<QbittorrentConfig>,
+    sabnzbd: Option<UpstreamConfig>,
+    cache_ttl: Option<u64>,
+    upstream_timeout: Option<u64>,
+    request_timeout: Option<u64>,
+    search_timeout: Option<u64>,
+    upstream_concurrency: Option<u64>,
+    #[serde(default)]
+    upstream_limits: BTreeMap<String, usize>,
+    skip_unreadable: Option<bool>,
+    path_mappings: Vec<PathMapping>,
+    api_addr: Option<String>,
+    stream_addr: Option<String>,
+    socket_mode: Option<String>,
+    web_root: Option<PathBuf>,
+    ffmpeg_path: Option<PathBuf>,
+    ffprobe_path: Option<PathBuf>,
+    download_ffmpeg: Option<bool>,
+    ffmpeg_download_url: Option<String>,
+    log_level: Option<String>,
+    max_stream_rate: Option<u64>,
+    stream_burst: Option<u64>,
+    stream_chunk_size: Option<u64>,
+    watched_threshold: Option<u64>,
+    adaptive_chunks: Option<bool>,
+    tcp_nodelay: Option<bool>,
+    send_buffer_size: Option<u64>,
+    tcp_keepalive: Option<u64>,
+    max_download_rate: Option<u64>,
+    zip_downloads: Option<bool>,
+    compression: Option<bool>,
+    uncompressed_paths: Vec<String>,
+    notifications: Vec<NotificationConfig>,
+    webhooks: Vec<WebhookConfig>,
+    media_roots: Vec<PathBuf>,
+    data_dir: Option<PathBuf>,
+    database_url: Option<String>,
+    redis_url: Option<String>,
+    sync_interval: Option<u64>,
+    webhook_token: Option<String>,
+    admin_token: Option<String>,
+    require_auth: Option<bool>,
+    detect_intros: Option<bool>,
+    jwt_secret: Option<String>,
+    signing_key_path: Option<PathBuf>,
+    oidc: Option<OidcConfig>,
+    trakt: Option<TraktConfig>,
+    upstream_tls: UpstreamTlsConfig,
+    restricted_tags: Vec<String>,
+    trickplay_widths: Vec<u32>,
+    prefetch_images: Option<bool>,
+    image_cache_size: Option<u64>,
+    schedules: BTreeMap<String, String>,
+    job_workers: Option<u64>,
+    mdns: Option<bool>,
+    server_name: Option<String>,
+    port_forwarding: Option<bool>,
+    primary_url: Option<String>,
+    edge_url: Option<String>,
+    edge_token: Option<String>,
+}
+
+fn redact<T: ?Sized, S: Serializer>(_: &T, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str("********")
+}
+
+fn redact_optional<T, S: Serializer>(value: &Option<T>, serializer: S) -> Result<S::Ok, S::Error> {
+    match value {
+        Some(_) => serializer.serialize_str("********"),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn as_secs<S: Serializer>(value: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(value.as_secs())
+}
+
+fn as_octal<S: Serializer>(value: &u32, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(&format_args!("{:04o}", value))
+}
+
+fn as_optional_secs<S: Serializer>(
+    value: &Option<Duration>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match value {
+        Some(value) => serializer.serialize_u64(value.as_secs()),
+        None => serializer.serialize_none(),
+    }
+}
+
+#[derive(Debug)]
+pub struct ConfigError(Vec<String>);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "invalid configuration:")?;
+        for problem in &self.0 {
+            writeln!(f, "  - {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+/// Path of the config file, `CENTARR_CONFIG` or `/etc/centarr/config.json`.
+pub fn path() -> PathBuf {
+    env::var("CENTARR_CONFIG")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| PathBuf::from(DEFAULT_CONFIG_PATH))
+}
+
+fn read_file(problems: &mut Vec<String>) -> ConfigFile {
+    let path = path();
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        // the file is optional unless it was asked for explicitly
+        Err(_) if env::var("CENTARR_CONFIG").is_err() => return ConfigFile::default(),
+        Err(e) => {
+            problems.push(format!("can't read {:?}: {}", path, e));
+            return ConfigFile::default();
+        }
+    };
+
+    serde_json::from_str(&contents).unwrap_or_else(|e| {
+        problems.push(format!("{:?} is not valid: {}", path, e));
+        ConfigFile::default()
+    })
+}
+
+impl Config {
+    /// Reads the config file and environment.
+    pub fn load() -> Result<Self, ConfigError> {
+        let mut problems = Vec::new();
+        let file = read_file(&mut problems);
+
+        let mut required = |name: &str, value: Option<String>| match env::var(name).ok().or(value) {
+            Some(value) if !value.is_empty() => value,
+            _ => {
+                problems.push(format!("{} is not set", name));
+                String::new()
+            }
+        };
+        let sonarr = UpstreamConfig {
+            url: required("SONARR_URL", file.sonarr_url),
+            api_key: required("SONARR_API_KEY", file.sonarr_api_key),
+        };
+
+        let mut optional = |name: &str, value: Option<UpstreamConfig>| match (
+            env::var(format!("{}_URL", name)),
+            env::var(format!("{}_API_KEY", name)),
+        ) {
+            (Ok(url), Ok(api_key)) => Some(UpstreamConfig { url, api_key }),
+            (Ok(_), Err(_)) => {
+                problems.push(format!("{0}_URL is set without {0}_API_KEY", name));
+                None
+            }
+            _ => value,
+        };
+        let lidarr = optional("LIDARR", file.lidarr);
+        let readarr = optional("READARR", file.readarr);
+        let radarr = optional("RADARR", file.radarr);
+        let prowlarr = optional("PROWLARR", file.prowlarr);
+        let sabnzbd = optional("SABNZBD", file.sabnzbd);
+
+        let mut upstream = |name: &str, mut upstream: UpstreamConfig| {
+            upstream.url = upstream.url.trim_end_matches('/').to_string();
+            if !upstream.url.is_empty()
+                && !upstream.url.starts_with("http://")
+                && !upstream.url.starts_with("https://")
+            {
+                problems.push(format!(
+                    "{} url {:?} is not a http(s) url",
+                    name, upstream.url
+                ));
+            }
+            upstream
+        };
+        let sonarr = upstream("Sonarr", sonarr);
+        let lidarr = lidarr.map(|lidarr| upstream("Lidarr", lidarr));
+        let readarr = readarr.map(|readarr| upstream("Readarr", readarr));
+        let radarr = radarr.map(|radarr| upstream("Radarr", radarr));
+        let prowlarr = prowlarr.map(|prowlarr| upstream("Prowlarr", prowlarr));
+        let sabnzbd = sabnzbd.map(|sabnzbd| upstream("SABnzbd", sabnzbd));
+
+        let qbittorrent = match env::var("QBITTORRENT_URL") {
+            Ok(url) => Some(QbittorrentConfig {
+                url: url.trim_end_matches('/').to_string(),
+                username: env::var("QBITTORRENT_USERNAME").unwrap_or_default(),
+                password: env::var("QBITTORRENT_PASSWORD").unwrap_or_default(),
+            }),
+            Err(_) => file.qbittorrent,
+        };
+
+        let oidc = match env::var("OIDC_ISSUER_URL") {
+            Ok(issuer) => Some(OidcConfig {
+                issuer: issuer.trim_end_matches('/').to_string(),
+                client_id: env::var("OIDC_CLIENT_ID").unwrap_or_default(),
+                client_secret: env::var("OIDC_CLIENT_SECRET").unwrap_or_default(),
+                redirect_url: env::var("OIDC_REDIRECT_URL").ok(),
+                username_claim: env::var("OIDC_USERNAME_CLAIM")
+                    .unwrap_or_else(|_| default_username_claim()),
+            }),
+            Err(_) => file.oidc,
+        };
+        if let Some(oidc) = &oidc {
+            if oidc.client_id.is_empty() || oidc.client_secret.is_empty() {
+                problems.push("OIDC needs both a client id and client secret".into());
+            }
+        }
+
+        let trakt = match env::var("TRAKT_CLIENT_ID") {
+            Ok(client_id) => Some(TraktConfig {
+                client_id,
+                client_secret: env::var("TRAKT_CLIENT_SECRET").unwrap_or_default(),
+                url: env::var("TRAKT_API_URL")
+                    .map(|url| url.trim_end_matches('/').to_string())
+                    .unwrap_or_else(|_| default_trakt_url()),
+            }),
+            Err(_) => file.trakt,
+        };
+        if let Some(trakt) = &trakt {
+            if trakt.client_id.is_empty() || trakt.client_secret.is_empty() {
+                problems.push("Trakt needs both a client id and client secret".into());
+            }
+        }
+
+        let mut path_mappings = file.path_mappings;
+        if let Ok(prefix) = env::var("SONARR_DISK_PATH_PREFIX") {
+            path_mappings.push(PathMapping {
+                remote: PathBuf::from("/"),
+                local: PathBuf::from(prefix),
+            });
+        }
+        if let Ok(mappings) = env::var("PATH_MAPPINGS") {
+            for mapping in mappings.split(',').filter(|m| !m.trim().is_empty()) {
+                match mapping.split_once('=') {
+                    Some((remote, local)) => path_mappings.push(PathMapping {
+                        remote: PathBuf::from(remote.trim()),
+                        local: PathBuf::from(local.trim()),
+                    }),
+                    None => problems.push(format!(
+                        "PATH_MAPPINGS entry {:?} should look like /remote/path=/local/path",
+                        mapping
+                    )),
+                }
+            }
+        }
+        // most specific mapping wins
+        path_mappings.sort_by_key(|m| std::cmp::Reverse(m.remote.components().count()));
+
+        let mut addr = |name: &str, value: Option<String>, default: &str| {
+            let value = env::var(name)
+                .ok()
+                .or(value)
+                .unwrap_or_else(|| default.into());
+            let addrs = value
+                .split(',')
+                .map(|addr| addr.trim().parse::<ListenAddr>())
+                .collect::<Result<Vec<_>, _>>();
+            match addrs {
+                Ok(addrs) => addrs,
+                Err(_) => {
+                    problems.push(format!(
+                        "{} {:?} should be comma separated socket addresses or unix:/absolute/paths",
+                        name, value
+                    ));
+                    vec![default.parse().unwrap()]
+                }
+            }
+        };
+        let api_addr = addr("CENTARR_API_ADDR", file.api_addr, "0.0.0.0:3000");
+        let stream_addr = addr("CENTARR_STREAM_ADDR", file.stream_addr, "0.0.0.0:3001");
+        let socket_mode = match env::var("CENTARR_SOCKET_MODE").ok().or(file.socket_mode) {
+            Some(mode) => u32::from_str_radix(&mode, 8)
+                .ok()
+                .filter(|mode| *mode <= 0o777)
+                .unwrap_or_else(|| {
+                    problems.push(format!(
+                        "CENTARR_SOCKET_MODE {:?} is not an octal mode like 660",
+                        mode
+                    ));
+                    DEFAULT_SOCKET_MODE
+                }),
+            None => DEFAULT_SOCKET_MODE,
+        };
+
+        let mut number = |name: &str, value: Option<u64>| match env::var(name) {
+            Ok(number) => number.parse::<u64>().map(Some).unwrap_or_else(|_| {
+                problems.push(format!("{} {:?} is not a number", name, number));
+                None
+            }),
+            Err(_) => value,
+        };
+        let max_stream_rate =
+            number("CENTARR_MAX_STREAM_RATE", file.max_stream_rate).filter(|rate| *rate > 0);
+        let stream_burst =
+            number("CENTARR_STREAM_BURST", file.stream_burst).filter(|burst| *burst > 0);
+        let max_download_rate =
+            number("CENTARR_MAX_DOWNLOAD_RATE", file.max_download_rate).filter(|rate| *rate > 0);
+        let stream_chunk_size = number("CENTARR_STREAM_CHUNK_SIZE", file.stream_chunk_size)
+            .filter(|size| *size > 0)
+            .unwrap_or(DEFAULT_STREAM_CHUNK_SIZE);
+        let watched_threshold =
+            number("CENTARR_WATCHED_THRESHOLD", file.watched_threshold).unwrap_or(95);
+        let send_buffer_size =
+            number("CENTARR_SEND_BUFFER_SIZE", file.send_buffer_size).filter(|size| *size > 0);
+        let tcp_keepalive = number("CENTARR_TCP_KEEPALIVE", file.tcp_keepalive)
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs);
+        let cache_ttl = number("CACHE_TTL", file.cache_ttl).unwrap_or(10);
+        let sync_interval = number("CENTARR_SYNC_INTERVAL", file.sync_interval).unwrap_or(300);
+        let image_cache_size = number("CENTARR_IMAGE_CACHE_SIZE", file.image_cache_size)
+            .unwrap_or(DEFAULT_IMAGE_CACHE_SIZE);
+        let job_workers = number("CENTARR_JOB_WORKERS", file.job_workers)
+            .filter(|workers| *workers > 0)
+            .unwrap_or(2);
+        let upstream_concurrency =
+            number("CENTARR_UPSTREAM_CONCURRENCY", file.upstream_concurrency)
+                .filter(|limit| *limit > 0)
+                .map(|limit| limit as usize);
+        let mut timeout = |name: &str, value: Option<u64>, default: u64| {
+            Duration::from_secs(
+                number(name, value)
+                    .filter(|secs| *secs > 0)
+                    .unwrap_or(default),
+            )
+        };
+        let upstream_timeout = timeout("CENTARR_UPSTREAM_TIMEOUT", file.upstream_timeout, 30);
+        let request_timeout = timeout("CENTARR_REQUEST_TIMEOUT", file.request_timeout, 60);
+        let search_timeout = timeout("CENTARR_SEARCH_TIMEOUT", file.search_timeout, 180);
+        let upstream_limits = match env::var("CENTARR_UPSTREAM_LIMITS") {
+            Ok(limits) => limits
+                .split(',')
+                .map(str::trim)
+                .filter(|limit| !limit.is_empty())
+                .filter_map(|limit| {
+                    let parsed = limit
+                        .split_once('=')
+                        .and_then(|(name, limit)| Some((name.trim(), limit.trim().parse().ok()?)));
+                    if parsed.is_none() {
+                        problems.push(format!(
+                            "CENTARR_UPSTREAM_LIMITS {:?} should be like sonarr=4",
+                            limit
+                        ));
+                    }
+                    parsed.map(|(name, limit)| (name.to_lowercase(), limit))
+                })
+                .collect(),
+            Err(_) => file.upstream_limits,
+        };
+        for (name, limit) in &upstream_limits {
+            if !upstream::NAMES.contains(&name.as_str()) {
+                problems.push(format!(
+                    "upstream limit {:?} should be for one of {}",
+                    name,
+                    upstream::NAMES.join(", ")
+                ));
+            } else if *limit == 0 {
+                problems.push(format!("the upstream limit of {} can't be 0", name));
+            }
+        }
+
+        if !(1..=100).contains(&watched_threshold) {
+            problems.push(format!(
+                "CENTARR_WATCHED_THRESHOLD {} should be a percentage between 1 and 100",
+                watched_threshold
+            ));
+        }
+
+        let server_name = env::var("CENTARR_SERVER_NAME")
+            .ok()
+            .or(file.server_name)
+            .filter(|name| !name.trim().is_empty());
+        if server_name.as_ref().is_some_and(|name| name.len() > 63) {
+            problems.push("CENTARR_SERVER_NAME can't be over 63 bytes".into());
+        }
+        let mut flag = |name: &str, value: Option<bool>| match env::var(name) {
+            Ok(flag) => match flag.to_lowercase().as_str() {
+                "1" | "true" | "yes" => true,
+                "0" | "false" | "no" | "" => false,
+                _ => {
+                    problems.push(format!("{} {:?} should be true or false", name, flag));
+                    false
+                }
+            },
+            Err(_) => value.unwrap_or(false),
+        };
+        let require_auth = flag("CENTARR_REQUIRE_AUTH", file.require_auth);
+        let detect_intros = flag("CENTARR_DETECT_INTROS", file.detect_intros);
+        let adaptive_chunks = flag("CENTARR_ADAPTIVE_CHUNKS", file.adaptive_chunks);
+        let tcp_nodelay = flag("CENTARR_TCP_NODELAY", file.tcp_nodelay);
+        let zip_downloads = flag(
+            "CENTARR_ZIP_DOWNLOADS",
+            Some(file.zip_downloads.unwrap_or(true)),
+        );
+        let compression = flag(
+            "CENTARR_COMPRESSION",
+            Some(file.compression.unwrap_or(true)),
+        );
+        let prefetch_images = flag(
+            "CENTARR_PREFETCH_IMAGES",
+            Some(file.prefetch_images.unwrap_or(true)),
+        );
+        let mdns = flag("CENTARR_MDNS", Some(file.mdns.unwrap_or(true)));
+        let skip_unreadable = flag(
+            "CENTARR_SKIP_UNREADABLE",
+            Some(file.skip_unreadable.unwrap_or(true)),
+        );
+        let port_forwarding = flag("CENTARR_PORT_FORWARDING", file.port_forwarding);
+        let download_ffmpeg = flag("CENTARR_DOWNLOAD_FFMPEG", file.download_ffmpeg);
+
+        let env_path = |name: &str| env::var(name).ok().map(PathBuf::from);
+        let upstream_tls = UpstreamTlsConfig {
+            ca_cert: env_path("CENTARR_UPSTREAM_CA_CERT").or(file.upstream_tls.ca_cert),
+            accept_invalid_certs: flag(
+                "CENTARR_UPSTREAM_ACCEPT_INVALID_CERTS",
+                Some(file.upstream_tls.accept_invalid_certs),
+            ),
+            client_cert: env_path("CENTARR_UPSTREAM_CLIENT_CERT").or(file.upstream_tls.client_cert),
+            client_key: env_path("CENTARR_UPSTREAM_CLIENT_KEY").or(file.upstream_tls.client_key),
+        };
+        if let Err(e) = upstream::build_client(&upstream_tls) {
+            problems.push(format!("the upstream TLS settings don't work: {}", e));
+        }
+        let database_url = env::var("CENTARR_DATABASE_URL")
+            .ok()
+            .or(file.database_url)
+            .filter(|url| !url.is_empty());
+        if let Some(Err(e)) = database_url.as_deref().map(postgres::options) {
+            problems.push(format!("CENTARR_DATABASE_URL is not usable: {}", e));
+        }
+        let redis_url = env::var("CENTARR_REDIS_URL")
+            .ok()
+            .or(file.redis_url)
+            .filter(|url| !url.is_empty());
+        if let Some(Err(e)) = redis_url.as_deref().map(redis::Options::parse) {
+            problems.push(format!("CENTARR_REDIS_URL is not usable: {}", e));
+        }
+        if port_forwarding && !require_auth {
+            problems.push(
+                "CENTARR_PORT_FORWARDING needs CENTARR_REQUIRE_AUTH, or anyone could browse and stream".into(),
+            );
+        }
+        let text = |name: &str, value: Option<String>| {
+            env::var(name)
+                .ok()
+                .or(value)
+                .map(|value| value.trim_end_matches('/').to_string())
+                .filter(|value| !value.is_empty())
+        };
+        let ffmpeg_download_url = text("CENTARR_FFMPEG_DOWNLOAD_URL", file.ffmpeg_download_url)
+            .unwrap_or_else(|| {
+                let arch = match std::env::consts::ARCH {
+                    "aarch64" => "arm64",
+                    _ => "amd64",
+                };
+                format!(
+                    "https://johnvansickle.com/ffmpeg/releases/ffmpeg-release-{}-static.tar.xz",
+                    arch
+                )
+            });
+        let primary_url = text("CENTARR_PRIMARY_URL", file.primary_url);
+        let edge_url = text("CENTARR_EDGE_URL", file.edge_url);
+        let edge_token = text("CENTARR_EDGE_TOKEN", file.edge_token);
+        for (name, url) in [
+            ("CENTARR_PRIMARY_URL", &primary_url),
+            ("CENTARR_EDGE_URL", &edge_url),
+            (
+                "CENTARR_FFMPEG_DOWNLOAD_URL",
+                &Some(ffmpeg_download_url.clone()),
+            ),
+        ] {
+            if let Some(url) = url {
+                if !matches!(reqwest::Url::parse(url), Ok(url) if url.scheme().starts_with("http"))
+                {
+                    problems.push(format!("{} {:?} is not an http url", name, url));
+                }
+            }
+        }
+        if primary_url.is_some() && (edge_url.is_none() || edge_token.is_none()) {
+            problems.push(
+                "CENTARR_PRIMARY_URL needs CENTARR_EDGE_URL and CENTARR_EDGE_TOKEN, for the primary to send players here".into(),
+            );
+        }
+
+        let mut trickplay_widths = match env::var("CENTARR_TRICKPLAY_WIDTHS") {
+            Ok(widths) => widths
+                .split(',')
+                .map(str::trim)
+                .filter(|width| !width.is_empty())
+                .filter_map(|width| match width.parse::<u32>() {
+                    Ok(width) => Some(width),
+                    Err(_) => {
+                        problems.push(format!(
+                            "CENTARR_TRICKPLAY_WIDTHS entry {:?} is not a number",
+                            width
+                        ));
+                        None
+                    }
+                })
+                .collect(),
+            Err(_) => file.trickplay_widths,
+        };
+        trickplay_widths.retain(|width| *width > 0);
+        trickplay_widths.sort_unstable();
+        trickplay_widths.dedup();
+
+        let subscribed = file
+            .notifications
+            .iter()
+            .flat_map(|notification| &notification.events)
+            .chain(file.webhooks.iter().flat_map(|webhook| &webhook.events));
+        for kind in subscribed {
+            if !Event::KINDS.contains(&kind.as_str()) {
+                problems.push(format!(
+                    "event {:?} should be one of {}",
+                    kind,
+                    Event::KINDS.join(", ")
+                ));
+            }
+        }
+
+        for (name, schedule) in &file.schedules {
+            if Task::from_name(name).is_none() {
+                let names = Task::ALL.map(Task::name);
+                problems.push(format!(
+                    "task {:?} should be one of {}",
+                    name,
+                    names.join(", ")
+                ));
+            } else if schedule != "off" {
+                if let Err(e) = Cron::parse(schedule) {
+                    problems.push(format!("the schedule of {} is not valid: {}", name, e));
+                }
+            }
+        }
+
+        let data_dir = env::var("CENTARR_DATA_DIR")
+            .map(PathBuf::from)
+            .ok()
+            .or(file.data_dir)
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_DATA_DIR));
+        // a downloaded ffmpeg is used unless the paths are set
+        let tool_path = |name: &str, path: Option<PathBuf>| {
+            env::var(format!("{}_PATH", name.to_uppercase()))
+                .map(PathBuf::from)
+                .ok()
+                .or(path)
+                .unwrap_or_else(|| match download_ffmpeg {
+                    true => data_dir.join("ffmpeg").join(name),
+                    false => PathBuf::from(name),
+                })
+        };
+        let ffmpeg_path = tool_path("ffmpeg", file.ffmpeg_path);
+        let ffprobe_path = tool_path("ffprobe", file.ffprobe_path);
+
+        let config = Config {
+            sonarr,
+            lidarr,
+            readarr,
+            radarr,
+            prowlarr,
+            qbittorrent,
+            sabnzbd,
+            cache_ttl: Duration::from_secs(cache_ttl),
+            upstream_timeout,
+            request_timeout,
+            search_timeout,
+            upstream_concurrency,
+            upstream_limits,
+            skip_unreadable,
+            path_mappings,
+            api_addr,
+            stream_addr,
+            socket_mode,
+            web_root: env::var("CENTARR_WEB_ROOT")
+                .map(PathBuf::from)
+                .ok()
+                .or(file.web_root),
+            ffmpeg_path,
+            ffprobe_path,
+            download_ffmpeg,
+            ffmpeg_download_url,
+            log_level: env::var("RUST_LOG")
+                .ok()
+                .or(file.log_level)
+                .unwrap_or_else(|| DEFAULT_LOG_LEVEL.into()),
+            max_stream_rate,
+            stream_burst,
+            stream_chunk_size,
+            watched_threshold,
+            adaptive_chunks,
+            tcp_nodelay,
+            send_buffer_size,
+            tcp_keepalive,
+            max_download_rate,
+            zip_downloads,
+            compression,
+            uncompressed_paths: env::var("CENTARR_UNCOMPRESSED_PATHS")
+                .map(|paths| paths.split(',').map(String::from).collect())
+                .unwrap_or(file.uncompressed_paths)
+                .into_iter()
+                .map(|path| path.trim().to_string())
+                .filter(|path| !path.is_empty())
+                .collect(),
+            notifications: file.notifications,
+            webhooks: file.webhooks,
+            media_roots: env::var("CENTARR_MEDIA_ROOTS")
+                .map(|roots| {
+                    roots
+                        .split(',')
+                        .filter(|root| !root.trim().is_empty())
+                        .map(|root| PathBuf::from(root.trim()))
+                        .collect()
+                })
+                .unwrap_or(file.media_roots),
+            data_dir,
+            database_url,
+            redis_url,
+            sync_interval: Duration::from_secs(sync_interval),
+            webhook_token: env::var("CENTARR_WEBHOOK_TOKEN")
+                .ok()
+                .or(file.webhook_token)
+                .filter(|token| !token.is_empty()),
+            admin_token: env::var("CENTARR_ADMIN_TOKEN")
+                .ok()
+                .or(file.admin_token)
+                .filter(|token| !token.is_empty()),
+            require_auth,
+            jwt_secret: env::var("CENTARR_JWT_SECRET")
+                .ok()
+                .or(file.jwt_secret)
+                .filter(|secret| !secret.is_empty()),
+            signing_key_path: env::var("CENTARR_SIGNING_KEY_PATH")
+                .map(PathBuf::from)
+                .ok()
+                .or(file.signing_key_path)
+                .unwrap_or_else(|| path().with_file_name("signing.key")),
+            oidc,
+            trakt,
+            upstream_tls,
+            restricted_tags: env::var("CENTARR_RESTRICTED_TAGS")
+                .map(|tags| tags.split(',').map(String::from).collect())
+                .unwrap_or(file.restricted_tags)
+                .into_iter()
+                .map(|tag| tag.trim().to_lowercase())
+                .filter(|tag| !tag.is_empty())
+                .collect(),
+            detect_intros,
+            trickplay_widths,
+            prefetch_images,
+            image_cache_size,
+            schedules: file.schedules,
+            job_workers: job_workers as usize,
+            mdns,
+            server_name,
+            port_forwarding,
+            primary_url,
+            edge_url,
+            edge_token,
+        };
+        if config.jwt_secret.is_none()
+            && std::iter::once(&config.data_dir)
+                .chain(&config.media_roots)
+                .any(|dir| config.signing_key_path.starts_with(dir))
+        {
+            problems.push(format!(
+                "the signing key {:?} must be outside the data dir and media roots",
+                config.signing_key_path
+            ));
+        }
+        if config.primary_url.is_some() && storage::roots(&config).is_empty() {
+            problems.push(
+                "an edge needs CENTARR_MEDIA_ROOTS or path mappings, for the primary to know which files it has".into(),
+            );
+        }
+
+        if problems.is_empty() {
+            Ok(config)
+        } else {
+            Err(ConfigError(problems))
+        }
+    }
+
+    /// Where a path reported by Sonarr can be found on this machine.
+    pub fn local_path(&self, remote: &Path) -> PathBuf {
+        for mapping in &self.path_mappings {
+            if let Ok(rest) = remote.strip_prefix(&mapping.remote) {
+                return mapping.local.join(rest);
+            }
+        }
+
+        remote.to_path_buf()
+    }
+}
+
+/// Loads the config for unit tests the first time it's called, with a
+/// data dir and signing key of their own.
+#[cfg(test)]
+pub fn init_for_tests() {
+    static INIT: std::sync::Once = std::sync::Once::new();
+    INIT.call_once(|| {
+        let dir = env::temp_dir().join(format!("centarr-unit-{}", std::process::id()));
+        env::set_var("SONARR_URL", "http://127.0.0.1:1");
+        env::set_var("SONARR_API_KEY", "test");
+        env::set_var("CENTARR_SYNC_INTERVAL", "0");
+        env::set_var("CENTARR_DATA_DIR", dir.join("data"));
+        env::set_var("CENTARR_SIGNING_KEY_PATH", dir.join("signing.key"));
+        init(Config::load().unwrap());
+    });
+}
+
+pub fn init(config: Config) {
+    CONFIG
+        .set(ArcSwap::from_pointee(config))
+        .expect("config should only be initialized once");
+}
+
+/// The current config. Hold on to the returned value only as long as
+/// needed, so reloads are picked up.
+pub fn get() -> Arc<Config> {
+    CONFIG
+        .get()
+        .expect("config should be initialized on startup")
+        .load_full()
+}
+
+/// Re-reads the config file and environment and swaps in the result, the
+/// old config stays active when the new one is invalid. Listen addresses
+/// can't change without a restart.
+pub fn reload() -> Result<Arc<Config>, ConfigError> {
+    let config = Arc::new(Config::load()?);
+    let previous = get();
+
+    if config.api_addr != previous.api_addr
+        || config.stream_addr != previous.stream_addr
+        || config.socket_mode != previous.socket_mode
+    {
+        tracing::warn!("Listen addresses changed, restart centarr to apply them");
+    }
+    if config.database_url != previous.database_url {
+        tracing::warn!("The database changed, restart centarr to use it");
+    }
+    if config.redis_url != previous.redis_url {
+        tracing::warn!("The Redis server changed, restart centarr to use it");
+    }
+    if config.log_level != previous.log_level {
+        if let Err(e) = crate::telemetry::set_log_level(&config.log_level) {
+            tracing::error!("Failed to change log level: {}", e);
+        }
+    }
+
+    CONFIG
+        .get()
+        .expect("config should be initialized on startup")
+        .store(config.clone());
+
+    tracing::info!("Reloaded configuration from {:?}", path());
+    Ok(config)
+}
@@ -0,0 +1,183 @@
+use chrono::{DateTime, FixedOffset, LocalResult, Offset, SecondsFormat, TimeZone as _};
+use chrono_tz::Tz;
+
+/// Formats a unix timestamp the way Sonarr does, `2022-08-01T12:00:00Z`.
+pub fn iso8601(timestamp: i64) -> String {
+    DateTime::from_timestamp(timestamp, 0)
+        .unwrap_or_default()
+        .to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// Parses `+02:00`, `-0530` or `+02` into seconds east of UTC.
+fn parse_offset(offset: &str) -> Option<i32> {
+    let (sign, offset) = match offset.as_bytes().first()? {
+        b'+' => (1, &offset[1..]),
+        b'-' => (-1, &offset[1..]),
+        _ => return None,
+    };
+    let digits = offset.replace(':', "");
+    if !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let (hours, minutes) = match digits.len() {
+        2 => (digits.parse::<i32>().ok()?, 0),
+        4 => (digits[..2].parse::<i32>().ok()?, digits[2..].parse().ok()?),
+        _ => return None,
+    };
+
+    (hours <= 14 && minutes < 60).then_some(sign * (hours * 3600 + minutes * 60))
+}
+
+/// A time zone from the tz database chrono-tz carries, or a fixed UTC
+/// offset.
+pub struct TimeZone {
+    pub name: String,
+    zone: Zone,
+}
+
+enum Zone {
+    Named(Tz),
+    Fixed(FixedOffset),
+}
+
+impl TimeZone {
+    pub fn utc() -> Self {
+        Self {
+            name: "UTC".into(),
+            zone: Zone::Named(Tz::UTC),
+        }
+    }
+
+    /// `UTC`, an offset like `+02:00` or a tz database name like
+    /// `Europe/Amsterdam`.
+    pub fn parse(name: &str) -> Result<Self, String> {
+        if matches!(name, "UTC" | "Z") {
+            return Ok(Self::utc());
+        }
+        let zone = match name.starts_with(['+', '-']) {
+            true => parse_offset(name)
+                .and_then(FixedOffset::east_opt)
+                .map(Zone::Fixed)
+                .ok_or_else(|| format!("{:?} isn't a UTC offset", name))?,
+            false => name
+                .parse()
+                .map(Zone::Named)
+                .map_err(|_| format!("There's no time zone {:?}", name))?,
+        };
+
+        Ok(Self {
+            name: name.into(),
+            zone,
+        })
+    }
+
+    /// Seconds east of UTC at `timestamp`, with the rules for when the
+    /// clocks change applying past the tz database's last listed change.
+    pub fn offset_at(&self, timestamp: i64) -> i32 {
+        let at = DateTime::from_timestamp(timestamp, 0)
+            .unwrap_or_default()
+            .naive_utc();
+
+        match &self.zone {
+            Zone::Named(tz) => tz.offset_from_utc_datetime(&at).fix().local_minus_utc(),
+            Zone::Fixed(offset) => offset.local_minus_utc(),
+        }
+    }
+
+    /// `timestamp` as local time, in seconds since 1970-01-01 00:00 there.
+    pub fn to_local(&self, timestamp: i64) -> i64 {
+        timestamp + i64::from(self.offset_at(timestamp))
+    }
+
+    /// The unix time of a local time. Times repeated when the clocks go back
+    /// are the first of the two, ones skipped when they go forward the
+    /// moment they did.
+    pub fn to_utc(&self, local: i64) -> i64 {
+        let tz = match &self.zone {
+            Zone::Named(tz) => tz,
+            Zone::Fixed(offset) => return local - i64::from(offset.local_minus_utc()),
+        };
+        let at = DateTime::from_timestamp(local, 0)
+            .unwrap_or_default()
+            .naive_utc();
+
+        match tz.from_local_datetime(&at) {
+            LocalResult::Single(time) | LocalResult::Ambiguous(time, _) => time.timestamp(),
+            // in the skipped hour, a day earlier being before the change
+            LocalResult::None => {
+                let before = tz
+                    .from_local_datetime(&(at - chrono::Duration::days(1)))
+                    .earliest()
+                    .map_or(0, |time| time.offset().fix().local_minus_utc());
+                local - i64::from(before)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The unix time of a UTC date and time.
+    fn utc(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> i64 {
+        chrono::Utc
+            .with_ymd_and_hms(year, month, day, hour, minute, 0)
+            .unwrap()
+            .timestamp()
+    }
+
+    #[test]
+    fn offsets_follow_the_clocks_changing() {
+        let amsterdam = TimeZone::parse("Europe/Amsterdam").unwrap();
+        // the clocks go forward at 01:00 UTC on the last Sunday of March
+        assert_eq!(amsterdam.offset_at(utc(2023, 3, 26, 0, 59)), 3600);
+        assert_eq!(amsterdam.offset_at(utc(2023, 3, 26, 1, 0)), 7200);
+        // and back on the last Sunday of October
+        assert_eq!(amsterdam.offset_at(utc(2023, 10, 29, 0, 59)), 7200);
+        assert_eq!(amsterdam.offset_at(utc(2023, 10, 29, 1, 0)), 3600);
+        // long after the last change a slim zoneinfo file lists
+        assert_eq!(amsterdam.offset_at(utc(2040, 3, 25, 0, 59)), 3600);
+        assert_eq!(amsterdam.offset_at(utc(2040, 3, 25, 1, 0)), 7200);
+        assert_eq!(amsterdam.offset_at(utc(2040, 7, 1, 12, 0)), 7200);
+
+        let sydney = TimeZone::parse("Australia/Sydney").unwrap();
+        assert_eq!(sydney.offset_at(utc(2040, 1, 1, 0, 0)), 11 * 3600);
+        assert_eq!(sydney.offset_at(utc(2040, 7, 1, 0, 0)), 10 * 3600);
+    }
+
+    #[test]
+    fn local_times_around_the_clocks_changing() {
+        let new_york = TimeZone::parse("America/New_York").unwrap();
+        // 01:30 happens twice when the clocks go back, the first is EDT
+        let twice = utc(2031, 11, 2, 1, 30);
+        assert_eq!(new_york.to_utc(twice), utc(2031, 11, 2, 5, 30));
+        // 02:30 never happens when they go forward
+        let skipped = utc(2031, 3, 9, 2, 30);
+        assert_eq!(new_york.to_utc(skipped), utc(2031, 3, 9, 7, 30));
+        assert_eq!(
+            new_york.to_local(utc(2031, 3, 9, 7, 0)),
+            utc(2031, 3, 9, 3, 0)
+        );
+
+        // in Santiago the clocks go forward at midnight, so the day starts
+        // at 01:00
+        let santiago = TimeZone::parse("America/Santiago").unwrap();
+        let midnight = utc(2023, 9, 3, 0, 0);
+        assert_eq!(santiago.to_utc(midnight), utc(2023, 9, 3, 4, 0));
+        assert_eq!(
+            santiago.to_local(utc(2023, 9, 3, 4, 0)),
+            utc(2023, 9, 3, 1, 0)
+        );
+    }
+
+    #[test]
+    fn names_and_offsets_are_parsed() {
+        assert_eq!(TimeZone::parse("UTC").unwrap().offset_at(0), 0);
+        assert_eq!(TimeZone::parse("+05:30").unwrap().offset_at(0), 19800);
+        assert_eq!(TimeZone::parse("-0800").unwrap().to_utc(0), 8 * 3600);
+        assert!(TimeZone::parse("+25:00").is_err());
+        assert!(TimeZone::parse("Mars/Olympus_Mons").is_err());
+        assert!(TimeZone::parse("../../etc/passwd").is_err());
+    }
+}
@@ -0,0 +1,324 @@
+use std::sync::RwLock;
+use std::time::{Duration, SystemTime};
+
+use axum::{routing::get, Json, Router};
+use once_cell::sync::Lazy;
+use reqwest::header;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::{self, QbittorrentConfig, UpstreamConfig},
+    upstream,
+};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// What qBittorrent reports as the ETA of a torrent that will never finish.
+const QBITTORRENT_INFINITE_ETA: u64 = 8_640_000;
+
+static SNAPSHOT: Lazy<RwLock<Snapshot>> = Lazy::new(Default::default);
+
+pub fn router() -> Router {
+    Router::new().route("/downloads", get(get_downloads))
+}
+
+#[derive(Serialize, Clone, Default)]
+struct Snapshot {
+    downloads: Vec<Download>,
+    clients: Vec<ClientStatus>,
+}
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct ClientStatus {
+    name: &'static str,
+    ok: bool,
+    error: Option<String>,
+    /// Unix timestamp of the last poll.
+    polled_at: u64,
+}
+
+/// A download as reported by any of the clients.
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Download {
+    client: &'static str,
+    id: String,
+    name: String,
+    category: Option<String>,
+    state: State,
+    /// In bytes.
+    size: u64,
+    /// Between 0 and 1.
+    progress: f64,
+    /// In bytes per second.
+    download_speed: u64,
+    eta_secs: Option<u64>,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum State {
+    Downloading,
+    Queued,
+    Paused,
+    Stalled,
+    Seeding,
+    Completed,
+    Error,
+}
+
+async fn get_downloads() -> Json<Snapshot> {
+    Json(SNAPSHOT.read().unwrap().clone())
+}
+
+/// Polls every configured download client forever, keeping the latest
+/// results around for `/downloads`.
+pub async fn poll() {
+    let mut qbittorrent_sid = None;
+
+    loop {
+        let config = config::get();
+        let client = upstream::client();
+        let mut snapshot = Snapshot::default();
+        let polled_at = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        let mut record = |name, result: Result<Vec<Download>, String>| {
+            let error = match result {
+                Ok(downloads) => {
+                    snapshot.downloads.extend(downloads);
+                    None
+                }
+                Err(e) => {
+                    tracing::warn!("Failed to poll {}: {}", name, e);
+                    Some(e)
+                }
+            };
+
+            snapshot.clients.push(ClientStatus {
+                name,
+                ok: error.is_none(),
+                error,
+                polled_at,
+            });
+        };
+
+        if let Some(qbittorrent) = &config.qbittorrent {
+            let result = poll_qbittorrent(&client, qbittorrent, &mut qbittorrent_sid).await;
+            record("qbittorrent", result);
+        }
+        if let Some(sabnzbd) = &config.sabnzbd {
+            let result = poll_sabnzbd(&client, sabnzbd).await;
+            record("sabnzbd", result);
+        }
+
+        *SNAPSHOT.write().unwrap() = snapshot;
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+#[derive(Deserialize)]
+struct Torrent {
+    hash: String,
+    name: String,
+    category: Option<String>,
+    state: String,
+    size: u64,
+    progress: f64,
+    dlspeed: u64,
+    eta: u64,
+}
+
+pub async fn qbittorrent_login(
+    client: &reqwest::Client,
+    config: &QbittorrentConfig,
+) -> Result<String, String> {
+    let res = client
+        .post(format!(
+            "{}/api/v2/auth/login",
+            config.url.trim_end_matches('/')
+        ))
+        // qBittorrent rejects logins without a matching Referer
+        .header(header::REFERER, &config.url)
+        .timeout(config::get().upstream_timeout)
+        .form(&[
+            ("username", &config.username),
+            ("password", &config.password),
+        ])
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    res.headers()
+        .get_all(header::SET_COOKIE)
+        .iter()
+        .filter_map(|cookie| cookie.to_str().ok())
+        .find_map(|cookie| {
+            cookie
+                .split(';')
+                .next()
+                .filter(|pair| pair.starts_with("SID="))
+                .map(String::from)
+        })
+        .ok_or_else(|| "login was rejected, check the username and password".into())
+}
+
+async fn poll_qbittorrent(
+    client: &reqwest::Client,
+    config: &QbittorrentConfig,
+    sid: &mut Option<String>,
+) -> Result<Vec<Download>, String> {
+    let url = format!("{}/api/v2/torrents/info", config.url.trim_end_matches('/'));
+
+    // sessions expire, so log in again once when we get turned away
+    for _ in 0..2 {
+        let cookie = match sid {
+            Some(cookie) => cookie.clone(),
+            None => sid.insert(qbittorrent_login(client, config).await?).clone(),
+        };
+
+        let res = client
+            .get(&url)
+            .header(header::COOKIE, cookie)
+            .timeout(config::get().upstream_timeout)
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+
+        if res.status() == reqwest::StatusCode::FORBIDDEN {
+            *sid = None;
+            continue;
+        }
+
+        let torrents = res
+            .json::<Vec<Torrent>>()
+            .await
+            .map_err(|e| e.to_string())?;
+
+        return Ok(torrents
+            .into_iter()
+            .map(|torrent| Download {
+                client: "qbittorrent",
+                id: torrent.hash,
+                name: torrent.name,
+                category: torrent.category.filter(|category| !category.is_empty()),
+                state: qbittorrent_state(&torrent.state),
+                size: torrent.size,
+                progress: torrent.progress,
+                download_speed: torrent.dlspeed,
+                eta_secs: Some(torrent.eta).filter(|eta| *eta < QBITTORRENT_INFINITE_ETA),
+            })
+            .collect());
+    }
+
+    Err("still forbidden after logging in again".into())
+}
+
+fn qbittorrent_state(state: &str) -> State {
+    match state {
+        "downloading" | "forcedDL" | "metaDL" | "allocating" => State::Downloading,
+        "queuedDL" | "checkingDL" | "checkingResumeData" | "moving" => State::Queued,
+        "pausedDL" | "stoppedDL" => State::Paused,
+        "stalledDL" => State::Stalled,
+        "uploading" | "forcedUP" | "stalledUP" | "queuedUP" | "checkingUP" => State::Seeding,
+        "pausedUP" | "stoppedUP" => State::Completed,
+        _ => State::Error,
+    }
+}
+
+#[derive(Deserialize)]
+struct SabnzbdResponse {
+    queue: SabnzbdQueue,
+}
+
+#[derive(Deserialize)]
+struct SabnzbdQueue {
+    kbpersec: String,
+    slots: Vec<SabnzbdSlot>,
+}
+
+#[derive(Deserialize)]
+struct SabnzbdSlot {
+    nzo_id: String,
+    filename: String,
+    cat: Option<String>,
+    status: String,
+    mb: String,
+    mbleft: String,
+    timeleft: String,
+}
+
+async fn poll_sabnzbd(
+    client: &reqwest::Client,
+    config: &UpstreamConfig,
+) -> Result<Vec<Download>, String> {
+    let res = client
+        .get(format!("{}/api", config.url))
+        .query(&[
+            ("mode", "queue"),
+            ("output", "json"),
+            ("apikey", config.api_key.as_str()),
+        ])
+        .timeout(config::get().upstream_timeout)
+        .send()
+        .await
+        .map_err(|e| e.to_string())?
+        .json::<SabnzbdResponse>()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    // SABnzbd downloads one job at a time, so the overall speed is that job's
+    let speed = (res.queue.kbpersec.parse::<f64>().unwrap_or_default() * 1024.0) as u64;
+
+    Ok(res
+        .queue
+        .slots
+        .into_iter()
+        .map(|slot| {
+            let megabytes = slot.mb.parse::<f64>().unwrap_or_default();
+            let left = slot.mbleft.parse::<f64>().unwrap_or_default();
+            let state = match slot.status.as_str() {
+                "Downloading" | "Fetching" | "Grabbing" => State::Downloading,
+                "Paused" => State::Paused,
+                "Queued" | "Checking" | "Propagating" => State::Queued,
+                "Completed" => State::Completed,
+                "Failed" => State::Error,
+                _ => State::Queued,
+            };
+
+            Download {
+                client: "sabnzbd",
+                id: slot.nzo_id,
+                name: slot.filename,
+                category: slot.cat.filter(|cat| cat != "*"),
+                state,
+                size: (megabytes * 1024.0 * 1024.0) as u64,
+                progress: if megabytes > 0.0 {
+                    (megabytes - left) / megabytes
+                } else {
+                    0.0
+                },
+                download_speed: if state == State::Downloading {
+                    speed
+                } else {
+                    0
+                },
+                eta_secs: parse_timeleft(&slot.timeleft).filter(|_| state == State::Downloading),
+            }
+        })
+        .collect())
+}
+
+/// Parses SABnzbd's `[days:]hours:minutes:seconds`.
+fn parse_timeleft(timeleft: &str) -> Option<u64> {
+    let mut secs = 0;
+    let mut multipliers = [1, 60, 3600, 86400].into_iter();
+
+    for part in timeleft.rsplit(':') {
+        secs += part.parse::<u64>().ok()? * multipliers.next()?;
+    }
+
+    Some(secs)
+}
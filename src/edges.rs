@@ -0,0 +1,275 @@
+//! Streaming from more than one machine. Edges, centarrs with
+//! `primary_url` set, check in with the primary every few seconds, telling
+//! which storage they have mounted and how busy they are, and the primary
+//! hands out watch urls on the least busy one that has the file, signed so
+//! the edge needs no users of its own.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use axum::{
+    http::{header, HeaderMap, StatusCode},
+    routing::post,
+    Json, Router,
+};
+use once_cell::sync::Lazy;
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+
+use crate::{admin, config, dates, errors::ApiError, storage, stream_stats, upstream, users};
+
+/// How often an edge checks in.
+const CHECK_IN_INTERVAL: Duration = Duration::from_secs(10);
+/// Edges that haven't checked in for this long get no more players, until
+/// they do again.
+const EDGE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Edges that haven't checked in for this long are forgotten.
+const FORGET_AFTER: Duration = Duration::from_secs(60 * 60);
+/// How long a watch url on an edge works, long enough for a movie to be
+/// paused and resumed.
+const URL_LIFETIME: Duration = Duration::from_secs(6 * 60 * 60);
+
+/// Edges by url, on the primary.
+static EDGES: Lazy<Mutex<HashMap<String, Edge>>> = Lazy::new(Default::default);
+
+pub fn router() -> Router {
+    Router::new().route("/edges", post(check_in))
+}
+
+/// What an edge tells the primary when it checks in.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct CheckIn {
+    /// Where players reach its streaming server.
+    url: String,
+    /// Storage roots it has mounted and can read.
+    roots: Vec<PathBuf>,
+    /// Responses it's sending.
+    streams: usize,
+}
+
+struct Edge {
+    check_in: CheckIn,
+    /// Unix timestamp of its last check in.
+    seen: u64,
+}
+
+impl Edge {
+    fn healthy(&self) -> bool {
+        now().saturating_sub(self.seen) < EDGE_TIMEOUT.as_secs()
+    }
+
+    fn has(&self, path: &Path) -> bool {
+        self.check_in
+            .roots
+            .iter()
+            .any(|root| path.starts_with(root))
+    }
+}
+
+/// An edge as `GET /admin/edges` lists it.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Status {
+    url: String,
+    roots: Vec<PathBuf>,
+    streams: usize,
+    healthy: bool,
+    last_seen: String,
+}
+
+async fn check_in(
+    headers: HeaderMap,
+    Json(check_in): Json<CheckIn>,
+) -> Result<StatusCode, ApiError> {
+    let config = config::get();
+    let token = match &config.edge_token {
+        Some(token) if config.primary_url.is_none() => token,
+        _ => return Err(ApiError::empty(404, None)),
+    };
+    let authorized = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|given| admin::constant_time_eq(given.as_bytes(), token.as_bytes()));
+    if !authorized {
+        return Err(ApiError::empty(401, None));
+    }
+    if reqwest::Url::parse(&check_in.url).is_err() {
+        return Err(ApiError::new(
+            422,
+            format!("{:?} is not a url", check_in.url),
+        ));
+    }
+
+    let mut edges = EDGES.lock().unwrap();
+    edges.retain(|_, edge| now().saturating_sub(edge.seen) < FORGET_AFTER.as_secs());
+    match edges.get(&check_in.url) {
+        Some(edge) if edge.healthy() => {}
+        Some(_) => tracing::info!("Edge {} is back", check_in.url),
+        None => tracing::info!(
+            "Edge {} checked in with {} roots",
+            check_in.url,
+            check_in.roots.len()
+        ),
+    }
+    edges.insert(
+        check_in.url.clone(),
+        Edge {
+            check_in,
+            seen: now(),
+        },
+    );
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Every edge that checked in in the last hour.
+pub fn list() -> Vec<Status> {
+    let mut edges = EDGES
+        .lock()
+        .unwrap()
+        .values()
+        .map(|edge| Status {
+            url: edge.check_in.url.clone(),
+            roots: edge.check_in.roots.clone(),
+            streams: edge.check_in.streams,
+            healthy: edge.healthy(),
+            last_seen: dates::iso8601(edge.seen as i64),
+        })
+        .collect::<Vec<_>>();
+    edges.sort_by(|a, b| a.url.cmp(&b.url));
+
+    edges
+}
+
+fn key(token: &str) -> hmac::Key {
+    hmac::Key::new(hmac::HMAC_SHA256, token.as_bytes())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// A signed url streaming `target`, like `/stream/12`, from the least busy
+/// healthy edge that has `path`. `None` when there's no such edge, and the
+/// primary streams it itself.
+pub fn url(path: &Path, target: &str) -> Option<String> {
+    let config = config::get();
+    let token = config.edge_token.as_ref()?;
+    if config.primary_url.is_some() {
+        return None;
+    }
+
+    // equally busy edges are picked between by file, so the urls of a
+    // listing are spread over them
+    let mut hasher = DefaultHasher::new();
+    path.hash(&mut hasher);
+    let spread = hasher.finish();
+
+    let edges = EDGES.lock().unwrap();
+    let edge = edges
+        .values()
+        .filter(|edge| edge.healthy() && edge.has(path))
+        .min_by_key(|edge| {
+            let mut hasher = DefaultHasher::new();
+            (spread, &edge.check_in.url).hash(&mut hasher);
+            (edge.check_in.streams, hasher.finish())
+        })?;
+
+    let separator = if target.contains('?') { '&' } else { '?' };
+    let signed = format!(
+        "{}{}expires={}",
+        target,
+        separator,
+        now() + URL_LIFETIME.as_secs()
+    );
+    let signature = hmac::sign(&key(token), signed.as_bytes());
+
+    Some(format!(
+        "{}{}&signature={}",
+        edge.check_in.url,
+        signed,
+        users::hex(signature.as_ref())
+    ))
+}
+
+/// Whether the primary signed `target`, the path and query of a request to
+/// this edge, and it hasn't expired.
+pub fn vouched(target: &str) -> bool {
+    let config = config::get();
+    let token = match (&config.primary_url, &config.edge_token) {
+        (Some(_), Some(token)) => token,
+        _ => return false,
+    };
+    let (signed, signature) = match target.split_once("&signature=") {
+        Some((signed, rest)) => (signed, rest.split('&').next().unwrap_or_default()),
+        None => return false,
+    };
+    let expires = signed
+        .rsplit_once("expires=")
+        .and_then(|(_, expires)| expires.parse::<u64>().ok());
+
+    let expected = hmac::sign(&key(token), signed.as_bytes());
+    admin::constant_time_eq(
+        users::hex(expected.as_ref()).as_bytes(),
+        signature.as_bytes(),
+    ) && expires.is_some_and(|expires| expires > now())
+}
+
+/// Checks in with the primary every few seconds when this is an edge.
+pub async fn run() {
+    let mut interval = tokio::time::interval(CHECK_IN_INTERVAL);
+    // whether the last check in went through, so failures are logged once
+    let mut checked_in = None;
+
+    loop {
+        interval.tick().await;
+        let config = config::get();
+        let (primary, url, token) =
+            match (&config.primary_url, &config.edge_url, &config.edge_token) {
+                (Some(primary), Some(url), Some(token)) => (primary, url, token),
+                _ => continue,
+            };
+
+        let roots = storage::check_roots(&config)
+            .await
+            .into_iter()
+            .filter(|root| root.online)
+            .map(|root| root.path)
+            .collect();
+        let check_in = CheckIn {
+            url: url.clone(),
+            roots,
+            streams: stream_stats::streaming(),
+        };
+
+        let sent = upstream::client()
+            .post(format!("{}/edges", primary))
+            .bearer_auth(token)
+            .json(&check_in)
+            .timeout(config.upstream_timeout)
+            .send()
+            .await;
+        let result = match sent {
+            Ok(res) if res.status().is_success() => Ok(()),
+            Ok(res) => Err(format!("it answered {}", res.status())),
+            Err(e) => Err(e.to_string()),
+        };
+
+        match (&result, checked_in) {
+            (Ok(()), Some(true)) => {}
+            (Ok(()), _) => tracing::info!("Checked in with the primary at {}", primary),
+            (Err(e), Some(false)) => tracing::debug!("Can't check in with the primary: {}", e),
+            (Err(e), _) => tracing::warn!("Can't check in with the primary at {}: {}", primary, e),
+        }
+        checked_in = Some(result.is_ok());
+    }
+}
@@ -4,37 +4,43 @@ use axum::{
     response::{IntoResponse, Response},
 };
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ApiError {
     status_code: StatusCode,
     message: Option<String>,
 }
 
 impl ApiError {
-    // pub fn new(status_code: u16, message: String) -> Self {
-    //     Self {
-    //         status_code: StatusCode::from_u16(status_code)
-    //             .expect("Status Code used that doesn't exist"),
-    //         message: Some(message),
-    //     }
-    // }
+    pub fn new(status_code: u16, message: String) -> Self {
+        Self {
+            status_code: StatusCode::from_u16(status_code)
+                .expect("Status Code used that doesn't exist"),
+            message: Some(message),
+        }
+    }
 
     pub fn empty(status_code: u16, log: Option<String>) -> Self {
-        println!("{:?}", log);
+        if let Some(log) = log {
+            tracing::error!("{}", log);
+        }
         Self {
             status_code: StatusCode::from_u16(status_code)
                 .expect("Status Code used that doesn't exist"),
             message: None,
         }
     }
+
+    pub fn status_code(&self) -> StatusCode {
+        self.status_code
+    }
 }
 
 impl IntoResponse for ApiError {
     fn into_response(self) -> Response {
-        if self.message.is_some() {
+        if let Some(message) = self.message {
             Response::builder()
                 .status(self.status_code)
-                .body(body::boxed(body::Full::from(self.message.unwrap())))
+                .body(body::boxed(body::Full::from(message)))
                 .unwrap()
         } else {
             Response::builder()
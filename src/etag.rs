@@ -0,0 +1,106 @@
+use axum::{
+    body::{boxed, Empty, Full},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::Response,
+};
+use ring::digest;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{errors::ApiError, users};
+
+/// Fields that follow the clock rather than the data. Tags leave them
+/// out, so they hold until something else changes.
+const TIMED: &[&str] = &["airsInMinutes"];
+
+/// `value` without any of the [`TIMED`] fields, at any depth.
+fn untimed(value: &mut Value) {
+    match value {
+        Value::Object(fields) => {
+            for field in TIMED {
+                fields.remove(*field);
+            }
+            fields.values_mut().for_each(untimed);
+        }
+        Value::Array(items) => items.iter_mut().for_each(untimed),
+        _ => {}
+    }
+}
+
+/// The opaque part of a validator for `body`, the start of its SHA-256.
+fn tag(body: &[u8]) -> String {
+    let hash = digest::digest(&digest::SHA256, body);
+    format!("\"{}\"", users::hex(&hash.as_ref()[..16]))
+}
+
+/// Whether `If-None-Match` lists `etag`, or is `*`. Weak tags compare
+/// equal to strong ones here, as RFC 9110 has it for GETs.
+fn matches(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+/// `value` as JSON with an `ETag`, or a bodiless 304 when the client
+/// already has it. The tag is of what's sent, so it changes with whatever
+/// goes into the response, like restrictions and tokens in urls, but not
+/// with the [`TIMED`] fields, which makes it a weak one.
+pub fn json<T: Serialize>(headers: &HeaderMap, value: &T) -> Result<Response, ApiError> {
+    let invalid = |e: serde_json::Error| ApiError::empty(500, Some(e.to_string()));
+    let body = serde_json::to_vec(value).map_err(invalid)?;
+    let mut tagged = serde_json::to_value(value).map_err(invalid)?;
+    untimed(&mut tagged);
+    let etag = tag(&serde_json::to_vec(&tagged).map_err(invalid)?);
+    let etag_header = HeaderValue::from_str(&format!("W/{}", etag)).unwrap();
+
+    if matches(headers, &etag) {
+        return Ok(Response::builder()
+            .status(StatusCode::NOT_MODIFIED)
+            .header(header::ETAG, etag_header)
+            .body(boxed(Empty::new()))
+            .unwrap());
+    }
+
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(header::ETAG, etag_header)
+        .body(boxed(Full::from(body)))
+        .unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn etag_of(value: &Value, headers: &HeaderMap) -> (StatusCode, String) {
+        let response = json(headers, value).unwrap();
+        let etag = response.headers()[header::ETAG]
+            .to_str()
+            .unwrap()
+            .to_owned();
+        (response.status(), etag)
+    }
+
+    #[test]
+    fn tags_hold_while_only_the_clock_moves() {
+        let before = serde_json::json!({ "episodes": [{ "id": 1, "airsInMinutes": 61 }] });
+        let after = serde_json::json!({ "episodes": [{ "id": 1, "airsInMinutes": 60 }] });
+        let changed = serde_json::json!({ "episodes": [{ "id": 2, "airsInMinutes": 60 }] });
+
+        let (status, etag) = etag_of(&before, &HeaderMap::new());
+        assert_eq!(status, StatusCode::OK);
+        assert!(etag.starts_with("W/\""));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::IF_NONE_MATCH, HeaderValue::from_str(&etag).unwrap());
+        assert_eq!(
+            etag_of(&after, &headers),
+            (StatusCode::NOT_MODIFIED, etag.clone())
+        );
+        assert_eq!(etag_of(&changed, &headers).0, StatusCode::OK);
+    }
+}
@@ -0,0 +1,118 @@
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+/// How many events a slow subscriber can fall behind before it misses some.
+const CAPACITY: usize = 256;
+
+static BUS: Lazy<broadcast::Sender<Event>> = Lazy::new(|| broadcast::channel(CAPACITY).0);
+
+/// Something that happened which notifications and the like may want to
+/// tell people about.
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase", untagged)]
+pub enum Event {
+    #[serde(rename_all = "camelCase")]
+    EpisodeImported {
+        series_id: i32,
+        series_title: String,
+        /// Like `S01E02 - Title`.
+        episodes: Vec<String>,
+    },
+    #[serde(rename_all = "camelCase")]
+    PlaybackStarted {
+        path: String,
+        client: String,
+        /// Name of the registered device streaming, when it's known.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        device: Option<String>,
+    },
+    #[serde(rename_all = "camelCase")]
+    PlaybackFinished {
+        path: String,
+        client: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        device: Option<String>,
+    },
+    #[serde(rename_all = "camelCase")]
+    LibraryUpdated { series_ids: Vec<i32> },
+    #[serde(rename_all = "camelCase")]
+    UpstreamUnreachable { service: String },
+}
+
+impl Event {
+    /// Every kind of event, as used in the config.
+    pub const KINDS: &'static [&'static str] = &[
+        "episode.imported",
+        "playback.started",
+        "playback.finished",
+        "library.updated",
+        "upstream.unreachable",
+    ];
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Event::EpisodeImported { .. } => "episode.imported",
+            Event::PlaybackStarted { .. } => "playback.started",
+            Event::PlaybackFinished { .. } => "playback.finished",
+            Event::LibraryUpdated { .. } => "library.updated",
+            Event::UpstreamUnreachable { .. } => "upstream.unreachable",
+        }
+    }
+
+    /// A short title and a line of text for people to read.
+    pub fn message(&self) -> (String, String) {
+        match self {
+            Event::EpisodeImported {
+                series_title,
+                episodes,
+                ..
+            } => (format!("{} imported", series_title), episodes.join("\n")),
+            Event::PlaybackStarted {
+                path,
+                client,
+                device,
+            } => (
+                "Playback started".into(),
+                format!(
+                    "{} is streaming {}",
+                    device.as_ref().unwrap_or(client),
+                    path
+                ),
+            ),
+            Event::PlaybackFinished {
+                path,
+                client,
+                device,
+            } => (
+                "Playback finished".into(),
+                format!(
+                    "{} finished streaming {}",
+                    device.as_ref().unwrap_or(client),
+                    path
+                ),
+            ),
+            Event::LibraryUpdated { series_ids } => (
+                "Library updated".into(),
+                format!("{} series changed", series_ids.len()),
+            ),
+            Event::UpstreamUnreachable { service } => (
+                format!("{} is unreachable", service),
+                format!(
+                    "Requests to {} keep failing and are paused for now",
+                    service
+                ),
+            ),
+        }
+    }
+}
+
+pub fn publish(event: Event) {
+    tracing::debug!("Publishing {}", event.kind());
+    // fails when nobody is listening, which is fine
+    let _ = BUS.send(event);
+}
+
+pub fn subscribe() -> broadcast::Receiver<Event> {
+    BUS.subscribe()
+}
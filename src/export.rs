@@ -0,0 +1,688 @@
+use std::collections::HashMap;
+use std::path::Path as FilePath;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use axum::{
+    extract::Query,
+    http::{header, HeaderMap},
+    middleware,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    auth::{self, AuthUser},
+    config::{self, Config},
+    dates,
+    errors::ApiError,
+    extras,
+    library::{self, Item, Kind},
+    playback::{self, Play, WatchState},
+    restrictions::Restrictions,
+    sonarr, users,
+};
+
+/// Most entries that couldn't be matched listed back after an import.
+const MAX_UNMATCHED: usize = 100;
+
+const COLUMNS: &[&str] = &[
+    "type",
+    "title",
+    "year",
+    "seasonNumber",
+    "episodeNumber",
+    "episodeTitle",
+    "path",
+    "size",
+    "watchState",
+    "position",
+    "lastPlayed",
+];
+
+// What fields are called in exports, as by `normalize`: ours first, then
+// Plex's (and Tautulli's) and Jellyfin's.
+const PATH: &[&str] = &["path", "file", "partfile", "mediapartfile", "filepath"];
+const SHOW: &[&str] = &[
+    "seriestitle",
+    "seriesname",
+    "grandparenttitle",
+    "showtitle",
+    "show",
+];
+const TITLE: &[&str] = &["title", "name", "movietitle"];
+const YEAR: &[&str] = &["year", "productionyear"];
+const SEASON: &[&str] = &[
+    "seasonnumber",
+    "season",
+    "parentindex",
+    "parentmediaindex",
+    "parentindexnumber",
+];
+const EPISODE: &[&str] = &[
+    "episodenumber",
+    "episode",
+    "index",
+    "mediaindex",
+    "indexnumber",
+];
+const KIND: &[&str] = &["type", "mediatype"];
+const WATCHED: &[&str] = &[
+    "watchstate",
+    "watched",
+    "watchedstatus",
+    "played",
+    "userdataplayed",
+];
+const PLAY_COUNT: &[&str] = &["viewcount", "playcount", "userdataplaycount"];
+/// In bytes.
+const POSITION: &[&str] = &["position"];
+const OFFSET_MILLIS: &[&str] = &["viewoffset"];
+/// Jellyfin's ticks are 100 nanoseconds.
+const OFFSET_TICKS: &[&str] = &["userdataplaybackpositionticks", "playbackpositionticks"];
+const LAST_PLAYED: &[&str] = &[
+    "lastplayed",
+    "lastviewedat",
+    "userdatalastplayeddate",
+    "lastplayeddate",
+    "date",
+];
+
+pub fn router() -> Router {
+    Router::new().route("/export", get(export)).merge(
+        Router::new()
+            .route("/import", post(import))
+            .route_layer(middleware::from_fn(auth::require_admin)),
+    )
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum Format {
+    Json,
+    Csv,
+}
+
+#[derive(Deserialize)]
+struct FormatQuery {
+    format: Option<Format>,
+}
+
+#[derive(Deserialize)]
+struct ImportQuery {
+    format: Option<Format>,
+    /// Whose watch history it is, the one of who streams without logging
+    /// in when left out.
+    user: Option<String>,
+}
+
+/// A file in the library and how far someone watched it.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Entry {
+    #[serde(rename = "type")]
+    kind: Kind,
+    /// Of the show or movie.
+    title: String,
+    year: Option<i32>,
+    season_number: Option<i32>,
+    episode_number: Option<i32>,
+    episode_title: Option<String>,
+    /// Where the file is, as the upstream service sees it.
+    path: String,
+    size: i64,
+    watch_state: WatchState,
+    /// Bytes streamed, when it wasn't finished.
+    position: Option<u64>,
+    last_played: Option<String>,
+}
+
+impl Entry {
+    fn new(config: &Config, viewer: Option<&str>, item: &Item, path: String, size: i64) -> Self {
+        let play = playback::get(viewer, &config.local_path(FilePath::new(&path)));
+
+        Entry {
+            kind: item.kind,
+            title: item.title.clone(),
+            year: item.year,
+            season_number: None,
+            episode_number: None,
+            episode_title: None,
+            path,
+            size,
+            watch_state: WatchState::of(play, viewer),
+            position: play
+                .filter(|play| play.in_progress(viewer))
+                .map(|play| play.position),
+            last_played: play
+                .and_then(|play| play.last_played.duration_since(UNIX_EPOCH).ok())
+                .map(|since| dates::iso8601(since.as_secs() as i64)),
+        }
+    }
+}
+
+/// Every file of the library `restrictions` allow with how far `viewer`
+/// watched it, episodes in the order Sonarr has them.
+async fn entries(
+    config: &Config,
+    viewer: Option<&str>,
+    restrictions: &Restrictions,
+) -> Result<Vec<Entry>, ApiError> {
+    let mut entries = Vec::new();
+
+    for provider in library::selected(config, None) {
+        let items = provider.list(config).await?;
+
+        for item in items.iter().filter(|item| restrictions.allows_item(item)) {
+            if provider.kind() == Kind::Movie {
+                for file in provider.files(config, item.id).await? {
+                    entries.push(Entry::new(config, viewer, item, file.path, file.size));
+                }
+                continue;
+            }
+
+            for episode in sonarr::episodes(item.id).await? {
+                let file = &episode["episodeFile"];
+                let path = match file["path"].as_str() {
+                    Some(path) => path.to_string(),
+                    None => continue,
+                };
+                let number = |field: &str| episode[field].as_i64().map(|number| number as i32);
+
+                entries.push(Entry {
+                    season_number: number("seasonNumber"),
+                    episode_number: number("episodeNumber"),
+                    episode_title: episode["title"].as_str().map(String::from),
+                    ..Entry::new(
+                        config,
+                        viewer,
+                        item,
+                        path,
+                        file["size"].as_i64().unwrap_or_default(),
+                    )
+                });
+            }
+        }
+    }
+
+    Ok(entries)
+}
+
+/// The library with what they watched of it, as JSON or a CSV file.
+async fn export(
+    Query(query): Query<FormatQuery>,
+    user: Option<AuthUser>,
+    restrictions: Restrictions,
+) -> Result<Response, ApiError> {
+    let config = config::get();
+    let viewer = user.as_ref().map(|user| user.name.as_str());
+    let entries = entries(&config, viewer, &restrictions).await?;
+
+    if query.format != Some(Format::Csv) {
+        return Ok(Json(entries).into_response());
+    }
+
+    let mut csv = csv_row(COLUMNS.iter().map(|column| column.to_string()));
+    for entry in entries {
+        let entry =
+            serde_json::to_value(entry).map_err(|e| ApiError::empty(500, Some(e.to_string())))?;
+        csv.push_str(&csv_row(COLUMNS.iter().map(|column| {
+            match &entry[column] {
+                Value::Null => String::new(),
+                Value::String(value) => value.clone(),
+                value => value.to_string(),
+            }
+        })));
+    }
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "text/csv; charset=utf-8"),
+            (
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=\"centarr-library.csv\"",
+            ),
+        ],
+        csv,
+    )
+        .into_response())
+}
+
+/// A line of CSV, quoting fields that need it.
+fn csv_row(fields: impl Iterator<Item = String>) -> String {
+    let mut row = fields
+        .map(|field| {
+            if field.contains(['"', ',', '\n', '\r']) {
+                format!("\"{}\"", field.replace('"', "\"\""))
+            } else {
+                field
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",");
+    row.push_str("\r\n");
+
+    row
+}
+
+/// The rows of CSV `text`, leaving out blank lines.
+fn parse_csv(text: &str) -> Vec<Vec<String>> {
+    let mut rows = Vec::new();
+    let mut row = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = text.trim_start_matches('\u{feff}').chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match (quoted, c) {
+            (true, '"') if chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            (true, '"') => quoted = false,
+            (false, '"') if field.is_empty() => quoted = true,
+            (false, ',') => row.push(std::mem::take(&mut field)),
+            (false, '\n') => {
+                row.push(std::mem::take(&mut field));
+                rows.push(std::mem::take(&mut row));
+            }
+            (false, '\r') => {}
+            (_, c) => field.push(c),
+        }
+    }
+    row.push(field);
+    rows.push(row);
+
+    rows.retain(|row| row.iter().any(|field| !field.is_empty()));
+    rows
+}
+
+/// `key` lowercased without spaces or punctuation, so `Series Title`,
+/// `series_title` and `seriesTitle` are the same field.
+fn normalize(key: &str) -> String {
+    key.chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+/// The list of items in `value`, the first one found looking depth
+/// first, as exports wrap them in all kinds of objects.
+fn items(value: Value) -> Option<Vec<Value>> {
+    match value {
+        Value::Array(items) => Some(items),
+        Value::Object(fields) => fields.into_iter().find_map(|(_, value)| items(value)),
+        _ => None,
+    }
+}
+
+/// Collects the fields of `value` by their path, `UserData.Played` as
+/// `userdataplayed`. Of lists only the first value of each field is kept.
+fn flatten(value: &Value, path: &str, fields: &mut HashMap<String, String>) {
+    match value {
+        Value::Object(object) => {
+            for (key, value) in object {
+                flatten(value, &format!("{}{}", path, normalize(key)), fields);
+            }
+        }
+        Value::Array(values) => {
+            for value in values {
+                flatten(value, path, fields);
+            }
+        }
+        Value::Null => {}
+        Value::String(value) => {
+            fields
+                .entry(path.to_string())
+                .or_insert_with(|| value.clone());
+        }
+        value => {
+            fields
+                .entry(path.to_string())
+                .or_insert_with(|| value.to_string());
+        }
+    }
+}
+
+/// The records of an export, each as its fields by normalized name.
+fn records(body: &str, format: Format) -> Result<Vec<HashMap<String, String>>, ApiError> {
+    if format == Format::Csv {
+        let mut rows = parse_csv(body).into_iter();
+        let columns = rows
+            .next()
+            .ok_or_else(|| ApiError::new(400, "The CSV has no header".into()))?
+            .iter()
+            .map(|column| normalize(column))
+            .collect::<Vec<_>>();
+
+        return Ok(rows
+            .map(|row| {
+                columns
+                    .iter()
+                    .cloned()
+                    .zip(row)
+                    .filter(|(_, value)| !value.is_empty())
+                    .collect()
+            })
+            .collect());
+    }
+
+    let value = serde_json::from_str::<Value>(body)
+        .map_err(|e| ApiError::new(400, format!("The export isn't valid JSON: {}", e)))?;
+    let items =
+        items(value).ok_or_else(|| ApiError::new(400, "The export has no list of items".into()))?;
+
+    Ok(items
+        .iter()
+        .map(|item| {
+            let mut fields = HashMap::new();
+            flatten(item, "", &mut fields);
+            fields
+        })
+        .collect())
+}
+
+/// How far into a file an imported record got.
+enum Progress {
+    Bytes(u64),
+    Millis(u64),
+}
+
+/// An entry of a watch history exported by centarr or elsewhere.
+struct Record {
+    kind: Kind,
+    path: Option<String>,
+    /// Of the show or movie.
+    title: Option<String>,
+    year: Option<i32>,
+    season_number: Option<i32>,
+    episode_number: Option<i32>,
+    watched: bool,
+    progress: Option<Progress>,
+    last_played: Option<SystemTime>,
+}
+
+fn parse_bool(value: &str) -> bool {
+    match value.to_lowercase().as_str() {
+        "true" | "yes" | "watched" => true,
+        value => value.parse::<f64>().is_ok_and(|number| number >= 1.0),
+    }
+}
+
+/// ISO 8601 like `2022-08-01T12:00:00Z`, with or without the time, or
+/// seconds or milliseconds since the epoch.
+fn parse_time(value: &str) -> Option<SystemTime> {
+    let secs = if let Ok(number) = value.parse::<u64>() {
+        match number {
+            // later than the year 5000 in seconds, so milliseconds
+            n if n > 100_000_000_000 => n / 1000,
+            n => n,
+        }
+    } else if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        time.timestamp().try_into().ok()?
+    } else if let Ok(time) = NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S") {
+        time.and_utc().timestamp().try_into().ok()?
+    } else {
+        NaiveDate::parse_from_str(value.get(..10)?, "%Y-%m-%d")
+            .ok()?
+            .and_time(NaiveTime::MIN)
+            .and_utc()
+            .timestamp()
+            .try_into()
+            .ok()?
+    };
+
+    Some(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+impl Record {
+    fn parse(fields: &HashMap<String, String>) -> Self {
+        let field = |names: &[&str]| names.iter().find_map(|name| fields.get(*name));
+        let number = |names: &[&str]| field(names).and_then(|value| value.parse::<i32>().ok());
+
+        let show = field(SHOW);
+        let season_number = number(SEASON);
+        let episode_number = number(EPISODE);
+        let kind = match field(KIND).map(|kind| kind.to_lowercase()).as_deref() {
+            Some("movie") => Kind::Movie,
+            Some("show" | "episode" | "series") => Kind::Show,
+            _ if show.is_some() || season_number.is_some() => Kind::Show,
+            _ => Kind::Movie,
+        };
+
+        let progress = if let Some(bytes) = field(POSITION).and_then(|value| value.parse().ok()) {
+            Some(Progress::Bytes(bytes))
+        } else if let Some(millis) = field(OFFSET_MILLIS).and_then(|value| value.parse().ok()) {
+            Some(Progress::Millis(millis))
+        } else {
+            field(OFFSET_TICKS)
+                .and_then(|value| value.parse::<u64>().ok())
+                .map(|ticks| Progress::Millis(ticks / 10_000))
+        };
+
+        Record {
+            kind,
+            path: field(PATH).cloned(),
+            title: show.or_else(|| field(TITLE)).cloned(),
+            year: number(YEAR),
+            season_number,
+            episode_number,
+            watched: field(WATCHED).is_some_and(|value| parse_bool(value))
+                || number(PLAY_COUNT).is_some_and(|count| count > 0),
+            progress: progress.filter(|progress| match progress {
+                Progress::Bytes(bytes) => *bytes > 0,
+                Progress::Millis(millis) => *millis > 0,
+            }),
+            last_played: field(LAST_PLAYED).and_then(|value| parse_time(value)),
+        }
+    }
+
+    /// How it's called in the list of what couldn't be matched.
+    fn describe(&self) -> String {
+        let title = self.title.as_deref().unwrap_or("?");
+        match (self.kind, &self.path) {
+            (Kind::Show, _) if self.season_number.is_some() => format!(
+                "{} S{:02}E{:02}",
+                title,
+                self.season_number.unwrap_or_default(),
+                self.episode_number.unwrap_or_default()
+            ),
+            (_, Some(path)) if self.title.is_none() => path.clone(),
+            (_, _) => match self.year {
+                Some(year) => format!("{} ({})", title, year),
+                None => title.to_string(),
+            },
+        }
+    }
+}
+
+/// The file name of `path`, whichever system it's from.
+fn file_name(path: &str) -> String {
+    path.rsplit(['/', '\\'])
+        .next()
+        .unwrap_or(path)
+        .to_lowercase()
+}
+
+/// Titles without case or punctuation, which exports don't agree on.
+fn simplify(title: &str) -> String {
+    title
+        .chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// The library looked up the ways records can point at files.
+struct Index<'a> {
+    by_file_name: HashMap<String, &'a Entry>,
+    by_episode: HashMap<(String, i32, i32), &'a Entry>,
+    by_movie: HashMap<String, Vec<&'a Entry>>,
+}
+
+impl<'a> Index<'a> {
+    fn new(entries: &'a [Entry]) -> Self {
+        let mut index = Index {
+            by_file_name: HashMap::new(),
+            by_episode: HashMap::new(),
+            by_movie: HashMap::new(),
+        };
+
+        for entry in entries {
+            index.by_file_name.insert(file_name(&entry.path), entry);
+            match (entry.kind, entry.season_number, entry.episode_number) {
+                (Kind::Show, Some(season), Some(episode)) => {
+                    index
+                        .by_episode
+                        .insert((simplify(&entry.title), season, episode), entry);
+                }
+                (Kind::Movie, _, _) => index
+                    .by_movie
+                    .entry(simplify(&entry.title))
+                    .or_default()
+                    .push(entry),
+                _ => {}
+            }
+        }
+
+        index
+    }
+
+    /// The file `record` is about, by file name or else by title. Movies
+    /// with the same title need the year to tell them apart.
+    fn find(&self, record: &Record) -> Option<&'a Entry> {
+        if let Some(entry) = record
+            .path
+            .as_ref()
+            .and_then(|path| self.by_file_name.get(&file_name(path)))
+        {
+            return Some(entry);
+        }
+
+        let title = simplify(record.title.as_deref()?);
+        match record.kind {
+            Kind::Show => self
+                .by_episode
+                .get(&(title, record.season_number?, record.episode_number?))
+                .copied(),
+            Kind::Movie => {
+                let movies = self.by_movie.get(&title)?;
+                match movies.as_slice() {
+                    [movie] if record.year.is_none() || record.year == movie.year => Some(movie),
+                    movies => movies
+                        .iter()
+                        .find(|movie| record.year.is_some() && movie.year == record.year)
+                        .copied(),
+                }
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Imported {
+    imported: usize,
+    /// Records with nothing watched, or that couldn't be placed in the file.
+    skipped: usize,
+    /// Records of files that aren't in the library.
+    unmatched: Vec<String>,
+    /// How many aren't listed in `unmatched`.
+    more_unmatched: usize,
+}
+
+/// Restores the watch history in an export of centarr's or another media
+/// server's, matching its entries to the library by file name or title.
+async fn import(
+    Query(query): Query<ImportQuery>,
+    headers: HeaderMap,
+    body: String,
+) -> Result<Json<Imported>, ApiError> {
+    if let Some(user) = query.user.as_deref().filter(|user| !users::exists(user)) {
+        return Err(ApiError::new(404, format!("There's no user {:?}", user)));
+    }
+    let format = query.format.unwrap_or_else(|| {
+        let content_type = headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        match content_type.contains("csv") {
+            true => Format::Csv,
+            false => Format::Json,
+        }
+    });
+    let records = records(&body, format)?;
+
+    let config = config::get();
+    let viewer = query.user.as_deref();
+    let entries = entries(&config, viewer, &Restrictions::none()).await?;
+    let index = Index::new(&entries);
+    let mut imported = Imported {
+        imported: 0,
+        skipped: 0,
+        unmatched: Vec::new(),
+        more_unmatched: 0,
+    };
+
+    for record in records.iter().map(Record::parse) {
+        if !record.watched && record.progress.is_none() {
+            imported.skipped += 1;
+            continue;
+        }
+        let entry = match index.find(&record) {
+            Some(entry) => entry,
+            None if imported.unmatched.len() < MAX_UNMATCHED => {
+                imported.unmatched.push(record.describe());
+                continue;
+            }
+            None => {
+                imported.more_unmatched += 1;
+                continue;
+            }
+        };
+
+        let local = config.local_path(FilePath::new(&entry.path));
+        let size = entry.size.max(0) as u64;
+        let position = match record.progress {
+            _ if record.watched => Some(size),
+            Some(Progress::Bytes(bytes)) => Some(bytes.min(size)),
+            Some(Progress::Millis(millis)) => extras::duration(&local)
+                .await
+                .filter(|duration| *duration > 0.0)
+                .map(|duration| {
+                    (size as f64 * (millis as f64 / 1000.0 / duration).min(1.0)) as u64
+                }),
+            None => None,
+        };
+        let position = match position {
+            Some(position) => position,
+            None => {
+                imported.skipped += 1;
+                continue;
+            }
+        };
+
+        playback::restore(
+            viewer,
+            &local,
+            Play {
+                position,
+                size,
+                last_played: record.last_played.unwrap_or(UNIX_EPOCH),
+            },
+        );
+        imported.imported += 1;
+    }
+
+    playback::save().await;
+
+    tracing::info!(
+        "Imported {} plays, {} entries didn't match the library",
+        imported.imported,
+        imported.unmatched.len() + imported.more_unmatched
+    );
+    Ok(Json(imported))
+}
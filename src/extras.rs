@@ -0,0 +1,347 @@
+use std::path::{Path as FilePath, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use axum::{
+    extract::Path,
+    http::header,
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use tokio::process::Command;
+
+use crate::{
+    config::{self, Config},
+    errors::ApiError,
+    markers,
+    restrictions::Restrictions,
+    sonarr, store,
+};
+
+/// Plex's collection of TV theme songs, by TVDB id.
+const PLEX_THEMES: &str = "http://tvthemes.plexapp.com";
+/// How much of the first episode becomes the theme when it has no intro
+/// marker and Plex has none.
+const THEME_LENGTH: f64 = 30.0;
+const PREVIEW_LENGTH: f64 = 10.0;
+
+/// Only one clip or stream is made at a time, ffmpeg keeps a core busy as
+/// it is.
+pub static GENERATING: Lazy<tokio::sync::Mutex<()>> = Lazy::new(Default::default);
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/shows/:showId/theme", get(theme))
+        .route("/episodes/:id/preview", get(preview))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Series {
+    tvdb_id: Option<i32>,
+    #[serde(default)]
+    tags: Vec<i32>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Episode {
+    id: i32,
+    season_number: i32,
+    episode_number: i32,
+    episode_file: Option<EpisodeFile>,
+}
+
+#[derive(Deserialize)]
+struct EpisodeFile {
+    path: String,
+}
+
+async fn series(config: &Config, id: i32, restrictions: &Restrictions) -> Result<Series, ApiError> {
+    let not_found = || ApiError::new(404, format!("There's no show {}", id));
+
+    let series = match store::library() {
+        Some(library) => Series::deserialize(library.series.get(&id).ok_or_else(not_found)?)
+            .map_err(|e| ApiError::empty(500, Some(e.to_string())))?,
+        None => {
+            let body = sonarr::get(&format!("/series/{}", id)).await?;
+            serde_json::from_str(&body).map_err(|e| ApiError::empty(500, Some(e.to_string())))?
+        }
+    };
+
+    let labels = sonarr::tag_labels(config).await?;
+    if !restrictions.allows(series.tags.iter().filter_map(|id| labels.get(id))) {
+        return Err(not_found());
+    }
+
+    Ok(series)
+}
+
+/// The show's first episode with a file, specials only when there's
+/// nothing else.
+async fn first_episode(series_id: i32) -> Result<Option<Episode>, ApiError> {
+    let episodes = match store::library() {
+        Some(library) => library
+            .episodes
+            .get(&series_id)
+            .into_iter()
+            .flatten()
+            .filter_map(|episode| Episode::deserialize(episode).ok())
+            .collect(),
+        None => {
+            let body = sonarr::get(&format!("/episode?seriesId={}", series_id)).await?;
+            serde_json::from_str::<Vec<Episode>>(&body)
+                .map_err(|e| ApiError::empty(500, Some(e.to_string())))?
+        }
+    };
+
+    Ok(episodes
+        .into_iter()
+        .filter(|episode| episode.episode_file.is_some())
+        .min_by_key(|episode| {
+            (
+                episode.season_number == 0,
+                episode.season_number,
+                episode.episode_number,
+            )
+        }))
+}
+
+/// Where a generated clip is kept in the data dir.
+fn cached(folder: &str, name: String) -> PathBuf {
+    config::get().data_dir.join(folder).join(name)
+}
+
+pub async fn modified(path: &FilePath) -> Option<SystemTime> {
+    tokio::fs::metadata(path)
+        .await
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// Whether `path` exists and was made after `source` last changed.
+pub async fn is_fresh(path: &FilePath, source: Option<&FilePath>) -> bool {
+    let made = match modified(path).await {
+        Some(made) => made,
+        None => return false,
+    };
+
+    match source {
+        Some(source) => matches!(modified(source).await, Some(changed) if made >= changed),
+        None => true,
+    }
+}
+
+/// Runs ffmpeg with `args`, which end with the output file. The file is
+/// written next to `path` first so a half-made clip is never served.
+pub async fn ffmpeg(args: &[String], path: &FilePath) -> Result<(), ApiError> {
+    let failed = |e: String| ApiError::empty(500, Some(format!("Making {:?} failed: {}", path, e)));
+
+    if let Some(folder) = path.parent() {
+        tokio::fs::create_dir_all(folder)
+            .await
+            .map_err(|e| failed(e.to_string()))?;
+    }
+
+    let extension = path.extension().unwrap_or_default().to_string_lossy();
+    let temp = path.with_extension(format!("tmp.{}", extension));
+    let ffmpeg = config::get().ffmpeg_path.clone();
+    let output = Command::new(&ffmpeg)
+        .args(["-hide_banner", "-loglevel", "error", "-y"])
+        .args(args)
+        .arg(&temp)
+        .output()
+        .await
+        .map_err(|e| failed(format!("{:?} can't be run: {}", ffmpeg, e)))?;
+
+    if !output.status.success() {
+        let _ = tokio::fs::remove_file(&temp).await;
+        return Err(failed(format!(
+            "ffmpeg exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    tokio::fs::rename(&temp, path)
+        .await
+        .map_err(|e| failed(e.to_string()))
+}
+
+pub async fn serve(path: &FilePath, content_type: &'static str) -> Result<Response, ApiError> {
+    let contents = tokio::fs::read(path)
+        .await
+        .map_err(|e| ApiError::empty(500, Some(format!("Can't read {:?}: {}", path, e))))?;
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, content_type),
+            (header::CACHE_CONTROL, "max-age=86400"),
+        ],
+        contents,
+    )
+        .into_response())
+}
+
+/// Saves the theme song from Plex's collection to `path`, false when it
+/// doesn't have one or can't be reached.
+async fn download_theme(tvdb_id: i32, path: &FilePath) -> Result<bool, ApiError> {
+    let url = format!("{}/{}.mp3", PLEX_THEMES, tvdb_id);
+
+    let res = reqwest::Client::new()
+        .get(&url)
+        .timeout(Duration::from_secs(10))
+        .send()
+        .await
+        .and_then(|res| res.error_for_status());
+    let contents = match res {
+        Ok(res) => res.bytes().await,
+        Err(e) => Err(e),
+    };
+    let contents = match contents {
+        Ok(contents) => contents,
+        Err(e) => {
+            tracing::debug!("No theme from {}: {}", url, e);
+            return Ok(false);
+        }
+    };
+
+    let temp = path.with_extension("tmp.mp3");
+    let write = async {
+        if let Some(folder) = path.parent() {
+            tokio::fs::create_dir_all(folder).await?;
+        }
+        tokio::fs::write(&temp, contents).await?;
+        tokio::fs::rename(&temp, path).await
+    };
+    write
+        .await
+        .map_err(|e| ApiError::empty(500, Some(format!("Can't save {:?}: {}", path, e))))?;
+
+    Ok(true)
+}
+
+/// The show's theme song. Plex's is used when it has one, otherwise the
+/// intro of the first episode, or its first 30 seconds without an intro
+/// marker. Kept in the data dir once found.
+async fn theme(Path(id): Path<i32>, restrictions: Restrictions) -> Result<Response, ApiError> {
+    let config = config::get();
+    let series = series(&config, id, &restrictions).await?;
+    let path = cached("themes", format!("{}.mp3", id));
+
+    if !is_fresh(&path, None).await {
+        let _generating = GENERATING.lock().await;
+
+        if !is_fresh(&path, None).await {
+            let downloaded = match series.tvdb_id.filter(|id| *id > 0) {
+                Some(tvdb_id) => download_theme(tvdb_id, &path).await?,
+                None => false,
+            };
+
+            if !downloaded {
+                let episode = first_episode(id)
+                    .await?
+                    .ok_or_else(|| ApiError::new(404, format!("Show {} has no theme", id)))?;
+                let source = config.local_path(FilePath::new(&episode.episode_file.unwrap().path));
+                let (start, end) = markers::for_episode(episode.id)
+                    .intro
+                    .map_or((0.0, THEME_LENGTH), |intro| (intro.start, intro.end));
+
+                let args = [
+                    "-ss".into(),
+                    start.to_string(),
+                    "-t".into(),
+                    (end - start).to_string(),
+                    "-i".into(),
+                    source.to_string_lossy().into_owned(),
+                    "-vn".into(),
+                    "-c:a".into(),
+                    "libmp3lame".into(),
+                    "-b:a".into(),
+                    "128k".into(),
+                    "-f".into(),
+                    "mp3".into(),
+                ];
+                ffmpeg(&args, &path).await?;
+            }
+        }
+    }
+
+    serve(&path, "audio/mpeg").await
+}
+
+/// How long the media at `path` runs, in seconds.
+pub async fn duration(path: &FilePath) -> Option<f64> {
+    let output = Command::new(&config::get().ffprobe_path)
+        .args([
+            "-v",
+            "quiet",
+            "-show_entries",
+            "format=duration",
+            "-of",
+            "csv=p=0",
+        ])
+        .arg(path)
+        .output()
+        .await
+        .ok()?;
+
+    String::from_utf8_lossy(&output.stdout).trim().parse().ok()
+}
+
+/// A 10 second, 360p clip of the episode, from after its intro or a
+/// tenth of the way in. Made again when the episode's file changes.
+async fn preview(Path(id): Path<i32>, restrictions: Restrictions) -> Result<Response, ApiError> {
+    let config = config::get();
+    let source = markers::episode_path(id).await?;
+    if !restrictions.allows_file(&config, &source).await {
+        return Err(ApiError::new(404, format!("There's no episode {}", id)));
+    }
+
+    let path = cached("previews", format!("{}.mp4", id));
+
+    if !is_fresh(&path, Some(&source)).await {
+        let _generating = GENERATING.lock().await;
+
+        if !is_fresh(&path, Some(&source)).await {
+            let start = match markers::for_episode(id).intro {
+                Some(intro) => intro.end,
+                None => duration(&source).await.unwrap_or_default() / 10.0,
+            };
+
+            let args = [
+                "-ss".into(),
+                start.to_string(),
+                "-t".into(),
+                PREVIEW_LENGTH.to_string(),
+                "-i".into(),
+                source.to_string_lossy().into_owned(),
+                "-map".into(),
+                "0:v:0".into(),
+                "-map".into(),
+                "0:a:0?".into(),
+                "-vf".into(),
+                "scale=-2:360".into(),
+                "-c:v".into(),
+                "libx264".into(),
+                "-preset".into(),
+                "veryfast".into(),
+                "-crf".into(),
+                "28".into(),
+                "-c:a".into(),
+                "aac".into(),
+                "-b:a".into(),
+                "96k".into(),
+                "-movflags".into(),
+                "+faststart".into(),
+                "-f".into(),
+                "mp4".into(),
+            ];
+            ffmpeg(&args, &path).await?;
+        }
+    }
+
+    serve(&path, "video/mp4").await
+}
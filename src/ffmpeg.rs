@@ -0,0 +1,274 @@
+//! The ffmpeg and ffprobe centarr runs for intros, seek bar thumbnails,
+//! previews, themes, audio-only streams and remuxing: which builds they
+//! are, whether they have what the enabled features need, and downloading
+//! a static build for whoever would rather not install one.
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use tokio::process::Command;
+
+use crate::{
+    config::{self, Config},
+    upstream,
+};
+
+/// What was found on startup, for `GET /admin/status`.
+static DETECTED: Lazy<Mutex<Option<Detected>>> = Lazy::new(Default::default);
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Encoder,
+    Filter,
+    Muxer,
+}
+
+impl Kind {
+    fn name(self) -> &'static str {
+        match self {
+            Kind::Encoder => "encoder",
+            Kind::Filter => "filter",
+            Kind::Muxer => "muxer",
+        }
+    }
+
+    /// The option ffmpeg lists them with.
+    fn list(self) -> &'static str {
+        match self {
+            Kind::Encoder => "-encoders",
+            Kind::Filter => "-filters",
+            Kind::Muxer => "-muxers",
+        }
+    }
+}
+
+/// What ffmpeg is built with that the features enabled in `config` use,
+/// with the feature using it.
+fn required(config: &Config) -> Vec<(Kind, &'static str, &'static str)> {
+    let mut required = vec![
+        (Kind::Encoder, "libx264", "previews"),
+        (Kind::Encoder, "aac", "previews"),
+        (Kind::Filter, "scale", "previews"),
+        (Kind::Muxer, "mp4", "previews"),
+        (Kind::Encoder, "libmp3lame", "themes"),
+        (Kind::Muxer, "mp3", "themes"),
+        (Kind::Encoder, "libopus", "audio-only streams"),
+        (Kind::Encoder, "aac", "audio-only streams"),
+        (Kind::Muxer, "webm", "audio-only streams"),
+        (Kind::Muxer, "mp4", "audio-only streams"),
+        (Kind::Muxer, "mp4", "remuxing"),
+    ];
+    if !config.trickplay_widths.is_empty() {
+        required.extend([
+            (Kind::Encoder, "mjpeg", "trickplay"),
+            (Kind::Filter, "fps", "trickplay"),
+            (Kind::Filter, "scale", "trickplay"),
+            (Kind::Filter, "tile", "trickplay"),
+            (Kind::Muxer, "image2", "trickplay"),
+        ]);
+    }
+    if config.detect_intros {
+        required.push((Kind::Muxer, "chromaprint", "intros"));
+    }
+
+    required
+}
+
+/// One of the binaries.
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Tool {
+    path: PathBuf,
+    /// The first line of its `-version`, like `ffmpeg version 6.0-static`.
+    pub version: Option<String>,
+    /// Why it can't be run.
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Detected {
+    pub ffmpeg: Tool,
+    pub ffprobe: Tool,
+    /// What the enabled features need that ffmpeg isn't built with, like
+    /// `encoder libx264 (previews)`.
+    pub missing: Vec<String>,
+}
+
+async fn version(path: &Path) -> Tool {
+    let output = Command::new(path).arg("-version").output().await;
+    let (version, error) = match output {
+        Ok(output) if output.status.success() => {
+            let version = String::from_utf8_lossy(&output.stdout);
+            (version.lines().next().map(String::from), None)
+        }
+        Ok(output) => (None, Some(format!("exited with {}", output.status))),
+        Err(e) => (None, Some(format!("can't be run: {}", e))),
+    };
+
+    Tool {
+        path: path.to_path_buf(),
+        version,
+        error,
+    }
+}
+
+/// The names of the encoders, filters or muxers `ffmpeg` lists, the
+/// second column of every line.
+async fn components(ffmpeg: &Path, kind: Kind) -> Result<HashSet<String>, String> {
+    let output = Command::new(ffmpeg)
+        .args(["-hide_banner", kind.list()])
+        .output()
+        .await
+        .map_err(|e| e.to_string())?;
+    if !output.status.success() {
+        return Err(format!("{} exited with {}", kind.list(), output.status));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| line.split_whitespace().nth(1))
+        .map(String::from)
+        .collect())
+}
+
+/// Runs both binaries, and checks ffmpeg has what the features enabled in
+/// `config` need.
+pub async fn detect(config: &Config) -> Detected {
+    let ffmpeg = version(&config.ffmpeg_path).await;
+    let ffprobe = version(&config.ffprobe_path).await;
+
+    let mut missing = Vec::new();
+    if ffmpeg.error.is_none() {
+        let required = required(config);
+        for kind in [Kind::Encoder, Kind::Filter, Kind::Muxer] {
+            // builds that can't list them are given the benefit of the doubt
+            let found = match components(&config.ffmpeg_path, kind).await {
+                Ok(found) => found,
+                Err(_) => continue,
+            };
+            missing.extend(
+                required
+                    .iter()
+                    .filter(|(required, name, _)| *required == kind && !found.contains(*name))
+                    .map(|(_, name, feature)| format!("{} {} ({})", kind.name(), name, feature)),
+            );
+        }
+    }
+
+    Detected {
+        ffmpeg,
+        ffprobe,
+        missing,
+    }
+}
+
+/// What was found on startup, `None` until it's checked.
+pub fn status() -> Option<Detected> {
+    DETECTED.lock().unwrap().clone()
+}
+
+/// The first file named `name` under `dir`.
+fn find(dir: &Path, name: &str) -> Option<PathBuf> {
+    for entry in std::fs::read_dir(dir).ok()?.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            if let Some(found) = find(&path, name) {
+                return Some(found);
+            }
+        } else if path.file_name().is_some_and(|file| file == name) {
+            return Some(path);
+        }
+    }
+
+    None
+}
+
+/// Downloads a static build and puts its `ffmpeg` and `ffprobe` in
+/// `ffmpeg/` in the data dir.
+async fn download(config: &Config) -> Result<(), String> {
+    let dir = config.data_dir.join("ffmpeg");
+    let unpacked = dir.join("unpacked");
+    let archive = dir.join("download.tar.xz");
+    tracing::info!("Downloading ffmpeg from {}", config.ffmpeg_download_url);
+
+    let body = upstream::client()
+        .get(&config.ffmpeg_download_url)
+        .send()
+        .await
+        .and_then(|res| res.error_for_status())
+        .map_err(|e| e.to_string())?
+        .bytes()
+        .await
+        .map_err(|e| e.to_string())?;
+    tokio::fs::create_dir_all(&unpacked)
+        .await
+        .map_err(|e| format!("{:?} can't be made: {}", unpacked, e))?;
+    tokio::fs::write(&archive, &body)
+        .await
+        .map_err(|e| format!("{:?} can't be written: {}", archive, e))?;
+
+    let output = Command::new("tar")
+        .arg("-xJf")
+        .arg(&archive)
+        .arg("-C")
+        .arg(&unpacked)
+        .output()
+        .await
+        .map_err(|e| format!("tar can't be run: {}", e))?;
+    let result = match output.status.success() {
+        true => ["ffmpeg", "ffprobe"].into_iter().try_for_each(|name| {
+            // builds put them at the top or in bin/, under a folder named
+            // after the version
+            let found = find(&unpacked, name).ok_or(format!("the archive has no {}", name))?;
+            std::fs::rename(&found, dir.join(name)).map_err(|e| e.to_string())
+        }),
+        false => Err(format!(
+            "tar exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )),
+    };
+    let _ = tokio::fs::remove_dir_all(&unpacked).await;
+    let _ = tokio::fs::remove_file(&archive).await;
+
+    result
+}
+
+/// Downloads ffmpeg when that's on and it isn't there yet, then checks
+/// both binaries, warning about what won't work.
+pub async fn run() {
+    let config = config::get();
+    let downloaded = config.data_dir.join("ffmpeg");
+    if config.download_ffmpeg
+        && [&config.ffmpeg_path, &config.ffprobe_path]
+            .iter()
+            .any(|path| path.starts_with(&downloaded) && !path.exists())
+    {
+        match download(&config).await {
+            Ok(()) => tracing::info!("Downloaded ffmpeg to {:?}", downloaded),
+            Err(e) => tracing::error!("Can't download ffmpeg: {}", e),
+        }
+    }
+
+    let detected = detect(&config).await;
+    for (name, tool) in [("ffmpeg", &detected.ffmpeg), ("ffprobe", &detected.ffprobe)] {
+        match (&tool.version, &tool.error) {
+            (Some(version), _) => tracing::info!("Using {}", version),
+            (_, Some(e)) => tracing::warn!(
+                "{} at {:?} {}, features using it will fail",
+                name,
+                tool.path,
+                e
+            ),
+            _ => {}
+        }
+    }
+    for missing in &detected.missing {
+        tracing::warn!("ffmpeg isn't built with {}", missing);
+    }
+    *DETECTED.lock().unwrap() = Some(detected);
+}
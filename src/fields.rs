@@ -0,0 +1,102 @@
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::errors::ApiError;
+
+/// Every field of an episode, as serialized.
+const EPISODE_FIELDS: &[&str] = &[
+    "id",
+    "seriesId",
+    "episodeFileId",
+    "seasonNumber",
+    "episodeNumber",
+    "title",
+    "airDate",
+    "airDateUtc",
+    "isAired",
+    "airsInMinutes",
+    "overview",
+    "episodeFile",
+    "hasFile",
+    "monitored",
+    "absoluteEpisodeNumber",
+    "sceneAbsoluteEpisodeNumber",
+    "sceneEpisodeNumber",
+    "sceneSeasonNumber",
+    "unverifiedSceneNumbering",
+    "lastSearchTime",
+];
+
+/// Left out unless asked for, Sonarr's scene numbering and searches are
+/// of no use to players.
+const NOT_COMPACT: &[&str] = &[
+    "sceneAbsoluteEpisodeNumber",
+    "sceneEpisodeNumber",
+    "sceneSeasonNumber",
+    "unverifiedSceneNumbering",
+    "lastSearchTime",
+];
+
+#[derive(Deserialize)]
+pub struct FieldsQuery {
+    fields: Option<String>,
+}
+
+/// Which fields of episodes are sent.
+pub enum EpisodeFields {
+    /// All but [`NOT_COMPACT`], what's sent without `?fields=`.
+    Compact,
+    /// `?fields=all`.
+    All,
+    /// `?fields=id,title,...`, `id` is always sent.
+    Only(Vec<String>),
+}
+
+impl TryFrom<FieldsQuery> for EpisodeFields {
+    type Error = ApiError;
+
+    fn try_from(query: FieldsQuery) -> Result<Self, ApiError> {
+        let fields = match query.fields.as_deref().map(str::trim) {
+            None | Some("") | Some("compact") => return Ok(Self::Compact),
+            Some("all") => return Ok(Self::All),
+            Some(fields) => fields,
+        };
+
+        let mut only = vec!["id".to_string()];
+        for field in fields.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+            if !EPISODE_FIELDS.contains(&field) {
+                return Err(ApiError::new(
+                    400,
+                    format!(
+                        "Episodes have no field {:?}, they have {}",
+                        field,
+                        EPISODE_FIELDS.join(", ")
+                    ),
+                ));
+            }
+            if !only.iter().any(|known| known == field) {
+                only.push(field.to_string());
+            }
+        }
+
+        Ok(Self::Only(only))
+    }
+}
+
+impl EpisodeFields {
+    fn keeps(&self, field: &str) -> bool {
+        match self {
+            Self::Compact => !NOT_COMPACT.contains(&field),
+            Self::All => true,
+            Self::Only(fields) => fields.iter().any(|kept| kept == field),
+        }
+    }
+
+    /// Drops what wasn't asked for from every episode in `episodes`.
+    pub fn select(&self, episodes: &mut Value) {
+        let episodes = episodes.as_array_mut().into_iter().flatten();
+        for episode in episodes.filter_map(Value::as_object_mut) {
+            episode.retain(|field, _| self.keeps(field));
+        }
+    }
+}
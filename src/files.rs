@@ -0,0 +1,324 @@
+use std::fs::Metadata;
+use std::io::SeekFrom;
+use std::path::{Path as FilePath, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use axum::{
+    body::{boxed, Body, Bytes, Empty},
+    extract::Path,
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::Response,
+    routing::get,
+    Router,
+};
+use serde::Deserialize;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use crate::{
+    config, errors::ApiError, markers, restrictions::Restrictions, sendfile, sonarr, store, zip,
+};
+
+const CHUNK_SIZE: usize = 64 * 1024;
+/// Files written to more recently than this are taken to still be being
+/// copied in, like Sonarr does while importing.
+const GROWING_AGE: Duration = Duration::from_secs(10);
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/episodes/:id/download", get(download))
+        .route(
+            "/shows/:showId/seasons/:season/download.zip",
+            get(download_season),
+        )
+}
+
+/// The inclusive byte range a `Range` header asks for out of `len` bytes.
+/// `Ok(None)` means the whole file, as for no or unsupported headers
+/// (multiple ranges), and `Err` that the range is past the end.
+pub fn byte_range(range: Option<&HeaderValue>, len: u64) -> Result<Option<(u64, u64)>, ()> {
+    let spec = match range
+        .and_then(|range| range.to_str().ok())
+        .and_then(|range| range.trim().strip_prefix("bytes="))
+    {
+        Some(spec) if !spec.contains(',') => spec,
+        _ => return Ok(None),
+    };
+    let (start, end) = match spec.split_once('-') {
+        Some(bounds) => bounds,
+        None => return Ok(None),
+    };
+
+    let range = match (start.trim().parse::<u64>(), end.trim().parse::<u64>()) {
+        // the last `end` bytes
+        (Err(_), Ok(suffix)) if start.trim().is_empty() => {
+            if suffix == 0 || len == 0 {
+                return Err(());
+            }
+            (len.saturating_sub(suffix), len - 1)
+        }
+        (Ok(start), Err(_)) if end.trim().is_empty() => (start, len.saturating_sub(1)),
+        (Ok(start), Ok(end)) if start <= end => (start, end.min(len.saturating_sub(1))),
+        _ => return Ok(None),
+    };
+
+    if range.0 >= len {
+        return Err(());
+    }
+
+    Ok(Some(range))
+}
+
+/// Whether the file is likely still being written to.
+pub fn is_growing(metadata: &Metadata) -> bool {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.elapsed().ok())
+        .is_some_and(|age| age < GROWING_AGE)
+}
+
+/// `len` bytes of `file` from `start`, at no more than `max_rate` bytes per
+/// second. Stops early when the client goes away.
+pub fn body(mut file: File, start: u64, len: u64, max_rate: Option<u64>) -> Body {
+    let (mut sender, body) = Body::channel();
+
+    tokio::spawn(async move {
+        if let Err(e) = file.seek(SeekFrom::Start(start)).await {
+            tracing::warn!("Can't seek to {}: {}", start, e);
+            return sender.abort();
+        }
+
+        let started = Instant::now();
+        let mut sent = 0;
+        let mut buf = vec![0; CHUNK_SIZE];
+        while sent < len {
+            let want = CHUNK_SIZE.min((len - sent) as usize);
+            let read = match file.read(&mut buf[..want]).await {
+                Ok(0) => return sender.abort(),
+                Ok(read) => read,
+                Err(e) => {
+                    tracing::warn!("Reading failed after {} bytes: {}", sent, e);
+                    return sender.abort();
+                }
+            };
+
+            if sender
+                .send_data(Bytes::copy_from_slice(&buf[..read]))
+                .await
+                .is_err()
+            {
+                tracing::debug!("Client went away after {} bytes", sent);
+                return;
+            }
+            sent += read as u64;
+
+            if let Some(rate) = max_rate {
+                sendfile::throttle(rate, sent, started).await;
+            }
+        }
+    });
+
+    body
+}
+
+fn file_name(path: &FilePath) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "download".into())
+}
+
+/// `attachment` saved as `name`, plainly for old clients and UTF-8
+/// encoded for the rest.
+fn content_disposition(name: &str) -> HeaderValue {
+    let plain = name
+        .chars()
+        .map(|c| match c {
+            ' '..='~' if c != '"' && c != '\\' => c,
+            _ => '_',
+        })
+        .collect::<String>();
+
+    HeaderValue::from_str(&format!(
+        "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+        plain,
+        urlencoding::encode(name)
+    ))
+    .unwrap_or_else(|_| HeaderValue::from_static("attachment"))
+}
+
+/// The episode's file as it is on disk, offered to be saved rather than
+/// played. Supports ranges so interrupted downloads can be resumed.
+async fn download(
+    Path(id): Path<i32>,
+    headers: HeaderMap,
+    restrictions: Restrictions,
+) -> Result<Response, ApiError> {
+    let config = config::get();
+    let path = markers::episode_path(id).await?;
+    if !restrictions.allows_file(&config, &path).await {
+        return Err(ApiError::new(404, format!("There's no episode {}", id)));
+    }
+
+    let file = File::open(&path)
+        .await
+        .map_err(|e| ApiError::new(404, format!("Episode {} can't be read: {}", id, e)))?;
+    let len = file
+        .metadata()
+        .await
+        .map_err(|e| ApiError::empty(500, Some(format!("Can't stat {:?}: {}", path, e))))?
+        .len();
+
+    let content_type = mime_guess::from_path(&path)
+        .first_raw()
+        .unwrap_or("application/octet-stream");
+    let mut response = Response::builder()
+        .header(header::CONTENT_TYPE, content_type)
+        .header(
+            header::CONTENT_DISPOSITION,
+            content_disposition(&file_name(&path)),
+        )
+        .header(header::ACCEPT_RANGES, "bytes");
+
+    let (start, end) = match byte_range(headers.get(header::RANGE), len) {
+        Ok(Some((start, end))) => {
+            response = response.status(StatusCode::PARTIAL_CONTENT).header(
+                header::CONTENT_RANGE,
+                format!("bytes {}-{}/{}", start, end, len),
+            );
+            (start, end)
+        }
+        Ok(None) => (0, len.saturating_sub(1)),
+        Err(()) => {
+            return Ok(response
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(header::CONTENT_RANGE, format!("bytes */{}", len))
+                .body(boxed(Empty::new()))
+                .unwrap())
+        }
+    };
+    let body_len = if len == 0 { 0 } else { end + 1 - start };
+
+    tracing::debug!("Downloading {:?} from {} to {}", path, start, end);
+    let body = body(file, start, body_len, config.max_download_rate);
+
+    Ok(response
+        .header(header::CONTENT_LENGTH, body_len)
+        .body(boxed(body))
+        .unwrap())
+}
+
+#[derive(Deserialize)]
+struct Series {
+    title: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Episode {
+    season_number: i32,
+    episode_number: i32,
+    episode_file: Option<EpisodeFile>,
+}
+
+#[derive(Deserialize)]
+struct EpisodeFile {
+    path: String,
+}
+
+/// The show's title and the local paths of the season's episode files, in
+/// order.
+async fn season_files(show_id: i32, season: i32) -> Result<(String, Vec<PathBuf>), ApiError> {
+    let not_found = || ApiError::new(404, format!("There's no show {}", show_id));
+    let invalid = |e: serde_json::Error| ApiError::empty(500, Some(e.to_string()));
+
+    let (series, episodes) = match store::library() {
+        Some(library) => {
+            let series = library.series.get(&show_id).ok_or_else(not_found)?;
+            let episodes = library
+                .episodes
+                .get(&show_id)
+                .into_iter()
+                .flatten()
+                .map(Episode::deserialize)
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(invalid)?;
+
+            (Series::deserialize(series).map_err(invalid)?, episodes)
+        }
+        None => {
+            let series = sonarr::get(&format!("/series/{}", show_id)).await?;
+            let episodes = sonarr::get(&format!("/episode?seriesId={}", show_id)).await?;
+
+            (
+                serde_json::from_str(&series).map_err(invalid)?,
+                serde_json::from_str::<Vec<Episode>>(&episodes).map_err(invalid)?,
+            )
+        }
+    };
+
+    let config = config::get();
+    let mut episodes = episodes
+        .into_iter()
+        .filter(|episode| episode.season_number == season)
+        .filter_map(|episode| Some((episode.episode_number, episode.episode_file?.path)))
+        .collect::<Vec<_>>();
+    episodes.sort();
+
+    let mut paths = episodes
+        .into_iter()
+        .map(|(_, path)| config.local_path(FilePath::new(&path)))
+        .collect::<Vec<_>>();
+    // multi-episode files show up once per episode
+    paths.dedup();
+
+    Ok((series.title, paths))
+}
+
+/// Every episode file of the season in one zip, for copying to a device
+/// at once. Files are stored as they are, not compressed.
+async fn download_season(
+    Path((show_id, season)): Path<(i32, i32)>,
+    restrictions: Restrictions,
+) -> Result<Response, ApiError> {
+    let config = config::get();
+    if !config.zip_downloads {
+        return Err(ApiError::new(404, "Season downloads are turned off".into()));
+    }
+
+    let (title, paths) = season_files(show_id, season).await?;
+    let no_files = || ApiError::new(404, format!("Season {} has no files", season));
+    // the files are all in the same show, so they're all allowed or none are
+    if !restrictions
+        .allows_file(&config, paths.first().ok_or_else(no_files)?)
+        .await
+    {
+        return Err(ApiError::new(404, format!("There's no show {}", show_id)));
+    }
+
+    let mut entries = Vec::with_capacity(paths.len());
+    for path in paths {
+        match tokio::fs::metadata(&path).await {
+            Ok(metadata) => entries.push(zip::Entry {
+                name: file_name(&path),
+                size: metadata.len(),
+                modified: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+                path,
+            }),
+            Err(e) => tracing::warn!("Leaving {:?} out of the zip: {}", path, e),
+        }
+    }
+    if entries.is_empty() {
+        return Err(no_files());
+    }
+
+    let name = format!("{} - Season {:02}.zip", title, season);
+    tracing::debug!("Downloading {} files as {:?}", entries.len(), name);
+
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, "application/zip")
+        .header(header::CONTENT_DISPOSITION, content_disposition(&name))
+        .header(header::CONTENT_LENGTH, zip::len(&entries))
+        .body(boxed(zip::body(entries, config.max_download_rate)))
+        .unwrap())
+}
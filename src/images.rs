@@ -0,0 +1,479 @@
+use std::collections::BTreeMap;
+use std::io::Cursor;
+use std::path::{Path as FilePath, PathBuf};
+use std::sync::RwLock;
+use std::time::{Duration, Instant, SystemTime};
+
+use axum::{
+    extract::{Path, Query},
+    response::Response,
+    routing::get,
+    Router,
+};
+use image::{imageops::FilterType, ImageFormat};
+use once_cell::sync::Lazy;
+use ring::digest;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    blurhash,
+    config::{self, Config},
+    errors::ApiError,
+    extras,
+    library::Image,
+    restrictions::Restrictions,
+    sonarr, store, users,
+};
+
+/// Wider than any screen needs for a poster or fanart.
+const MAX_WIDTH: u32 = 3840;
+const IMAGES_TABLE: &str = "images";
+/// Images are scaled down to fit this many pixels before working out their
+/// blurhash.
+const BLURHASH_SIZE: u32 = 32;
+/// Waited after each image fetched ahead of time, not to keep Sonarr busy.
+const PREFETCH_DELAY: Duration = Duration::from_millis(250);
+
+/// What's cached of each show's artwork, by show id and cover type,
+/// `1/poster`.
+static IMAGES: Lazy<RwLock<BTreeMap<String, Cached>>> = Lazy::new(Default::default);
+/// The images as they were last saved.
+static SAVED: Lazy<tokio::sync::Mutex<BTreeMap<String, Cached>>> = Lazy::new(Default::default);
+
+pub fn router() -> Router {
+    Router::new().route("/shows/:showId/images/:coverType", get(image))
+}
+
+#[derive(Deserialize)]
+struct ImageQuery {
+    /// In pixels, keeping the aspect ratio. Images are never made larger.
+    width: Option<u32>,
+    format: Option<Format>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+enum Format {
+    #[serde(alias = "jpg")]
+    Jpeg,
+    Png,
+    Webp,
+}
+
+impl Format {
+    fn of(name: &str) -> Option<Self> {
+        match FilePath::new(name).extension()?.to_str()? {
+            "jpg" | "jpeg" => Some(Self::Jpeg),
+            "png" => Some(Self::Png),
+            "webp" => Some(Self::Webp),
+            _ => None,
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Jpeg => "jpg",
+            Self::Png => "png",
+            Self::Webp => "webp",
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            Self::Jpeg => "image/jpeg",
+            Self::Png => "image/png",
+            Self::Webp => "image/webp",
+        }
+    }
+
+    fn image_format(self) -> ImageFormat {
+        match self {
+            Self::Jpeg => ImageFormat::Jpeg,
+            Self::Png => ImageFormat::Png,
+            Self::Webp => ImageFormat::WebP,
+        }
+    }
+}
+
+/// A show's image as it was last fetched from Sonarr.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+struct Cached {
+    /// Sonarr's url for it, which changes along with the image.
+    url: String,
+    /// Hash of the image itself, which it and its variants are named after
+    /// in the data dir, so artwork shared by shows is only kept once.
+    content: String,
+    format: Format,
+    blurhash: Option<String>,
+}
+
+impl Cached {
+    fn path(&self) -> PathBuf {
+        cached(&format!("{}.{}", self.content, self.format.extension()))
+    }
+}
+
+#[derive(Deserialize)]
+struct Series {
+    id: i32,
+    #[serde(default)]
+    images: Vec<Image>,
+    #[serde(default)]
+    tags: Vec<i32>,
+}
+
+/// Loads what's known of the cached artwork.
+pub async fn load() {
+    let images = store::rows::<String, Cached>(IMAGES_TABLE).await;
+    tracing::debug!("Loaded {} cached images", images.len());
+    *IMAGES.write().unwrap() = images.clone();
+    *SAVED.lock().await = images;
+}
+
+/// Saves the images that changed since they were last saved.
+async fn save_index() {
+    let mut saved = SAVED.lock().await;
+    let all = IMAGES.read().unwrap().clone();
+
+    match store::apply(&store::diff(IMAGES_TABLE, &saved, &all)).await {
+        Ok(()) => *saved = all,
+        Err(e) => tracing::warn!("Failed to save the cached images: {}", e),
+    }
+}
+
+fn key(show_id: i32, cover_type: &str) -> String {
+    format!("{}/{}", show_id, cover_type)
+}
+
+/// The blurhash of the image Sonarr has at `url`, once it was cached.
+pub fn blurhash(show_id: i32, cover_type: &str, url: &str) -> Option<String> {
+    IMAGES
+        .read()
+        .unwrap()
+        .get(&key(show_id, cover_type))
+        .filter(|cached| cached.url == url)
+        .and_then(|cached| cached.blurhash.clone())
+}
+
+/// Where artwork is kept in the data dir.
+fn cached(name: &str) -> PathBuf {
+    config::get().data_dir.join("images").join(name)
+}
+
+/// Writes `contents` next to `path` first, so a half-written image is never
+/// served.
+async fn save(path: &FilePath, contents: &[u8]) -> Result<(), ApiError> {
+    let failed =
+        |e: std::io::Error| ApiError::empty(500, Some(format!("Saving {:?} failed: {}", path, e)));
+
+    if let Some(folder) = path.parent() {
+        tokio::fs::create_dir_all(folder).await.map_err(failed)?;
+    }
+    // the same image may be saved by two requests at once
+    let temp = path.with_extension(format!("{}.tmp", users::random_hex(4)));
+    tokio::fs::write(&temp, contents).await.map_err(failed)?;
+    tokio::fs::rename(&temp, path).await.map_err(failed)
+}
+
+async fn read(path: &FilePath) -> Result<Vec<u8>, ApiError> {
+    tokio::fs::read(path)
+        .await
+        .map_err(|e| ApiError::empty(500, Some(format!("Can't read {:?}: {}", path, e))))
+}
+
+/// Marks `path` as just used, the least recently used images being the
+/// first to go when the cache is full.
+async fn touch(path: PathBuf) {
+    let touched = tokio::task::spawn_blocking(move || {
+        std::fs::File::options()
+            .write(true)
+            .open(path)?
+            .set_modified(SystemTime::now())
+    })
+    .await;
+
+    if let Ok(Err(e)) = touched {
+        tracing::debug!("Can't mark an image as used: {}", e);
+    }
+}
+
+/// Works out the blurhash of `original`, on a small copy of it as that
+/// looks the same blurred.
+fn encode_blurhash(original: &[u8]) -> Result<String, String> {
+    let image = image::load_from_memory(original).map_err(|e| e.to_string())?;
+    let small = image.thumbnail(BLURHASH_SIZE, BLURHASH_SIZE).into_rgb8();
+    let (x, y) = if small.width() >= small.height() {
+        (4, 3)
+    } else {
+        (3, 4)
+    };
+
+    Ok(blurhash::encode(&small, x, y))
+}
+
+/// The `cover_type` image Sonarr has at `url` for show `show_id`, fetched
+/// with its blurhash unless it's cached already, and whether it had to be.
+/// `None` when the url isn't that of an image. The index of cached images
+/// is saved right away with `save_now`, prefetching saves it once at the
+/// end instead.
+async fn cache(
+    show_id: i32,
+    cover_type: &str,
+    url: &str,
+    save_now: bool,
+) -> Result<Option<(Cached, bool)>, ApiError> {
+    let known = IMAGES
+        .read()
+        .unwrap()
+        .get(&key(show_id, cover_type))
+        .filter(|cached| cached.url == url)
+        .cloned();
+    if let Some(known) = known {
+        if extras::is_fresh(&known.path(), None).await {
+            return Ok(Some((known, false)));
+        }
+    }
+
+    // `/MediaCover/1/poster.jpg?lastWrite=...`
+    let name = url
+        .split('?')
+        .next()
+        .and_then(|path| path.rsplit('/').next())
+        .unwrap_or_default();
+    let format = match Format::of(name) {
+        Some(format) => format,
+        None => return Ok(None),
+    };
+
+    let contents = sonarr::media_cover(show_id, name).await?;
+    let hash = digest::digest(&digest::SHA256, &contents);
+    let content = users::hex(&hash.as_ref()[..16]);
+    // other shows may have the same image, blurhash and all
+    let blurhash = IMAGES
+        .read()
+        .unwrap()
+        .values()
+        .find(|cached| cached.content == content)
+        .and_then(|cached| cached.blurhash.clone());
+
+    let mut cached = Cached {
+        url: url.into(),
+        content,
+        format,
+        blurhash,
+    };
+    save(&cached.path(), &contents).await?;
+
+    if cached.blurhash.is_none() {
+        let hash = tokio::task::spawn_blocking(move || encode_blurhash(&contents))
+            .await
+            .map_err(|e| ApiError::empty(500, Some(e.to_string())))?;
+        match hash {
+            Ok(hash) => cached.blurhash = Some(hash),
+            Err(e) => tracing::warn!("Can't work out the blurhash of {}: {}", url, e),
+        }
+    }
+
+    IMAGES
+        .write()
+        .unwrap()
+        .insert(key(show_id, cover_type), cached.clone());
+    if save_now {
+        save_index().await;
+    }
+
+    Ok(Some((cached, true)))
+}
+
+/// Scales `original` down to `width` and encodes it as `format`.
+fn convert(original: &[u8], width: Option<u32>, format: Format) -> Result<Vec<u8>, String> {
+    let mut image = image::load_from_memory(original).map_err(|e| e.to_string())?;
+    if let Some(width) = width.filter(|width| *width < image.width()) {
+        let height =
+            (u64::from(image.height()) * u64::from(width) / u64::from(image.width())).max(1) as u32;
+        image = image.resize_exact(width, height, FilterType::Lanczos3);
+    }
+    // JPEG has no alpha channel
+    if format == Format::Jpeg {
+        image = image.into_rgb8().into();
+    }
+
+    let mut encoded = Cursor::new(Vec::new());
+    image
+        .write_to(&mut encoded, format.image_format())
+        .map_err(|e| e.to_string())?;
+    Ok(encoded.into_inner())
+}
+
+/// A show's poster, fanart or banner, resized to `width` and converted to
+/// `format` when asked, so grids don't download full size posters. Each
+/// size and format is made once and kept.
+async fn image(
+    Path((show_id, cover_type)): Path<(i32, String)>,
+    Query(query): Query<ImageQuery>,
+    restrictions: Restrictions,
+) -> Result<Response, ApiError> {
+    let not_found = || ApiError::new(404, format!("Show {} has no {} image", show_id, cover_type));
+    if query.width == Some(0) || query.width > Some(MAX_WIDTH) {
+        return Err(ApiError::new(
+            400,
+            format!("Widths are 1 to {} pixels", MAX_WIDTH),
+        ));
+    }
+
+    let series = match store::library() {
+        Some(library) => library.series.get(&show_id).cloned(),
+        None => sonarr::series_by_id().await?.remove(&show_id.into()),
+    };
+    let series = series
+        .and_then(|series| Series::deserialize(series).ok())
+        .ok_or_else(|| ApiError::new(404, format!("There's no show {}", show_id)))?;
+
+    let labels = sonarr::tag_labels(&config::get()).await?;
+    if !restrictions.allows(series.tags.iter().filter_map(|id| labels.get(id))) {
+        return Err(ApiError::new(404, format!("There's no show {}", show_id)));
+    }
+
+    let url = series
+        .images
+        .into_iter()
+        .find(|image| image.cover_type == cover_type)
+        .and_then(|image| image.url)
+        .ok_or_else(not_found)?;
+    let (original, _) = cache(show_id, &cover_type, &url, true)
+        .await?
+        .ok_or_else(not_found)?;
+    let path = original.path();
+
+    let format = query.format.unwrap_or(original.format);
+    if query.width.is_none() && format == original.format {
+        touch(path.clone()).await;
+        return extras::serve(&path, format.content_type()).await;
+    }
+
+    let variant = cached(&format!(
+        "{}-{}.{}",
+        original.content,
+        query.width.map_or_else(|| "full".into(), |w| w.to_string()),
+        format.extension()
+    ));
+    if extras::is_fresh(&variant, None).await {
+        touch(variant.clone()).await;
+    } else {
+        let contents = read(&path).await?;
+        let width = query.width;
+        let converted = tokio::task::spawn_blocking(move || convert(&contents, width, format))
+            .await
+            .map_err(|e| ApiError::empty(500, Some(e.to_string())))?
+            .map_err(|e| ApiError::empty(500, Some(format!("Converting {} failed: {}", url, e))))?;
+        save(&variant, &converted).await?;
+    }
+
+    extras::serve(&variant, format.content_type()).await
+}
+
+/// The files in the image cache with when they were last used and their
+/// size, least recently used first.
+async fn cache_files() -> Vec<(SystemTime, u64, PathBuf)> {
+    let mut files = Vec::new();
+    let mut entries = match tokio::fs::read_dir(cached("")).await {
+        Ok(entries) => entries,
+        Err(_) => return files,
+    };
+
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let path = entry.path();
+        // being written
+        if path.extension().is_some_and(|extension| extension == "tmp") {
+            continue;
+        }
+        if let Some(metadata) = entry
+            .metadata()
+            .await
+            .ok()
+            .filter(|metadata| metadata.is_file())
+        {
+            let used = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            files.push((used, metadata.len(), path));
+        }
+    }
+
+    files.sort();
+    files
+}
+
+/// Deletes the least recently used images until the cache fits in
+/// `max_size` bytes, returning how many went. Shows whose image went have
+/// it fetched again when it's asked for.
+pub async fn trim(max_size: u64) -> usize {
+    let files = cache_files().await;
+    let mut size = files.iter().map(|(_, size, _)| size).sum::<u64>();
+    let mut removed = 0;
+
+    for (_, file_size, path) in files {
+        if size <= max_size {
+            break;
+        }
+        match tokio::fs::remove_file(&path).await {
+            Ok(()) => {
+                size -= file_size;
+                removed += 1;
+            }
+            Err(e) => tracing::warn!("Can't remove {:?} from the image cache: {}", path, e),
+        }
+    }
+
+    removed
+}
+
+/// Fetches the artwork of every show that isn't cached yet, one image at a
+/// time, until the cache is full. Returns how many were fetched, so grids
+/// aren't waiting on Sonarr the first time they're shown.
+pub async fn prefetch(config: &Config) -> Result<usize, String> {
+    let series = sonarr::series_by_id()
+        .await
+        .map_err(|e| format!("the shows can't be listed: {:?}", e))?;
+    let started = Instant::now();
+    let mut size = cache_files()
+        .await
+        .iter()
+        .map(|(_, size, _)| size)
+        .sum::<u64>();
+    let mut fetched = 0;
+
+    let images = series
+        .values()
+        .filter_map(|series| Series::deserialize(series).ok())
+        .flat_map(|series| {
+            let id = series.id;
+            series
+                .images
+                .into_iter()
+                .filter_map(move |image| Some((id, image.cover_type, image.url?)))
+        });
+    for (id, cover_type, url) in images {
+        if size >= config.image_cache_size {
+            tracing::debug!("The image cache is full, not prefetching more");
+            break;
+        }
+
+        match cache(id, &cover_type, &url, false).await {
+            Ok(Some((cached, true))) => {
+                fetched += 1;
+                size += tokio::fs::metadata(cached.path())
+                    .await
+                    .map_or(0, |metadata| metadata.len());
+                tokio::time::sleep(PREFETCH_DELAY).await;
+            }
+            Ok(_) => {}
+            Err(e) => tracing::warn!("Can't prefetch the {} of show {}: {:?}", cover_type, id, e),
+        }
+    }
+
+    if fetched > 0 {
+        save_index().await;
+        tracing::debug!("Prefetched {} images in {:?}", fetched, started.elapsed());
+    }
+
+    Ok(fetched)
+}
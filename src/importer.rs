@@ -0,0 +1,412 @@
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::process::ExitCode;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use chrono::DateTime;
+use reqwest::header::ACCEPT;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+
+use crate::{
+    config,
+    errors::ApiError,
+    playback::{self, Play},
+    sonarr, store, upstream, users,
+};
+
+/// Where watch state is imported from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Source {
+    Jellyfin,
+    Plex,
+}
+
+/// An episode someone watched some or all of on the other server.
+struct Watched {
+    tvdb_id: i64,
+    season_number: i32,
+    episode_number: i32,
+    played: bool,
+    /// How far into the episode they got, between 0 and 1.
+    progress: f64,
+    last_played: Option<SystemTime>,
+}
+
+async fn get_json<T: DeserializeOwned>(
+    url: &str,
+    token_header: &str,
+    token: &str,
+) -> Result<T, String> {
+    let res = upstream::client()
+        .get(url)
+        .header(token_header, token)
+        .header(ACCEPT, "application/json")
+        .send()
+        .await
+        .map_err(|e| format!("Can't reach {}: {}", url, e))?;
+
+    if !res.status().is_success() {
+        return Err(format!("{} answered {}", url, res.status()));
+    }
+
+    res.json()
+        .await
+        .map_err(|e| format!("{} sent something unexpected: {}", url, e))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct JellyfinUser {
+    id: String,
+    name: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct JellyfinItems {
+    items: Vec<JellyfinItem>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct JellyfinItem {
+    id: String,
+    #[serde(default)]
+    provider_ids: HashMap<String, String>,
+    series_id: Option<String>,
+    parent_index_number: Option<i32>,
+    index_number: Option<i32>,
+    run_time_ticks: Option<u64>,
+    user_data: Option<JellyfinUserData>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct JellyfinUserData {
+    #[serde(default)]
+    played: bool,
+    #[serde(default)]
+    playback_position_ticks: u64,
+    last_played_date: Option<String>,
+}
+
+/// Everything `user` (or anyone) watched on Jellyfin at `url`.
+async fn jellyfin(url: &str, api_key: &str, user: Option<&str>) -> Result<Vec<Watched>, String> {
+    let get = |path: String| async move {
+        get_json::<JellyfinItems>(&format!("{}{}", url, path), "X-Emby-Token", api_key).await
+    };
+
+    let users = get_json::<Vec<JellyfinUser>>(&format!("{}/Users", url), "X-Emby-Token", api_key)
+        .await?
+        .into_iter()
+        .filter(|jellyfin_user| user.is_none() || user == Some(jellyfin_user.name.as_str()))
+        .collect::<Vec<_>>();
+    if users.is_empty() {
+        return Err(format!(
+            "Jellyfin has no user {:?}",
+            user.unwrap_or_default()
+        ));
+    }
+
+    let mut watched = Vec::new();
+    for user in users {
+        let tvdb_ids = get(format!(
+            "/Users/{}/Items?Recursive=true&IncludeItemTypes=Series&Fields=ProviderIds",
+            user.id
+        ))
+        .await?
+        .items
+        .into_iter()
+        .filter_map(|series| {
+            let tvdb_id = series.provider_ids.get("Tvdb")?.parse::<i64>().ok()?;
+            Some((series.id, tvdb_id))
+        })
+        .collect::<HashMap<_, _>>();
+
+        for filter in ["IsPlayed", "IsResumable"] {
+            let episodes = get(format!(
+                "/Users/{}/Items?Recursive=true&IncludeItemTypes=Episode&Filters={}",
+                user.id, filter
+            ))
+            .await?
+            .items;
+
+            watched.extend(episodes.into_iter().filter_map(|episode| {
+                let data = episode.user_data?;
+                let progress = match episode.run_time_ticks {
+                    Some(ticks) if ticks > 0 => data.playback_position_ticks as f64 / ticks as f64,
+                    _ => 0.0,
+                };
+                Some(Watched {
+                    tvdb_id: *tvdb_ids.get(episode.series_id.as_ref()?)?,
+                    season_number: episode.parent_index_number?,
+                    episode_number: episode.index_number?,
+                    played: data.played,
+                    progress,
+                    last_played: data
+                        .last_played_date
+                        .and_then(|date| DateTime::parse_from_rfc3339(&date).ok())
+                        .and_then(|date| u64::try_from(date.timestamp()).ok())
+                        .map(|secs| UNIX_EPOCH + Duration::from_secs(secs)),
+                })
+            }));
+        }
+        tracing::debug!("Read the watch state of {} from Jellyfin", user.name);
+    }
+
+    Ok(watched)
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct PlexResponse<T> {
+    media_container: T,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct PlexSections {
+    #[serde(default)]
+    directory: Vec<PlexSection>,
+}
+
+#[derive(Deserialize)]
+struct PlexSection {
+    key: String,
+    #[serde(rename = "type")]
+    kind: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct PlexItems {
+    #[serde(default)]
+    metadata: Vec<PlexItem>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PlexItem {
+    rating_key: Option<String>,
+    /// The legacy agents' id, like `com.plexapp.agents.thetvdb://121361?lang=en`.
+    guid: Option<String>,
+    /// The new agents' ids, like `tvdb://121361`.
+    #[serde(rename = "Guid", default)]
+    guids: Vec<PlexGuid>,
+    grandparent_rating_key: Option<String>,
+    parent_index: Option<i32>,
+    index: Option<i32>,
+    #[serde(default)]
+    view_count: u32,
+    #[serde(default)]
+    view_offset: u64,
+    duration: Option<u64>,
+    last_viewed_at: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct PlexGuid {
+    id: String,
+}
+
+impl PlexItem {
+    fn tvdb_id(&self) -> Option<i64> {
+        let new = self
+            .guids
+            .iter()
+            .find_map(|guid| guid.id.strip_prefix("tvdb://"));
+        let legacy = self
+            .guid
+            .as_deref()
+            .and_then(|guid| guid.split_once("thetvdb://"))
+            .map(|(_, id)| id);
+        let id = new.or(legacy)?;
+
+        id.split(|c: char| !c.is_ascii_digit()).next()?.parse().ok()
+    }
+}
+
+/// Everything the owner of `token` watched on Plex at `url`.
+async fn plex(url: &str, token: &str) -> Result<Vec<Watched>, String> {
+    let get = |path: String| async move {
+        get_json::<PlexResponse<PlexItems>>(&format!("{}{}", url, path), "X-Plex-Token", token)
+            .await
+            .map(|res| res.media_container.metadata)
+    };
+
+    let sections = get_json::<PlexResponse<PlexSections>>(
+        &format!("{}/library/sections", url),
+        "X-Plex-Token",
+        token,
+    )
+    .await?
+    .media_container
+    .directory;
+
+    let mut watched = Vec::new();
+    for section in sections.iter().filter(|section| section.kind == "show") {
+        let tvdb_ids = get(format!(
+            "/library/sections/{}/all?type=2&includeGuids=1",
+            section.key
+        ))
+        .await?
+        .into_iter()
+        .filter_map(|show| Some((show.rating_key.clone()?, show.tvdb_id()?)))
+        .collect::<HashMap<_, _>>();
+
+        let episodes = get(format!("/library/sections/{}/all?type=4", section.key)).await?;
+        watched.extend(
+            episodes
+                .into_iter()
+                .filter(|episode| episode.view_count > 0 || episode.view_offset > 0)
+                .filter_map(|episode| {
+                    let progress = match episode.duration {
+                        Some(duration) if duration > 0 => {
+                            episode.view_offset as f64 / duration as f64
+                        }
+                        _ => 0.0,
+                    };
+                    Some(Watched {
+                        tvdb_id: *tvdb_ids.get(episode.grandparent_rating_key.as_ref()?)?,
+                        season_number: episode.parent_index?,
+                        episode_number: episode.index?,
+                        played: episode.view_count > 0,
+                        progress,
+                        last_played: episode
+                            .last_viewed_at
+                            .map(|secs| UNIX_EPOCH + Duration::from_secs(secs)),
+                    })
+                }),
+        );
+    }
+
+    Ok(watched)
+}
+
+/// The files of series `id` by season and episode number, with their sizes.
+async fn episode_files(id: i32) -> Result<HashMap<(i32, i32), (String, u64)>, ApiError> {
+    let episodes = sonarr::episodes(id).await?;
+
+    Ok(episodes
+        .iter()
+        .filter_map(|episode| {
+            let number = |field: &str| Some(episode[field].as_i64()? as i32);
+            let file = &episode["episodeFile"];
+            Some((
+                (number("seasonNumber")?, number("episodeNumber")?),
+                (file["path"].as_str()?.to_string(), file["size"].as_u64()?),
+            ))
+        })
+        .collect())
+}
+
+/// Copies what was watched on Jellyfin or Plex to `viewer`'s plays in
+/// centarr, matching series to Sonarr's by TVDB id. Meant to be run once,
+/// while centarr isn't running, as it only reads plays on startup.
+pub async fn run(
+    source: Source,
+    url: &str,
+    api_key: &str,
+    user: Option<&str>,
+    viewer: Option<&str>,
+) -> ExitCode {
+    let url = url.trim_end_matches('/');
+    let config = config::get();
+    if let Err(e) = store::open().await {
+        eprintln!("{}", e);
+        return ExitCode::FAILURE;
+    }
+    playback::load().await;
+    users::load().await;
+    if let Some(viewer) = viewer.filter(|viewer| !users::exists(viewer)) {
+        eprintln!("There's no user {:?}", viewer);
+        return ExitCode::FAILURE;
+    }
+
+    if let Err(e) = sonarr::connect(&config).await {
+        eprintln!("Can't reach Sonarr: {:?}", e);
+        return ExitCode::FAILURE;
+    }
+
+    let watched = match source {
+        Source::Jellyfin => jellyfin(url, api_key, user).await,
+        Source::Plex => plex(url, api_key).await,
+    };
+    let watched = match watched {
+        Ok(watched) => watched,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let series = match sonarr::series_by_id().await {
+        Ok(series) => series,
+        Err(e) => {
+            eprintln!("Can't list Sonarr's series: {:?}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let by_tvdb_id = series
+        .iter()
+        .filter_map(|(id, series)| Some((series["tvdbId"].as_i64()?, *id as i32)))
+        .collect::<HashMap<_, _>>();
+
+    // episode files by season and episode number, of the series asked about
+    let mut files = BTreeMap::<i32, HashMap<(i32, i32), (String, u64)>>::new();
+    let (mut imported, mut unmatched) = (0, 0);
+
+    for watched in watched {
+        let series_id = match by_tvdb_id.get(&watched.tvdb_id) {
+            Some(id) => *id,
+            None => {
+                unmatched += 1;
+                continue;
+            }
+        };
+        let of_series = match files.entry(series_id) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => match episode_files(series_id).await {
+                Ok(of_series) => entry.insert(of_series),
+                Err(e) => {
+                    eprintln!("Can't list the episodes of series {}: {:?}", series_id, e);
+                    return ExitCode::FAILURE;
+                }
+            },
+        };
+
+        let key = (watched.season_number, watched.episode_number);
+        let (path, size) = match of_series.get(&key) {
+            Some(file) => file,
+            None => {
+                unmatched += 1;
+                continue;
+            }
+        };
+        let position = match watched.played {
+            true => *size,
+            false => (*size as f64 * watched.progress.clamp(0.0, 1.0)) as u64,
+        };
+
+        playback::restore(
+            viewer,
+            &config.local_path(Path::new(path)),
+            Play {
+                position,
+                size: *size,
+                last_played: watched.last_played.unwrap_or(UNIX_EPOCH),
+            },
+        );
+        imported += 1;
+    }
+
+    playback::save().await;
+    println!(
+        "Imported {} watched episodes, {} aren't in Sonarr or have no file",
+        imported, unmatched
+    );
+
+    ExitCode::SUCCESS
+}
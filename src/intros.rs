@@ -0,0 +1,328 @@
+use std::collections::{hash_map::Entry, BTreeMap, BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use tokio::process::Command;
+
+use crate::{
+    config,
+    markers::{self, Markers, Range},
+    store::{self, Library},
+};
+
+const ANALYZED_TABLE: &str = "analyzed_files";
+/// How much of the start of each episode is fingerprinted.
+const ANALYZED_DURATION: Duration = Duration::from_secs(10 * 60);
+/// Seconds of audio each chromaprint point covers.
+const POINT_DURATION: f64 = 0.1238;
+/// Points this many bits apart still count as the same audio.
+const MAX_BIT_ERRORS: u32 = 6;
+/// Points that don't match can be skipped for about two seconds before a
+/// shared segment counts as ended.
+const MAX_GAP: usize = 16;
+/// How many of the most common offsets between two episodes are tried.
+const CANDIDATE_SHIFTS: usize = 5;
+const MIN_INTRO: f64 = 15.0;
+const MAX_INTRO: f64 = 120.0;
+
+/// Files that were analyzed, whether an intro was found or not.
+static ANALYZED: Lazy<RwLock<BTreeSet<PathBuf>>> = Lazy::new(Default::default);
+
+/// Loads which files were analyzed.
+pub async fn load() {
+    *ANALYZED.write().unwrap() = store::keys(ANALYZED_TABLE).await;
+}
+
+async fn mark_analyzed(paths: impl IntoIterator<Item = PathBuf>) {
+    let changes = {
+        let mut analyzed = ANALYZED.write().unwrap();
+        paths
+            .into_iter()
+            .filter(|path| analyzed.insert(path.clone()))
+            .map(|path| store::put(ANALYZED_TABLE, &path, &serde_json::Value::Null))
+            .collect::<Vec<_>>()
+    };
+
+    if let Err(e) = store::apply(&changes).await {
+        tracing::warn!("Failed to save which episodes were analyzed: {}", e);
+    }
+}
+
+/// Chromaprint of the start of the file's audio.
+async fn fingerprint(path: &Path) -> Result<Vec<u32>, String> {
+    let ffmpeg = config::get().ffmpeg_path.clone();
+    let output = Command::new(&ffmpeg)
+        .args(["-hide_banner", "-loglevel", "error", "-t"])
+        .arg(ANALYZED_DURATION.as_secs().to_string())
+        .arg("-i")
+        .arg(path)
+        .args(["-ac", "1", "-f", "chromaprint", "-fp_format", "raw", "-"])
+        .output()
+        .await
+        .map_err(|e| format!("{:?} can't be run: {}", ffmpeg, e))?;
+
+    if !output.status.success() {
+        return Err(format!(
+            "ffmpeg exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    Ok(output
+        .stdout
+        .chunks_exact(4)
+        .map(|point| u32::from_le_bytes([point[0], point[1], point[2], point[3]]))
+        .collect())
+}
+
+/// The longest stretch of audio both fingerprints share, as ranges in
+/// `a` and `b`, when it's about as long as an intro.
+fn shared_segment(a: &[u32], b: &[u32]) -> Option<(Range, Range)> {
+    let mut positions = HashMap::<u32, Vec<usize>>::new();
+    for (j, point) in b.iter().enumerate() {
+        positions.entry(*point).or_default().push(j);
+    }
+
+    // offsets at which identical points line up are where shared audio is
+    let mut shifts = HashMap::<isize, usize>::new();
+    for (i, point) in a.iter().enumerate() {
+        for j in positions.get(point).into_iter().flatten() {
+            *shifts.entry(*j as isize - i as isize).or_default() += 1;
+        }
+    }
+    let mut shifts = shifts.into_iter().collect::<Vec<_>>();
+    shifts.sort_by_key(|(shift, count)| (std::cmp::Reverse(*count), *shift));
+
+    // (start in a, start in b, length) in points
+    let mut best = (0, 0, 0);
+    for (shift, _) in shifts.into_iter().take(CANDIDATE_SHIFTS) {
+        let (offset_a, offset_b) = if shift >= 0 {
+            (0, shift as usize)
+        } else {
+            (shift.unsigned_abs(), 0)
+        };
+        let overlap = a
+            .len()
+            .saturating_sub(offset_a)
+            .min(b.len().saturating_sub(offset_b));
+
+        let mut start = None;
+        let mut last = 0;
+        for k in 0..overlap {
+            if (a[offset_a + k] ^ b[offset_b + k]).count_ones() > MAX_BIT_ERRORS {
+                continue;
+            }
+
+            let run_start = match start {
+                Some(start) if k - last <= MAX_GAP => start,
+                _ => k,
+            };
+            start = Some(run_start);
+            last = k;
+
+            let len = last + 1 - run_start;
+            if len > best.2 {
+                best = (offset_a + run_start, offset_b + run_start, len);
+            }
+        }
+    }
+
+    let (start_a, start_b, len) = best;
+    let duration = len as f64 * POINT_DURATION;
+    if !(MIN_INTRO..=MAX_INTRO).contains(&duration) {
+        return None;
+    }
+
+    let range = |start: usize| Range {
+        start: start as f64 * POINT_DURATION,
+        end: (start + len) as f64 * POINT_DURATION,
+    };
+    Some((range(start_a), range(start_b)))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Episode {
+    id: i32,
+    season_number: i32,
+    episode_number: i32,
+    episode_file: Option<EpisodeFile>,
+}
+
+#[derive(Deserialize)]
+struct EpisodeFile {
+    path: String,
+}
+
+/// Episodes with a file, by series and season, in order. Specials are
+/// left out as they rarely share the intro.
+fn seasons(library: &Library) -> Vec<Vec<(i32, PathBuf)>> {
+    let config = config::get();
+    let mut seasons = Vec::new();
+
+    for episodes in library.episodes.values() {
+        let mut by_season = BTreeMap::<i32, Vec<Episode>>::new();
+        for episode in episodes
+            .iter()
+            .filter_map(|episode| Episode::deserialize(episode).ok())
+            .filter(|episode| episode.season_number > 0 && episode.episode_file.is_some())
+        {
+            by_season
+                .entry(episode.season_number)
+                .or_default()
+                .push(episode);
+        }
+
+        for mut episodes in by_season.into_values() {
+            episodes.sort_by_key(|episode| episode.episode_number);
+            let mut files = episodes
+                .into_iter()
+                .filter_map(|episode| {
+                    let path = config.local_path(Path::new(&episode.episode_file?.path));
+                    Some((episode.id, path))
+                })
+                .collect::<Vec<_>>();
+            // multi-episode files show up once per episode
+            files.dedup_by(|a, b| a.1 == b.1);
+            seasons.push(files);
+        }
+    }
+
+    seasons
+}
+
+/// Looks for the intro of each episode in `season` without one by
+/// comparing it to the next episode, or the one before for the last.
+async fn detect_season(season: &[(i32, PathBuf)]) -> Result<(), String> {
+    let todo = season
+        .iter()
+        .enumerate()
+        .filter(|(_, (id, path))| {
+            markers::for_episode(*id).intro.is_none() && !ANALYZED.read().unwrap().contains(path)
+        })
+        .map(|(index, _)| index)
+        .collect::<Vec<_>>();
+    if todo.is_empty() || season.len() < 2 {
+        return Ok(());
+    }
+
+    let mut fingerprints = HashMap::new();
+    for index in todo {
+        let other = if index + 1 < season.len() {
+            index + 1
+        } else {
+            index - 1
+        };
+
+        for index in [index, other] {
+            if let Entry::Vacant(entry) = fingerprints.entry(index) {
+                entry.insert(fingerprint(&season[index].1).await?);
+            }
+        }
+
+        let (id, path) = &season[index];
+        let (other_id, _) = &season[other];
+        match shared_segment(&fingerprints[&index], &fingerprints[&other]) {
+            Some((intro, other_intro)) => {
+                tracing::info!(
+                    "Found an intro in episode {} from {:.1}s to {:.1}s",
+                    id,
+                    intro.start,
+                    intro.end
+                );
+
+                for (id, intro) in [(*id, intro), (*other_id, other_intro)] {
+                    let existing = markers::for_episode(id);
+                    if existing.intro.is_none() {
+                        let markers = Markers {
+                            intro: Some(intro),
+                            ..existing
+                        };
+                        markers::set(id, markers).await;
+                    }
+                }
+            }
+            None => tracing::debug!("No intro found in episode {}", id),
+        }
+
+        mark_analyzed([path.clone()]).await;
+    }
+
+    Ok(())
+}
+
+/// Looks for intros in the synced library.
+pub async fn detect() -> Result<(), String> {
+    let library = match store::library() {
+        Some(library) => library,
+        None => return Ok(()),
+    };
+    let started = Instant::now();
+
+    for season in seasons(&library) {
+        // most likely ffmpeg is missing or built without chromaprint, which
+        // won't change for the next season
+        detect_season(&season).await?;
+    }
+
+    tracing::debug!("Intro detection took {:?}", started.elapsed());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Fingerprint points of made up audio, different for every `seed`.
+    fn noise(seed: u64, len: usize) -> Vec<u32> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (state >> 32) as u32
+            })
+            .collect()
+    }
+
+    fn points(range: &Range) -> (usize, usize) {
+        let point = |at: f64| (at / POINT_DURATION).round() as usize;
+        (point(range.start), point(range.end))
+    }
+
+    #[test]
+    fn intros_are_found_wherever_they_start() {
+        let intro = noise(1, 300);
+        let a = [noise(2, 100), intro.clone(), noise(3, 200)].concat();
+        let mut b = [noise(4, 400), intro, noise(5, 100)].concat();
+        // a little different audio, and a bit of it gone, still count
+        b[450] ^= 0b101;
+        b[500..508].copy_from_slice(&noise(6, 8));
+
+        let (in_a, in_b) = shared_segment(&a, &b).unwrap();
+        assert_eq!(points(&in_a), (100, 400));
+        assert_eq!(points(&in_b), (400, 700));
+        assert_eq!(shared_segment(&b, &a), Some((in_b, in_a)));
+    }
+
+    #[test]
+    fn only_intro_length_segments_count() {
+        // about five seconds, a jingle rather than an intro
+        let jingle = noise(1, 40);
+        let a = [noise(2, 100), jingle.clone(), noise(3, 200)].concat();
+        let b = [noise(4, 50), jingle, noise(5, 300)].concat();
+        assert_eq!(shared_segment(&a, &b), None);
+
+        // the same episode twice is all shared, longer than any intro
+        let episode = noise(6, 2000);
+        assert_eq!(shared_segment(&episode, &episode), None);
+
+        assert_eq!(shared_segment(&noise(7, 1000), &noise(8, 1000)), None);
+        assert_eq!(shared_segment(&[], &noise(9, 100)), None);
+    }
+}
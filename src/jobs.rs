@@ -0,0 +1,324 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use axum::{
+    extract::{Path, Query},
+    middleware,
+    routing::get,
+    Json, Router,
+};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    select,
+    sync::{Mutex as AsyncMutex, Notify},
+};
+
+use crate::{
+    auth, config, dates,
+    errors::ApiError,
+    scheduler::{self, Task},
+    store, trickplay,
+};
+
+const JOBS_TABLE: &str = "jobs";
+/// Tries before a job counts as failed.
+const MAX_ATTEMPTS: u32 = 3;
+/// Waited before the first retry, doubling with every one after.
+const RETRY_DELAY: u64 = 60;
+/// Finished jobs kept around to look at, the oldest go first.
+const MAX_FINISHED: usize = 1000;
+const DEFAULT_LIMIT: usize = 100;
+/// How long the workers wait for work when nothing wakes them.
+const IDLE_POLL: Duration = Duration::from_secs(60);
+
+/// What a job does.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Kind {
+    /// One of the scheduled tasks, by name.
+    Task { name: String },
+    /// The seek bar thumbnails of one episode.
+    #[serde(rename_all = "camelCase")]
+    Thumbnails { episode_id: i32, path: PathBuf },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum State {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Job {
+    pub id: u64,
+    #[serde(flatten)]
+    pub kind: Kind,
+    pub state: State,
+    pub attempts: u32,
+    pub created_at: String,
+    pub started_at: Option<String>,
+    pub finished_at: Option<String>,
+    /// When a failed job is tried again, ISO 8601 in UTC like the others so
+    /// they compare as strings.
+    pub retry_at: Option<String>,
+    /// What it did, or why it failed the last time.
+    pub message: Option<String>,
+}
+
+/// Every job by id, queued ones in the order they're run.
+static JOBS: Lazy<Mutex<BTreeMap<u64, Job>>> = Lazy::new(Default::default);
+/// Wakes the workers up when a job is queued or finished.
+static WAKE: Lazy<Notify> = Lazy::new(Notify::new);
+/// The jobs as they were last saved.
+static SAVED: Lazy<AsyncMutex<BTreeMap<u64, Job>>> = Lazy::new(Default::default);
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/jobs", get(get_jobs))
+        .route("/jobs/:id", get(get_job))
+        .route_layer(middleware::from_fn(auth::require_admin))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Loads the saved jobs. Ones that were running when centarr stopped are
+/// queued again.
+pub async fn load() {
+    let mut saved = SAVED.lock().await;
+    *saved = store::rows(JOBS_TABLE).await;
+
+    let mut loaded = JOBS.lock().unwrap();
+    for job in saved.values() {
+        let mut job = job.clone();
+        if job.state == State::Running {
+            job.state = State::Queued;
+        }
+        loaded.insert(job.id, job);
+    }
+}
+
+/// Saves the jobs that changed since they were last saved, in one
+/// transaction.
+pub async fn save() {
+    let mut saved = SAVED.lock().await;
+    let jobs = JOBS.lock().unwrap().clone();
+
+    match store::apply(&store::diff(JOBS_TABLE, &saved, &jobs)).await {
+        Ok(()) => *saved = jobs,
+        Err(e) => tracing::warn!("Can't save the jobs: {}", e),
+    }
+}
+
+/// Queues `kind`, unless the same job is queued or running already.
+fn add(jobs: &mut BTreeMap<u64, Job>, kind: Kind) -> Option<u64> {
+    let pending = |job: &Job| matches!(job.state, State::Queued | State::Running);
+    if jobs.values().any(|job| job.kind == kind && pending(job)) {
+        return None;
+    }
+
+    let id = jobs.keys().next_back().map_or(1, |id| id + 1);
+    jobs.insert(
+        id,
+        Job {
+            id,
+            kind,
+            state: State::Queued,
+            attempts: 0,
+            created_at: dates::iso8601(now() as i64),
+            started_at: None,
+            finished_at: None,
+            retry_at: None,
+            message: None,
+        },
+    );
+
+    Some(id)
+}
+
+/// Queues a job, returning its id, or `None` when the same one is queued
+/// or running already.
+pub async fn enqueue(kind: Kind) -> Option<u64> {
+    let id = add(&mut JOBS.lock().unwrap(), kind)?;
+    save().await;
+    WAKE.notify_one();
+
+    Some(id)
+}
+
+/// Queues jobs that aren't queued or running already, returning how many
+/// were.
+pub async fn enqueue_all(kinds: impl IntoIterator<Item = Kind>) -> usize {
+    let queued = {
+        let mut jobs = JOBS.lock().unwrap();
+        kinds
+            .into_iter()
+            .filter_map(|kind| add(&mut jobs, kind))
+            .count()
+    };
+
+    if queued > 0 {
+        save().await;
+        WAKE.notify_one();
+    }
+    queued
+}
+
+async fn execute(kind: &Kind) -> Result<Option<String>, String> {
+    match kind {
+        Kind::Task { name } => match Task::from_name(name) {
+            Some(task) => scheduler::execute(task).await,
+            None => Err(format!("there's no task {:?}", name)),
+        },
+        Kind::Thumbnails { episode_id, path } => {
+            trickplay::make(*episode_id, path).await.map(|_| None)
+        }
+    }
+}
+
+/// Records how job `id` went, queueing it again with a backoff when it
+/// failed and has tries left.
+fn finish(id: u64, result: Result<Option<String>, String>) {
+    let mut jobs = JOBS.lock().unwrap();
+    let job = match jobs.get_mut(&id) {
+        Some(job) => job,
+        None => return,
+    };
+
+    match result {
+        Ok(message) => {
+            job.state = State::Succeeded;
+            job.message = message;
+        }
+        Err(e) if job.attempts < MAX_ATTEMPTS => {
+            let delay = RETRY_DELAY << (job.attempts - 1);
+            tracing::debug!("Job {} failed, retrying in {}s: {}", id, delay, e);
+            job.state = State::Queued;
+            job.retry_at = Some(dates::iso8601((now() + delay) as i64));
+            job.message = Some(e);
+            return;
+        }
+        Err(e) => {
+            tracing::warn!("Job {} failed {} times: {}", id, job.attempts, e);
+            job.state = State::Failed;
+            job.message = Some(e);
+        }
+    }
+    job.finished_at = Some(dates::iso8601(now() as i64));
+    job.retry_at = None;
+
+    let finished = jobs
+        .values()
+        .filter(|job| matches!(job.state, State::Succeeded | State::Failed))
+        .map(|job| job.id)
+        .collect::<Vec<_>>();
+    for id in finished
+        .iter()
+        .take(finished.len().saturating_sub(MAX_FINISHED))
+    {
+        jobs.remove(id);
+    }
+}
+
+/// Marks as many due jobs running as there are idle workers, oldest first.
+fn take_due(workers: usize) -> Vec<Job> {
+    let mut jobs = JOBS.lock().unwrap();
+    let now = dates::iso8601(now() as i64);
+    let running = jobs
+        .values()
+        .filter(|job| job.state == State::Running)
+        .count();
+
+    jobs.values_mut()
+        .filter(|job| job.state == State::Queued)
+        .filter(|job| job.retry_at.as_ref().is_none_or(|at| *at <= now))
+        .take(workers.saturating_sub(running))
+        .map(|job| {
+            job.state = State::Running;
+            job.attempts += 1;
+            job.started_at = Some(now.clone());
+            job.clone()
+        })
+        .collect()
+}
+
+/// How long until the first retry is due, if any is waiting.
+fn next_retry() -> Option<Duration> {
+    let jobs = JOBS.lock().unwrap();
+    let at = jobs
+        .values()
+        .filter(|job| job.state == State::Queued)
+        .filter_map(|job| job.retry_at.as_deref())
+        .min()?;
+    let at = chrono::DateTime::parse_from_rfc3339(at).ok()?.timestamp();
+
+    Some(Duration::from_secs((at as u64).saturating_sub(now())))
+}
+
+/// Runs queued jobs on up to `job_workers` at a time.
+pub async fn run() {
+    loop {
+        let started = take_due(config::get().job_workers);
+        if !started.is_empty() {
+            save().await;
+        }
+
+        for job in started {
+            tokio::spawn(async move {
+                let result = execute(&job.kind).await;
+                finish(job.id, result);
+                save().await;
+                WAKE.notify_one();
+            });
+        }
+
+        // retries that are due wait for a worker to finish
+        let wait = next_retry().map_or(IDLE_POLL, |wait| {
+            wait.clamp(Duration::from_secs(1), IDLE_POLL)
+        });
+        select! {
+            _ = tokio::time::sleep(wait) => {},
+            _ = WAKE.notified() => {},
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct JobsQuery {
+    state: Option<State>,
+    limit: Option<usize>,
+}
+
+/// The most recent jobs first.
+async fn get_jobs(Query(query): Query<JobsQuery>) -> Json<Vec<Job>> {
+    let jobs = JOBS.lock().unwrap();
+
+    Json(
+        jobs.values()
+            .rev()
+            .filter(|job| query.state.is_none_or(|state| job.state == state))
+            .take(query.limit.unwrap_or(DEFAULT_LIMIT))
+            .cloned()
+            .collect(),
+    )
+}
+
+async fn get_job(Path(id): Path<u64>) -> Result<Json<Job>, ApiError> {
+    let jobs = JOBS.lock().unwrap();
+
+    jobs.get(&id)
+        .cloned()
+        .map(Json)
+        .ok_or_else(|| ApiError::empty(404, None))
+}
@@ -0,0 +1,280 @@
+use std::path::Path as FilePath;
+
+use async_trait::async_trait;
+use axum::{
+    extract::{Path, Query},
+    http::HeaderMap,
+    routing::get,
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auth::AuthUser,
+    config::{self, Config},
+    errors::ApiError,
+    playback, radarr,
+    restrictions::Restrictions,
+    sendfile, sonarr,
+};
+
+const DEFAULT_LIMIT: usize = 20;
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/library", get(get_library))
+        .route("/library/search", get(search))
+        .route("/library/recently-added", get(recently_added))
+        .route("/library/continue-watching", get(continue_watching))
+        .route("/library/:type/:id", get(get_item))
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    Show,
+    Movie,
+}
+
+/// A show or movie, whichever service it comes from.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Item {
+    #[serde(rename = "type")]
+    pub kind: Kind,
+    pub id: i32,
+    pub title: String,
+    pub year: Option<i32>,
+    pub overview: Option<String>,
+    pub images: Vec<Image>,
+    /// When it was added to the library, as reported upstream.
+    pub added: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub files: Option<Vec<MediaFile>>,
+    /// Where its files are, as the upstream service sees it.
+    #[serde(skip)]
+    pub path: Option<String>,
+    /// Labels of its tags, only filled in when tags restrict anything.
+    #[serde(skip)]
+    pub tags: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Image {
+    pub cover_type: String,
+    pub url: Option<String>,
+    pub remote_url: Option<String>,
+}
+
+/// The *arrs label things with these, referring to them by id.
+#[derive(Deserialize, Debug)]
+pub struct Tag {
+    pub id: i32,
+    pub label: String,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct MediaFile {
+    pub id: i32,
+    pub path: String,
+    pub size: i64,
+    pub date_added: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub season_number: Option<i32>,
+    pub watch_url: Option<String>,
+    /// Bytes streamed the last time it was played, when it wasn't finished.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub position: Option<u64>,
+}
+
+/// A service that knows about a kind of media and where its files are.
+#[async_trait]
+pub trait MediaProvider: Sync {
+    fn kind(&self) -> Kind;
+
+    fn is_configured(&self, config: &Config) -> bool;
+
+    async fn list(&self, config: &Config) -> Result<Vec<Item>, ApiError>;
+
+    async fn get(&self, config: &Config, id: i32) -> Result<Item, ApiError>;
+
+    async fn files(&self, config: &Config, id: i32) -> Result<Vec<MediaFile>, ApiError>;
+}
+
+impl MediaFile {
+    /// Url streaming the file, episodes by id and the rest by path.
+    fn watch_url(&self, headers: &HeaderMap, config: &Config, kind: Kind) -> String {
+        match kind {
+            Kind::Show => sendfile::episode_url(headers, config, self.id, &self.path),
+            Kind::Movie => sendfile::watch_url(headers, config, &self.path),
+        }
+    }
+}
+
+fn providers() -> [&'static dyn MediaProvider; 2] {
+    [&sonarr::Provider, &radarr::Provider]
+}
+
+#[derive(Deserialize)]
+struct LibraryQuery {
+    #[serde(rename = "type")]
+    kind: Option<Kind>,
+    q: Option<String>,
+    limit: Option<usize>,
+}
+
+/// The configured providers, narrowed down to `kind` when given.
+pub fn selected(config: &Config, kind: Option<Kind>) -> Vec<&'static dyn MediaProvider> {
+    providers()
+        .into_iter()
+        .filter(|provider| provider.is_configured(config))
+        .filter(|provider| kind.is_none() || kind == Some(provider.kind()))
+        .collect()
+}
+
+async fn list(
+    config: &Config,
+    kind: Option<Kind>,
+    restrictions: &Restrictions,
+) -> Result<Vec<Item>, ApiError> {
+    let mut items = Vec::new();
+
+    for provider in selected(config, kind) {
+        let listed = provider.list(config).await?;
+        items.extend(
+            listed
+                .into_iter()
+                .filter(|item| restrictions.allows_item(item)),
+        );
+    }
+
+    Ok(items)
+}
+
+async fn get_library(
+    Query(query): Query<LibraryQuery>,
+    restrictions: Restrictions,
+) -> Result<Json<Vec<Item>>, ApiError> {
+    let config = config::get();
+    let mut items = list(&config, query.kind, &restrictions).await?;
+
+    items.sort_by_key(|item| item.title.to_lowercase());
+
+    Ok(items.into())
+}
+
+async fn get_item(
+    Path((kind, id)): Path<(Kind, i32)>,
+    headers: HeaderMap,
+    restrictions: Restrictions,
+) -> Result<Json<Item>, ApiError> {
+    let config = config::get();
+    let provider = selected(&config, Some(kind))
+        .pop()
+        .ok_or_else(|| ApiError::new(404, format!("Nothing provides {:?}s", kind)))?;
+
+    let mut item = provider.get(&config, id).await?;
+    if !restrictions.allows_item(&item) {
+        return Err(ApiError::new(404, format!("There's no {:?} {}", kind, id)));
+    }
+    let mut files = provider.files(&config, id).await?;
+
+    for file in &mut files {
+        file.watch_url = Some(file.watch_url(&headers, &config, kind));
+    }
+    item.files = Some(files);
+
+    Ok(item.into())
+}
+
+async fn search(
+    Query(query): Query<LibraryQuery>,
+    restrictions: Restrictions,
+) -> Result<Json<Vec<Item>>, ApiError> {
+    let needle = query.q.unwrap_or_default().trim().to_lowercase();
+    if needle.is_empty() {
+        return Err(ApiError::new(400, "q can't be empty".into()));
+    }
+
+    let config = config::get();
+    let mut items = list(&config, query.kind, &restrictions)
+        .await?
+        .into_iter()
+        .filter(|item| item.title.to_lowercase().contains(&needle))
+        .collect::<Vec<_>>();
+
+    // titles starting with the query first, then alphabetically
+    items.sort_by_key(|item| {
+        let title = item.title.to_lowercase();
+        (!title.starts_with(&needle), title)
+    });
+    items.truncate(query.limit.unwrap_or(DEFAULT_LIMIT));
+
+    Ok(items.into())
+}
+
+async fn recently_added(
+    Query(query): Query<LibraryQuery>,
+    restrictions: Restrictions,
+) -> Result<Json<Vec<Item>>, ApiError> {
+    let config = config::get();
+    let mut items = list(&config, query.kind, &restrictions).await?;
+
+    // the *arrs all use ISO 8601 in UTC, which sorts as a string
+    items.sort_by(|a, b| b.added.cmp(&a.added));
+    items.truncate(query.limit.unwrap_or(DEFAULT_LIMIT));
+
+    Ok(items.into())
+}
+
+/// Items with a file that was started but not finished, most recently
+/// played first. Each only lists those unfinished files.
+async fn continue_watching(
+    Query(query): Query<LibraryQuery>,
+    headers: HeaderMap,
+    user: Option<AuthUser>,
+    restrictions: Restrictions,
+) -> Result<Json<Vec<Item>>, ApiError> {
+    let plays = playback::in_progress(user.as_ref().map(|user| user.name.as_str()));
+    if plays.is_empty() {
+        return Ok(Json(Vec::new()));
+    }
+
+    let config = config::get();
+    let mut found = Vec::new();
+
+    for provider in selected(&config, query.kind) {
+        let listed = provider.list(&config).await?;
+
+        for mut item in listed
+            .into_iter()
+            .filter(|item| restrictions.allows_item(item))
+        {
+            let mut files = Vec::new();
+
+            for mut file in provider.files(&config, item.id).await? {
+                let local = config.local_path(FilePath::new(&file.path));
+                let position = plays.iter().position(|(path, _)| *path == local);
+
+                if let Some(position) = position {
+                    file.position = Some(plays[position].1.position);
+                    file.watch_url = Some(file.watch_url(&headers, &config, provider.kind()));
+                    files.push((position, file));
+                }
+            }
+
+            if let Some(latest) = files.iter().map(|(position, _)| *position).min() {
+                files.sort_by_key(|(position, _)| *position);
+                item.files = Some(files.into_iter().map(|(_, file)| file).collect());
+                found.push((latest, item));
+            }
+        }
+    }
+
+    found.sort_by_key(|(latest, _)| *latest);
+    found.truncate(query.limit.unwrap_or(DEFAULT_LIMIT));
+
+    Ok(Json(found.into_iter().map(|(_, item)| item).collect()))
+}
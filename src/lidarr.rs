@@ -0,0 +1,139 @@
+use axum::{extract::Path, http::HeaderMap, routing::get, Json, Router};
+use once_cell::sync::Lazy;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    config::{self, Config, UpstreamConfig},
+    errors::ApiError,
+    sendfile,
+    upstream::Upstream,
+};
+
+pub static UPSTREAM: Lazy<Upstream> = Lazy::new(|| Upstream::new("lidarr"));
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/artists", get(get_artists))
+        .route("/artists/:artistId", get(get_artist))
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Artist {
+    id: i32,
+    artist_name: String,
+    overview: Option<String>,
+    images: Vec<Image>,
+    #[serde(default)]
+    genres: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    albums: Option<Vec<Album>>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Image {
+    cover_type: String,
+    url: Option<String>,
+    remote_url: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Album {
+    id: i32,
+    artist_id: i32,
+    title: String,
+    album_type: Option<String>,
+    release_date: Option<String>,
+    images: Vec<Image>,
+    #[serde(default)]
+    tracks: Vec<Track>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Track {
+    id: i32,
+    album_id: i32,
+    track_file_id: i32,
+    track_number: String,
+    absolute_track_number: i32,
+    title: String,
+    /// In milliseconds.
+    duration: i64,
+    has_file: bool,
+    #[serde(skip_deserializing)]
+    track_file: Option<TrackFile>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct TrackFile {
+    id: i32,
+    album_id: i32,
+    path: String,
+    size: i64,
+    date_added: String,
+    #[serde(skip_deserializing)]
+    watch_url: Option<String>,
+}
+
+/// The Lidarr connection, or a 404 when it isn't configured.
+fn lidarr(config: &Config) -> Result<&UpstreamConfig, ApiError> {
+    config
+        .lidarr
+        .as_ref()
+        .ok_or_else(|| ApiError::new(404, "Lidarr is not configured".into()))
+}
+
+async fn get_json<T: DeserializeOwned>(config: &Config, path: &str) -> Result<T, ApiError> {
+    let body = UPSTREAM
+        .get(lidarr(config)?, config.cache_ttl, path)
+        .await?;
+
+    serde_json::from_str(&body).map_err(|e| ApiError::empty(500, Some(e.to_string())))
+}
+
+async fn get_artists() -> Result<Json<Vec<Artist>>, ApiError> {
+    let config = config::get();
+    let artists = get_json::<Vec<Artist>>(&config, "/artist").await?;
+
+    Ok(artists.into())
+}
+
+async fn get_artist(Path(id): Path<i32>, headers: HeaderMap) -> Result<Json<Artist>, ApiError> {
+    let config = config::get();
+
+    let mut artist = get_json::<Artist>(&config, &format!("/artist/{}", id)).await?;
+    let mut albums = get_json::<Vec<Album>>(&config, &format!("/album?artistId={}", id)).await?;
+    let tracks = get_json::<Vec<Track>>(&config, &format!("/track?artistId={}", id)).await?;
+    let mut files =
+        get_json::<Vec<TrackFile>>(&config, &format!("/trackfile?artistId={}", id)).await?;
+
+    for file in &mut files {
+        file.watch_url = Some(sendfile::watch_url(&headers, &config, &file.path));
+    }
+
+    for mut track in tracks {
+        if track.has_file {
+            if let Some(index) = files.iter().position(|file| file.id == track.track_file_id) {
+                track.track_file = Some(files.swap_remove(index));
+            }
+        }
+
+        if let Some(album) = albums.iter_mut().find(|album| album.id == track.album_id) {
+            album.tracks.push(track);
+        }
+    }
+
+    for album in &mut albums {
+        album
+            .tracks
+            .sort_by_key(|track| track.absolute_track_number);
+    }
+
+    artist.albums = Some(albums);
+
+    Ok(artist.into())
+}
@@ -0,0 +1,52 @@
+use axum::{
+    body::Body,
+    http::{header, Request},
+    middleware::Next,
+    response::Response,
+};
+use hyper::body::HttpBody;
+
+use crate::errors::ApiError;
+
+/// Bodies the API takes are small JSON documents.
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+/// Sonarr's webhook payloads are a few KB, anything much bigger isn't
+/// from Sonarr.
+const MAX_WEBHOOK_BODY_SIZE: usize = 64 * 1024;
+
+/// Reads the whole body, turning it away with a 413 once it's over
+/// `limit`, as told by `Content-Length` or counted while reading.
+async fn limit(req: Request<Body>, next: Next<Body>, limit: usize) -> Result<Response, ApiError> {
+    let too_large = || ApiError::new(413, format!("Bodies can't be over {} bytes", limit));
+
+    let declared = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok())
+        .and_then(|len| len.parse::<usize>().ok());
+    if declared.is_some_and(|len| len > limit) {
+        return Err(too_large());
+    }
+
+    let (parts, mut body) = req.into_parts();
+    let mut bytes = Vec::with_capacity(declared.unwrap_or_default());
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| ApiError::new(400, format!("Can't read the body: {}", e)))?;
+        if bytes.len() + chunk.len() > limit {
+            return Err(too_large());
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+
+    Ok(next
+        .run(Request::from_parts(parts, Body::from(bytes)))
+        .await)
+}
+
+pub async fn body(req: Request<Body>, next: Next<Body>) -> Result<Response, ApiError> {
+    limit(req, next, MAX_BODY_SIZE).await
+}
+
+pub async fn webhook_body(req: Request<Body>, next: Next<Body>) -> Result<Response, ApiError> {
+    limit(req, next, MAX_WEBHOOK_BODY_SIZE).await
+}
@@ -0,0 +1,193 @@
+use std::fs::Permissions;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::task::Poll;
+
+use nix::sys::socket::{
+    getsockname, getsockopt, setsockopt, sockopt, AddressFamily, SockType, SockaddrLike,
+    SockaddrStorage,
+};
+use tokio::net::{TcpListener, TcpSocket, UnixListener, UnixStream};
+
+use crate::config::ListenAddr;
+
+/// Connections the kernel queues until they're accepted, like std's.
+const LISTEN_BACKLOG: u32 = 128;
+
+/// A bound TCP port or Unix socket. The file of a socket centarr made
+/// itself comes along, ones handed over by systemd are its to remove.
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener, Option<SocketFile>),
+}
+
+impl Listener {
+    /// Where it ended up listening, the port picked when it was 0.
+    pub fn addr(&self) -> Option<ListenAddr> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr().ok().map(ListenAddr::Tcp),
+            Listener::Unix(listener, _) => {
+                let addr = listener.local_addr().ok()?;
+                addr.as_pathname()
+                    .map(|path| ListenAddr::Unix(path.to_path_buf()))
+            }
+        }
+    }
+
+    /// Where it listens, for the logs.
+    pub fn describe(&self) -> String {
+        match self.addr() {
+            Some(ListenAddr::Tcp(addr)) => format!("http://{}", addr),
+            Some(addr) => addr.to_string(),
+            None => "an unknown address".into(),
+        }
+    }
+}
+
+/// A Unix socket's file, removed when it's no longer listened on so a
+/// restart doesn't find it in the way.
+pub struct SocketFile(PathBuf);
+
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.0) {
+            tracing::warn!("Can't remove the socket {:?}: {}", self.0, e);
+        }
+    }
+}
+
+/// Removes a socket left behind by a centarr that didn't shut down
+/// cleanly, refusing to when something is still listening on it or the
+/// path isn't a socket at all.
+async fn remove_stale(path: &Path) -> io::Result<()> {
+    let metadata = match tokio::fs::symlink_metadata(path).await {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "something that isn't a socket is there",
+        ));
+    }
+    if UnixStream::connect(path).await.is_ok() {
+        return Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            "something is listening on it already",
+        ));
+    }
+
+    tokio::fs::remove_file(path).await
+}
+
+async fn bind_unix(path: &Path, mode: u32) -> io::Result<Listener> {
+    remove_stale(path).await?;
+    let listener = UnixListener::bind(path)?;
+    let file = SocketFile(path.to_path_buf());
+    tokio::fs::set_permissions(path, Permissions::from_mode(mode)).await?;
+
+    Ok(Listener::Unix(listener, Some(file)))
+}
+
+/// Binds like `TcpListener::bind` does, but IPv6 addresses take IPv4
+/// connections too unless `v6only`.
+fn bind_tcp(addr: SocketAddr, v6only: bool) -> io::Result<Listener> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => {
+            let socket = TcpSocket::new_v6()?;
+            setsockopt(socket.as_raw_fd(), sockopt::Ipv6V6Only, &v6only)?;
+            socket
+        }
+    };
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr)?;
+
+    socket.listen(LISTEN_BACKLOG).map(Listener::Tcp)
+}
+
+/// Listens on every one of `addrs`, port 0 picking a free one. Unix
+/// sockets get `mode` as their permissions.
+pub async fn bind(name: &str, addrs: &[ListenAddr], mode: u32) -> Result<Vec<Listener>, String> {
+    let mut listeners = Vec::with_capacity(addrs.len());
+
+    for addr in addrs {
+        let bound = match addr {
+            ListenAddr::Tcp(tcp) => {
+                // an IPv4 address on the same port would be taken already
+                let v6only = addrs.iter().any(|other| {
+                    matches!(other, ListenAddr::Tcp(other)
+                        if other.is_ipv4() && other.port() == tcp.port() && tcp.port() != 0)
+                });
+                bind_tcp(*tcp, v6only)
+            }
+            ListenAddr::Unix(path) => bind_unix(path, mode).await,
+        };
+
+        listeners
+            .push(bound.map_err(|e| format!("Can't listen for the {} on {}: {}", name, addr, e))?);
+    }
+
+    Ok(listeners)
+}
+
+/// Runs a server for each listener, until any of them stops.
+pub async fn serve_each<F: Future<Output = ()>>(
+    listeners: Vec<Listener>,
+    serve: impl Fn(Listener) -> F,
+) {
+    let mut servers = listeners
+        .into_iter()
+        .map(|listener| Box::pin(serve(listener)))
+        .collect::<Vec<_>>();
+
+    std::future::poll_fn(|cx| {
+        match servers
+            .iter_mut()
+            .any(|server| server.as_mut().poll(cx).is_ready())
+        {
+            true => Poll::Ready(()),
+            false => Poll::Pending,
+        }
+    })
+    .await
+}
+
+/// Takes over a socket some other process bound and listens on, like
+/// systemd does for socket activation.
+pub fn from_fd(fd: RawFd) -> Result<Listener, String> {
+    let listening = getsockopt(fd, sockopt::SockType).is_ok_and(|kind| kind == SockType::Stream)
+        && getsockopt(fd, sockopt::AcceptConn).unwrap_or(false);
+    if !listening {
+        return Err(format!("fd {} is not a listening stream socket", fd));
+    }
+    let family = getsockname::<SockaddrStorage>(fd)
+        .map_err(|e| format!("Can't tell what fd {} is bound to: {}", fd, e))?
+        .family();
+
+    let adopted = match family {
+        Some(AddressFamily::Inet | AddressFamily::Inet6) => {
+            // safe as the fd is ours from here on and only wrapped once
+            let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+            listener
+                .set_nonblocking(true)
+                .and_then(|_| TcpListener::from_std(listener))
+                .map(Listener::Tcp)
+        }
+        Some(AddressFamily::Unix) => {
+            let listener = unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) };
+            listener
+                .set_nonblocking(true)
+                .and_then(|_| UnixListener::from_std(listener))
+                .map(|listener| Listener::Unix(listener, None))
+        }
+        family => return Err(format!("fd {} is a {:?} socket", fd, family)),
+    };
+
+    adopted.map_err(|e| format!("Can't listen on fd {}: {}", fd, e))
+}
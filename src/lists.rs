@@ -0,0 +1,333 @@
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+
+use axum::{
+    extract::Path,
+    http::StatusCode,
+    routing::{get, put},
+    Json, Router,
+};
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auth::AuthUser, config, errors::ApiError, restrictions::Restrictions, sonarr, store,
+    users::Role,
+};
+
+const LISTS_TABLE: &str = "lists";
+const MAX_NAME_LENGTH: usize = 100;
+const MAX_ITEMS: usize = 1000;
+
+static LISTS: Lazy<RwLock<BTreeMap<u32, List>>> = Lazy::new(Default::default);
+static UPDATING: Lazy<tokio::sync::Mutex<()>> = Lazy::new(Default::default);
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/lists", get(get_lists).post(create_list))
+        .route("/lists/:id", get(get_list).delete(delete_list))
+        .route(
+            "/lists/:id/shows/:showId",
+            put(add_show).delete(remove_show),
+        )
+        .route(
+            "/lists/:id/episodes/:episodeId",
+            put(add_episode).delete(remove_episode),
+        )
+}
+
+/// A named list of shows and episodes, like "Rewatch" or "Kids".
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+struct List {
+    id: u32,
+    name: String,
+    /// Who made it, `None` when logging in isn't required.
+    created_by: Option<String>,
+    /// Shared lists are seen and changed by everyone, others only by whoever
+    /// made them.
+    shared: bool,
+    created_at: DateTime<Utc>,
+    items: Vec<ListItem>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum ListItem {
+    #[serde(rename_all = "camelCase")]
+    Show { show_id: i32 },
+    #[serde(rename_all = "camelCase")]
+    Episode { episode_id: i32 },
+}
+
+impl List {
+    fn visible_to(&self, user: Option<&AuthUser>) -> bool {
+        self.shared || self.created_by.as_deref() == user.map(|user| user.name.as_str())
+    }
+
+    /// Sharing a list doesn't let others delete it.
+    fn deletable_by(&self, user: Option<&AuthUser>) -> bool {
+        match (&self.created_by, user) {
+            (None, _) => true,
+            (Some(_), Some(user)) if user.role == Role::Admin => true,
+            (Some(creator), Some(user)) => *creator == user.name,
+            (Some(_), None) => false,
+        }
+    }
+}
+
+/// A list with what's in it looked up, leaving out what's gone or
+/// restricted.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ListDetails {
+    #[serde(flatten)]
+    list: List,
+    shows: Vec<ShowSummary>,
+    episodes: Vec<EpisodeSummary>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ShowSummary {
+    id: i32,
+    title: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct EpisodeSummary {
+    id: i32,
+    series_id: i32,
+    season_number: i32,
+    episode_number: i32,
+    title: String,
+}
+
+#[derive(Deserialize)]
+struct NewList {
+    name: String,
+    /// Lists are private to whoever is logged in by default.
+    shared: Option<bool>,
+}
+
+/// Loads the stored lists.
+pub async fn load() {
+    let lists = store::rows::<u32, List>(LISTS_TABLE).await;
+    tracing::debug!("Loaded {} lists", lists.len());
+    *LISTS.write().unwrap() = lists;
+}
+
+fn not_found(id: u32) -> ApiError {
+    ApiError::new(404, format!("There's no list {}", id))
+}
+
+/// Changes list `id` when `user` may see it, saving it after.
+async fn update<T>(
+    id: u32,
+    user: Option<&AuthUser>,
+    change: impl FnOnce(&mut BTreeMap<u32, List>) -> Result<T, ApiError>,
+) -> Result<T, ApiError> {
+    let _updating = UPDATING.lock().await;
+
+    let (result, changed) = {
+        let mut all = LISTS.write().unwrap();
+        if !all.get(&id).is_some_and(|list| list.visible_to(user)) {
+            return Err(not_found(id));
+        }
+        let result = change(&mut all)?;
+        let changed = match all.get(&id) {
+            Some(list) => store::put(LISTS_TABLE, &id, list),
+            None => store::delete(LISTS_TABLE, &id),
+        };
+        (result, changed)
+    };
+
+    if let Err(e) = store::apply(&[changed]).await {
+        tracing::warn!("Failed to save lists: {}", e);
+    }
+
+    Ok(result)
+}
+
+async fn get_lists(user: Option<AuthUser>) -> Json<Vec<List>> {
+    Json(
+        LISTS
+            .read()
+            .unwrap()
+            .values()
+            .filter(|list| list.visible_to(user.as_ref()))
+            .cloned()
+            .collect(),
+    )
+}
+
+async fn create_list(
+    user: Option<AuthUser>,
+    Json(new): Json<NewList>,
+) -> Result<(StatusCode, Json<List>), ApiError> {
+    let name = new.name.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LENGTH {
+        return Err(ApiError::new(
+            400,
+            format!("Names are 1 to {} characters", MAX_NAME_LENGTH),
+        ));
+    }
+    let shared = new.shared.unwrap_or(user.is_none());
+    if user.is_none() && !shared {
+        return Err(ApiError::new(
+            400,
+            "Lists of nobody in particular have to be shared".into(),
+        ));
+    }
+
+    let _updating = UPDATING.lock().await;
+    let list = {
+        let mut all = LISTS.write().unwrap();
+        let id = all.keys().next_back().map_or(1, |last| last + 1);
+        let list = List {
+            id,
+            name: name.into(),
+            created_by: user.map(|user| user.name),
+            shared,
+            created_at: Utc::now(),
+            items: Vec::new(),
+        };
+        all.insert(id, list.clone());
+        list
+    };
+
+    if let Err(e) = store::apply(&[store::put(LISTS_TABLE, &list.id, &list)]).await {
+        tracing::warn!("Failed to save lists: {}", e);
+    }
+
+    Ok((StatusCode::CREATED, Json(list)))
+}
+
+async fn get_list(
+    Path(id): Path<u32>,
+    user: Option<AuthUser>,
+    restrictions: Restrictions,
+) -> Result<Json<ListDetails>, ApiError> {
+    let list = LISTS
+        .read()
+        .unwrap()
+        .get(&id)
+        .filter(|list| list.visible_to(user.as_ref()))
+        .cloned()
+        .ok_or_else(|| not_found(id))?;
+
+    let config = config::get();
+    let labels = sonarr::tag_labels(&config).await?;
+    let series = sonarr::series_by_id().await?;
+    let allowed = |series_id: i64| {
+        series.get(&series_id).is_some_and(|series| {
+            let tags = series["tags"].as_array().into_iter().flatten();
+            restrictions.allows(tags.filter_map(|id| labels.get(&(id.as_i64()? as i32))))
+        })
+    };
+
+    let mut shows = Vec::new();
+    let mut episodes = Vec::new();
+    for item in &list.items {
+        match *item {
+            ListItem::Show { show_id } if allowed(show_id.into()) => {
+                shows.extend(ShowSummary::deserialize(&series[&show_id.into()]).ok());
+            }
+            ListItem::Episode { episode_id } => {
+                let episode = sonarr::episode(episode_id)
+                    .await?
+                    .and_then(|episode| EpisodeSummary::deserialize(episode).ok())
+                    .filter(|episode| allowed(episode.series_id.into()));
+                episodes.extend(episode);
+            }
+            ListItem::Show { .. } => {}
+        }
+    }
+
+    Ok(Json(ListDetails {
+        list,
+        shows,
+        episodes,
+    }))
+}
+
+async fn delete_list(Path(id): Path<u32>, user: Option<AuthUser>) -> Result<StatusCode, ApiError> {
+    update(id, user.as_ref(), |all| {
+        if !all[&id].deletable_by(user.as_ref()) {
+            return Err(ApiError::new(
+                403,
+                "Only whoever made a list can delete it".into(),
+            ));
+        }
+        all.remove(&id);
+        Ok(StatusCode::NO_CONTENT)
+    })
+    .await
+}
+
+/// Adds `item` to list `id` once.
+async fn add(id: u32, user: Option<AuthUser>, item: ListItem) -> Result<Json<List>, ApiError> {
+    update(id, user.as_ref(), |all| {
+        let list = all.get_mut(&id).unwrap();
+        if !list.items.contains(&item) {
+            if list.items.len() >= MAX_ITEMS {
+                return Err(ApiError::new(
+                    400,
+                    format!("Lists can't have over {} items", MAX_ITEMS),
+                ));
+            }
+            list.items.push(item);
+        }
+        Ok(Json(list.clone()))
+    })
+    .await
+}
+
+async fn remove(id: u32, user: Option<AuthUser>, item: ListItem) -> Result<Json<List>, ApiError> {
+    update(id, user.as_ref(), |all| {
+        let list = all.get_mut(&id).unwrap();
+        list.items.retain(|listed| *listed != item);
+        Ok(Json(list.clone()))
+    })
+    .await
+}
+
+async fn add_show(
+    Path((id, show_id)): Path<(u32, i32)>,
+    user: Option<AuthUser>,
+) -> Result<Json<List>, ApiError> {
+    if !sonarr::series_by_id().await?.contains_key(&show_id.into()) {
+        return Err(ApiError::new(404, format!("There's no show {}", show_id)));
+    }
+
+    add(id, user, ListItem::Show { show_id }).await
+}
+
+async fn remove_show(
+    Path((id, show_id)): Path<(u32, i32)>,
+    user: Option<AuthUser>,
+) -> Result<Json<List>, ApiError> {
+    remove(id, user, ListItem::Show { show_id }).await
+}
+
+async fn add_episode(
+    Path((id, episode_id)): Path<(u32, i32)>,
+    user: Option<AuthUser>,
+) -> Result<Json<List>, ApiError> {
+    if sonarr::episode(episode_id).await?.is_none() {
+        return Err(ApiError::new(
+            404,
+            format!("There's no episode {}", episode_id),
+        ));
+    }
+
+    add(id, user, ListItem::Episode { episode_id }).await
+}
+
+async fn remove_episode(
+    Path((id, episode_id)): Path<(u32, i32)>,
+    user: Option<AuthUser>,
+) -> Result<Json<List>, ApiError> {
+    remove(id, user, ListItem::Episode { episode_id }).await
+}
@@ -1,65 +1,509 @@
-use axum::{extract::Path, http::HeaderMap, routing::get, Json, Router};
+use axum::{
+    extract,
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    middleware,
+    response::Response,
+    routing::{get, post},
+    Extension, Json, Router,
+};
+use chrono::{DateTime, NaiveDate, Utc};
 use errors::ApiError;
+use fields::{EpisodeFields, FieldsQuery};
+use futures::StreamExt;
+use listen::Listener;
+use playback::WatchState;
+use restrictions::Restrictions;
 
-use reqwest::RequestBuilder;
 use serde::{Deserialize, Serialize};
-use std::env;
-use std::{net::SocketAddr, path::PathBuf};
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::process::ExitCode;
+use std::sync::Arc;
+use titles::{AlternateTitle, Language};
 use tokio::select;
-use tower_http::trace::TraceLayer;
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use tokio::signal::unix::{signal, SignalKind};
+use tower_http::{timeout::TimeoutLayer, trace::TraceLayer};
+mod admin;
+mod auth;
+mod backup;
+mod blocklist;
+mod blurhash;
+mod cache;
+mod cassettes;
+mod circuit_breaker;
+mod cli;
+mod compression;
+mod config;
+mod dates;
+mod downloads;
+mod edges;
 mod errors;
+mod etag;
+mod events;
+mod export;
+mod extras;
+mod ffmpeg;
+mod fields;
+mod files;
+mod images;
+mod importer;
+mod intros;
+mod jobs;
+mod library;
+mod lidarr;
+mod limits;
+mod listen;
+mod lists;
+mod markers;
+mod mdns;
+mod migrations;
+mod notifications;
+mod oidc;
+mod playback;
+mod port_forwarding;
+mod postgres;
+mod probe;
+mod prowlarr;
+mod queues;
+mod radarr;
+mod readarr;
+mod redis;
+mod releases;
+mod reports;
+mod request_id;
+mod restrictions;
+mod schedule;
+mod scheduler;
 mod sendfile;
+mod sonarr;
+mod storage;
+mod store;
+mod stream_stats;
+mod sync;
+mod systemd;
+mod telemetry;
+mod titles;
+mod trakt;
+mod transcode;
+mod trickplay;
+mod upstream;
+mod users;
+mod wanted;
+mod watcher;
+mod web;
+mod webhooks;
+mod zip;
 
 #[tokio::main]
-async fn main() {
-    tracing_subscriber::registry()
-        .with(tracing_subscriber::EnvFilter::new(
-            std::env::var("RUST_LOG").unwrap_or_else(|_| "centarr=debug,tower_http=debug".into()),
-        ))
-        .with(tracing_subscriber::fmt::layer())
-        .init();
+async fn main() -> ExitCode {
+    let command = cli::parse(std::env::args_os()).unwrap_or_else(|e| e.exit());
+
+    let config = match config::Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprint!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    config::init(config);
+
+    match command {
+        cli::Command::Serve { mock, cassettes } => {
+            if let Some(mode) = mock {
+                cassettes::init(cassettes::Mock {
+                    mode,
+                    dir: cassettes.unwrap_or_else(|| config::get().data_dir.join("cassettes")),
+                });
+            }
+            serve().await
+        }
+        cli::Command::Check => cli::check().await,
+        cli::Command::Doctor => cli::doctor().await,
+        cli::Command::Import {
+            from,
+            url,
+            api_key,
+            user,
+            viewer,
+        } => importer::run(from, &url, &api_key, user.as_deref(), viewer.as_deref()).await,
+        cli::Command::Migrate { to, dry_run } => cli::migrate(to, dry_run).await,
+        cli::Command::Restore {
+            file,
+            without_config,
+        } => cli::restore(&file, !without_config).await,
+    }
+}
+
+async fn serve() -> ExitCode {
+    let config = config::get();
+    telemetry::init(&config.log_level);
+    if let Err(e) = store::open().await {
+        tracing::error!("{}", e);
+        return ExitCode::FAILURE;
+    }
+    if let Err(e) = redis::open().await {
+        tracing::error!("{}", e);
+        return ExitCode::FAILURE;
+    }
+    load_state().await;
+    tokio::spawn(ffmpeg::run());
+
+    let (api_listeners, stream_listeners) = match bind(&config).await {
+        Ok(listeners) => listeners,
+        Err(e) => {
+            tracing::error!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    systemd::notify("READY=1");
+    let advertised = reachable(&api_listeners);
+    let forwarded_ports = (
+        advertised.map(|addr| addr.port()),
+        reachable(&stream_listeners).map(|addr| addr.port()),
+    );
+
+    select! {
+        _ = app(config, api_listeners) => {},
+        _ = sendfile::server(stream_listeners) => {},
+        _ = reload_on_sighup() => {},
+        _ = reload_on_change() => {},
+        _ = shutdown() => {},
+        _ = systemd::watchdog() => {},
+        _ = mdns::advertise(advertised) => {},
+        _ = port_forwarding::run(forwarded_ports.0, forwarded_ports.1) => {},
+        _ = downloads::poll() => {},
+        _ = sync::run() => {},
+        _ = sonarr::watch_version() => {},
+        _ = scheduler::run() => {},
+        _ = jobs::run() => {},
+        _ = watcher::watch() => {},
+        _ = notifications::run() => {},
+        _ = webhooks::run() => {},
+        _ = playback::run() => {},
+        _ = edges::run() => {},
+    }
+
+    // plays are saved every so often and jobs as they change, what's
+    // left would be lost with the process
+    playback::save().await;
+    jobs::save().await;
+    telemetry::shutdown();
+    ExitCode::SUCCESS
+}
+
+/// Loads what's kept in the data dir, again after a backup was restored.
+async fn load_state() {
+    store::load().await;
+    users::load().await;
+    markers::load().await;
+    lists::load().await;
+    images::load().await;
+    intros::load().await;
+    playback::load().await;
+    jobs::load().await;
+}
+
+/// The first TCP address of `listeners` other hosts can connect to.
+fn reachable(listeners: &[Listener]) -> Option<SocketAddr> {
+    listeners
+        .iter()
+        .filter_map(Listener::addr)
+        .find_map(|addr| match addr {
+            config::ListenAddr::Tcp(addr) if !addr.ip().is_loopback() => Some(addr),
+            _ => None,
+        })
+}
+
+/// Listens on the sockets systemd passed, or the API and streaming
+/// addresses.
+async fn bind(config: &config::Config) -> Result<(Vec<Listener>, Vec<Listener>), String> {
+    let mut inherited = systemd::listeners()?;
+    if inherited.api.is_empty() {
+        inherited.api = listen::bind("API", &config.api_addr, config.socket_mode).await?;
+    }
+    if inherited.stream.is_empty() {
+        inherited.stream =
+            listen::bind("streaming server", &config.stream_addr, config.socket_mode).await?;
+    }
+
+    Ok((inherited.api, inherited.stream))
+}
+
+/// Waits for SIGTERM or SIGINT, after which the servers are dropped and
+/// their Unix sockets removed.
+async fn shutdown() {
+    let mut terminate = signal(SignalKind::terminate()).expect("failed to listen for SIGTERM");
+    let mut interrupt = signal(SignalKind::interrupt()).expect("failed to listen for SIGINT");
 
     select! {
-        _ = app() => {},
-        _ = sendfile::server() => {},
+        _ = terminate.recv() => {},
+        _ = interrupt.recv() => {},
     }
+    tracing::info!("Shutting down");
+    systemd::notify("STOPPING=1");
 }
 
-async fn app() {
-    let app: _ = Router::new()
+async fn reload_on_sighup() {
+    let mut hangups = signal(SignalKind::hangup()).expect("failed to listen for SIGHUP");
+
+    while hangups.recv().await.is_some() {
+        if let Err(e) = config::reload() {
+            tracing::error!("Keeping the current configuration, {}", e);
+        }
+    }
+}
+
+/// Loads again what other centarrs sharing the database changed, so they
+/// serve the same users, plays and library. Jobs and the image index aren't
+/// shared.
+async fn reload_on_change() {
+    loop {
+        let changed = store::changed().await;
+        tracing::debug!("Loading what other centarrs changed: {:?}", changed);
+
+        let library = [
+            store::SERIES,
+            store::EPISODES,
+            store::TAGS,
+            store::MISSING_FILES,
+            store::SYNCS,
+        ];
+        if library.iter().any(|table| changed.contains(table)) {
+            store::load().await;
+        }
+        for table in changed {
+            match table {
+                "users" => users::load().await,
+                "plays" => playback::load().await,
+                "lists" => lists::load().await,
+                "markers" => markers::load().await,
+                "analyzed_files" => intros::load().await,
+                _ => {}
+            }
+        }
+    }
+}
+
+async fn app(config: Arc<config::Config>, listeners: Vec<Listener>) {
+    let api = Router::new()
         .route("/shows", get(get_shows))
+        .route("/shows/batch", post(get_shows_batch))
         .route("/shows/:showId", get(get_show))
-        .layer(TraceLayer::new_for_http());
+        .route("/facets", get(get_facets))
+        .route("/episodes/status", post(episodes_status))
+        .merge(wanted::router())
+        .merge(blocklist::router())
+        .merge(library::router())
+        .merge(schedule::router())
+        .merge(lists::router())
+        .merge(queues::router())
+        .merge(playback::router())
+        .merge(markers::router())
+        .merge(extras::router())
+        .merge(images::router())
+        .merge(files::router())
+        .merge(trickplay::router())
+        .merge(lidarr::router())
+        .merge(readarr::router())
+        .merge(downloads::router())
+        .merge(reports::router())
+        .merge(export::router())
+        .merge(jobs::router())
+        .merge(trakt::router())
+        .route_layer(TimeoutLayer::new(config.request_timeout))
+        .merge(
+            prowlarr::router()
+                .merge(releases::router())
+                .route_layer(TimeoutLayer::new(config.search_timeout)),
+        )
+        .route_layer(middleware::from_fn(auth::require));
 
-    let addr = SocketAddr::from(([0, 0, 0, 0], 3000));
-    tracing::debug!("Listening on http://{}", addr);
+    let mut app = Router::new()
+        .route("/readyz", get(readyz))
+        .merge(auth::router())
+        .merge(edges::router())
+        .merge(oidc::router())
+        .merge(sync::router().route_layer(middleware::from_fn(limits::webhook_body)))
+        .nest("/admin", admin::router())
+        .route_layer(TimeoutLayer::new(config.request_timeout))
+        .merge(api);
 
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service())
-        .await
-        .unwrap();
+    if let Some(web_ui) = web::ui() {
+        app = app.fallback(web_ui);
+    }
+
+    let app = app
+        .layer(middleware::from_fn(sonarr::inject))
+        .layer(Extension(sonarr::client()))
+        .layer(middleware::from_fn(limits::body))
+        .layer(compression::layer())
+        .layer(middleware::from_fn(compression::exclude))
+        .layer(TraceLayer::new_for_http())
+        .layer(middleware::from_fn(request_id::middleware));
+
+    drop(config);
+    listen::serve_each(listeners, |listener| serve_api(app.clone(), listener)).await;
+}
+
+async fn serve_api(app: Router, listener: Listener) {
+    tracing::debug!("API listening on {}", listener.describe());
+
+    match listener {
+        Listener::Tcp(listener) => {
+            axum::Server::from_tcp(listener.into_std().unwrap())
+                .unwrap()
+                .serve(app.into_make_service())
+                .await
+                .unwrap();
+        }
+        Listener::Unix(listener, _file) => {
+            let accept = hyper::server::accept::poll_fn(move |cx| {
+                listener
+                    .poll_accept(cx)
+                    .map(|accepted| Some(accepted.map(|(stream, _)| stream)))
+            });
+
+            axum::Server::builder(accept)
+                .serve(app.into_make_service())
+                .await
+                .unwrap();
+        }
+    }
 }
 
-fn sonarr_url(path: &str) -> String {
-    format!("{}{}", env::var("SONARR_URL").unwrap(), path)
+#[derive(Serialize)]
+struct Readiness {
+    ready: bool,
+    sonarr: Option<sonarr::Detected>,
+    /// Whether the media roots and path mappings can be reached.
+    storage: Vec<storage::RootStatus>,
 }
 
-fn sonarr_client(path: &str) -> RequestBuilder {
-    let client = reqwest::Client::new();
+/// Ready once Sonarr has been reached, with its version and API, while the
+/// media can be read.
+async fn readyz() -> (StatusCode, Json<Readiness>) {
+    let sonarr = sonarr::detected();
+    let storage = storage::check_roots(&config::get()).await;
+    let ready = sonarr.is_some() && storage.iter().all(|root| root.online);
+    let status = match ready {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
 
-    client
-        .get(sonarr_url(path))
-        .header("X-Api-Key", env::var("SONARR_API_KEY").unwrap())
+    (
+        status,
+        Json(Readiness {
+            ready,
+            sonarr,
+            storage,
+        }),
+    )
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 struct Show {
     id: i32,
     title: String,
+    /// [`Show::title`] in the language the client prefers, when Sonarr has
+    /// it.
+    #[serde(
+        rename = "localizedTitle",
+        skip_serializing_if = "Option::is_none",
+        skip_deserializing
+    )]
+    localized_title: Option<String>,
+    #[serde(rename = "alternateTitles", default)]
+    alternate_titles: Vec<AlternateTitle>,
+    #[serde(
+        rename = "originalLanguage",
+        skip_serializing_if = "Option::is_none",
+        default
+    )]
+    original_language: Option<Language>,
+    #[serde(default)]
     images: Vec<ShowImage>,
+    #[serde(default)]
+    genres: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    network: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none", default)]
     episodes: Option<Vec<Episode>>,
+    #[serde(rename(deserialize = "tags"), skip_serializing, default)]
+    tag_ids: Vec<i32>,
+    /// Labels of the tags, lowercase as Sonarr keeps them.
+    #[serde(skip_deserializing)]
+    tags: Vec<String>,
+    /// Sonarr v2 has these on the series itself.
+    #[serde(flatten)]
+    stats: ShowStats,
+    /// Where Sonarr v3 has them, moved to `stats` by [`Show::parse`].
+    #[serde(skip_serializing, default)]
+    statistics: Option<ShowStats>,
+}
+
+/// How much of a show is on disk, for completion badges.
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct ShowStats {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    episode_count: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    episode_file_count: Option<u32>,
+    /// Between 0 and 100.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    percent_of_episodes: Option<f64>,
+    /// In bytes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size_on_disk: Option<u64>,
+    /// Monitored episodes whose file is below the quality cutoff, which
+    /// Sonarr will still upgrade.
+    #[serde(skip_serializing_if = "Option::is_none", skip_deserializing)]
+    quality_cutoff_not_met_count: Option<u32>,
+}
+
+impl Show {
+    /// A series as Sonarr v2 or v3 sends it.
+    fn parse(series: &serde_json::Value) -> Result<Self, serde_json::Error> {
+        let mut show = Show::deserialize(series)?;
+
+        for image in &mut show.images {
+            image.blurhash = images::blurhash(show.id, &image.cover_type, &image.url);
+        }
+        if let Some(statistics) = show.statistics.take() {
+            show.stats = statistics;
+        }
+        let stats = &mut show.stats;
+        if let (None, Some(files), Some(episodes)) = (
+            stats.percent_of_episodes,
+            stats.episode_file_count,
+            stats.episode_count,
+        ) {
+            stats.percent_of_episodes = Some(match episodes {
+                0 => 0.0,
+                _ => f64::from(files) * 100.0 / f64::from(episodes),
+            });
+        }
+
+        Ok(show)
+    }
+
+    /// Looks up the labels of the show's tags, and whether they're allowed.
+    async fn allowed(&mut self, restrictions: &Restrictions) -> Result<bool, ApiError> {
+        let labels = sonarr::tags().await?;
+        self.tags = self
+            .tag_ids
+            .iter()
+            .filter_map(|id| labels.get(id).cloned())
+            .collect();
+
+        Ok(restrictions.allows(&self.tags))
+    }
+
+    /// Picks the title to show for `Accept-Language`.
+    fn localize(&mut self, languages: &[&str]) {
+        self.localized_title = titles::localized(&self.alternate_titles, languages);
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -67,8 +511,12 @@ struct ShowImage {
     #[serde(rename = "coverType")]
     cover_type: String,
     url: String,
-    #[serde(rename = "remoteUrl")]
+    /// Left out by Sonarr v4 for artwork it only has locally.
+    #[serde(rename = "remoteUrl", default)]
     remote_url: String,
+    /// A placeholder to show while the image loads, once it was cached.
+    #[serde(skip_serializing_if = "Option::is_none", skip_deserializing)]
+    blurhash: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -76,22 +524,32 @@ struct Episode {
     id: i32,
     #[serde(rename = "seriesId")]
     series_id: i32,
-    #[serde(rename = "episodeFileId")]
+    #[serde(rename = "episodeFileId", default)]
     episode_file_id: i32,
     #[serde(rename = "seasonNumber")]
     season_number: i32,
     #[serde(rename = "episodeNumber")]
     episode_number: i32,
+    /// Left out by Sonarr while it's TBA.
+    #[serde(default)]
     title: String,
+    /// The day it airs where it's broadcast.
     #[serde(rename = "airDate")]
-    air_date: String,
+    air_date: Option<NaiveDate>,
     #[serde(rename = "airDateUtc")]
-    air_date_utc: String,
+    air_date_utc: Option<DateTime<Utc>>,
+    #[serde(rename = "isAired", skip_deserializing)]
+    is_aired: bool,
+    /// Until it airs, rounded up. Left out of ETags, which would change
+    /// by the minute otherwise.
+    #[serde(rename = "airsInMinutes", skip_deserializing)]
+    airs_in_minutes: Option<i64>,
     overview: Option<String>,
     #[serde(rename = "episodeFile")]
     episode_file: Option<EpisodeFile>,
-    #[serde(rename = "hasFile")]
+    #[serde(rename = "hasFile", default)]
     has_file: bool,
+    #[serde(default)]
     monitored: bool,
     #[serde(rename = "absoluteEpisodeNumber")]
     absolute_episode_number: Option<i32>,
@@ -101,10 +559,21 @@ struct Episode {
     scene_episode_number: Option<i32>,
     #[serde(rename = "sceneSeasonNumber")]
     scene_season_number: Option<i32>,
-    #[serde(rename = "unverifiedSceneNumbering")]
+    #[serde(rename = "unverifiedSceneNumbering", default)]
     unverified_scene_numbering: bool,
     #[serde(rename = "lastSearchTime")]
-    last_search_time: Option<String>,
+    last_search_time: Option<DateTime<Utc>>,
+}
+
+impl Episode {
+    /// Fills in what depends on the time, `now`.
+    fn set_airing(&mut self, now: DateTime<Utc>) {
+        self.is_aired = self.air_date_utc.is_some_and(|aired| aired <= now);
+        self.airs_in_minutes = self
+            .air_date_utc
+            .filter(|_| !self.is_aired)
+            .map(|aired| ((aired - now).num_seconds() + 59) / 60);
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -114,81 +583,549 @@ struct EpisodeFile {
     series_id: i32,
     #[serde(rename = "seasonNumber")]
     season_number: i32,
-    #[serde(rename = "relativePath")]
+    #[serde(rename = "relativePath", default)]
     relative_path: String,
+    /// Where it is on disk, which clients stream by `watchUrl` instead.
+    #[serde(skip_serializing)]
     path: String,
     size: i64,
     #[serde(rename = "dateAdded")]
-    date_added: String,
-    // quality: Quality;
-    // language: Language;
-    // mediaInfo: MediaInfo;
-    #[serde(rename = "originalFilePath")]
-    original_file_path: String,
-    #[serde(rename = "qualityCutoffNotMet")]
+    date_added: DateTime<Utc>,
+    quality: Option<Quality>,
+    /// Sonarr v4 lists them, v3 has `language` which is moved here by
+    /// [`EpisodeFile::move_language`].
+    #[serde(default)]
+    languages: Vec<Language>,
+    #[serde(skip_serializing, default)]
+    language: Option<Language>,
+    #[serde(rename = "mediaInfo")]
+    media_info: Option<MediaInfo>,
+    #[serde(rename = "qualityCutoffNotMet", default)]
     quality_cutoff_not_met: bool,
     #[serde(rename = "sceneName")]
     scene_name: Option<String>,
 
     #[serde(rename = "watchUrl")]
     watch_url: Option<String>,
+    /// Deleted or moved away on disk since Sonarr last reported it.
+    #[serde(default)]
+    missing: bool,
+    /// Still being copied in, it streams without a length or seeking.
+    #[serde(rename = "importInProgress", default)]
+    import_in_progress: bool,
+    /// Whether the file could be found where it's mapped to, so clients can
+    /// tell before playing it.
+    #[serde(rename = "fileAvailable", default)]
+    file_available: bool,
+    #[serde(rename = "fileSizeOnDisk", default)]
+    file_size_on_disk: Option<u64>,
 }
 
-async fn get_shows() -> Result<Json<Vec<Show>>, ApiError> {
-    let body = sonarr_client("/series")
-        .send()
-        .await
-        .map_err(|e| ApiError::empty(500, Some(e.to_string())))?
-        .text()
-        .await
-        .map_err(|e| ApiError::empty(500, Some(e.to_string())))?;
+impl EpisodeFile {
+    fn move_language(&mut self) {
+        if let Some(language) = self.language.take() {
+            if self.languages.is_empty() {
+                self.languages.push(language);
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Quality {
+    quality: QualityDefinition,
+    revision: Option<Revision>,
+}
 
-    let shows = serde_json::from_str::<Vec<Show>>(&body).unwrap();
+#[derive(Serialize, Deserialize, Debug)]
+struct QualityDefinition {
+    id: i32,
+    /// Like `WEBDL-1080p`.
+    name: String,
+    /// Like `web` or `bluray`, Sonarr v2 leaves it out.
+    source: Option<String>,
+    resolution: Option<i32>,
+}
 
-    Ok(shows.into())
+/// Which release of the same quality it is, repacks and propers being
+/// newer versions.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Revision {
+    version: i32,
+    #[serde(default)]
+    real: i32,
+    #[serde(default)]
+    is_repack: bool,
 }
 
-async fn get_show(Path(id): Path<i32>, headers: HeaderMap) -> Result<Json<Show>, ApiError> {
-    let body = sonarr_client(format!("/series/{}", id).as_str())
-        .send()
-        .await
-        .map_err(|e| ApiError::empty(500, Some(e.to_string())))?
-        .text()
-        .await
-        .map_err(|e| ApiError::empty(500, Some(e.to_string())))?;
+/// What Sonarr read from the file with ffprobe, for badges and to tell
+/// whether a player can play it as it is.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct MediaInfo {
+    /// Like `x265` or `h264`.
+    video_codec: Option<String>,
+    video_bit_depth: Option<u32>,
+    video_bitrate: Option<u64>,
+    video_fps: Option<f64>,
+    /// `HDR`, empty for SDR.
+    video_dynamic_range: Option<String>,
+    /// Like `HDR10` or `DV`.
+    video_dynamic_range_type: Option<String>,
+    /// Like `1920x1080`.
+    resolution: Option<String>,
+    scan_type: Option<String>,
+    /// Like `EAC3 Atmos` or `AAC`.
+    audio_codec: Option<String>,
+    /// `5.1` for 5.1 surround.
+    audio_channels: Option<f64>,
+    audio_bitrate: Option<u64>,
+    audio_stream_count: Option<u32>,
+    /// The audio streams' languages, like `English/Japanese`.
+    audio_languages: Option<String>,
+    /// The subtitles' languages, like `English/Spanish`.
+    subtitles: Option<String>,
+    /// Like `23:40`.
+    run_time: Option<String>,
+}
 
-    let mut show = serde_json::from_str::<Show>(&body).unwrap();
+/// Shows the user is allowed to see, with their tags' labels.
+async fn allowed_shows(restrictions: &Restrictions) -> Result<Vec<Show>, ApiError> {
+    let shows = match store::library() {
+        Some(library) => sonarr::parse_each("show", library.series.values(), Show::parse)?,
+        None => {
+            let body = sonarr::get("/series").await?;
+            let series = serde_json::from_str::<Vec<serde_json::Value>>(&body)
+                .map_err(|e| ApiError::empty(500, Some(e.to_string())))?;
 
-    let body = sonarr_client(format!("/episode?seriesId={}", id).as_str())
-        .send()
-        .await
-        .map_err(|e| ApiError::empty(500, Some(e.to_string())))?
-        .text()
+            sonarr::parse_each("show", &series, Show::parse)?
+        }
+    };
+
+    let mut allowed = Vec::with_capacity(shows.len());
+    for mut show in shows {
+        if show.allowed(restrictions).await? {
+            allowed.push(show);
+        }
+    }
+
+    Ok(allowed)
+}
+
+/// Narrows `/shows` down to a genre, network or tag, ignoring case.
+#[derive(Deserialize)]
+struct ShowsQuery {
+    genre: Option<String>,
+    network: Option<String>,
+    tag: Option<String>,
+}
+
+fn matches<'a>(wanted: &Option<String>, values: impl IntoIterator<Item = &'a str>) -> bool {
+    match wanted {
+        Some(wanted) => values
+            .into_iter()
+            .any(|value| value.eq_ignore_ascii_case(wanted)),
+        None => true,
+    }
+}
+
+async fn get_shows(
+    extract::Query(query): extract::Query<ShowsQuery>,
+    headers: HeaderMap,
+    restrictions: Restrictions,
+) -> Result<Response, ApiError> {
+    let mut shows = allowed_shows(&restrictions)
+        .await?
+        .into_iter()
+        .filter(|show| {
+            matches(&query.genre, show.genres.iter().map(String::as_str))
+                && matches(&query.network, show.network.as_deref())
+                && matches(&query.tag, show.tags.iter().map(String::as_str))
+        })
+        .collect::<Vec<_>>();
+
+    let cutoff_unmet = wanted::counts()
         .await
-        .map_err(|e| ApiError::empty(500, Some(e.to_string())))?;
+        .map_err(|e| tracing::warn!("Can't count episodes below the cutoff: {:?}", e))
+        .ok();
+    let languages = titles::preferred_languages(&headers);
+    for show in &mut shows {
+        show.localize(&languages);
+        show.stats.quality_cutoff_not_met_count = cutoff_unmet
+            .as_ref()
+            .map(|counts| counts.get(&show.id).copied().unwrap_or_default());
+    }
 
-    let mut episodes = serde_json::from_str::<Vec<Episode>>(&body).unwrap();
+    localized(etag::json(&headers, &shows))
+}
 
-    for episode in &mut episodes {
-        if episode.episode_file.is_some() {
-            let mut file = episode.episode_file.as_mut().unwrap();
-            let path = PathBuf::from(file.path.clone());
-            file.watch_url = Some(format!(
-                "http://{}?file={}",
-                headers
-                    .get("Host")
-                    .unwrap()
-                    .to_str()
-                    .unwrap()
-                    .replace("3000", "3001"),
-                urlencoding::encode(path.to_str().unwrap())
-            ));
+/// Marks a response as depending on `Accept-Language`, for caches.
+fn localized(response: Result<Response, ApiError>) -> Result<Response, ApiError> {
+    response.map(|mut response| {
+        response
+            .headers_mut()
+            .append(header::VARY, HeaderValue::from_static("accept-language"));
+        response
+    })
+}
+
+#[derive(Serialize)]
+struct Facet {
+    name: String,
+    count: usize,
+}
+
+#[derive(Serialize)]
+struct Facets {
+    genres: Vec<Facet>,
+    networks: Vec<Facet>,
+    tags: Vec<Facet>,
+}
+
+/// The genres, networks and tags of the shows the user can see, most
+/// common first, to browse `/shows` by.
+async fn get_facets(headers: HeaderMap, restrictions: Restrictions) -> Result<Response, ApiError> {
+    let mut genres = BTreeMap::<String, usize>::new();
+    let mut networks = BTreeMap::<String, usize>::new();
+    let mut tags = BTreeMap::<String, usize>::new();
+
+    for show in allowed_shows(&restrictions).await? {
+        for genre in show.genres {
+            *genres.entry(genre).or_default() += 1;
+        }
+        if let Some(network) = show.network.filter(|network| !network.is_empty()) {
+            *networks.entry(network).or_default() += 1;
+        }
+        for label in show.tags {
+            *tags.entry(label).or_default() += 1;
         }
     }
 
+    let sorted = |counts: BTreeMap<String, usize>| {
+        let mut facets = counts
+            .into_iter()
+            .map(|(name, count)| Facet { name, count })
+            .collect::<Vec<_>>();
+        // stable, so equally common ones stay by name
+        facets.sort_by_key(|facet| std::cmp::Reverse(facet.count));
+        facets
+    };
+
+    etag::json(
+        &headers,
+        &Facets {
+            genres: sorted(genres),
+            networks: sorted(networks),
+            tags: sorted(tags),
+        },
+    )
+}
+
+/// The show and its episodes, from the library or Sonarr, a 404 when
+/// there's no such show or it's hidden from whoever is asking.
+async fn show_with_episodes(id: i32, restrictions: &Restrictions) -> Result<Show, ApiError> {
+    let invalid = |e: serde_json::Error| ApiError::empty(500, Some(e.to_string()));
+    let (mut show, episodes) = match store::library() {
+        Some(library) => {
+            let series = library
+                .series
+                .get(&id)
+                .ok_or_else(|| ApiError::new(404, format!("There's no show {}", id)))?;
+            let episodes = library
+                .episodes
+                .get(&id)
+                .map(Vec::as_slice)
+                .unwrap_or_default();
+
+            let show = Show::parse(series).map_err(invalid)?;
+            let episodes = sonarr::parse_each("episode", episodes, Episode::deserialize)?;
+
+            (show, episodes)
+        }
+        None => {
+            let body = sonarr::get(format!("/series/{}", id).as_str()).await?;
+
+            let series = serde_json::from_str::<serde_json::Value>(&body).map_err(invalid)?;
+            // Sonarr's cached answers pass its 404s on as they are
+            if series.get("id").is_none() {
+                return Err(ApiError::new(404, format!("There's no show {}", id)));
+            }
+            let show = Show::parse(&series).map_err(invalid)?;
+
+            let body = sonarr::get(format!("/episode?seriesId={}", id).as_str()).await?;
+
+            let episodes =
+                serde_json::from_str::<Vec<serde_json::Value>>(&body).map_err(invalid)?;
+            let episodes = sonarr::parse_each("episode", &episodes, Episode::deserialize)?;
+
+            (show, episodes)
+        }
+    };
+
+    if !show.allowed(restrictions).await? {
+        return Err(ApiError::new(404, format!("There's no show {}", id)));
+    }
+
+    show.stats.quality_cutoff_not_met_count = Some(
+        episodes
+            .iter()
+            .filter(|episode| {
+                episode.monitored
+                    && episode
+                        .episode_file
+                        .as_ref()
+                        .is_some_and(|file| file.quality_cutoff_not_met)
+            })
+            .count() as u32,
+    );
     show.episodes = Some(episodes);
+    Ok(show)
+}
+
+/// Fills in whether the show's episode files are on disk and where they
+/// stream from, and sends it with the episode `fields` asked for.
+async fn show_details(
+    mut show: Show,
+    fields: &EpisodeFields,
+    headers: &HeaderMap,
+    config: &config::Config,
+    preflight: &storage::Preflight,
+    missing_files: &BTreeSet<std::path::PathBuf>,
+) -> Result<serde_json::Value, ApiError> {
+    let now = Utc::now();
+    for episode in show.episodes.iter_mut().flatten() {
+        episode.set_airing(now);
+        if let Some(file) = episode.episode_file.as_mut() {
+            file.move_language();
+            let local_path = config.local_path(Path::new(&file.path));
+            let metadata = preflight.stat(&local_path).await;
+            file.missing = missing_files.contains(&local_path);
+            file.import_in_progress = metadata.as_ref().is_some_and(files::is_growing);
+            file.file_available = metadata.is_some();
+            file.file_size_on_disk = metadata.map(|metadata| metadata.len());
+            file.watch_url = Some(sendfile::episode_url(headers, config, file.id, &file.path));
+        }
+    }
+
+    show.localize(&titles::preferred_languages(headers));
 
-    Ok(show.into())
+    let mut show =
+        serde_json::to_value(&show).map_err(|e| ApiError::empty(500, Some(e.to_string())))?;
+    fields.select(&mut show["episodes"]);
+
+    Ok(show)
+}
+
+async fn get_show(
+    extract::Path(id): extract::Path<i32>,
+    extract::Query(fields): extract::Query<FieldsQuery>,
+    headers: HeaderMap,
+    restrictions: Restrictions,
+) -> Result<Response, ApiError> {
+    let config = config::get();
+    let fields = EpisodeFields::try_from(fields)?;
+
+    let show = show_with_episodes(id, &restrictions).await?;
+
+    let missing_files = store::library()
+        .map(|library| library.missing_files.clone())
+        .unwrap_or_default();
+    let preflight = storage::Preflight::new(&config).await;
+    let show = show_details(show, &fields, &headers, &config, &preflight, &missing_files).await?;
+
+    localized(etag::json(&headers, &show))
+}
+
+/// Most shows asked for at once.
+const MAX_BATCH_SIZE: usize = 100;
+/// How many of a batch's shows are fetched from Sonarr at a time.
+const BATCH_CONCURRENCY: usize = 4;
+
+#[derive(Deserialize)]
+struct ShowsBatch {
+    ids: Vec<i32>,
+}
+
+/// The details of many shows at once, like [`get_show`] for each, fetched
+/// a few at a time when they come from Sonarr. Shows that aren't there
+/// are listed under `notFound` instead of failing the rest.
+async fn get_shows_batch(
+    extract::Query(fields): extract::Query<FieldsQuery>,
+    headers: HeaderMap,
+    restrictions: Restrictions,
+    Json(batch): Json<ShowsBatch>,
+) -> Result<Response, ApiError> {
+    let config = config::get();
+    let fields = EpisodeFields::try_from(fields)?;
+    if batch.ids.len() > MAX_BATCH_SIZE {
+        return Err(ApiError::new(
+            422,
+            format!("Ask for at most {} shows at a time", MAX_BATCH_SIZE),
+        ));
+    }
+
+    let mut ids = batch.ids;
+    let mut seen = BTreeSet::new();
+    ids.retain(|id| seen.insert(*id));
+
+    // fetched within the request, so they're logged and traced with it
+    let fetched = futures::stream::iter(ids.iter().copied())
+        .map(|id| show_with_episodes(id, &restrictions))
+        .buffered(BATCH_CONCURRENCY)
+        .collect::<Vec<_>>()
+        .await;
+
+    let missing_files = store::library()
+        .map(|library| library.missing_files.clone())
+        .unwrap_or_default();
+    let preflight = storage::Preflight::new(&config).await;
+    let mut shows = Vec::with_capacity(ids.len());
+    let mut not_found = Vec::new();
+    for (id, show) in ids.into_iter().zip(fetched) {
+        match show {
+            Ok(show) => shows.push(
+                show_details(show, &fields, &headers, &config, &preflight, &missing_files).await?,
+            ),
+            Err(e) if e.status_code() == StatusCode::NOT_FOUND => not_found.push(id),
+            Err(e) => return Err(e),
+        }
+    }
+
+    localized(etag::json(
+        &headers,
+        &serde_json::json!({ "shows": shows, "notFound": not_found }),
+    ))
+}
+
+/// Most episodes asked for at once, a few seasons' worth.
+const MAX_STATUS_EPISODES: usize = 500;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct StatusQuery {
+    episode_ids: Vec<i32>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct EpisodeStatus {
+    id: i32,
+    has_file: bool,
+    monitored: bool,
+    watch_state: WatchState,
+    /// Bytes into the file the last stream got, while it's in progress.
+    position: Option<u64>,
+}
+
+/// What changes about episodes while a season is on screen, for those of
+/// `episodeIds` that exist and aren't restricted.
+async fn episodes_status(
+    user: Option<auth::AuthUser>,
+    restrictions: Restrictions,
+    Json(query): Json<StatusQuery>,
+) -> Result<Json<Vec<EpisodeStatus>>, ApiError> {
+    if query.episode_ids.len() > MAX_STATUS_EPISODES {
+        return Err(ApiError::new(
+            400,
+            format!("Ask for at most {} episodes", MAX_STATUS_EPISODES),
+        ));
+    }
+
+    let config = config::get();
+    let wanted = query.episode_ids.into_iter().collect::<BTreeSet<_>>();
+    let invalid = |e: serde_json::Error| ApiError::empty(500, Some(e.to_string()));
+
+    let (series, episodes) = match store::library() {
+        Some(library) => {
+            let episodes = library.episodes.values().flatten().filter(|episode| {
+                episode["id"]
+                    .as_i64()
+                    .is_some_and(|id| wanted.contains(&(id as i32)))
+            });
+            let episodes = sonarr::parse_each("episode", episodes, Episode::deserialize)?;
+            let series = episodes
+                .iter()
+                .map(|episode| episode.series_id)
+                .collect::<BTreeSet<_>>()
+                .into_iter()
+                .filter_map(|id| Some((id, library.series.get(&id)?.clone())))
+                .collect::<BTreeMap<_, _>>();
+
+            (series, episodes)
+        }
+        None => {
+            if wanted.is_empty() {
+                return Ok(Json(Vec::new()));
+            }
+
+            // only whole series come with their files in v3
+            let ids = wanted
+                .iter()
+                .map(|id| format!("episodeIds={}", id))
+                .collect::<Vec<_>>()
+                .join("&");
+            let listed = sonarr::get(&format!("/episode?{}", ids)).await?;
+            let series_ids = serde_json::from_str::<Vec<serde_json::Value>>(&listed)
+                .map_err(invalid)?
+                .iter()
+                .filter_map(|episode| episode["seriesId"].as_i64())
+                .collect::<BTreeSet<_>>();
+
+            let mut series = BTreeMap::new();
+            let mut episodes = Vec::new();
+            for id in series_ids {
+                let body = sonarr::get(&format!("/episode?seriesId={}", id)).await?;
+                let listed =
+                    serde_json::from_str::<Vec<serde_json::Value>>(&body).map_err(invalid)?;
+                episodes.extend(
+                    sonarr::parse_each("episode", &listed, Episode::deserialize)?
+                        .into_iter()
+                        .filter(|episode| wanted.contains(&episode.id)),
+                );
+                let body = sonarr::get(&format!("/series/{}", id)).await?;
+                series.insert(id as i32, serde_json::from_str(&body).map_err(invalid)?);
+            }
+
+            (series, episodes)
+        }
+    };
+
+    let mut allowed = BTreeSet::new();
+    for (id, series) in &series {
+        if Show::parse(series)
+            .map_err(invalid)?
+            .allowed(&restrictions)
+            .await?
+        {
+            allowed.insert(*id);
+        }
+    }
+
+    let viewer = user.as_ref().map(|user| user.name.as_str());
+    let statuses = episodes
+        .into_iter()
+        .filter(|episode| allowed.contains(&episode.series_id))
+        .map(|episode| {
+            let play = episode
+                .episode_file
+                .as_ref()
+                .and_then(|file| playback::get(viewer, &config.local_path(Path::new(&file.path))));
+            let watch_state = WatchState::of(play, viewer);
+
+            EpisodeStatus {
+                id: episode.id,
+                has_file: episode.has_file,
+                monitored: episode.monitored,
+                watch_state,
+                position: play
+                    .filter(|_| watch_state == WatchState::InProgress)
+                    .map(|play| play.position),
+            }
+        })
+        .collect();
+
+    Ok(Json(statuses))
 }
 
 // async fn get_episode(
@@ -227,3 +1164,159 @@ async fn get_show(Path(id): Path<i32>, headers: HeaderMap) -> Result<Json<Show>,
 
 //     Ok(Json(episode))
 // }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::body::Body;
+    use axum::http::Request;
+    use serde_json::Value;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    const SERIES: &str = r#"{
+        "id": 1, "title": "Mocked", "images": [], "tags": [],
+        "episodeCount": 2, "episodeFileCount": 1
+    }"#;
+
+    fn episode(id: i32, aired: &str, file: Option<&str>) -> String {
+        format!(
+            r#"{{
+                "id": {id}, "seriesId": 1, "episodeFileId": {file_id},
+                "seasonNumber": 1, "episodeNumber": {id}, "title": "Episode {id}",
+                "airDate": null, "airDateUtc": {aired:?}, "overview": null,
+                "episodeFile": {file}, "hasFile": {has_file}, "monitored": true,
+                "absoluteEpisodeNumber": null, "sceneAbsoluteEpisodeNumber": null,
+                "sceneEpisodeNumber": null, "sceneSeasonNumber": null,
+                "unverifiedSceneNumbering": false, "lastSearchTime": null
+            }}"#,
+            file_id = if file.is_some() { 10 + id } else { 0 },
+            file = file.unwrap_or("null"),
+            has_file = file.is_some(),
+        )
+    }
+
+    const FILE: &str = r#"{
+        "id": 11, "seriesId": 1, "seasonNumber": 1,
+        "relativePath": "Season 1/Mocked - S01E01.mkv",
+        "path": "/tv/Mocked/Season 1/Mocked - S01E01.mkv", "size": 1000,
+        "dateAdded": "2020-01-01T00:00:00Z", "originalFilePath": "",
+        "qualityCutoffNotMet": false, "sceneName": null
+    }"#;
+
+    /// Asks for show 1 of `sonarr`, like a client would.
+    async fn get_show_of(sonarr: sonarr::Mock) -> (StatusCode, Value) {
+        config::init_for_tests();
+        let client: sonarr::Client = Arc::new(sonarr.with("/tag", "[]"));
+        let app = Router::new()
+            .route("/shows/:showId", get(get_show))
+            .layer(middleware::from_fn(sonarr::inject))
+            .layer(Extension(client));
+
+        let req = Request::get("/shows/1")
+            .header(header::HOST, "centarr.local:3000")
+            .body(Body::empty())
+            .unwrap();
+        let res = app.oneshot(req).await.unwrap();
+        let status = res.status();
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+
+        (status, serde_json::from_slice(&body).unwrap_or_default())
+    }
+
+    #[tokio::test]
+    async fn shows_get_watch_urls_and_airing() {
+        let episodes = format!(
+            "[{}, {}]",
+            episode(1, "2020-01-01T00:00:00Z", Some(FILE)),
+            episode(2, "2999-01-01T00:00:00Z", None)
+        );
+        let sonarr = sonarr::Mock::default()
+            .with("/series/1", SERIES)
+            .with("/episode?seriesId=1", &episodes);
+
+        let (status, show) = get_show_of(sonarr).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(show["title"], "Mocked");
+        assert_eq!(show["percentOfEpisodes"], 50.0);
+
+        let episodes = show["episodes"].as_array().unwrap();
+        assert_eq!(episodes[0]["isAired"], true);
+        assert_eq!(episodes[1]["isAired"], false);
+        assert!(episodes[1]["airsInMinutes"].as_i64().unwrap() > 0);
+        assert_eq!(
+            episodes[0]["episodeFile"]["watchUrl"],
+            "http://centarr.local:3001/stream/11"
+        );
+    }
+
+    #[tokio::test]
+    async fn episode_files_have_quality_languages_and_media_info() {
+        let file = FILE.replace(
+            r#""sceneName": null"#,
+            r#""sceneName": null,
+            "quality": {
+                "quality": { "id": 3, "name": "WEBDL-1080p", "source": "web", "resolution": 1080 },
+                "revision": { "version": 2, "real": 0, "isRepack": true }
+            },
+            "language": { "id": 1, "name": "English" },
+            "mediaInfo": {
+                "videoCodec": "x265", "videoBitDepth": 10, "videoDynamicRangeType": "HDR10",
+                "resolution": "1920x1080", "audioCodec": "EAC3", "audioChannels": 5.1,
+                "audioLanguages": "English/Japanese", "subtitles": "English"
+            }"#,
+        );
+        let episodes = format!("[{}]", episode(1, "2020-01-01T00:00:00Z", Some(&file)));
+        let sonarr = sonarr::Mock::default()
+            .with("/series/1", SERIES)
+            .with("/episode?seriesId=1", &episodes);
+
+        let (status, show) = get_show_of(sonarr).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let file = &show["episodes"][0]["episodeFile"];
+        assert_eq!(file["quality"]["quality"]["name"], "WEBDL-1080p");
+        assert_eq!(file["quality"]["revision"]["isRepack"], true);
+        assert_eq!(file["languages"][0]["name"], "English");
+        assert!(file.get("language").is_none());
+        assert_eq!(file["mediaInfo"]["videoCodec"], "x265");
+        assert_eq!(file["mediaInfo"]["audioChannels"], 5.1);
+    }
+
+    #[tokio::test]
+    async fn unreadable_episodes_are_left_out() {
+        let episodes = format!(
+            r#"[{}, {{"id": 2, "seriesId": 1, "seasonNumber": "one"}}]"#,
+            episode(1, "2020-01-01T00:00:00Z", Some(FILE))
+        );
+        let sonarr = sonarr::Mock::default()
+            .with("/series/1", SERIES)
+            .with("/episode?seriesId=1", &episodes);
+
+        let (status, show) = get_show_of(sonarr).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(show["episodes"].as_array().unwrap().len(), 1);
+        assert_eq!(sonarr::skipped()["episode"], 1);
+    }
+
+    #[tokio::test]
+    async fn sonarr_errors_are_passed_on() {
+        let sonarr = sonarr::Mock::default()
+            .failing("/series/1", ApiError::new(504, "Sonarr timed out".into()));
+
+        let (status, _) = get_show_of(sonarr).await;
+        assert_eq!(status, StatusCode::GATEWAY_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn unreadable_episodes_are_a_server_error() {
+        let sonarr = sonarr::Mock::default()
+            .with("/series/1", SERIES)
+            .with("/episode?seriesId=1", r#"{"message": "not a list"}"#);
+
+        let (status, _) = get_show_of(sonarr).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    }
+}
@@ -0,0 +1,239 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path as FilePath, PathBuf};
+use std::sync::{Mutex, RwLock};
+use std::time::SystemTime;
+
+use axum::{
+    extract::Path,
+    http::StatusCode,
+    routing::{get, put},
+    Json, Router,
+};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+
+use crate::{config, errors::ApiError, sonarr, store};
+
+const MARKERS_TABLE: &str = "markers";
+
+static MARKERS: Lazy<RwLock<BTreeMap<i32, Markers>>> = Lazy::new(Default::default);
+static UPDATING: Lazy<tokio::sync::Mutex<()>> = Lazy::new(Default::default);
+static CHAPTERS: Lazy<Mutex<HashMap<PathBuf, Probed>>> = Lazy::new(Default::default);
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/episodes/:id/media-info", get(media_info))
+        .route("/episodes/:id/markers", put(put_markers))
+}
+
+/// A stretch of an episode, in seconds from the start.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct Range {
+    pub start: f64,
+    pub end: f64,
+}
+
+/// Parts of an episode players offer to skip.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Markers {
+    pub intro: Option<Range>,
+    pub credits: Option<Range>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Chapter {
+    pub title: Option<String>,
+    pub start: f64,
+    pub end: f64,
+}
+
+/// The chapters of a file, as of when it was last modified.
+struct Probed {
+    modified: SystemTime,
+    chapters: Vec<Chapter>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct MediaInfo {
+    episode_id: i32,
+    chapters: Vec<Chapter>,
+    markers: Markers,
+}
+
+/// Loads the stored markers.
+pub async fn load() {
+    let markers = store::rows::<i32, Markers>(MARKERS_TABLE).await;
+    tracing::debug!("Loaded markers of {} episodes", markers.len());
+    *MARKERS.write().unwrap() = markers;
+}
+
+pub fn for_episode(episode_id: i32) -> Markers {
+    MARKERS
+        .read()
+        .unwrap()
+        .get(&episode_id)
+        .cloned()
+        .unwrap_or_default()
+}
+
+/// Stores the markers of an episode, forgetting them when there are none.
+pub async fn set(episode_id: i32, markers: Markers) {
+    let _updating = UPDATING.lock().await;
+
+    let changed = {
+        let mut all = MARKERS.write().unwrap();
+        if markers == Markers::default() {
+            all.remove(&episode_id);
+            store::delete(MARKERS_TABLE, &episode_id)
+        } else {
+            all.insert(episode_id, markers.clone());
+            store::put(MARKERS_TABLE, &episode_id, &markers)
+        }
+    };
+
+    if let Err(e) = store::apply(&[changed]).await {
+        tracing::warn!("Failed to save markers: {}", e);
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Episode {
+    id: i32,
+    episode_file: Option<EpisodeFile>,
+}
+
+#[derive(Deserialize)]
+struct EpisodeFile {
+    path: String,
+}
+
+/// Local path of the episode's file.
+pub async fn episode_path(episode_id: i32) -> Result<PathBuf, ApiError> {
+    let episode = match store::library() {
+        Some(library) => library
+            .episodes
+            .values()
+            .flatten()
+            .filter_map(|episode| Episode::deserialize(episode).ok())
+            .find(|episode| episode.id == episode_id),
+        None => {
+            let body = sonarr::get(&format!("/episode/{}", episode_id)).await?;
+            serde_json::from_str::<Episode>(&body).ok()
+        }
+    };
+
+    let file = episode
+        .ok_or_else(|| ApiError::new(404, format!("There's no episode {}", episode_id)))?
+        .episode_file
+        .ok_or_else(|| ApiError::new(404, format!("Episode {} has no file", episode_id)))?;
+
+    Ok(config::get().local_path(FilePath::new(&file.path)))
+}
+
+#[derive(Deserialize)]
+struct Probe {
+    #[serde(default)]
+    chapters: Vec<ProbeChapter>,
+}
+
+#[derive(Deserialize)]
+struct ProbeChapter {
+    start_time: String,
+    end_time: String,
+    #[serde(default)]
+    tags: HashMap<String, String>,
+}
+
+async fn probe_chapters(path: &FilePath) -> Result<Vec<Chapter>, String> {
+    let ffprobe = config::get().ffprobe_path.clone();
+    let output = Command::new(&ffprobe)
+        .args(["-v", "quiet", "-print_format", "json", "-show_chapters"])
+        .arg(path)
+        .output()
+        .await
+        .map_err(|e| format!("{:?} can't be run: {}", ffprobe, e))?;
+
+    if !output.status.success() {
+        return Err(format!("ffprobe exited with {}", output.status));
+    }
+
+    let probe = serde_json::from_slice::<Probe>(&output.stdout).map_err(|e| e.to_string())?;
+
+    Ok(probe
+        .chapters
+        .into_iter()
+        .map(|chapter| Chapter {
+            title: chapter.tags.get("title").cloned(),
+            start: chapter.start_time.parse().unwrap_or_default(),
+            end: chapter.end_time.parse().unwrap_or_default(),
+        })
+        .collect())
+}
+
+/// The chapters in the file at `path`, only probed again when it changed.
+pub async fn chapters(path: &FilePath) -> Result<Vec<Chapter>, String> {
+    let modified = tokio::fs::metadata(path)
+        .await
+        .and_then(|metadata| metadata.modified())
+        .map_err(|e| format!("{:?} can't be read: {}", path, e))?;
+
+    if let Some(probed) = CHAPTERS.lock().unwrap().get(path) {
+        if probed.modified == modified {
+            return Ok(probed.chapters.clone());
+        }
+    }
+
+    let chapters = probe_chapters(path).await?;
+    CHAPTERS.lock().unwrap().insert(
+        path.to_path_buf(),
+        Probed {
+            modified,
+            chapters: chapters.clone(),
+        },
+    );
+
+    Ok(chapters)
+}
+
+async fn media_info(Path(id): Path<i32>) -> Result<Json<MediaInfo>, ApiError> {
+    let path = episode_path(id).await?;
+
+    // the markers are still worth having when the file can't be probed
+    let chapters = chapters(&path).await.unwrap_or_else(|e| {
+        tracing::warn!("No chapters for episode {}: {}", id, e);
+        Vec::new()
+    });
+
+    Ok(Json(MediaInfo {
+        episode_id: id,
+        chapters,
+        markers: for_episode(id),
+    }))
+}
+
+/// Replaces the episode's markers, `null` ones are cleared.
+async fn put_markers(
+    Path(id): Path<i32>,
+    Json(markers): Json<Markers>,
+) -> Result<StatusCode, ApiError> {
+    for range in [markers.intro, markers.credits].into_iter().flatten() {
+        if !(range.start >= 0.0 && range.start < range.end) {
+            return Err(ApiError::new(
+                400,
+                format!(
+                    "{}-{} isn't a range, start should come before end",
+                    range.start, range.end
+                ),
+            ));
+        }
+    }
+
+    episode_path(id).await?;
+    set(id, markers).await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
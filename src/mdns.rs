@@ -0,0 +1,180 @@
+//! Advertises the API on the local network over multicast DNS as
+//! `_centarr._tcp` and `_http._tcp`, so players can find centarr without
+//! being told its address. mdns-sd answers the questions, announces the
+//! services on startup and says goodbye on shutdown.
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use mdns_sd::{ServiceDaemon, ServiceInfo};
+
+use crate::config;
+
+const SERVICES: [&str; 2] = ["_centarr._tcp.local.", "_http._tcp.local."];
+/// How long the goodbyes get to go out when centarr stops.
+const GOODBYE_WAIT: Duration = Duration::from_secs(1);
+
+/// The host's name without its domain, which mDNS replaces with `.local`.
+fn host_name() -> String {
+    let mut buf = [0; 256];
+    nix::unistd::gethostname(&mut buf)
+        .ok()
+        .and_then(|name| name.to_str().ok())
+        .and_then(|name| name.split('.').next())
+        .filter(|name| !name.is_empty())
+        .unwrap_or("centarr")
+        .to_string()
+}
+
+/// The services `instance` on `host` advertises, the API being on `api`,
+/// on every interface's addresses when it listens on all of them.
+fn services(api: SocketAddr, instance: &str, host: &str) -> Result<Vec<ServiceInfo>, String> {
+    let host = format!("{}.local.", host);
+    let properties = [("version", env!("CARGO_PKG_VERSION")), ("path", "/")];
+
+    SERVICES
+        .iter()
+        .map(|service| {
+            let info = match api.ip().is_unspecified() {
+                true => ServiceInfo::new(service, instance, &host, "", api.port(), &properties[..])
+                    .map(ServiceInfo::enable_addr_auto),
+                false => ServiceInfo::new(
+                    service,
+                    instance,
+                    &host,
+                    api.ip(),
+                    api.port(),
+                    &properties[..],
+                ),
+            };
+            info.map_err(|e| format!("{} can't be advertised: {}", service, e))
+        })
+        .collect()
+}
+
+/// Registers `services` with `daemon`, saying goodbye to them when dropped
+/// so browsers forget us when centarr stops.
+struct Advertised {
+    daemon: ServiceDaemon,
+    names: Vec<String>,
+}
+
+impl Advertised {
+    fn new(daemon: ServiceDaemon, services: Vec<ServiceInfo>) -> Result<Self, String> {
+        let mut advertised = Self {
+            daemon,
+            names: Vec::new(),
+        };
+        for info in services {
+            let name = info.get_fullname().to_string();
+            advertised
+                .daemon
+                .register(info)
+                .map_err(|e| format!("{} can't be advertised: {}", name, e))?;
+            advertised.names.push(name);
+        }
+
+        Ok(advertised)
+    }
+}
+
+impl Drop for Advertised {
+    fn drop(&mut self) {
+        for name in &self.names {
+            match self.daemon.unregister(name) {
+                Ok(done) => {
+                    let _ = done.recv_timeout(GOODBYE_WAIT);
+                }
+                Err(e) => tracing::debug!("Can't say goodbye to {} over mDNS: {}", name, e),
+            }
+        }
+        let _ = self.daemon.shutdown();
+    }
+}
+
+/// Advertises the API on `api`, one of the addresses it listens on, until
+/// centarr stops.
+pub async fn advertise(api: Option<SocketAddr>) {
+    let api = match api {
+        Some(api) if config::get().mdns => api,
+        _ => return std::future::pending().await,
+    };
+    let host = host_name();
+    let instance = config::get().server_name.clone().unwrap_or(host.clone());
+
+    let advertised = ServiceDaemon::new()
+        .map_err(|e| e.to_string())
+        .and_then(|daemon| Advertised::new(daemon, services(api, &instance, &host)?));
+    let _advertised = match advertised {
+        Ok(advertised) => advertised,
+        Err(e) => {
+            tracing::warn!("Can't advertise centarr over mDNS: {}", e);
+            return std::future::pending().await;
+        }
+    };
+    tracing::debug!("Advertising the API on port {} over mDNS", api.port());
+
+    std::future::pending().await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use mdns_sd::ServiceEvent;
+
+    use super::*;
+
+    /// The next event about `instance`, skipping the ones about others.
+    fn next_event(events: &mdns_sd::Receiver<ServiceEvent>, instance: &str) -> ServiceEvent {
+        loop {
+            let event = events.recv_timeout(Duration::from_secs(5)).unwrap();
+            let about = match &event {
+                ServiceEvent::ServiceResolved(resolved) => resolved.get_fullname(),
+                ServiceEvent::ServiceRemoved(_, name) => name,
+                _ => continue,
+            };
+            if about.starts_with(instance) {
+                return event;
+            }
+        }
+    }
+
+    #[test]
+    fn services_are_found_and_forgotten() {
+        let instance = format!("centarr-test-{}", std::process::id());
+        let api = SocketAddr::from((Ipv4Addr::LOCALHOST, 8080));
+        let services = services(api, &instance, "centarr-test").unwrap();
+        assert_eq!(
+            services[0].get_fullname(),
+            format!("{}._centarr._tcp.local.", instance)
+        );
+
+        let browser = ServiceDaemon::new().unwrap();
+        let events = browser.browse(SERVICES[0]).unwrap();
+        let advertised = Advertised::new(ServiceDaemon::new().unwrap(), services).unwrap();
+
+        match next_event(&events, &instance) {
+            ServiceEvent::ServiceResolved(resolved) => {
+                assert_eq!(resolved.get_hostname(), "centarr-test.local.");
+                assert_eq!(resolved.get_port(), 8080);
+                assert!(resolved
+                    .get_addresses()
+                    .iter()
+                    .any(|addr| addr.to_ip_addr() == IpAddr::from(Ipv4Addr::LOCALHOST)));
+                assert_eq!(
+                    resolved.get_property_val_str("version"),
+                    Some(env!("CARGO_PKG_VERSION"))
+                );
+            }
+            event => panic!("{:?}", event),
+        }
+
+        drop(advertised);
+        assert!(matches!(
+            next_event(&events, &instance),
+            ServiceEvent::ServiceRemoved(..)
+        ));
+        let _ = browser.shutdown();
+    }
+}
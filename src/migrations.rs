@@ -0,0 +1,260 @@
+//! Versions of the database's tables and the steps between them, sqlx
+//! migrations in `migrations/sqlite` and `migrations/postgres` each with
+//! what undoes it, so a newer centarr brings the tables an older one left
+//! up to date, and `centarr migrate` can take them back down before
+//! downgrading.
+
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+use sqlx::migrate::{Migrate, MigrateError, Migration, Migrator};
+
+use crate::store;
+
+pub static SQLITE: Migrator = sqlx::migrate!("./migrations/sqlite");
+pub static POSTGRES: Migrator = sqlx::migrate!("./migrations/postgres");
+
+/// Which version the tables are at, and the newest this centarr knows.
+#[derive(Serialize, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub struct Status {
+    pub version: i64,
+    pub latest: i64,
+}
+
+/// The version of the newest migration of `migrator`.
+fn newest(migrator: &Migrator) -> i64 {
+    migrator
+        .iter()
+        .map(|migration| migration.version)
+        .max()
+        .unwrap_or(0)
+}
+
+pub fn latest() -> i64 {
+    newest(store::migrator())
+}
+
+/// The versions applied to the tables so far, with their checksums.
+async fn applied(
+    connection: &mut (dyn Migrate + Send),
+) -> Result<BTreeMap<i64, Vec<u8>>, MigrateError> {
+    connection.ensure_migrations_table().await?;
+    if let Some(version) = connection.dirty_version().await? {
+        return Err(MigrateError::Dirty(version));
+    }
+
+    Ok(connection
+        .list_applied_migrations()
+        .await?
+        .into_iter()
+        .map(|applied| (applied.version, applied.checksum.into_owned()))
+        .collect())
+}
+
+pub async fn status() -> Result<Status, String> {
+    let mut connection = store::migration_connection()
+        .await
+        .map_err(|e| format!("Can't connect to the database: {}", e))?;
+    let applied = applied(&mut *connection)
+        .await
+        .map_err(|e| format!("Can't tell which migrations were applied: {}", e))?;
+
+    Ok(Status {
+        version: applied.keys().next_back().copied().unwrap_or(0),
+        latest: latest(),
+    })
+}
+
+/// A migration applied or undone on the way from one version to another.
+pub struct Step {
+    pub migration: &'static Migration,
+    pub undo: bool,
+}
+
+/// The steps to take the tables from the `applied` versions to `to`,
+/// undoing newer migrations in reverse when going back.
+fn plan(migrator: &'static Migrator, applied: &BTreeMap<i64, Vec<u8>>, to: i64) -> Vec<Step> {
+    let up = migrator
+        .iter()
+        .filter(|migration| migration.migration_type.is_up_migration())
+        .filter(|migration| migration.version <= to && !applied.contains_key(&migration.version))
+        .map(|migration| Step {
+            migration,
+            undo: false,
+        });
+    let down = migrator
+        .iter()
+        .rev()
+        .filter(|migration| migration.migration_type.is_down_migration())
+        .filter(|migration| migration.version > to && applied.contains_key(&migration.version))
+        .map(|migration| Step {
+            migration,
+            undo: true,
+        });
+
+    down.chain(up).collect()
+}
+
+/// Brings the tables to version `to`, one step at a time, calling
+/// `report` before each. With `dry_run` nothing is changed. Other
+/// centarrs sharing the database wait until it's done.
+pub async fn migrate(
+    to: i64,
+    dry_run: bool,
+    mut report: impl FnMut(&Step),
+) -> Result<Vec<Step>, String> {
+    let migrator = store::migrator();
+    if to > latest() {
+        return Err(format!(
+            "There's no version {}, the latest is {}",
+            to,
+            latest()
+        ));
+    }
+
+    let mut connection = store::migration_connection()
+        .await
+        .map_err(|e| format!("Can't connect to the database: {}", e))?;
+    connection
+        .lock()
+        .await
+        .map_err(|e| format!("Can't lock the database for migrating: {}", e))?;
+    let migrated = async {
+        let applied = applied(&mut *connection)
+            .await
+            .map_err(|e| format!("Can't tell which migrations were applied: {}", e))?;
+        let current = applied.keys().next_back().copied().unwrap_or(0);
+        if current > latest() {
+            return Err(format!(
+                "The tables are at version {}, newer than the {} this centarr knows, \
+                 run `centarr migrate --to {}` with the newer one first",
+                current,
+                latest(),
+                latest()
+            ));
+        }
+        for (version, checksum) in &applied {
+            let known = migrator
+                .iter()
+                .find(|migration| migration.version == *version);
+            if known.is_some_and(|migration| *migration.checksum != **checksum) {
+                return Err(format!(
+                    "Migration {} was changed after it was applied",
+                    version
+                ));
+            }
+        }
+
+        let steps = plan(migrator, &applied, to);
+        for step in &steps {
+            report(step);
+            if dry_run {
+                continue;
+            }
+
+            let migration = step.migration;
+            let done = match step.undo {
+                true => connection.revert(migration).await,
+                false => connection.apply(migration).await,
+            };
+            done.map_err(|e| {
+                format!(
+                    "Migration {} ({}) failed: {}",
+                    migration.version, migration.description, e
+                )
+            })?;
+        }
+
+        Ok(steps)
+    }
+    .await;
+    let _ = connection.unlock().await;
+
+    migrated
+}
+
+/// Migrates the tables to the latest version before anything reads them,
+/// refusing to touch ones a newer centarr left behind.
+pub async fn run() -> Result<(), String> {
+    migrate(latest(), false, |step| {
+        tracing::info!(
+            "Migrating the tables to {}: {}",
+            step.migration.version,
+            step.migration.description
+        )
+    })
+    .await
+    .map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rolling_back_undoes_migrations_newest_first() {
+        let versions = |steps: Vec<Step>| {
+            steps
+                .iter()
+                .map(|step| (step.migration.version, step.undo))
+                .collect::<Vec<_>>()
+        };
+        let none = BTreeMap::new();
+        let all = (1..=newest(&SQLITE))
+            .map(|version| (version, Vec::new()))
+            .collect::<BTreeMap<_, _>>();
+
+        assert_eq!(
+            versions(plan(&SQLITE, &none, newest(&SQLITE))),
+            all.keys()
+                .map(|version| (*version, false))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            versions(plan(&SQLITE, &all, 0)),
+            all.keys()
+                .rev()
+                .map(|version| (*version, true))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(versions(plan(&SQLITE, &all, 1)), [(2, true)]);
+        assert!(plan(&SQLITE, &all, newest(&SQLITE)).is_empty());
+    }
+
+    #[test]
+    fn both_databases_have_the_same_versions() {
+        let versions = |migrator: &Migrator| {
+            migrator
+                .iter()
+                .map(|migration| (migration.version, migration.description.to_string()))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(versions(&SQLITE), versions(&POSTGRES));
+    }
+
+    #[tokio::test]
+    async fn migrations_are_applied_and_undone() {
+        crate::config::init_for_tests();
+        let _opening = store::tests::OPENING.lock().await;
+        store::open().await.unwrap();
+        assert_eq!(status().await.unwrap().version, latest());
+
+        let dry_run = migrate(1, true, |_| {}).await.unwrap();
+        assert_eq!(dry_run.len(), 1);
+        assert_eq!(status().await.unwrap().version, latest());
+
+        migrate(1, false, |_| {}).await.unwrap();
+        assert_eq!(status().await.unwrap().version, 1);
+        assert!(store::try_rows::<u64, serde_json::Value>("jobs")
+            .await
+            .is_err());
+
+        run().await.unwrap();
+        assert_eq!(status().await.unwrap().version, latest());
+        assert!(store::try_rows::<u64, serde_json::Value>("jobs")
+            .await
+            .is_ok());
+    }
+}
@@ -0,0 +1,89 @@
+use serde_json::json;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::{
+    config::{self, Notifier},
+    events::{self, Event},
+};
+
+const PUSHOVER_URL: &str = "https://api.pushover.net/1/messages.json";
+
+async fn send(client: &reqwest::Client, notifier: &Notifier, event: &Event) -> Result<(), String> {
+    let (title, message) = event.message();
+
+    let request = match notifier {
+        Notifier::Discord { webhook_url } => client.post(webhook_url).json(&json!({
+            "content": format!("**{}**\n{}", title, message),
+        })),
+        Notifier::Pushover { token, user } => client.post(PUSHOVER_URL).form(&[
+            ("token", token.as_str()),
+            ("user", user),
+            ("title", &title),
+            ("message", &message),
+        ]),
+        Notifier::Ntfy { url, token } => {
+            let request = client
+                .post(url)
+                .header("Title", &title)
+                .header("Tags", event.kind())
+                .body(message);
+
+            match token {
+                Some(token) => request.bearer_auth(token),
+                None => request,
+            }
+        }
+        Notifier::Webhook { url } => client.post(url).json(&json!({
+            "event": event.kind(),
+            "title": title,
+            "message": message,
+            "payload": event,
+        })),
+    };
+
+    let res = request.send().await.map_err(|e| e.to_string())?;
+    if !res.status().is_success() {
+        return Err(format!("got {}", res.status()));
+    }
+
+    Ok(())
+}
+
+/// Sends every event to the notifiers configured for it.
+pub async fn run() {
+    let client = reqwest::Client::builder()
+        .timeout(config::get().upstream_timeout)
+        .build()
+        .expect("the HTTP client should build");
+    let mut events = events::subscribe();
+
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(missed)) => {
+                tracing::warn!("Dropped {} notifications, too many at once", missed);
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+
+        let config = config::get();
+        let wanted = config.notifications.iter().filter(|notification| {
+            notification.events.is_empty()
+                || notification.events.iter().any(|kind| kind == event.kind())
+        });
+
+        for notification in wanted {
+            let client = client.clone();
+            let notifier = notification.notifier.clone();
+            let event = event.clone();
+
+            // one slow service shouldn't hold up the others
+            tokio::spawn(async move {
+                if let Err(e) = send(&client, &notifier, &event).await {
+                    tracing::warn!("Failed to send {} notification: {}", event.kind(), e);
+                }
+            });
+        }
+    }
+}
@@ -0,0 +1,280 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use axum::{
+    extract::Query,
+    http::{header, HeaderMap},
+    response::Redirect,
+    routing::get,
+    Json, Router,
+};
+use once_cell::sync::Lazy;
+use ring::digest;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+use crate::{
+    auth::{self, Tokens},
+    config::{self, OidcConfig},
+    errors::ApiError,
+    upstream, users,
+};
+
+/// How long someone has to finish logging in at the provider.
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// A login started by `/auth/oidc/login` that hasn't come back yet.
+struct Pending {
+    started: Instant,
+    /// Proves to the token endpoint it's us trading the code (PKCE).
+    code_verifier: String,
+    /// Has to come back in the ID token, so one from another login can't
+    /// be passed off as this one's.
+    nonce: String,
+}
+
+/// Logins by the `state` they were handed out with.
+static PENDING: Lazy<Mutex<HashMap<String, Pending>>> = Lazy::new(Default::default);
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/auth/oidc/login", get(login))
+        .route("/auth/oidc/callback", get(callback))
+}
+
+fn oidc() -> Result<OidcConfig, ApiError> {
+    config::get()
+        .oidc
+        .clone()
+        .ok_or_else(|| ApiError::new(404, "OIDC is not configured".into()))
+}
+
+/// The parts of the provider's discovery document we use.
+#[derive(Deserialize)]
+struct Discovery {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+}
+
+async fn discover(oidc: &OidcConfig) -> Result<Discovery, ApiError> {
+    let url = format!("{}/.well-known/openid-configuration", oidc.issuer);
+
+    let res = upstream::client()
+        .get(&url)
+        .timeout(config::get().upstream_timeout)
+        .send()
+        .await
+        .and_then(|res| res.error_for_status())
+        .map_err(|e| ApiError::empty(502, Some(format!("OIDC discovery failed: {}", e))))?;
+
+    res.json()
+        .await
+        .map_err(|e| ApiError::empty(502, Some(format!("OIDC discovery failed: {}", e))))
+}
+
+fn redirect_url(oidc: &OidcConfig, headers: &HeaderMap) -> String {
+    if let Some(url) = &oidc.redirect_url {
+        return url.clone();
+    }
+
+    let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+    format!(
+        "{}://{}/auth/oidc/callback",
+        header("X-Forwarded-Proto").unwrap_or("http"),
+        header(header::HOST.as_str()).unwrap_or("localhost")
+    )
+}
+
+/// Sends the browser to the provider to log in.
+async fn login(headers: HeaderMap) -> Result<Redirect, ApiError> {
+    let oidc = oidc()?;
+    let discovery = discover(&oidc).await?;
+
+    let state = users::random_hex(16);
+    let login = Pending {
+        started: Instant::now(),
+        code_verifier: users::random_hex(32),
+        nonce: users::random_hex(16),
+    };
+    let code_challenge = base64::encode_config(
+        digest::digest(&digest::SHA256, login.code_verifier.as_bytes()),
+        base64::URL_SAFE_NO_PAD,
+    );
+
+    let url = format!(
+        "{}?response_type=code&scope=openid%20profile%20email&client_id={}&redirect_uri={}&state={}&nonce={}&code_challenge={}&code_challenge_method=S256",
+        discovery.authorization_endpoint,
+        urlencoding::encode(&oidc.client_id),
+        urlencoding::encode(&redirect_url(&oidc, &headers)),
+        state,
+        login.nonce,
+        code_challenge
+    );
+    {
+        let mut pending = PENDING.lock().unwrap();
+        pending.retain(|_, login| login.started.elapsed() < LOGIN_TIMEOUT);
+        pending.insert(state, login);
+    }
+
+    Ok(Redirect::to(&url))
+}
+
+#[derive(Deserialize)]
+struct CallbackQuery {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+/// The claims in an ID token, its signature isn't checked as it came
+/// straight from the provider's token endpoint over TLS.
+fn id_token_claims(id_token: &str) -> Option<Map<String, Value>> {
+    let payload = id_token.split('.').nth(1)?;
+    let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD).ok()?;
+
+    serde_json::from_slice(&payload).ok()
+}
+
+/// Who the ID token is for, when it's meant for us, for the login with
+/// `nonce` and still valid.
+fn username(
+    oidc: &OidcConfig,
+    discovery: &Discovery,
+    nonce: &str,
+    claims: &Map<String, Value>,
+) -> Option<String> {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    let audience = match claims.get("aud")? {
+        Value::String(aud) => aud == &oidc.client_id,
+        Value::Array(aud) => aud.iter().any(|aud| aud == oidc.client_id.as_str()),
+        _ => false,
+    };
+    let issuer = claims.get("iss")?.as_str()? == discovery.issuer;
+    let unexpired = claims.get("exp")?.as_u64()? > now;
+    let this_login = claims.get("nonce")?.as_str()? == nonce;
+
+    if !(audience && issuer && unexpired && this_login) {
+        return None;
+    }
+
+    claims
+        .get(&oidc.username_claim)?
+        .as_str()
+        .filter(|name| !name.is_empty())
+        .map(String::from)
+}
+
+/// Where the provider sends the browser back to, trades the code for an
+/// ID token and logs in as the user it names.
+async fn callback(
+    headers: HeaderMap,
+    Query(query): Query<CallbackQuery>,
+) -> Result<Json<Tokens>, ApiError> {
+    let oidc = oidc()?;
+
+    if let Some(error) = query.error {
+        return Err(ApiError::new(401, query.error_description.unwrap_or(error)));
+    }
+
+    let login = query
+        .state
+        .and_then(|state| PENDING.lock().unwrap().remove(&state))
+        .filter(|login| login.started.elapsed() < LOGIN_TIMEOUT);
+    let (login, code) = match (login, query.code) {
+        (Some(login), Some(code)) => (login, code),
+        _ => return Err(ApiError::new(400, "Unknown or expired login".into())),
+    };
+
+    let discovery = discover(&oidc).await?;
+    let redirect_url = redirect_url(&oidc, &headers);
+    let res = upstream::client()
+        .post(&discovery.token_endpoint)
+        .timeout(config::get().upstream_timeout)
+        .basic_auth(&oidc.client_id, Some(&oidc.client_secret))
+        .form(&[
+            ("grant_type", "authorization_code"),
+            ("code", &code),
+            ("redirect_uri", &redirect_url),
+            ("code_verifier", &login.code_verifier),
+        ])
+        .send()
+        .await
+        .map_err(|e| ApiError::empty(502, Some(format!("OIDC token exchange failed: {}", e))))?;
+
+    if !res.status().is_success() {
+        let status = res.status();
+        let body = res.text().await.unwrap_or_default();
+        tracing::warn!("OIDC token exchange got {}: {}", status, body);
+        return Err(ApiError::new(401, "The provider rejected the login".into()));
+    }
+
+    let tokens = res
+        .json::<TokenResponse>()
+        .await
+        .map_err(|e| ApiError::empty(502, Some(format!("OIDC token exchange failed: {}", e))))?;
+
+    let name = id_token_claims(&tokens.id_token)
+        .and_then(|claims| username(&oidc, &discovery, &login.nonce, &claims))
+        .ok_or_else(|| ApiError::new(401, "The ID token is invalid".into()))?;
+
+    users::ensure(&name).await;
+    let device = auth::register_device(&name, None, &headers).await;
+    tracing::info!("{} logged in through OIDC", name);
+
+    Ok(auth::tokens(&name, Some(device)).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn id_tokens_have_to_be_for_this_login() {
+        let oidc = OidcConfig {
+            issuer: "https://auth.example.com".into(),
+            client_id: "centarr".into(),
+            client_secret: String::new(),
+            redirect_url: None,
+            username_claim: "preferred_username".into(),
+        };
+        let discovery = Discovery {
+            issuer: oidc.issuer.clone(),
+            authorization_endpoint: String::new(),
+            token_endpoint: String::new(),
+        };
+        let claims = |nonce: Option<&str>| {
+            let mut claims = json!({
+                "iss": "https://auth.example.com",
+                "aud": ["centarr"],
+                "exp": u64::MAX,
+                "preferred_username": "bob",
+            });
+            if let Some(nonce) = nonce {
+                claims["nonce"] = nonce.into();
+            }
+            claims.as_object().unwrap().clone()
+        };
+
+        let bob = username(&oidc, &discovery, "abc", &claims(Some("abc")));
+        assert_eq!(bob.as_deref(), Some("bob"));
+        assert_eq!(
+            username(&oidc, &discovery, "abc", &claims(Some("xyz"))),
+            None
+        );
+        assert_eq!(username(&oidc, &discovery, "abc", &claims(None)), None);
+    }
+}
@@ -0,0 +1,621 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use axum::{extract::Json, http::StatusCode, routing::post, Router};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auth::AuthUser,
+    config, dates,
+    errors::ApiError,
+    events::{self, Event},
+    extras, markers,
+    restrictions::Restrictions,
+    store,
+    trakt::{self, Action},
+    users,
+};
+
+/// Streams of the same file this close together count as one play.
+const REPLAY_AFTER: Duration = Duration::from_secs(10 * 60);
+/// How many files are remembered, the least recently played are forgotten first.
+const MAX_PLAYS: usize = 100_000;
+/// How often plays are saved, when there are new ones.
+const SAVE_INTERVAL: Duration = Duration::from_secs(30);
+const PLAYS_TABLE: &str = "plays";
+
+static PLAYS: Lazy<Mutex<HashMap<PlayKey, Play>>> = Lazy::new(Default::default);
+/// Plays that changed since they were last saved.
+static CHANGED: Lazy<Mutex<HashSet<PlayKey>>> = Lazy::new(Default::default);
+static SAVING: Lazy<tokio::sync::Mutex<()>> = Lazy::new(Default::default);
+/// Players reporting where they're at, by user, device and episode.
+static SESSIONS: Lazy<Mutex<HashMap<SessionKey, Session>>> = Lazy::new(Default::default);
+
+/// Sessions a player stopped reporting on for this long are forgotten.
+const SESSION_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+type SessionKey = (String, Option<String>, i32);
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/playback/start", post(start))
+        .route("/playback/progress", post(progress))
+        .route("/playback/stop", post(stop))
+}
+
+/// Whose play of which file it is, `None` being whoever streams without
+/// logging in.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+struct PlayKey {
+    user: Option<String>,
+    path: PathBuf,
+}
+
+impl PlayKey {
+    fn new(user: Option<&str>, path: &Path) -> Self {
+        Self {
+            user: user.map(String::from),
+            path: path.to_path_buf(),
+        }
+    }
+}
+
+/// Saved as the path for anonymous plays, like before plays had users, and
+/// as `["user","path"]` for a user's.
+impl store::Key for PlayKey {
+    fn to_key(&self) -> String {
+        match &self.user {
+            Some(user) => serde_json::to_string(&(user, &self.path)).unwrap_or_default(),
+            None => self.path.to_key(),
+        }
+    }
+
+    fn from_key(key: &str) -> Option<Self> {
+        match serde_json::from_str::<(String, PathBuf)>(key) {
+            Ok((user, path)) => Some(Self {
+                user: Some(user),
+                path,
+            }),
+            Err(_) => Some(Self {
+                user: None,
+                path: PathBuf::from_key(key)?,
+            }),
+        }
+    }
+}
+
+/// How far into a file the last stream of it got, by bytes sent.
+#[derive(Clone, Copy, Debug)]
+pub struct Play {
+    pub position: u64,
+    pub size: u64,
+    pub last_played: SystemTime,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WatchState {
+    Unwatched,
+    InProgress,
+    Watched,
+}
+
+/// A [`Play`] as it's saved.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Saved {
+    position: u64,
+    size: u64,
+    /// Unix timestamp.
+    last_played: u64,
+}
+
+impl WatchState {
+    /// Of `user`'s `play`, past their threshold counting as watched.
+    pub fn of(play: Option<Play>, user: Option<&str>) -> Self {
+        Self::at(play, threshold(user))
+    }
+
+    fn at(play: Option<Play>, threshold: u64) -> Self {
+        match play {
+            Some(play) if is_watched_at(play.position, play.size, threshold) => Self::Watched,
+            Some(play) if play.position > 0 => Self::InProgress,
+            _ => Self::Unwatched,
+        }
+    }
+}
+
+impl Play {
+    /// Whether it was started but not finished, going by `user`'s
+    /// threshold.
+    pub fn in_progress(&self, user: Option<&str>) -> bool {
+        WatchState::of(Some(*self), user) == WatchState::InProgress
+    }
+}
+
+/// Whether `user` having streamed up to `position` of a file counts as
+/// having watched it.
+pub fn is_watched(user: Option<&str>, position: u64, size: u64) -> bool {
+    is_watched_at(position, size, threshold(user))
+}
+
+fn is_watched_at(position: u64, size: u64, threshold: u64) -> bool {
+    position as f64 >= size as f64 * threshold as f64 / 100.0
+}
+
+/// Percent of an episode past which it counts as watched for `user`.
+pub fn threshold(user: Option<&str>) -> u64 {
+    user.and_then(|user| users::preferences(user).watched_threshold)
+        .unwrap_or_else(|| config::get().watched_threshold)
+}
+
+/// Whether `user` streaming `path` from the start now is a new play,
+/// rather than a player probing or reconnecting to one that's going on.
+pub fn is_new_play(user: Option<&str>, path: &Path) -> bool {
+    !is_reported(user, path)
+        && PLAYS
+            .lock()
+            .unwrap()
+            .get(&PlayKey::new(user, path))
+            .and_then(|play| play.last_played.elapsed().ok())
+            .filter(|elapsed| *elapsed < REPLAY_AFTER)
+            .is_none()
+}
+
+/// Whether a player of `user`'s is reporting where it's at in `path`,
+/// which is closer to the truth than how much of it was streamed.
+pub fn is_reported(user: Option<&str>, path: &Path) -> bool {
+    SESSIONS
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|((of, _, _), session)| {
+            Some(of.as_str()) == user
+                && session.path == path
+                && session.reported.elapsed() < SESSION_TIMEOUT
+        })
+}
+
+/// Remembers that `user` streamed `path` up to `position`, unless a player
+/// is reporting on it.
+pub fn record(user: Option<&str>, path: &Path, position: u64, size: u64) {
+    if is_reported(user, path) {
+        return;
+    }
+
+    insert(
+        &mut PLAYS.lock().unwrap(),
+        PlayKey::new(user, path),
+        Play {
+            position,
+            size,
+            last_played: SystemTime::now(),
+        },
+    );
+}
+
+/// Remembers `user`'s `play` of `path` from elsewhere, like an imported
+/// watch history, unless they streamed it since.
+pub fn restore(user: Option<&str>, path: &Path, play: Play) {
+    let key = PlayKey::new(user, path);
+    let mut plays = PLAYS.lock().unwrap();
+    if plays
+        .get(&key)
+        .is_some_and(|known| known.last_played >= play.last_played)
+    {
+        return;
+    }
+
+    insert(&mut plays, key, play);
+}
+
+fn insert(plays: &mut HashMap<PlayKey, Play>, key: PlayKey, play: Play) {
+    let mut changed = CHANGED.lock().unwrap();
+    changed.insert(key.clone());
+    plays.insert(key, play);
+
+    if plays.len() > MAX_PLAYS {
+        if let Some(oldest) = plays
+            .iter()
+            .min_by_key(|(_, play)| play.last_played)
+            .map(|(key, _)| key.clone())
+        {
+            plays.remove(&oldest);
+            changed.insert(oldest);
+        }
+    }
+}
+
+/// How far `user`'s last stream of `path` got, if it's remembered.
+pub fn get(user: Option<&str>, path: &Path) -> Option<Play> {
+    PLAYS
+        .lock()
+        .unwrap()
+        .get(&PlayKey::new(user, path))
+        .copied()
+}
+
+/// Files `user` started but didn't finish, most recently played first.
+pub fn in_progress(user: Option<&str>) -> Vec<(PathBuf, Play)> {
+    let mut plays = PLAYS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(key, play)| key.user.as_deref() == user && play.in_progress(user))
+        .map(|(key, play)| (key.path.clone(), *play))
+        .collect::<Vec<_>>();
+
+    plays.sort_by_key(|(_, play)| std::cmp::Reverse(play.last_played));
+    plays
+}
+
+/// Loads the saved plays, keeping the ones that changed since they were
+/// last saved.
+pub async fn load() {
+    let _saving = SAVING.lock().await;
+    let saved = store::rows::<PlayKey, Saved>(PLAYS_TABLE).await;
+    tracing::debug!("Loaded {} plays", saved.len());
+    let mut loaded = saved
+        .into_iter()
+        .map(|(key, saved)| {
+            let play = Play {
+                position: saved.position,
+                size: saved.size,
+                last_played: UNIX_EPOCH + Duration::from_secs(saved.last_played),
+            };
+            (key, play)
+        })
+        .collect::<HashMap<_, _>>();
+
+    let mut plays = PLAYS.lock().unwrap();
+    for key in CHANGED.lock().unwrap().iter() {
+        match plays.get(key) {
+            Some(play) => loaded.insert(key.clone(), *play),
+            None => loaded.remove(key),
+        };
+    }
+    *plays = loaded;
+}
+
+/// Saves the plays that changed since last time.
+pub async fn save() {
+    let _saving = SAVING.lock().await;
+    let changed = std::mem::take(&mut *CHANGED.lock().unwrap());
+    if changed.is_empty() {
+        return;
+    }
+
+    let changes = {
+        let plays = PLAYS.lock().unwrap();
+        changed
+            .iter()
+            .map(|key| match plays.get(key) {
+                Some(play) => {
+                    let last_played = play
+                        .last_played
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs();
+                    let saved = Saved {
+                        position: play.position,
+                        size: play.size,
+                        last_played,
+                    };
+                    store::put(PLAYS_TABLE, key, &saved)
+                }
+                None => store::delete(PLAYS_TABLE, key),
+            })
+            .collect::<Vec<_>>()
+    };
+
+    if let Err(e) = store::apply(&changes).await {
+        CHANGED.lock().unwrap().extend(changed);
+        tracing::warn!("Failed to save plays: {}", e);
+    }
+}
+
+/// What a player reports about playing an episode.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Report {
+    episode_id: i32,
+    /// Seconds into the episode.
+    position: f64,
+    /// Of the episode in seconds, read from the file when left out.
+    duration: Option<f64>,
+    #[serde(default)]
+    paused: bool,
+}
+
+struct Session {
+    path: PathBuf,
+    size: u64,
+    duration: Option<f64>,
+    position: f64,
+    paused: bool,
+    /// Whether it went past the watched threshold already.
+    finished: bool,
+    started: SystemTime,
+    reported: Instant,
+}
+
+/// A session as `GET /admin/playback-sessions` lists it.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionStatus {
+    user: String,
+    device: Option<String>,
+    episode_id: i32,
+    path: PathBuf,
+    /// In seconds.
+    position: f64,
+    duration: Option<f64>,
+    paused: bool,
+    started_at: String,
+    /// Seconds since the player last reported.
+    last_report: u64,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Reported {
+    Start,
+    Progress,
+    Stop,
+}
+
+async fn start(
+    user: AuthUser,
+    restrictions: Restrictions,
+    Json(report): Json<Report>,
+) -> Result<StatusCode, ApiError> {
+    session(Reported::Start, user, restrictions, report).await
+}
+
+async fn progress(
+    user: AuthUser,
+    restrictions: Restrictions,
+    Json(report): Json<Report>,
+) -> Result<StatusCode, ApiError> {
+    session(Reported::Progress, user, restrictions, report).await
+}
+
+async fn stop(
+    user: AuthUser,
+    restrictions: Restrictions,
+    Json(report): Json<Report>,
+) -> Result<StatusCode, ApiError> {
+    session(Reported::Stop, user, restrictions, report).await
+}
+
+/// Keeps the session of a player up to date with what it reported, and
+/// the episode's play with where it's at, starting a session when there's
+/// none yet so players that skip reporting the start are tracked too.
+/// What's playing is scrobbled to their Trakt account as it starts, pauses
+/// and stops.
+async fn session(
+    reported: Reported,
+    user: AuthUser,
+    restrictions: Restrictions,
+    report: Report,
+) -> Result<StatusCode, ApiError> {
+    if !report.position.is_finite() || report.position < 0.0 {
+        return Err(ApiError::new(422, "position should be 0 or more".into()));
+    }
+    let key = (user.name, user.device, report.episode_id);
+
+    let known = SESSIONS
+        .lock()
+        .unwrap()
+        .get(&key)
+        .map(|session| (session.path.clone(), session.size, session.duration));
+    let (path, size, duration) = match known {
+        Some(known) if reported != Reported::Start => known,
+        _ => {
+            let config = config::get();
+            let path = markers::episode_path(report.episode_id).await?;
+            if !restrictions.allows_file(&config, &path).await {
+                return Err(ApiError::new(
+                    404,
+                    format!("There's no episode {}", report.episode_id),
+                ));
+            }
+            let size = tokio::fs::metadata(&path)
+                .await
+                .map_err(|_| {
+                    ApiError::new(
+                        404,
+                        format!("Episode {}'s file is missing", report.episode_id),
+                    )
+                })?
+                .len();
+            let duration = match report.duration {
+                Some(duration) => Some(duration),
+                None => extras::duration(&path).await,
+            };
+            (path, size, duration)
+        }
+    };
+    let duration = report
+        .duration
+        .or(duration)
+        .filter(|duration| *duration > 0.0);
+
+    let device = key
+        .1
+        .as_deref()
+        .and_then(|id| users::device(&key.0, id).map(|device| device.name));
+    let client = key.0.clone();
+    if reported == Reported::Start && is_new_play(Some(&key.0), &path) {
+        events::publish(Event::PlaybackStarted {
+            path: path.to_string_lossy().into(),
+            client: client.clone(),
+            device: device.clone(),
+        });
+    }
+
+    let threshold = threshold(Some(&key.0));
+    let position =
+        duration.map(|duration| (size as f64 * (report.position / duration).min(1.0)) as u64);
+    let (finished, was_paused) = {
+        let mut sessions = SESSIONS.lock().unwrap();
+        sessions.retain(|_, session| session.reported.elapsed() < SESSION_TIMEOUT);
+        let known = sessions.contains_key(&key);
+        let session = sessions.entry(key.clone()).or_insert_with(|| Session {
+            path: path.clone(),
+            size,
+            duration,
+            position: 0.0,
+            paused: false,
+            finished: false,
+            started: SystemTime::now(),
+            reported: Instant::now(),
+        });
+        let was_paused = (known && reported != Reported::Start).then_some(session.paused);
+        if reported == Reported::Start {
+            session.started = SystemTime::now();
+            session.finished = false;
+        }
+        session.duration = duration;
+        session.position = report.position;
+        session.paused = report.paused;
+        session.reported = Instant::now();
+
+        // crossing the threshold marks it watched, once per session
+        let finished = !session.finished
+            && position.is_some_and(|position| is_watched_at(position, size, threshold));
+        session.finished |= finished;
+        if reported == Reported::Stop {
+            sessions.remove(&key);
+        }
+        (finished, was_paused)
+    };
+
+    if let Some(position) = position {
+        insert(
+            &mut PLAYS.lock().unwrap(),
+            PlayKey::new(Some(&key.0), &path),
+            Play {
+                position,
+                size,
+                last_played: SystemTime::now(),
+            },
+        );
+    }
+    if finished {
+        events::publish(Event::PlaybackFinished {
+            path: path.to_string_lossy().into(),
+            client,
+            device,
+        });
+    }
+
+    // Trakt hears about it starting, pausing, resuming and stopping
+    let action = match (reported, report.paused, was_paused) {
+        (Reported::Stop, _, _) => Some(Action::Stop),
+        (_, paused, Some(was_paused)) if paused == was_paused => None,
+        (_, true, _) => Some(Action::Pause),
+        (_, false, _) => Some(Action::Start),
+    };
+    if let (Some(action), Some(duration)) = (action, duration) {
+        let progress = report.position / duration * 100.0;
+        trakt::scrobble(&key.0, action, report.episode_id, progress);
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// The sessions players are reporting on, most recently started first.
+pub fn sessions() -> Vec<SessionStatus> {
+    let mut sessions = SESSIONS.lock().unwrap();
+    sessions.retain(|_, session| session.reported.elapsed() < SESSION_TIMEOUT);
+
+    let mut listed = sessions
+        .iter()
+        .map(|((user, device, episode_id), session)| {
+            let started = session
+                .started
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            SessionStatus {
+                device: device
+                    .as_deref()
+                    .and_then(|id| users::device(user, id))
+                    .map(|device| device.name),
+                user: user.clone(),
+                episode_id: *episode_id,
+                path: session.path.clone(),
+                position: session.position,
+                duration: session.duration,
+                paused: session.paused,
+                started_at: dates::iso8601(started as i64),
+                last_report: session.reported.elapsed().as_secs(),
+            }
+        })
+        .collect::<Vec<_>>();
+    listed.sort_by(|a, b| b.started_at.cmp(&a.started_at));
+
+    listed
+}
+
+/// Saves new plays every [`SAVE_INTERVAL`].
+pub async fn run() {
+    loop {
+        tokio::time::sleep(SAVE_INTERVAL).await;
+        save().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use store::Key;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn plays_are_kept_per_user_with_their_threshold() {
+        config::init_for_tests();
+        users::set_password("playback-test", "secret").await;
+        let preferences = users::Preferences {
+            watched_threshold: Some(50),
+            ..Default::default()
+        };
+        users::set_preferences("playback-test", preferences).await;
+        let path = PathBuf::from(format!("/tv/playback-test-{}.mkv", std::process::id()));
+
+        record(Some("playback-test"), &path, 60, 100);
+        let play = get(Some("playback-test"), &path);
+        assert_eq!(play.map(|play| play.position), Some(60));
+        assert_eq!(
+            WatchState::of(play, Some("playback-test")),
+            WatchState::Watched
+        );
+        assert_eq!(WatchState::of(play, None), WatchState::InProgress);
+        assert!(get(None, &path).is_none());
+        assert!(get(Some("someone-else"), &path).is_none());
+        assert!(in_progress(Some("playback-test")).is_empty());
+
+        record(None, &path, 30, 100);
+        assert_eq!(
+            in_progress(None).iter().filter(|(p, _)| *p == path).count(),
+            1
+        );
+        assert_eq!(get(Some("playback-test"), &path).unwrap().position, 60);
+        users::remove("playback-test").await;
+    }
+
+    #[test]
+    fn keys_of_anonymous_plays_are_the_paths() {
+        let anonymous = PlayKey::new(None, Path::new("/tv/Show/S01E01.mkv"));
+        assert_eq!(anonymous.to_key(), "/tv/Show/S01E01.mkv");
+        let users = PlayKey::new(Some("amy"), Path::new("/tv/Show/S01E01.mkv"));
+        assert_eq!(users.to_key(), r#"["amy","/tv/Show/S01E01.mkv"]"#);
+
+        for key in [anonymous, users] {
+            assert_eq!(PlayKey::from_key(&key.to_key()), Some(key));
+        }
+    }
+}
@@ -0,0 +1,351 @@
+//! Asks the router to forward the API and streaming ports, over NAT-PMP
+//! (RFC 6886) or else UPnP, so centarr can be reached from outside the
+//! network without setting up the router by hand.
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket as StdUdpSocket};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use igd_next::aio::{tokio::Tokio, Gateway};
+use igd_next::{GatewayIpVersion, PortMappingProtocol, SearchOptions};
+use natpmp::{Protocol, Response};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+use crate::{config, dates};
+
+/// Waited for the first NAT-PMP answer, doubling with each try.
+const NAT_PMP_WAIT: Duration = Duration::from_millis(250);
+const NAT_PMP_TRIES: u32 = 4;
+/// How long routers get to answer the UPnP search.
+const SSDP_WAIT: Duration = Duration::from_secs(3);
+/// How long the router is asked to keep the ports forwarded, they're
+/// asked for again halfway through.
+const LEASE: Duration = Duration::from_secs(60 * 60);
+/// Waited before trying again when the router couldn't be asked.
+const RETRY: Duration = Duration::from_secs(5 * 60);
+const DESCRIPTION: &str = "centarr";
+
+/// Whether the ports are forwarded, and where centarr can be reached from
+/// outside when they are.
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Status {
+    enabled: bool,
+    /// `nat-pmp` or `upnp`.
+    method: Option<&'static str>,
+    external_ip: Option<Ipv4Addr>,
+    external_url: Option<String>,
+    /// The router's ports, which it may pick differently than asked.
+    api_port: Option<u16>,
+    stream_port: Option<u16>,
+    forwarded_at: Option<String>,
+    error: Option<String>,
+}
+
+static STATUS: Lazy<Mutex<Status>> = Lazy::new(Default::default);
+
+pub fn status() -> Status {
+    let mut status = STATUS.lock().unwrap().clone();
+    status.enabled = config::get().port_forwarding;
+    status
+}
+
+/// How the router was reached the last time.
+#[derive(Clone)]
+enum Router {
+    NatPmp(Ipv4Addr),
+    Upnp(Gateway<Tokio>),
+}
+
+/// The address this host has on the network `gateway` is on.
+fn local_ip(gateway: SocketAddr) -> Result<Ipv4Addr, String> {
+    let socket = StdUdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).map_err(|e| e.to_string())?;
+    socket
+        .connect(gateway)
+        .and_then(|_| socket.local_addr())
+        .map_err(|e| format!("can't tell our address towards {}: {}", gateway, e))
+        .and_then(|addr| match addr.ip() {
+            IpAddr::V4(ip) => Ok(ip),
+            ip => Err(format!("{} is not an IPv4 address", ip)),
+        })
+}
+
+/// What a NAT-PMP gateway is asked.
+#[derive(Clone, Copy)]
+enum Request {
+    ExternalIp,
+    /// Forwarding a TCP port to us.
+    Map(u16),
+}
+
+/// Asks `gateway` over NAT-PMP, asking again with longer waits while it
+/// doesn't answer.
+async fn nat_pmp(gateway: Ipv4Addr, request: Request) -> Result<Response, String> {
+    let mut client = natpmp::new_tokio_natpmp_with(gateway)
+        .await
+        .map_err(|e| format!("can't reach {}: {}", gateway, e))?;
+
+    let mut wait = NAT_PMP_WAIT;
+    for _ in 0..NAT_PMP_TRIES {
+        let sent = match request {
+            Request::ExternalIp => client.send_public_address_request().await,
+            Request::Map(port) => {
+                client
+                    .send_port_mapping_request(Protocol::TCP, port, port, LEASE.as_secs() as u32)
+                    .await
+            }
+        };
+        sent.map_err(|e| format!("can't reach {}: {}", gateway, e))?;
+
+        if let Ok(answer) = tokio::time::timeout(wait, client.read_response_or_retry()).await {
+            return answer.map_err(|e| format!("{} refused over NAT-PMP: {}", gateway, e));
+        }
+        wait *= 2;
+    }
+
+    Err(format!("{} didn't answer over NAT-PMP", gateway))
+}
+
+/// Forwards TCP port `port` to us, returning the port the router picked.
+async fn nat_pmp_map(gateway: Ipv4Addr, port: u16) -> Result<u16, String> {
+    match nat_pmp(gateway, Request::Map(port)).await? {
+        Response::TCP(mapping) => Ok(mapping.public_port()),
+        answer => Err(format!("{} answered {:?}", gateway, answer)),
+    }
+}
+
+async fn nat_pmp_external_ip(gateway: Ipv4Addr) -> Result<Ipv4Addr, String> {
+    match nat_pmp(gateway, Request::ExternalIp).await? {
+        Response::Gateway(answer) => Ok(*answer.public_address()),
+        answer => Err(format!("{} answered {:?}", gateway, answer)),
+    }
+}
+
+/// Looks for a router with UPnP on an IPv4 address.
+async fn upnp_discover() -> Result<Router, String> {
+    let mut options = SearchOptions::default();
+    options.timeout = Some(SSDP_WAIT);
+    options.gateway_ip_version = GatewayIpVersion::V4;
+
+    let gateway = igd_next::aio::tokio::search_gateway(options)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    Ok(Router::Upnp(gateway))
+}
+
+async fn upnp_map(gateway: &Gateway<Tokio>, local_ip: Ipv4Addr, port: u16) -> Result<u16, String> {
+    gateway
+        .add_port(
+            PortMappingProtocol::TCP,
+            port,
+            SocketAddr::new(local_ip.into(), port),
+            LEASE.as_secs() as u32,
+            DESCRIPTION,
+        )
+        .await
+        .map_err(|e| format!("forwarding port {} failed: {}", port, e))?;
+
+    Ok(port)
+}
+
+struct Forwarded {
+    method: &'static str,
+    external_ip: Ipv4Addr,
+    api_port: u16,
+    stream_port: u16,
+}
+
+/// Forwards both ports through `router`.
+async fn forward_through(router: &Router, api: u16, stream: u16) -> Result<Forwarded, String> {
+    match router {
+        Router::NatPmp(gateway) => Ok(Forwarded {
+            method: "nat-pmp",
+            external_ip: nat_pmp_external_ip(*gateway).await?,
+            api_port: nat_pmp_map(*gateway, api).await?,
+            stream_port: nat_pmp_map(*gateway, stream).await?,
+        }),
+        Router::Upnp(gateway) => {
+            let local_ip = local_ip(gateway.addr)?;
+            let external_ip = match gateway.get_external_ip().await {
+                Ok(IpAddr::V4(ip)) => ip,
+                Ok(ip) => return Err(format!("the router's external address {} isn't IPv4", ip)),
+                Err(e) => {
+                    return Err(format!(
+                        "the router didn't say what its external address is: {}",
+                        e
+                    ))
+                }
+            };
+
+            Ok(Forwarded {
+                method: "upnp",
+                external_ip,
+                api_port: upnp_map(gateway, local_ip, api).await?,
+                stream_port: upnp_map(gateway, local_ip, stream).await?,
+            })
+        }
+    }
+}
+
+/// Forwards both ports through the router that worked before, or else
+/// the first that does, NAT-PMP being tried before UPnP.
+async fn forward(known: &mut Option<Router>, api: u16, stream: u16) -> Result<Forwarded, String> {
+    if let Some(router) = known.as_ref() {
+        match forward_through(router, api, stream).await {
+            Ok(forwarded) => return Ok(forwarded),
+            Err(e) => tracing::debug!("The router that forwarded the ports before failed: {}", e),
+        }
+    }
+    *known = None;
+
+    let gateway = natpmp::get_default_gateway().map_err(|_| "there's no default gateway")?;
+    let nat_pmp = Router::NatPmp(gateway);
+    let nat_pmp_error = match forward_through(&nat_pmp, api, stream).await {
+        Ok(forwarded) => {
+            *known = Some(nat_pmp);
+            return Ok(forwarded);
+        }
+        Err(e) => e,
+    };
+
+    let upnp = match upnp_discover().await {
+        Ok(upnp) => upnp,
+        Err(e) => return Err(format!("NAT-PMP: {}, UPnP: {}", nat_pmp_error, e)),
+    };
+    let forwarded = forward_through(&upnp, api, stream)
+        .await
+        .map_err(|e| format!("NAT-PMP: {}, UPnP: {}", nat_pmp_error, e))?;
+    *known = Some(upnp);
+
+    Ok(forwarded)
+}
+
+/// Asks a NAT-PMP router to stop forwarding when centarr stops, UPnP ones
+/// let the lease run out.
+struct Unforward {
+    gateway: Ipv4Addr,
+    ports: [u16; 2],
+}
+
+impl Drop for Unforward {
+    fn drop(&mut self) {
+        let mut client = match natpmp::Natpmp::new_with(self.gateway) {
+            Ok(client) => client,
+            Err(_) => return,
+        };
+        for port in self.ports {
+            // a lifetime of 0 removes the mapping
+            let _ = client.send_port_mapping_request(Protocol::TCP, port, 0, 0);
+        }
+    }
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
+
+/// Keeps the API and streaming ports forwarded while port forwarding is
+/// on, renewing the lease before it runs out.
+pub async fn run(api: Option<u16>, stream: Option<u16>) {
+    let (api, stream) = match (api, stream) {
+        (Some(api), Some(stream)) if config::get().port_forwarding => (api, stream),
+        _ => return std::future::pending().await,
+    };
+
+    let mut router = None;
+    let mut _unforward = None;
+    loop {
+        let wait = match forward(&mut router, api, stream).await {
+            Ok(forwarded) => {
+                let url = format!("http://{}:{}", forwarded.external_ip, forwarded.api_port);
+                if (forwarded.api_port, forwarded.stream_port) != (api, stream) {
+                    tracing::warn!(
+                        "The router forwards ports {} and {} instead of {} and {}, stream urls won't work from outside",
+                        forwarded.api_port,
+                        forwarded.stream_port,
+                        api,
+                        stream
+                    );
+                }
+                tracing::info!(
+                    "Forwarded the ports with {}, reachable at {}",
+                    forwarded.method,
+                    url
+                );
+
+                _unforward = match &router {
+                    Some(Router::NatPmp(gateway)) => Some(Unforward {
+                        gateway: *gateway,
+                        ports: [api, stream],
+                    }),
+                    _ => None,
+                };
+                *STATUS.lock().unwrap() = Status {
+                    enabled: true,
+                    method: Some(forwarded.method),
+                    external_ip: Some(forwarded.external_ip),
+                    external_url: Some(url),
+                    api_port: Some(forwarded.api_port),
+                    stream_port: Some(forwarded.stream_port),
+                    forwarded_at: Some(dates::iso8601(now())),
+                    error: None,
+                };
+                LEASE / 2
+            }
+            Err(e) => {
+                tracing::warn!("Can't forward the ports: {}", e);
+                let mut status = STATUS.lock().unwrap();
+                status.error = Some(e);
+                RETRY
+            }
+        };
+
+        tokio::time::sleep(wait).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::UdpSocket;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn ports_are_forwarded_over_nat_pmp() {
+        let gateway = Ipv4Addr::new(127, 0, 0, 2);
+        let router = UdpSocket::bind((gateway, 5351)).await.unwrap();
+        tokio::spawn(async move {
+            let mut request = [0; 12];
+            // the first request gets lost
+            router.recv_from(&mut request).await.unwrap();
+            loop {
+                let (_, from) = router.recv_from(&mut request).await.unwrap();
+                let mut answer = vec![0, request[1] + 128, 0, 0, 0, 0, 0, 1];
+                match request[1] {
+                    0 => answer.extend_from_slice(&[203, 0, 113, 7]),
+                    _ => {
+                        // the API's port is taken, so the router picks another
+                        let port = u16::from_be_bytes([request[4], request[5]]);
+                        let public: u16 = if port == 8080 { 18080 } else { port };
+                        answer.extend_from_slice(&port.to_be_bytes());
+                        answer.extend_from_slice(&public.to_be_bytes());
+                        answer.extend_from_slice(&request[8..12]);
+                    }
+                }
+                router.send_to(&answer, from).await.unwrap();
+            }
+        });
+
+        let forwarded = forward_through(&Router::NatPmp(gateway), 8080, 8081)
+            .await
+            .unwrap();
+        assert_eq!(forwarded.method, "nat-pmp");
+        assert_eq!(forwarded.external_ip, Ipv4Addr::new(203, 0, 113, 7));
+        assert_eq!((forwarded.api_port, forwarded.stream_port), (18080, 8081));
+    }
+}
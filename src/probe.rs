@@ -0,0 +1,253 @@
+//! Checks that the configured services can be reached with the url and
+//! credentials centarr has for them, for `POST /admin/test/:service`.
+
+use std::error::Error;
+use std::time::Instant;
+
+use reqwest::{header, Method, StatusCode};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::{self, Config, QbittorrentConfig, UpstreamConfig},
+    downloads,
+    errors::ApiError,
+    lidarr, prowlarr, radarr, readarr, sonarr,
+    upstream::{self, Upstream},
+};
+
+/// How a service answered.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Probe {
+    service: String,
+    ok: bool,
+    version: Option<String>,
+    /// Where it was reached, the API Sonarr was found at.
+    url: String,
+    latency_ms: u64,
+    error: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SystemStatus {
+    version: String,
+    /// Left out by Sonarr v2.
+    app_name: Option<String>,
+}
+
+/// What went wrong, down to what caused it, as reqwest's own message only
+/// says which request failed.
+fn describe(e: &reqwest::Error, name: &str) -> String {
+    if e.is_timeout() {
+        return format!(
+            "{} didn't answer within {:?}",
+            name,
+            config::get().upstream_timeout
+        );
+    }
+
+    let mut cause: &dyn Error = e;
+    while let Some(source) = cause.source() {
+        cause = source;
+    }
+    match e.is_connect() {
+        true => format!("Can't connect to {}: {}", name, cause),
+        false => cause.to_string(),
+    }
+}
+
+/// What a response other than a 2xx means for the setup.
+fn rejected(status: StatusCode, name: &str, url: &str) -> String {
+    match status {
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+            format!("{} answered {}, check the API key", name, status)
+        }
+        StatusCode::NOT_FOUND => format!("{} is not {}'s API, check the url", url, name),
+        status => format!("{} answered {}", name, status),
+    }
+}
+
+/// Asks an *arr service for its version, past the cache and circuit
+/// breaker so the answer is the service's own.
+async fn system_status(
+    upstream: &Upstream,
+    config: &UpstreamConfig,
+    name: &str,
+) -> Result<String, String> {
+    let res = upstream
+        .request(config, Method::GET, "/system/status")
+        .send()
+        .await
+        .map_err(|e| describe(&e, name))?;
+    if !res.status().is_success() {
+        return Err(rejected(res.status(), name, &config.url));
+    }
+
+    let status = res.json::<SystemStatus>().await.map_err(|e| {
+        format!(
+            "{} didn't answer like {} does: {}",
+            config.url,
+            name,
+            describe(&e, name)
+        )
+    })?;
+    match status.app_name {
+        Some(app) if !app.eq_ignore_ascii_case(name) => {
+            Err(format!("{} is {}, not {}", config.url, app, name))
+        }
+        _ => Ok(status.version),
+    }
+}
+
+/// Tries Sonarr under both API prefixes, like [`sonarr::detect`], so an
+/// url with the wrong one isn't reported as broken.
+async fn sonarr_status(config: &Config) -> (String, Result<String, String>) {
+    let url = &config.sonarr.url;
+    let root = url
+        .strip_suffix("/api/v3")
+        .or_else(|| url.strip_suffix("/api"))
+        .unwrap_or(url);
+
+    let mut first_error = None;
+    for api_url in [format!("{}/api/v3", root), format!("{}/api", root)] {
+        let upstream = UpstreamConfig {
+            url: api_url.clone(),
+            ..config.sonarr.clone()
+        };
+        match system_status(&sonarr::UPSTREAM, &upstream, "Sonarr").await {
+            Ok(version) => return (api_url, Ok(version)),
+            Err(e) => {
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+
+    (url.clone(), Err(first_error.unwrap()))
+}
+
+async fn qbittorrent_version(config: &QbittorrentConfig) -> Result<String, String> {
+    let client = upstream::client();
+    let cookie = downloads::qbittorrent_login(&client, config).await?;
+
+    let res = client
+        .get(format!(
+            "{}/api/v2/app/version",
+            config.url.trim_end_matches('/')
+        ))
+        .header(header::COOKIE, cookie)
+        .timeout(config::get().upstream_timeout)
+        .send()
+        .await
+        .map_err(|e| describe(&e, "qBittorrent"))?;
+    if !res.status().is_success() {
+        return Err(rejected(res.status(), "qBittorrent", &config.url));
+    }
+
+    res.text()
+        .await
+        .map(|version| version.trim().trim_start_matches('v').to_string())
+        .map_err(|e| describe(&e, "qBittorrent"))
+}
+
+#[derive(Deserialize)]
+struct SabnzbdAnswer {
+    version: Option<String>,
+    error: Option<String>,
+}
+
+/// Asks SABnzbd for `mode`, failing with the error it answers with.
+async fn sabnzbd(config: &UpstreamConfig, mode: &str) -> Result<SabnzbdAnswer, String> {
+    let res = upstream::client()
+        .get(format!("{}/api", config.url))
+        .query(&[
+            ("mode", mode),
+            ("output", "json"),
+            ("apikey", config.api_key.as_str()),
+        ])
+        .timeout(config::get().upstream_timeout)
+        .send()
+        .await
+        .map_err(|e| describe(&e, "SABnzbd"))?;
+    if !res.status().is_success() {
+        return Err(rejected(res.status(), "SABnzbd", &config.url));
+    }
+
+    let answer = res.json::<SabnzbdAnswer>().await.map_err(|e| {
+        format!(
+            "{} didn't answer like SABnzbd does: {}",
+            config.url,
+            describe(&e, "SABnzbd")
+        )
+    })?;
+    match answer.error {
+        Some(error) => Err(format!("SABnzbd refused: {}", error)),
+        None => Ok(answer),
+    }
+}
+
+async fn sabnzbd_version(config: &UpstreamConfig) -> Result<String, String> {
+    // anyone may ask for the version, the queue needs the API key
+    let version = sabnzbd(config, "version")
+        .await?
+        .version
+        .unwrap_or_default();
+    sabnzbd(config, "queue").await?;
+
+    Ok(version)
+}
+
+fn configured<T: Clone>(service: Option<&T>, name: &str) -> Result<T, ApiError> {
+    service
+        .cloned()
+        .ok_or_else(|| ApiError::new(404, format!("{} is not configured", name)))
+}
+
+/// Checks `service`, a 404 when it's unknown or isn't configured.
+pub async fn probe(service: &str) -> Result<Probe, ApiError> {
+    let config = config::get();
+    let started = Instant::now();
+
+    let (url, result) = match service {
+        "sonarr" => sonarr_status(&config).await,
+        "radarr" | "lidarr" | "readarr" | "prowlarr" => {
+            let (upstream, settings, name) = match service {
+                "radarr" => (&*radarr::UPSTREAM, &config.radarr, "Radarr"),
+                "lidarr" => (&*lidarr::UPSTREAM, &config.lidarr, "Lidarr"),
+                "readarr" => (&*readarr::UPSTREAM, &config.readarr, "Readarr"),
+                _ => (&*prowlarr::UPSTREAM, &config.prowlarr, "Prowlarr"),
+            };
+            let settings = configured(settings.as_ref(), name)?;
+            let result = system_status(upstream, &settings, name).await;
+            (settings.url, result)
+        }
+        "qbittorrent" => {
+            let settings = configured(config.qbittorrent.as_ref(), "qBittorrent")?;
+            let result = qbittorrent_version(&settings).await;
+            (settings.url, result)
+        }
+        "sabnzbd" => {
+            let settings = configured(config.sabnzbd.as_ref(), "SABnzbd")?;
+            let result = sabnzbd_version(&settings).await;
+            (settings.url, result)
+        }
+        _ => return Err(ApiError::new(404, format!("{} is not a service", service))),
+    };
+
+    if let Err(e) = &result {
+        tracing::warn!("Testing {} failed: {}", service, e);
+    }
+    let (version, error) = match result {
+        Ok(version) => (Some(version), None),
+        Err(e) => (None, Some(e)),
+    };
+
+    Ok(Probe {
+        service: service.to_string(),
+        ok: error.is_none(),
+        version,
+        url,
+        latency_ms: started.elapsed().as_millis() as u64,
+        error,
+    })
+}
@@ -0,0 +1,131 @@
+use axum::{
+    extract::Query,
+    middleware,
+    routing::{get, post},
+    Json, Router,
+};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    auth,
+    config::{self, Config, UpstreamConfig},
+    errors::ApiError,
+    radarr, sonarr,
+    upstream::Upstream,
+};
+
+pub static UPSTREAM: Lazy<Upstream> = Lazy::new(|| Upstream::searching("prowlarr"));
+
+/// Search types Prowlarr understands.
+const SEARCH_TYPES: &[&str] = &["search", "tvsearch", "movie", "music", "book"];
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/indexer-search", get(search))
+        .route("/grab", post(grab))
+        .route_layer(middleware::from_fn(auth::require_admin))
+}
+
+#[derive(Deserialize)]
+struct SearchQuery {
+    q: String,
+    #[serde(rename = "type", default = "default_search_type")]
+    search_type: String,
+}
+
+fn default_search_type() -> String {
+    "search".into()
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Release {
+    guid: String,
+    title: String,
+    size: i64,
+    seeders: Option<i32>,
+    leechers: Option<i32>,
+    indexer: String,
+    indexer_id: i32,
+    protocol: String,
+    publish_date: String,
+    download_url: Option<String>,
+    magnet_url: Option<String>,
+    info_url: Option<String>,
+}
+
+/// A release to hand to Sonarr, as found through `/indexer-search`.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Grab {
+    title: String,
+    download_url: String,
+    protocol: String,
+    publish_date: String,
+    #[serde(default = "default_target", skip_serializing)]
+    target: String,
+}
+
+fn default_target() -> String {
+    "sonarr".into()
+}
+
+/// The Prowlarr connection, or a 404 when it isn't configured.
+fn prowlarr(config: &Config) -> Result<&UpstreamConfig, ApiError> {
+    config
+        .prowlarr
+        .as_ref()
+        .ok_or_else(|| ApiError::new(404, "Prowlarr is not configured".into()))
+}
+
+async fn search(Query(query): Query<SearchQuery>) -> Result<Json<Vec<Release>>, ApiError> {
+    if query.q.trim().is_empty() {
+        return Err(ApiError::new(400, "q can't be empty".into()));
+    }
+    if !SEARCH_TYPES.contains(&query.search_type.as_str()) {
+        return Err(ApiError::new(
+            400,
+            format!("type should be one of {}", SEARCH_TYPES.join(", ")),
+        ));
+    }
+
+    let config = config::get();
+    let path = format!(
+        "/search?query={}&type={}",
+        urlencoding::encode(&query.q),
+        query.search_type
+    );
+    let body = UPSTREAM
+        .get(prowlarr(&config)?, config.cache_ttl, &path)
+        .await?;
+
+    let releases = serde_json::from_str::<Vec<Release>>(&body)
+        .map_err(|e| ApiError::empty(500, Some(e.to_string())))?;
+
+    Ok(releases.into())
+}
+
+async fn grab(Json(grab): Json<Grab>) -> Result<Json<Value>, ApiError> {
+    let config = config::get();
+    let body = match grab.target.as_str() {
+        "sonarr" => sonarr::post("/release/push", &grab).await?,
+        "radarr" => {
+            radarr::UPSTREAM
+                .post(radarr::radarr(&config)?, "/release/push", &grab)
+                .await?
+        }
+        target => {
+            return Err(ApiError::new(
+                400,
+                format!("Can't push releases to {:?}", target),
+            ))
+        }
+    };
+
+    let decision =
+        serde_json::from_str(&body).map_err(|e| ApiError::empty(500, Some(e.to_string())))?;
+
+    Ok(Json(decision))
+}
@@ -0,0 +1,551 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path as FilePath;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::{
+    extract::{Path, Query},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    auth::AuthUser,
+    config::{self, Config},
+    errors::ApiError,
+    playback::{self, WatchState},
+    restrictions::Restrictions,
+    sendfile, sonarr, titles, users,
+};
+
+/// Queues nobody asked for the next episode of in this long are forgotten.
+const SESSION_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+const MAX_SESSIONS: usize = 1000;
+const MAX_EPISODES: usize = 5000;
+
+/// Each is locked on its own, as finding the next episode may wait on
+/// Sonarr.
+static SESSIONS: Lazy<Mutex<HashMap<String, Arc<tokio::sync::Mutex<Session>>>>> =
+    Lazy::new(Default::default);
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/queue-sessions", post(create_session))
+        .route(
+            "/queue-sessions/:id",
+            get(get_session).delete(delete_session),
+        )
+        .route("/queue-sessions/:id/next", get(next))
+}
+
+/// Episodes to play one after the other.
+struct Session {
+    /// Who made it, only they can use it.
+    owner: Option<String>,
+    episodes: Vec<i32>,
+    /// Index of the episode `next` looks at first.
+    position: usize,
+    skip_watched: bool,
+    /// Whether the player goes on to the next episode by itself.
+    autoplay_next: bool,
+    last_used: Instant,
+}
+
+impl Session {
+    fn info(&self, id: &str) -> SessionInfo {
+        SessionInfo {
+            id: id.into(),
+            episode_ids: self.episodes.clone(),
+            position: self.position,
+            skip_watched: self.skip_watched,
+            autoplay_next: self.autoplay_next,
+        }
+    }
+
+    fn usable_by(&self, user: Option<&AuthUser>) -> bool {
+        self.last_used.elapsed() < SESSION_LIFETIME
+            && self.owner.as_deref() == user.map(|user| user.name.as_str())
+    }
+}
+
+/// Either `episodeIds`, or a show to play from `seasonNumber` and
+/// `episodeNumber` on until its end.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct NewSession {
+    episode_ids: Option<Vec<i32>>,
+    show_id: Option<i32>,
+    #[serde(default = "first")]
+    season_number: i32,
+    #[serde(default = "first")]
+    episode_number: i32,
+    #[serde(default = "skip_watched")]
+    skip_watched: bool,
+    /// The user's preference unless sent.
+    autoplay_next: Option<bool>,
+}
+
+fn first() -> i32 {
+    1
+}
+
+fn skip_watched() -> bool {
+    true
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SessionInfo {
+    id: String,
+    episode_ids: Vec<i32>,
+    position: usize,
+    skip_watched: bool,
+    autoplay_next: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Episode {
+    id: i32,
+    series_id: i32,
+    season_number: i32,
+    episode_number: i32,
+    title: String,
+    #[serde(default)]
+    has_file: bool,
+    episode_file: Option<EpisodeFile>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct EpisodeFile {
+    id: i32,
+    path: String,
+    media_info: Option<MediaInfo>,
+}
+
+/// The languages of a file's streams, like `English/Japanese`, in the
+/// order of the streams.
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+struct MediaInfo {
+    audio_languages: Option<String>,
+    subtitles: Option<String>,
+}
+
+/// An audio or subtitle stream picked for the user.
+#[derive(Serialize, Debug, PartialEq)]
+struct Track {
+    /// Among the file's streams of its kind, counting from 0.
+    index: usize,
+    language: String,
+}
+
+/// The first stream in the first of `languages`, a user's preferred ones
+/// like `eng`, that `streams` has. `streams` are as Sonarr lists them.
+fn pick(streams: Option<&str>, languages: &[String]) -> Option<Track> {
+    let streams = streams?
+        .split('/')
+        .map(|stream| (stream.trim(), titles::language_name(stream)))
+        .collect::<Vec<_>>();
+
+    languages
+        .iter()
+        .filter_map(|language| titles::language_name(language))
+        .find_map(|wanted| {
+            streams
+                .iter()
+                .position(|(_, language)| *language == Some(wanted))
+                .map(|index| Track {
+                    index,
+                    language: streams[index].0.to_owned(),
+                })
+        })
+}
+
+#[derive(Deserialize)]
+struct NextQuery {
+    /// Sent by players going on by themselves at the end of an episode.
+    #[serde(default)]
+    auto: bool,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Next {
+    /// Of the episode in the queue, counting from 0.
+    position: usize,
+    remaining: usize,
+    id: i32,
+    series_id: i32,
+    season_number: i32,
+    episode_number: i32,
+    title: String,
+    watch_state: WatchState,
+    /// Whether the player goes on to the episode after by itself.
+    autoplay_next: bool,
+    /// Bytes into the file to resume at, when it was started before.
+    resume_at: Option<u64>,
+    /// Going by the user's `audioLanguages`, when the file has one of them.
+    audio_track: Option<Track>,
+    /// Going by the user's `subtitleLanguages`.
+    subtitle_track: Option<Track>,
+    watch_url: String,
+}
+
+fn not_found(id: &str) -> ApiError {
+    ApiError::new(404, format!("There's no queue session {}", id))
+}
+
+/// Whether series `id` is one `restrictions` allows.
+async fn allowed(
+    config: &Config,
+    restrictions: &Restrictions,
+    series: &BTreeMap<i64, Value>,
+    id: i32,
+) -> Result<bool, ApiError> {
+    let labels = sonarr::tag_labels(config).await?;
+    let tags = series
+        .get(&id.into())
+        .and_then(|series| series["tags"].as_array())
+        .into_iter()
+        .flatten()
+        .filter_map(|id| labels.get(&(id.as_i64()? as i32)));
+
+    Ok(series.contains_key(&id.into()) && restrictions.allows(tags))
+}
+
+async fn create_session(
+    user: Option<AuthUser>,
+    restrictions: Restrictions,
+    Json(new): Json<NewSession>,
+) -> Result<(StatusCode, Json<SessionInfo>), ApiError> {
+    let config = config::get();
+
+    let episodes = match (new.episode_ids, new.show_id) {
+        (Some(ids), None) => ids,
+        (None, Some(show_id)) => {
+            let series = sonarr::series_by_id().await?;
+            if !allowed(&config, &restrictions, &series, show_id).await? {
+                return Err(ApiError::new(404, format!("There's no show {}", show_id)));
+            }
+
+            let mut episodes = sonarr::episodes(show_id)
+                .await?
+                .iter()
+                .filter_map(|episode| Episode::deserialize(episode).ok())
+                // specials are left out, they're in season 0
+                .filter(|episode| episode.season_number > 0)
+                .map(|episode| (episode.season_number, episode.episode_number, episode.id))
+                .filter(|(season, number, _)| {
+                    (*season, *number) >= (new.season_number, new.episode_number)
+                })
+                .collect::<Vec<_>>();
+            episodes.sort_unstable();
+            episodes.into_iter().map(|(_, _, id)| id).collect()
+        }
+        _ => {
+            return Err(ApiError::new(
+                400,
+                "Send either episodeIds or a showId".into(),
+            ))
+        }
+    };
+    if episodes.len() > MAX_EPISODES {
+        return Err(ApiError::new(
+            400,
+            format!("Queues can't have over {} episodes", MAX_EPISODES),
+        ));
+    }
+
+    let id = users::random_hex(16);
+    let autoplay_next = new.autoplay_next.unwrap_or_else(|| match &user {
+        Some(user) => users::preferences(&user.name).autoplay_next,
+        None => true,
+    });
+    let session = Session {
+        owner: user.map(|user| user.name),
+        episodes,
+        position: 0,
+        skip_watched: new.skip_watched,
+        autoplay_next,
+        last_used: Instant::now(),
+    };
+    let info = session.info(&id);
+
+    let mut sessions = SESSIONS.lock().unwrap();
+    // sessions busy finding their next episode are in use anyway
+    let last_used = |session: &Arc<tokio::sync::Mutex<Session>>| {
+        session
+            .try_lock()
+            .map_or_else(|_| Instant::now(), |session| session.last_used)
+    };
+    sessions.retain(|_, session| last_used(session).elapsed() < SESSION_LIFETIME);
+    if sessions.len() >= MAX_SESSIONS {
+        if let Some(oldest) = sessions
+            .iter()
+            .min_by_key(|(_, session)| last_used(session))
+            .map(|(id, _)| id.clone())
+        {
+            sessions.remove(&oldest);
+        }
+    }
+    sessions.insert(id, Arc::new(tokio::sync::Mutex::new(session)));
+
+    Ok((StatusCode::CREATED, Json(info)))
+}
+
+/// Session `id`, which may be someone else's.
+fn session(id: &str) -> Result<Arc<tokio::sync::Mutex<Session>>, ApiError> {
+    SESSIONS
+        .lock()
+        .unwrap()
+        .get(id)
+        .cloned()
+        .ok_or_else(|| not_found(id))
+}
+
+async fn get_session(
+    Path(id): Path<String>,
+    user: Option<AuthUser>,
+) -> Result<Json<SessionInfo>, ApiError> {
+    let session = session(&id)?;
+    let session = session.lock().await;
+    if !session.usable_by(user.as_ref()) {
+        return Err(not_found(&id));
+    }
+
+    Ok(Json(session.info(&id)))
+}
+
+async fn delete_session(
+    Path(id): Path<String>,
+    user: Option<AuthUser>,
+) -> Result<StatusCode, ApiError> {
+    if !session(&id)?.lock().await.usable_by(user.as_ref()) {
+        return Err(not_found(&id));
+    }
+
+    SESSIONS.lock().unwrap().remove(&id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Moves on to the next episode in the queue that can be played, skipping
+/// those without a file, restricted ones and, unless turned off, watched
+/// ones. A 204 once there are none left, or when the player is going on
+/// by itself and the session says it shouldn't.
+async fn next(
+    Path(id): Path<String>,
+    Query(query): Query<NextQuery>,
+    user: Option<AuthUser>,
+    restrictions: Restrictions,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let config = config::get();
+    let session = session(&id)?;
+    let mut session = session.lock().await;
+    if !session.usable_by(user.as_ref()) {
+        return Err(not_found(&id));
+    }
+    session.last_used = Instant::now();
+    if query.auto && !session.autoplay_next {
+        return Ok(StatusCode::NO_CONTENT.into_response());
+    }
+
+    let preferences = user
+        .as_ref()
+        .map(|user| users::preferences(&user.name))
+        .unwrap_or_default();
+
+    let mut series = None;
+    while let Some(&episode_id) = session.episodes.get(session.position) {
+        let position = session.position;
+        session.position += 1;
+
+        let episode = match sonarr::episode(episode_id).await? {
+            Some(episode) => Episode::deserialize(&episode)
+                .map_err(|e| ApiError::empty(500, Some(e.to_string())))?,
+            None => continue,
+        };
+        let file = match episode.episode_file.filter(|_| episode.has_file) {
+            Some(file) => file,
+            None => continue,
+        };
+
+        if series.is_none() {
+            series = Some(sonarr::series_by_id().await?);
+        }
+        let series = series.as_ref().unwrap();
+        if !allowed(&config, &restrictions, series, episode.series_id).await? {
+            continue;
+        }
+
+        let viewer = user.as_ref().map(|user| user.name.as_str());
+        let play = playback::get(viewer, &config.local_path(FilePath::new(&file.path)));
+        let watch_state = WatchState::of(play, viewer);
+        if session.skip_watched && watch_state == WatchState::Watched {
+            continue;
+        }
+
+        let media_info = file.media_info.unwrap_or_default();
+        return Ok(Json(Next {
+            position,
+            remaining: session.episodes.len() - session.position,
+            id: episode.id,
+            series_id: episode.series_id,
+            season_number: episode.season_number,
+            episode_number: episode.episode_number,
+            title: episode.title,
+            watch_state,
+            autoplay_next: session.autoplay_next,
+            resume_at: play
+                .filter(|_| watch_state == WatchState::InProgress)
+                .map(|play| play.position),
+            audio_track: pick(
+                media_info.audio_languages.as_deref(),
+                &preferences.audio_languages,
+            ),
+            subtitle_track: pick(
+                media_info.subtitles.as_deref(),
+                &preferences.subtitle_languages,
+            ),
+            watch_url: sendfile::episode_url(&headers, &config, file.id, &file.path),
+        })
+        .into_response());
+    }
+
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{header, Request},
+        middleware, Extension,
+    };
+    use serde_json::json;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    fn episode(id: i32, series_id: i32, has_file: bool) -> String {
+        json!({
+            "id": id,
+            "seriesId": series_id,
+            "seasonNumber": 1,
+            "episodeNumber": id,
+            "title": format!("Episode {}", id),
+            "hasFile": has_file,
+            "episodeFile": {
+                "id": 10 + id,
+                "path": format!("/tv/Queued/Season 1/Queued - S01E0{}.mkv", id),
+                "mediaInfo": { "audioLanguages": "English/Japanese" },
+            },
+        })
+        .to_string()
+    }
+
+    /// A queue of `episode_ids` on a Sonarr where 1 is gone, 2 has no
+    /// file, 3 is of a show it doesn't list and the rest can be played.
+    async fn queue(episode_ids: &[i32], autoplay_next: bool) -> (Router, String) {
+        config::init_for_tests();
+        let mut sonarr = sonarr::Mock::default()
+            .with("/series", r#"[{ "id": 1, "tags": [] }]"#)
+            .with("/tag", "[]")
+            .with("/episode/2", &episode(2, 1, false))
+            .with("/episode/3", &episode(3, 9, true));
+        for id in 4..10 {
+            sonarr = sonarr.with(&format!("/episode/{}", id), &episode(id, 1, true));
+        }
+        let client: sonarr::Client = Arc::new(sonarr);
+        let app = router()
+            .layer(middleware::from_fn(sonarr::inject))
+            .layer(Extension(client));
+
+        let body = json!({ "episodeIds": episode_ids, "autoplayNext": autoplay_next });
+        let req = Request::post("/queue-sessions")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let (status, session) = send(&app, req).await;
+        assert_eq!(status, StatusCode::CREATED);
+
+        (app, session["id"].as_str().unwrap().to_owned())
+    }
+
+    async fn send(app: &Router, req: Request<Body>) -> (StatusCode, Value) {
+        let res = app.clone().oneshot(req).await.unwrap();
+        let status = res.status();
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+
+        (status, serde_json::from_slice(&body).unwrap_or_default())
+    }
+
+    async fn next_of(app: &Router, id: &str, query: &str) -> (StatusCode, Value) {
+        let req = Request::get(format!("/queue-sessions/{}/next{}", id, query))
+            .header(header::HOST, "centarr.local:3000")
+            .body(Body::empty())
+            .unwrap();
+
+        send(app, req).await
+    }
+
+    #[tokio::test]
+    async fn unplayable_episodes_are_skipped() {
+        let (app, id) = queue(&[1, 2, 3, 4, 5], true).await;
+
+        let (status, next) = next_of(&app, &id, "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(next["id"], 4);
+        assert_eq!(next["position"], 3);
+        assert_eq!(next["remaining"], 1);
+        assert_eq!(next["watchState"], "unwatched");
+        assert!(next["watchUrl"].as_str().unwrap().contains("centarr.local"));
+
+        let (_, next) = next_of(&app, &id, "?auto=true").await;
+        assert_eq!(next["id"], 5);
+        assert_eq!(next["remaining"], 0);
+
+        let (status, _) = next_of(&app, &id, "").await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+    }
+
+    #[tokio::test]
+    async fn players_only_go_on_by_themselves_when_allowed() {
+        let (app, id) = queue(&[6, 7], false).await;
+
+        let (status, _) = next_of(&app, &id, "?auto=true").await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        // asking for it still moves on, from where the queue was
+        let (_, next) = next_of(&app, &id, "").await;
+        assert_eq!(next["id"], 6);
+        assert_eq!(next["autoplayNext"], false);
+
+        let (status, _) = next_of(&app, "unknown", "").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn tracks_are_picked_by_preferred_language() {
+        let preferred = ["jpn".to_owned(), "eng".to_owned()];
+
+        let track = pick(Some("English/Japanese"), &preferred);
+        assert_eq!(
+            track,
+            Some(Track {
+                index: 1,
+                language: "Japanese".into()
+            })
+        );
+        assert_eq!(pick(Some("eng / ger"), &preferred).unwrap().index, 0);
+        assert_eq!(pick(Some("German/French"), &preferred), None);
+        assert_eq!(pick(None, &preferred), None);
+        assert_eq!(pick(Some("English"), &[]), None);
+    }
+}
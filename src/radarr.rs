@@ -0,0 +1,138 @@
+use std::collections::BTreeMap;
+
+use async_trait::async_trait;
+use once_cell::sync::Lazy;
+use serde::{de::DeserializeOwned, Deserialize};
+
+use crate::{
+    config::{Config, UpstreamConfig},
+    errors::ApiError,
+    library::{Image, Item, Kind, MediaFile, MediaProvider, Tag},
+    upstream::Upstream,
+};
+
+pub static UPSTREAM: Lazy<Upstream> = Lazy::new(|| Upstream::new("radarr"));
+
+/// Radarr's movies for the library.
+pub struct Provider;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Movie {
+    id: i32,
+    title: String,
+    year: Option<i32>,
+    overview: Option<String>,
+    #[serde(default)]
+    images: Vec<Image>,
+    added: Option<String>,
+    movie_file: Option<MovieFile>,
+    path: Option<String>,
+    #[serde(default)]
+    tags: Vec<i32>,
+}
+
+impl Movie {
+    fn into_item(self, labels: &BTreeMap<i32, String>) -> Item {
+        Item {
+            kind: Kind::Movie,
+            id: self.id,
+            title: self.title,
+            year: self.year.filter(|year| *year > 0),
+            overview: self.overview,
+            images: self.images,
+            added: self.added,
+            files: None,
+            path: self.path,
+            tags: self
+                .tags
+                .iter()
+                .filter_map(|id| labels.get(id).cloned())
+                .collect(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MovieFile {
+    id: i32,
+    path: String,
+    size: i64,
+    date_added: Option<String>,
+}
+
+/// The Radarr connection, or a 404 when it isn't configured.
+pub fn radarr(config: &Config) -> Result<&UpstreamConfig, ApiError> {
+    config
+        .radarr
+        .as_ref()
+        .ok_or_else(|| ApiError::new(404, "Radarr is not configured".into()))
+}
+
+async fn get_json<T: DeserializeOwned>(config: &Config, path: &str) -> Result<T, ApiError> {
+    let body = UPSTREAM
+        .get(radarr(config)?, config.cache_ttl, path)
+        .await?;
+
+    serde_json::from_str(&body).map_err(|e| ApiError::empty(500, Some(e.to_string())))
+}
+
+/// Radarr's tag labels by id, lowercased. Empty when no tags are
+/// restricted, as nothing needs them then.
+async fn tag_labels(config: &Config) -> Result<BTreeMap<i32, String>, ApiError> {
+    if config.restricted_tags.is_empty() {
+        return Ok(BTreeMap::new());
+    }
+
+    Ok(get_json::<Vec<Tag>>(config, "/tag")
+        .await?
+        .into_iter()
+        .map(|tag| (tag.id, tag.label.to_lowercase()))
+        .collect())
+}
+
+#[async_trait]
+impl MediaProvider for Provider {
+    fn kind(&self) -> Kind {
+        Kind::Movie
+    }
+
+    fn is_configured(&self, config: &Config) -> bool {
+        config.radarr.is_some()
+    }
+
+    async fn list(&self, config: &Config) -> Result<Vec<Item>, ApiError> {
+        let movies = get_json::<Vec<Movie>>(config, "/movie").await?;
+        let labels = tag_labels(config).await?;
+
+        Ok(movies
+            .into_iter()
+            .map(|movie| movie.into_item(&labels))
+            .collect())
+    }
+
+    async fn get(&self, config: &Config, id: i32) -> Result<Item, ApiError> {
+        let movie = get_json::<Movie>(config, &format!("/movie/{}", id)).await?;
+
+        Ok(movie.into_item(&tag_labels(config).await?))
+    }
+
+    async fn files(&self, config: &Config, id: i32) -> Result<Vec<MediaFile>, ApiError> {
+        let movie = get_json::<Movie>(config, &format!("/movie/{}", id)).await?;
+
+        Ok(movie
+            .movie_file
+            .into_iter()
+            .map(|file| MediaFile {
+                id: file.id,
+                path: file.path,
+                size: file.size,
+                date_added: file.date_added,
+                season_number: None,
+                watch_url: None,
+                position: None,
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,125 @@
+use std::path::Path as FilePath;
+
+use axum::{extract::Path, http::HeaderMap, routing::get, Json, Router};
+use once_cell::sync::Lazy;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    config::{self, Config, UpstreamConfig},
+    errors::ApiError,
+    sendfile,
+    upstream::Upstream,
+};
+
+pub static UPSTREAM: Lazy<Upstream> = Lazy::new(|| Upstream::new("readarr"));
+
+/// Audiobook formats, these get a `streamUrl` on top of the download url.
+const AUDIO_EXTENSIONS: &[&str] = &["m4b", "m4a", "mp3", "flac", "ogg", "opus", "aac"];
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/authors", get(get_authors))
+        .route("/authors/:authorId", get(get_author))
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Author {
+    id: i32,
+    author_name: String,
+    overview: Option<String>,
+    images: Vec<Image>,
+    #[serde(default)]
+    genres: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    books: Option<Vec<Book>>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Image {
+    cover_type: String,
+    url: Option<String>,
+    remote_url: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Book {
+    id: i32,
+    author_id: i32,
+    title: String,
+    overview: Option<String>,
+    release_date: Option<String>,
+    page_count: Option<i32>,
+    images: Vec<Image>,
+    #[serde(default)]
+    files: Vec<BookFile>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct BookFile {
+    id: i32,
+    book_id: i32,
+    path: String,
+    size: i64,
+    date_added: String,
+    #[serde(skip_deserializing)]
+    download_url: Option<String>,
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    stream_url: Option<String>,
+}
+
+/// The Readarr connection, or a 404 when it isn't configured.
+fn readarr(config: &Config) -> Result<&UpstreamConfig, ApiError> {
+    config
+        .readarr
+        .as_ref()
+        .ok_or_else(|| ApiError::new(404, "Readarr is not configured".into()))
+}
+
+async fn get_json<T: DeserializeOwned>(config: &Config, path: &str) -> Result<T, ApiError> {
+    let body = UPSTREAM
+        .get(readarr(config)?, config.cache_ttl, path)
+        .await?;
+
+    serde_json::from_str(&body).map_err(|e| ApiError::empty(500, Some(e.to_string())))
+}
+
+async fn get_authors() -> Result<Json<Vec<Author>>, ApiError> {
+    let config = config::get();
+    let authors = get_json::<Vec<Author>>(&config, "/author").await?;
+
+    Ok(authors.into())
+}
+
+async fn get_author(Path(id): Path<i32>, headers: HeaderMap) -> Result<Json<Author>, ApiError> {
+    let config = config::get();
+
+    let mut author = get_json::<Author>(&config, &format!("/author/{}", id)).await?;
+    let mut books = get_json::<Vec<Book>>(&config, &format!("/book?authorId={}", id)).await?;
+    let files = get_json::<Vec<BookFile>>(&config, &format!("/bookfile?authorId={}", id)).await?;
+
+    for mut file in files {
+        let url = sendfile::watch_url(&headers, &config, &file.path);
+        let is_audio = FilePath::new(&file.path)
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(|extension| AUDIO_EXTENSIONS.contains(&extension.to_lowercase().as_str()))
+            .unwrap_or(false);
+
+        if is_audio {
+            file.stream_url = Some(url.clone());
+        }
+        file.download_url = Some(url);
+
+        if let Some(book) = books.iter_mut().find(|book| book.id == file.book_id) {
+            book.files.push(file);
+        }
+    }
+
+    author.books = Some(books);
+
+    Ok(author.into())
+}
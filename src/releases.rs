@@ -0,0 +1,128 @@
+//! Interactive search: the releases Sonarr finds on its indexers for an
+//! episode, and grabbing one of them instead of what Sonarr would pick.
+
+use axum::{extract::Path, middleware, routing::get, Json, Router};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{auth, errors::ApiError, sonarr};
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/episodes/:id/releases", get(search).post(grab))
+        .route_layer(middleware::from_fn(auth::require_admin))
+}
+
+/// A release as Sonarr answers `/release` with, the parts of it a search
+/// screen shows.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SonarrRelease {
+    guid: String,
+    indexer_id: i32,
+    #[serde(default)]
+    indexer: String,
+    title: String,
+    size: i64,
+    seeders: Option<i32>,
+    leechers: Option<i32>,
+    protocol: String,
+    #[serde(default)]
+    age: i32,
+    publish_date: Option<String>,
+    quality: Option<Value>,
+    #[serde(default)]
+    approved: bool,
+    #[serde(default)]
+    rejections: Vec<Value>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Release {
+    guid: String,
+    indexer_id: i32,
+    indexer: String,
+    title: String,
+    /// In bytes.
+    size: i64,
+    seeders: Option<i32>,
+    leechers: Option<i32>,
+    protocol: String,
+    /// In days.
+    age: i32,
+    publish_date: Option<String>,
+    /// Like `WEBDL-1080p`.
+    quality: Option<String>,
+    /// Whether Sonarr would grab it by itself.
+    approved: bool,
+    /// Why Sonarr wouldn't.
+    rejections: Vec<String>,
+}
+
+impl From<SonarrRelease> for Release {
+    fn from(release: SonarrRelease) -> Self {
+        Self {
+            guid: release.guid,
+            indexer_id: release.indexer_id,
+            indexer: release.indexer,
+            title: release.title,
+            size: release.size,
+            seeders: release.seeders,
+            leechers: release.leechers,
+            protocol: release.protocol,
+            age: release.age,
+            publish_date: release.publish_date,
+            quality: release
+                .quality
+                .as_ref()
+                .and_then(|quality| quality["quality"]["name"].as_str())
+                .map(String::from),
+            approved: release.approved,
+            // plain text up to v3, objects with a reason after
+            rejections: release
+                .rejections
+                .iter()
+                .filter_map(|rejection| rejection.as_str().or(rejection["reason"].as_str()))
+                .map(String::from)
+                .collect(),
+        }
+    }
+}
+
+/// Searches every indexer for episode `id`, answering with what Sonarr
+/// found in the order it prefers them.
+async fn search(Path(id): Path<i32>) -> Result<Json<Vec<Release>>, ApiError> {
+    let body = sonarr::search(&format!("/release?episodeId={}", id)).await?;
+    let releases = serde_json::from_str::<Vec<SonarrRelease>>(&body)
+        .map_err(|e| ApiError::empty(500, Some(e.to_string())))?;
+
+    Ok(Json(releases.into_iter().map(Release::from).collect()))
+}
+
+/// A release from the last search for the episode.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Grab {
+    guid: String,
+    indexer_id: i32,
+}
+
+/// Has Sonarr download a release found by [`search`], even one it
+/// rejected. Sonarr only remembers them for a while after searching.
+async fn grab(Path(id): Path<i32>, Json(grab): Json<Grab>) -> Result<Json<Value>, ApiError> {
+    let body = sonarr::post(
+        "/release",
+        &json!({ "guid": grab.guid, "indexerId": grab.indexer_id, "episodeId": id }),
+    )
+    .await?;
+    let release = serde_json::from_str::<Value>(&body)
+        .map_err(|e| ApiError::empty(500, Some(e.to_string())))?;
+    tracing::info!(
+        "Grabbed {} for episode {}",
+        release["title"].as_str().unwrap_or(&grab.guid),
+        id
+    );
+
+    Ok(Json(release))
+}
@@ -0,0 +1,328 @@
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+use axum::{extract::Query, routing::get, Json, Router};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::{self, Config},
+    errors::ApiError,
+    sonarr, store,
+};
+
+const DEFAULT_LIMIT: usize = 20;
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/reports/integrity", get(integrity))
+        .route("/stats/storage", get(storage))
+}
+
+#[derive(Serialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct IntegrityReport {
+    /// Files Sonarr has on record that aren't on disk.
+    missing_files: Vec<ReportedFile>,
+    /// Video files in a series folder that Sonarr doesn't know about.
+    orphaned_files: Vec<ReportedFile>,
+    /// Series folders that don't exist here, usually a wrong path mapping.
+    missing_folders: Vec<ReportedFile>,
+}
+
+impl IntegrityReport {
+    pub fn is_clean(&self) -> bool {
+        self.missing_files.is_empty()
+            && self.orphaned_files.is_empty()
+            && self.missing_folders.is_empty()
+    }
+
+    pub fn summary(&self) -> String {
+        format!(
+            "{} missing files, {} orphaned files, {} missing folders",
+            self.missing_files.len(),
+            self.orphaned_files.len(),
+            self.missing_folders.len()
+        )
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ReportedFile {
+    series_id: i32,
+    series_title: String,
+    /// The path as Sonarr sees it, when Sonarr knows about it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<String>,
+    local_path: PathBuf,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Series {
+    id: i32,
+    title: String,
+    path: String,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct EpisodeFile {
+    id: i32,
+    season_number: i32,
+    path: String,
+    size: u64,
+}
+
+/// Every series with its episode files, from the synced library when
+/// there is one.
+async fn series_files() -> Result<Vec<(Series, Vec<EpisodeFile>)>, ApiError> {
+    let parse = |e: serde_json::Error| ApiError::empty(500, Some(e.to_string()));
+
+    if let Some(library) = store::library() {
+        return library
+            .series
+            .iter()
+            .map(|(id, series)| {
+                let mut files = library
+                    .episodes
+                    .get(id)
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|episode| episode.get("episodeFile"))
+                    .map(EpisodeFile::deserialize)
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(parse)?;
+                // multi-episode files show up once per episode
+                files.sort_by_key(|file| file.id);
+                files.dedup_by_key(|file| file.id);
+
+                Ok((Series::deserialize(series).map_err(parse)?, files))
+            })
+            .collect();
+    }
+
+    let body = sonarr::get("/series").await?;
+    let mut result = Vec::new();
+
+    for series in serde_json::from_str::<Vec<Series>>(&body).map_err(parse)? {
+        let body = sonarr::get(&format!("/episodefile?seriesId={}", series.id)).await?;
+        let files = serde_json::from_str::<Vec<EpisodeFile>>(&body).map_err(parse)?;
+
+        result.push((series, files));
+    }
+
+    Ok(result)
+}
+
+fn is_video(path: &Path) -> bool {
+    mime_guess::from_path(path)
+        .first_raw()
+        .filter(|mime| mime.starts_with("video/"))
+        .is_some()
+}
+
+/// Every video file below `dir`.
+fn videos(dir: &Path, found: &mut Vec<PathBuf>) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            tracing::debug!("Can't read {:?}: {}", dir, e);
+            return;
+        }
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        match entry.file_type() {
+            Ok(kind) if kind.is_dir() => videos(&path, found),
+            Ok(_) if is_video(&path) => found.push(path),
+            _ => {}
+        }
+    }
+}
+
+fn check(config: &Config, library: Vec<(Series, Vec<EpisodeFile>)>) -> IntegrityReport {
+    let mut report = IntegrityReport::default();
+
+    for (series, files) in library {
+        let folder = config.local_path(Path::new(&series.path));
+        let reported = |path: Option<String>, local_path| ReportedFile {
+            series_id: series.id,
+            series_title: series.title.clone(),
+            path,
+            local_path,
+        };
+
+        if !folder.is_dir() {
+            report
+                .missing_folders
+                .push(reported(Some(series.path.clone()), folder));
+            continue;
+        }
+
+        let mut known = BTreeSet::new();
+        for file in files {
+            let local = config.local_path(Path::new(&file.path));
+            if !local.is_file() {
+                report
+                    .missing_files
+                    .push(reported(Some(file.path), local.clone()));
+            }
+            known.insert(local);
+        }
+
+        let mut on_disk = Vec::new();
+        videos(&folder, &mut on_disk);
+        for file in on_disk.into_iter().filter(|file| !known.contains(file)) {
+            report.orphaned_files.push(reported(None, file));
+        }
+    }
+
+    report
+}
+
+/// Compares what Sonarr has on record with what's on disk.
+pub async fn integrity_report() -> Result<IntegrityReport, ApiError> {
+    let config = config::get();
+    let library = series_files().await?;
+
+    // walking every series folder can take a while on slow disks
+    tokio::task::spawn_blocking(move || check(&config, library))
+        .await
+        .map_err(|e| ApiError::empty(500, Some(e.to_string())))
+}
+
+async fn integrity() -> Result<Json<IntegrityReport>, ApiError> {
+    Ok(integrity_report().await?.into())
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct StorageStats {
+    root_folders: Vec<RootFolderUsage>,
+    /// Biggest first.
+    shows: Vec<ShowUsage>,
+    largest_files: Vec<FileUsage>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RootFolder {
+    path: String,
+    free_space: Option<u64>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RootFolderUsage {
+    path: String,
+    local_path: PathBuf,
+    /// Whether the numbers come from this machine, otherwise only Sonarr's
+    /// view of the free space is known.
+    local: bool,
+    total_bytes: Option<u64>,
+    used_bytes: Option<u64>,
+    free_bytes: Option<u64>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ShowUsage {
+    series_id: i32,
+    title: String,
+    file_count: usize,
+    size_on_disk: u64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct FileUsage {
+    series_id: i32,
+    series_title: String,
+    season_number: i32,
+    path: String,
+    size: u64,
+}
+
+#[derive(Deserialize)]
+struct StorageQuery {
+    limit: Option<usize>,
+}
+
+fn root_folder_usage(config: &Config, root: RootFolder) -> RootFolderUsage {
+    let local_path = config.local_path(Path::new(&root.path));
+
+    match nix::sys::statvfs::statvfs(&local_path) {
+        Ok(stats) => {
+            let block = stats.fragment_size();
+            RootFolderUsage {
+                path: root.path,
+                local_path,
+                local: true,
+                total_bytes: Some(stats.blocks() * block),
+                used_bytes: Some((stats.blocks() - stats.blocks_free()) * block),
+                free_bytes: Some(stats.blocks_available() * block),
+            }
+        }
+        Err(e) => {
+            tracing::debug!("Can't statvfs {:?}: {}", local_path, e);
+            RootFolderUsage {
+                path: root.path,
+                local_path,
+                local: false,
+                total_bytes: None,
+                used_bytes: None,
+                free_bytes: root.free_space,
+            }
+        }
+    }
+}
+
+async fn storage(Query(query): Query<StorageQuery>) -> Result<Json<StorageStats>, ApiError> {
+    let config = config::get();
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT);
+
+    let body = sonarr::get("/rootfolder").await?;
+    let roots = serde_json::from_str::<Vec<RootFolder>>(&body)
+        .map_err(|e| ApiError::empty(500, Some(e.to_string())))?;
+    let library = series_files().await?;
+
+    let mut shows = Vec::new();
+    let mut files = Vec::new();
+    for (series, series_files) in library {
+        shows.push(ShowUsage {
+            series_id: series.id,
+            title: series.title.clone(),
+            file_count: series_files.len(),
+            size_on_disk: series_files.iter().map(|file| file.size).sum(),
+        });
+        files.extend(series_files.into_iter().map(|file| FileUsage {
+            series_id: series.id,
+            series_title: series.title.clone(),
+            season_number: file.season_number,
+            path: file.path,
+            size: file.size,
+        }));
+    }
+
+    shows.sort_by_key(|show| std::cmp::Reverse(show.size_on_disk));
+    files.sort_by_key(|file| std::cmp::Reverse(file.size));
+    files.truncate(limit);
+
+    // statvfs can hang for a while on network mounts
+    let root_folders = tokio::task::spawn_blocking(move || {
+        roots
+            .into_iter()
+            .map(|root| root_folder_usage(&config, root))
+            .collect()
+    })
+    .await
+    .map_err(|e| ApiError::empty(500, Some(e.to_string())))?;
+
+    Ok(Json(StorageStats {
+        root_folders,
+        shows,
+        largest_files: files,
+    }))
+}
@@ -0,0 +1,60 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+use axum::{
+    http::{header::HeaderName, HeaderValue, Request},
+    middleware::Next,
+    response::Response,
+};
+use tracing::Instrument;
+
+pub static X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// The id of the API request currently being handled, if any.
+pub fn current() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+fn generate() -> String {
+    // every RandomState is seeded with fresh keys, so hashing nothing still
+    // gives us a different 64 bit value per call without pulling in a rng
+    let high = RandomState::new().build_hasher().finish();
+    let low = RandomState::new().build_hasher().finish();
+    format!("{:016x}{:016x}", high, low)
+}
+
+fn is_valid(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= 128
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+/// Assigns an `x-request-id` to every request (reusing the one sent by the
+/// client or a reverse proxy when it looks sane), runs the rest of the stack
+/// inside a span carrying it, and echoes it back on the response.
+pub async fn middleware<B>(mut req: Request<B>, next: Next<B>) -> Response {
+    let id = req
+        .headers()
+        .get(&X_REQUEST_ID)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| is_valid(id))
+        .map(String::from)
+        .unwrap_or_else(generate);
+
+    let header = HeaderValue::from_str(&id).expect("request ids are valid header values");
+    req.headers_mut()
+        .insert(X_REQUEST_ID.clone(), header.clone());
+
+    let span = tracing::info_span!("request", request_id = %id);
+
+    let mut res = REQUEST_ID.scope(id, next.run(req).instrument(span)).await;
+    res.headers_mut().insert(X_REQUEST_ID.clone(), header);
+
+    res
+}
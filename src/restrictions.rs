@@ -0,0 +1,176 @@
+use std::collections::BTreeSet;
+use std::convert::Infallible;
+use std::path::{Component, Path};
+
+use async_trait::async_trait;
+use axum::extract::{FromRequest, RequestParts};
+
+use crate::{
+    auth::{self, AuthUser},
+    config::{self, Config},
+    library::{self, Item},
+    users::{self, Role},
+};
+
+/// The restricted tags hidden from whoever is making a request, all of
+/// them when nobody is logged in and none for admins.
+pub struct Restrictions {
+    hidden: BTreeSet<String>,
+}
+
+impl Restrictions {
+    pub fn for_user(config: &Config, user: Option<&AuthUser>) -> Self {
+        let allowed = match user {
+            Some(user) if user.role == Role::Admin => return Restrictions::none(),
+            Some(user) => users::allowed_tags(&user.name),
+            None => BTreeSet::new(),
+        };
+
+        Restrictions {
+            hidden: config
+                .restricted_tags
+                .iter()
+                .filter(|tag| !allowed.contains(*tag))
+                .cloned()
+                .collect(),
+        }
+    }
+
+    pub fn none() -> Self {
+        Restrictions {
+            hidden: BTreeSet::new(),
+        }
+    }
+
+    /// Whether the labels say `tags` may be shown.
+    pub fn allows<'a>(&self, tags: impl IntoIterator<Item = &'a String>) -> bool {
+        !tags.into_iter().any(|tag| self.hidden.contains(tag))
+    }
+
+    pub fn allows_item(&self, item: &Item) -> bool {
+        self.allows(&item.tags)
+    }
+
+    /// Whether the file at local `path` is in the folder of a show or
+    /// movie that may be shown, and of none that may not. Users with nothing
+    /// hidden from them may have any file, and everyone else is refused
+    /// files outside the library, like music, or when the library can't
+    /// be listed.
+    pub async fn allows_file(&self, config: &Config, path: &Path) -> bool {
+        if self.hidden.is_empty() {
+            return true;
+        }
+
+        let mut items = Vec::new();
+        for provider in library::selected(config, None) {
+            match provider.list(config).await {
+                Ok(listed) => items.extend(listed),
+                Err(e) => {
+                    tracing::warn!("Can't tell if {:?} is restricted: {:?}", path, e);
+                    return false;
+                }
+            }
+        }
+
+        self.allows_file_in(config, &items, path)
+    }
+
+    fn allows_file_in(&self, config: &Config, items: &[Item], path: &Path) -> bool {
+        if path
+            .components()
+            .any(|component| component == Component::ParentDir)
+        {
+            return false;
+        }
+
+        let mut containing = items
+            .iter()
+            .filter(|item| {
+                item.path
+                    .as_ref()
+                    .is_some_and(|folder| path.starts_with(config.local_path(Path::new(folder))))
+            })
+            .peekable();
+
+        containing.peek().is_some() && containing.all(|item| self.allows_item(item))
+    }
+}
+
+#[async_trait]
+impl<B: Send> FromRequest<B> for Restrictions {
+    type Rejection = Infallible;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let user = auth::authenticate(req.headers()).await.ok();
+
+        Ok(Restrictions::for_user(&config::get(), user.as_ref()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::library::Kind;
+
+    fn item(path: &str, tags: &[&str]) -> Item {
+        Item {
+            kind: Kind::Show,
+            id: 1,
+            title: path.into(),
+            year: None,
+            overview: None,
+            images: Vec::new(),
+            added: None,
+            files: None,
+            path: Some(path.into()),
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+        }
+    }
+
+    fn hiding(tag: &str) -> Restrictions {
+        Restrictions {
+            hidden: BTreeSet::from([tag.to_string()]),
+        }
+    }
+
+    #[test]
+    fn restricted_users_only_get_files_of_shows_they_may_see() {
+        config::init_for_tests();
+        let config = config::get();
+        let items = [
+            item("/tv/Cartoons", &[]),
+            item("/tv/Horror", &["adult"]),
+            // a hidden show in the folder of one that isn't
+            item("/tv/Cartoons/Horror Special", &["adult"]),
+        ];
+        let allows = |path: &str| hiding("adult").allows_file_in(&config, &items, Path::new(path));
+
+        assert!(allows("/tv/Cartoons/Season 1/S01E01.mkv"));
+        assert!(!allows("/tv/Horror/Season 1/S01E01.mkv"));
+        assert!(!allows("/tv/Cartoons/Horror Special/S00E01.mkv"));
+        assert!(!allows("/tv/Cartoons/../Horror/Season 1/S01E01.mkv"));
+        assert!(!allows("/tv/Unknown/S01E01.mkv"));
+        assert!(!allows("/music/Artist/track.flac"));
+        assert!(!allows("/var/lib/centarr/users.json"));
+    }
+
+    #[tokio::test]
+    async fn users_with_nothing_hidden_get_every_file() {
+        config::init_for_tests();
+        let config = config::get();
+
+        // without listing the library, whose Sonarr can't be reached
+        for path in ["/tv/Horror/S01E01.mkv", "/music/Artist/track.flac"] {
+            assert!(
+                Restrictions::none()
+                    .allows_file(&config, Path::new(path))
+                    .await
+            );
+        }
+        assert!(
+            !hiding("adult")
+                .allows_file(&config, Path::new("/tv/Horror/S01E01.mkv"))
+                .await
+        );
+    }
+}
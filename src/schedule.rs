@@ -0,0 +1,296 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use axum::{extract::Query, http::HeaderMap, routing::get, Json, Router};
+use chrono::{DateTime, Datelike, Days, NaiveDate, NaiveTime, Utc, Weekday};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    auth::AuthUser,
+    config,
+    dates::{self, TimeZone},
+    errors::ApiError,
+    playback::{self, WatchState},
+    restrictions::Restrictions,
+    sendfile, sonarr, store,
+};
+
+const WEEKDAYS: [&str; 7] = [
+    "monday",
+    "tuesday",
+    "wednesday",
+    "thursday",
+    "friday",
+    "saturday",
+    "sunday",
+];
+
+pub fn router() -> Router {
+    Router::new().route("/schedule", get(schedule))
+}
+
+#[derive(Deserialize)]
+struct ScheduleQuery {
+    /// `2022-W31`, or any date in the week, like `2022-08-03`.
+    week: Option<String>,
+    tz: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Schedule {
+    /// The ISO week, `2022-W31`.
+    week: String,
+    tz: String,
+    previous_week: String,
+    next_week: String,
+    /// Monday through Sunday.
+    days: Vec<Day>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Day {
+    date: String,
+    weekday: &'static str,
+    episodes: Vec<Airing>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Airing {
+    id: i32,
+    series_id: i32,
+    series_title: String,
+    season_number: i32,
+    episode_number: i32,
+    title: String,
+    air_date_utc: DateTime<Utc>,
+    /// When it airs in `tz`, `21:00`.
+    air_time: String,
+    is_aired: bool,
+    /// Until it airs, rounded up.
+    airs_in_minutes: Option<i64>,
+    has_file: bool,
+    watch_state: WatchState,
+    watch_url: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Episode {
+    id: i32,
+    series_id: i32,
+    season_number: i32,
+    episode_number: i32,
+    title: String,
+    air_date_utc: Option<DateTime<Utc>>,
+    #[serde(default)]
+    has_file: bool,
+    episode_file: Option<EpisodeFile>,
+}
+
+#[derive(Deserialize)]
+struct EpisodeFile {
+    id: i32,
+    path: String,
+}
+
+#[derive(Deserialize)]
+struct Series {
+    id: i32,
+    title: String,
+    #[serde(default)]
+    tags: Vec<i32>,
+}
+
+/// The Monday starting the week `week` names.
+fn week_start(week: &str) -> Option<NaiveDate> {
+    match week.split_once("-W") {
+        // only some years have a 53rd week
+        Some((year, number)) => {
+            NaiveDate::from_isoywd_opt(year.parse().ok()?, number.parse().ok()?, Weekday::Mon)
+        }
+        None => NaiveDate::parse_from_str(week, "%Y-%m-%d")
+            .ok()
+            .map(|date| date.week(Weekday::Mon).first_day()),
+    }
+}
+
+/// The ISO week of the week starting on Monday `monday`, which belongs to
+/// the year its Thursday is in.
+fn week_name(monday: NaiveDate) -> String {
+    let week = monday.iso_week();
+
+    format!("{}-W{:02}", week.year(), week.week())
+}
+
+/// `date` at midnight, in local seconds like [`TimeZone`] takes.
+fn midnight(date: NaiveDate) -> i64 {
+    date.and_time(NaiveTime::MIN).and_utc().timestamp()
+}
+
+/// Episodes airing in a week, by day in the time zone `tz` (UTC by
+/// default), with whether they can be watched yet.
+async fn schedule(
+    Query(query): Query<ScheduleQuery>,
+    headers: HeaderMap,
+    user: Option<AuthUser>,
+    restrictions: Restrictions,
+) -> Result<Json<Schedule>, ApiError> {
+    let viewer = user.as_ref().map(|user| user.name.as_str());
+    let config = config::get();
+    let now = Utc::now();
+    let tz = match query.tz.as_deref() {
+        Some(tz) => TimeZone::parse(tz).map_err(|e| ApiError::new(400, e))?,
+        None => TimeZone::utc(),
+    };
+
+    let monday = match query.week.as_deref() {
+        Some(week) => week_start(week).ok_or_else(|| {
+            ApiError::new(
+                400,
+                format!("{:?} isn't a week like 2022-W31 or a date", week),
+            )
+        })?,
+        None => DateTime::from_timestamp(tz.to_local(now.timestamp()), 0)
+            .unwrap_or_default()
+            .date_naive()
+            .week(Weekday::Mon)
+            .first_day(),
+    };
+    let start = tz.to_utc(midnight(monday));
+    let end = tz.to_utc(midnight(monday + Days::new(7)));
+
+    let invalid = |e: serde_json::Error| ApiError::empty(500, Some(e.to_string()));
+    let airs_this_week = |episode: &Episode| {
+        episode
+            .air_date_utc
+            .is_some_and(|aired| (start..end).contains(&aired.timestamp()))
+    };
+
+    // the synced library has every episode, Sonarr's calendar only when asked
+    let (series, episodes) = match store::library() {
+        Some(library) => {
+            let series = library
+                .series
+                .values()
+                .map(Series::deserialize)
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(invalid)?;
+            let episodes = library
+                .episodes
+                .values()
+                .flatten()
+                .filter_map(|episode| Episode::deserialize(episode).ok())
+                .filter(airs_this_week)
+                .collect::<Vec<_>>();
+
+            (series, episodes)
+        }
+        None => {
+            let body = sonarr::get("/series").await?;
+            let series = serde_json::from_str::<Vec<Series>>(&body).map_err(invalid)?;
+            let body = sonarr::get(&format!(
+                "/calendar?start={}&end={}&includeEpisodeFile=true",
+                dates::iso8601(start),
+                dates::iso8601(end)
+            ))
+            .await?;
+            let episodes = serde_json::from_str::<Vec<Value>>(&body)
+                .map_err(invalid)?
+                .iter()
+                .filter_map(|episode| Episode::deserialize(episode).ok())
+                .filter(airs_this_week)
+                .collect::<Vec<_>>();
+
+            (series, episodes)
+        }
+    };
+
+    let labels = sonarr::tag_labels(&config).await?;
+    let series = series
+        .into_iter()
+        .filter(|series| restrictions.allows(series.tags.iter().filter_map(|id| labels.get(id))))
+        .map(|series| (series.id, series.title))
+        .collect::<BTreeMap<_, _>>();
+
+    let mut days = (0..7)
+        .map(|day| Day {
+            date: (monday + Days::new(day as u64)).to_string(),
+            weekday: WEEKDAYS[day],
+            episodes: Vec::new(),
+        })
+        .collect::<Vec<_>>();
+
+    for episode in episodes {
+        let series_title = match series.get(&episode.series_id) {
+            Some(title) => title.clone(),
+            None => continue,
+        };
+        let air_date_utc = episode.air_date_utc.unwrap_or_default();
+        let local = DateTime::from_timestamp(tz.to_local(air_date_utc.timestamp()), 0)
+            .unwrap_or_default()
+            .naive_utc();
+        let is_aired = air_date_utc <= now;
+        let day = (local.date() - monday).num_days().clamp(0, 6) as usize;
+        let file = episode.episode_file.filter(|_| episode.has_file);
+        let play = file
+            .as_ref()
+            .and_then(|file| playback::get(viewer, &config.local_path(Path::new(&file.path))));
+
+        days[day].episodes.push(Airing {
+            id: episode.id,
+            series_id: episode.series_id,
+            series_title,
+            season_number: episode.season_number,
+            episode_number: episode.episode_number,
+            title: episode.title,
+            air_time: local.format("%H:%M").to_string(),
+            air_date_utc,
+            is_aired,
+            airs_in_minutes: (!is_aired).then(|| ((air_date_utc - now).num_seconds() + 59) / 60),
+            has_file: file.is_some(),
+            watch_state: WatchState::of(play, viewer),
+            watch_url: file
+                .map(|file| sendfile::episode_url(&headers, &config, file.id, &file.path)),
+        });
+    }
+
+    for day in &mut days {
+        day.episodes.sort_by(|a, b| {
+            (&a.air_date_utc, &a.series_title).cmp(&(&b.air_date_utc, &b.series_title))
+        });
+    }
+
+    Ok(Json(Schedule {
+        week: week_name(monday),
+        tz: tz.name,
+        previous_week: week_name(monday - Days::new(7)),
+        next_week: week_name(monday + Days::new(7)),
+        days,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn weeks_are_iso_weeks() {
+        let date = |date: &str| NaiveDate::parse_from_str(date, "%Y-%m-%d").unwrap();
+
+        assert_eq!(week_start("2022-W31"), Some(date("2022-08-01")));
+        assert_eq!(week_start("2022-08-07"), Some(date("2022-08-01")));
+        assert_eq!(week_start("2020-W53"), Some(date("2020-12-28")));
+        assert_eq!(week_start("2021-W53"), None);
+        assert_eq!(week_start("2022-W0"), None);
+        assert_eq!(week_start("2022-13-01"), None);
+
+        // the week a year starts in can belong to either year
+        assert_eq!(week_name(date("2024-12-30")), "2025-W01");
+        assert_eq!(week_name(date("2021-01-04")), "2021-W01");
+        assert_eq!(week_name(date("2020-12-28")), "2020-W53");
+    }
+}
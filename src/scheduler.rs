@@ -0,0 +1,432 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use chrono::{Datelike, Local, NaiveDate, NaiveDateTime, TimeZone, Timelike};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+use crate::{
+    cassettes::{self, Mode},
+    config::{self, Config},
+    dates, images, intros, jobs, reports, sync, trickplay,
+};
+
+/// How many years ahead the next run is looked for, schedules like
+/// `0 0 30 2 *` never match.
+const MAX_YEARS_AHEAD: i32 = 5;
+
+const MONTHS: [&str; 12] = [
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+const WEEKDAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+/// A cron expression, `minute hour day-of-month month day-of-week` in
+/// local time, each a `*`, a number, a range like `1-5` or a list of
+/// those, optionally with a step like `*/10`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cron {
+    /// Bit `n` is set when `n` matches.
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Like cron, a day matches either field when both are restricted.
+    any_day: bool,
+    any_weekday: bool,
+}
+
+/// The values `text` matches between `min` and `max`, as bits. `names` are
+/// what `min` and on can be called instead.
+fn field(text: &str, min: u32, max: u32, names: &[&str]) -> Result<u64, String> {
+    let value = |text: &str| -> Result<u32, String> {
+        let lowercase = text.to_lowercase();
+        let value = match names.iter().position(|name| *name == lowercase) {
+            Some(index) => min + index as u32,
+            None => text
+                .parse()
+                .map_err(|_| format!("{:?} is not a number", text))?,
+        };
+        match value {
+            // Sunday is both 0 and 7
+            7 if names == WEEKDAYS => Ok(0),
+            value if (min..=max).contains(&value) => Ok(value),
+            value => Err(format!("{} is not between {} and {}", value, min, max)),
+        }
+    };
+
+    let mut bits = 0;
+    for part in text.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => match step.parse::<u32>() {
+                Ok(step) if step > 0 => (range, Some(step)),
+                _ => return Err(format!("{:?} is not a step", step)),
+            },
+            None => (part, None),
+        };
+        let (first, last) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((first, last)) => (value(first)?, value(last)?),
+            // `5/15` counts from 5 to the end
+            None if step.is_some() => (value(range)?, max),
+            None => (value(range)?, value(range)?),
+        };
+        if first > last {
+            return Err(format!("{:?} is backwards", range));
+        }
+        for value in (first..=last).step_by(step.unwrap_or(1) as usize) {
+            bits |= 1 << value;
+        }
+    }
+
+    Ok(bits)
+}
+
+impl Cron {
+    pub fn parse(expression: &str) -> Result<Self, String> {
+        let expression = match expression.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            expression => expression,
+        };
+        let fields = expression.split_whitespace().collect::<Vec<_>>();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(format!(
+                "{:?} should have 5 fields, minute hour day month weekday",
+                expression
+            ));
+        };
+
+        Ok(Self {
+            minutes: field(minutes, 0, 59, &[])?,
+            hours: field(hours, 0, 23, &[])?,
+            days: field(days, 1, 31, &[])?,
+            months: field(months, 1, 12, &MONTHS)?,
+            weekdays: field(weekdays, 0, 7, &WEEKDAYS)?,
+            any_day: days == "*",
+            any_weekday: weekdays == "*",
+        })
+    }
+
+    fn matches_day(&self, date: NaiveDate) -> bool {
+        let day = self.days & 1 << date.day() != 0;
+        let weekday = self.weekdays & 1 << date.weekday().num_days_from_sunday() != 0;
+
+        match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        }
+    }
+
+    /// The first minute after `time` this matches.
+    pub fn next_after(&self, time: NaiveDateTime) -> Option<NaiveDateTime> {
+        let mut next = time.with_second(0)?.with_nanosecond(0)? + chrono::Duration::minutes(1);
+        let last_year = next.year() + MAX_YEARS_AHEAD;
+
+        while next.year() <= last_year {
+            let date = next.date();
+            if self.months & 1 << date.month() == 0 {
+                let (year, month) = match date.month() {
+                    12 => (date.year() + 1, 1),
+                    month => (date.year(), month + 1),
+                };
+                next = NaiveDate::from_ymd_opt(year, month, 1)?.and_hms_opt(0, 0, 0)?;
+            } else if !self.matches_day(date) {
+                next = date.succ_opt()?.and_hms_opt(0, 0, 0)?;
+            } else if self.hours & 1 << next.hour() == 0 {
+                next = next.with_minute(0)? + chrono::Duration::hours(1);
+            } else if self.minutes & 1 << next.minute() == 0 {
+                next += chrono::Duration::minutes(1);
+            } else {
+                return Some(next);
+            }
+        }
+
+        None
+    }
+}
+
+/// The jobs that run on a schedule, and can be run from the admin API.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Task {
+    Sync,
+    PrefetchImages,
+    PruneImages,
+    IntegrityReport,
+    Trickplay,
+    Intros,
+}
+
+impl Task {
+    pub const ALL: [Task; 6] = [
+        Task::Sync,
+        Task::PrefetchImages,
+        Task::PruneImages,
+        Task::IntegrityReport,
+        Task::Trickplay,
+        Task::Intros,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Task::Sync => "sync",
+            Task::PrefetchImages => "prefetch-images",
+            Task::PruneImages => "prune-images",
+            Task::IntegrityReport => "integrity-report",
+            Task::Trickplay => "trickplay",
+            Task::Intros => "intros",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Task> {
+        Task::ALL.into_iter().find(|task| task.name() == name)
+    }
+
+    fn default_schedule(self) -> &'static str {
+        match self {
+            // delta syncs can miss changes that don't show up in Sonarr's
+            // history, so everything is refetched daily
+            Task::Sync => "0 4 * * *",
+            Task::PrefetchImages => "*/10 * * * *",
+            Task::PruneImages => "0 * * * *",
+            Task::IntegrityReport => "0 5 * * *",
+            Task::Trickplay => "15 * * * *",
+            Task::Intros => "45 * * * *",
+        }
+    }
+
+    /// The configured cron expression, or the default one.
+    pub fn expression(self, config: &Config) -> &str {
+        config
+            .schedules
+            .get(self.name())
+            .map_or(self.default_schedule(), String::as_str)
+    }
+
+    /// When the task runs, `None` when it's turned off with `"off"`.
+    pub fn schedule(self, config: &Config) -> Option<Cron> {
+        match self.expression(config) {
+            "off" => None,
+            // the config checks schedules when it's loaded
+            schedule => Cron::parse(schedule).ok(),
+        }
+    }
+
+    /// Does the work, with a summary of what was done when there's one
+    /// worth showing.
+    async fn run(self) -> Result<Option<String>, String> {
+        let config = config::get();
+
+        match self {
+            Task::Sync if config.sync_interval.is_zero() => Ok(Some("syncing is off".into())),
+            Task::Sync => sync::full()
+                .await
+                .map(|_| None)
+                .map_err(|e| format!("{:?}", e)),
+            // artwork isn't recorded
+            Task::PrefetchImages
+                if !config.prefetch_images || cassettes::mode() == Some(Mode::Replay) =>
+            {
+                Ok(Some("prefetching is off".into()))
+            }
+            Task::PrefetchImages => images::prefetch(&config)
+                .await
+                .map(|fetched| Some(format!("fetched {} images", fetched))),
+            Task::PruneImages => {
+                let removed = images::trim(config.image_cache_size).await;
+                Ok(Some(format!("removed {} images", removed)))
+            }
+            Task::IntegrityReport => {
+                let report = reports::integrity_report()
+                    .await
+                    .map_err(|e| format!("{:?}", e))?;
+                let summary = report.summary();
+                if !report.is_clean() {
+                    tracing::warn!("The library has problems: {}", summary);
+                }
+                Ok(Some(summary))
+            }
+            Task::Trickplay if config.trickplay_widths.is_empty() => {
+                Ok(Some("no thumbnail widths are configured".into()))
+            }
+            Task::Trickplay => trickplay::scan(&config)
+                .await
+                .map(|queued| Some(format!("queued {} episodes", queued))),
+            Task::Intros if !config.detect_intros => Ok(Some("intro detection is off".into())),
+            Task::Intros => intros::detect().await.map(|_| None),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Succeeded,
+    Failed,
+}
+
+/// How a task went the last time it ran.
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Status {
+    pub running: bool,
+    pub last_started: Option<String>,
+    pub last_finished: Option<String>,
+    pub last_duration_ms: Option<u64>,
+    pub last_outcome: Option<Outcome>,
+    pub message: Option<String>,
+}
+
+static STATUS: Lazy<Mutex<BTreeMap<Task, Status>>> = Lazy::new(Default::default);
+
+fn now() -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    dates::iso8601(secs as i64)
+}
+
+pub fn status(task: Task) -> Status {
+    STATUS
+        .lock()
+        .unwrap()
+        .get(&task)
+        .cloned()
+        .unwrap_or_default()
+}
+
+/// When `task` runs next, in UTC.
+pub fn next_run(task: Task, config: &Config) -> Option<String> {
+    let next = task
+        .schedule(config)?
+        .next_after(Local::now().naive_local())?;
+    // skipped by a DST change, it'll run the hour after
+    let next = Local
+        .from_local_datetime(&next)
+        .earliest()
+        .unwrap_or_else(|| Local.from_utc_datetime(&next));
+
+    Some(dates::iso8601(next.timestamp()))
+}
+
+/// Queues `task` as a job, returning its id, unless it's queued or
+/// running already.
+pub async fn start(task: Task) -> Option<u64> {
+    jobs::enqueue(jobs::Kind::Task {
+        name: task.name().into(),
+    })
+    .await
+}
+
+/// Runs `task` now, keeping track of how it went. Called by the job
+/// workers.
+pub async fn execute(task: Task) -> Result<Option<String>, String> {
+    {
+        let mut statuses = STATUS.lock().unwrap();
+        let status = statuses.entry(task).or_default();
+        status.running = true;
+        status.last_started = Some(now());
+    }
+
+    tracing::debug!("Running {}", task.name());
+    let started = Instant::now();
+    let result = task.run().await;
+    let duration = started.elapsed();
+
+    let mut statuses = STATUS.lock().unwrap();
+    let status = statuses.entry(task).or_default();
+    status.running = false;
+    status.last_finished = Some(now());
+    status.last_duration_ms = Some(duration.as_millis() as u64);
+    match &result {
+        Ok(message) => {
+            tracing::debug!("{} took {:?}", task.name(), duration);
+            status.last_outcome = Some(Outcome::Succeeded);
+            status.message = message.clone();
+        }
+        Err(e) => {
+            tracing::warn!("{} failed: {}", task.name(), e);
+            status.last_outcome = Some(Outcome::Failed);
+            status.message = Some(e.clone());
+        }
+    }
+
+    result
+}
+
+/// Starts every task whose schedule matches, checking once a minute.
+/// Minutes missed while the machine slept run their tasks once on waking.
+pub async fn run() {
+    let mut checked = Local::now().naive_local();
+
+    loop {
+        let wait = 60 - checked.second() as u64;
+        tokio::time::sleep(Duration::from_secs(wait)).await;
+
+        let now = Local::now().naive_local();
+        let config = config::get();
+        for task in Task::ALL {
+            let due = task
+                .schedule(&config)
+                .and_then(|cron| cron.next_after(checked))
+                .filter(|next| *next <= now);
+            if due.is_some() && start(task).await.is_none() {
+                tracing::debug!("Not queueing {}, it's queued already", task.name());
+            }
+        }
+        checked = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(text: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    fn next(cron: &str, after: &str) -> String {
+        let next = Cron::parse(cron).unwrap().next_after(at(after)).unwrap();
+        next.format("%Y-%m-%d %H:%M").to_string()
+    }
+
+    #[test]
+    fn cron_finds_the_next_minute() {
+        assert_eq!(next("*/10 * * * *", "2023-01-01 10:05"), "2023-01-01 10:10");
+        assert_eq!(next("0 4 * * *", "2023-01-01 04:00"), "2023-01-02 04:00");
+        assert_eq!(
+            next("30 9 * * mon-fri", "2023-01-06 10:00"),
+            "2023-01-09 09:30"
+        );
+        assert_eq!(next("@monthly", "2023-12-15 00:00"), "2024-01-01 00:00");
+        // either the day of the month or the weekday
+        assert_eq!(next("0 0 13 * 5", "2023-01-01 00:00"), "2023-01-06 00:00");
+        assert_eq!(next("0 0 29 feb *", "2023-01-01 00:00"), "2024-02-29 00:00");
+    }
+
+    #[test]
+    fn cron_rejects_nonsense() {
+        for cron in [
+            "* * * *",
+            "60 * * * *",
+            "*/0 * * * *",
+            "5-1 * * * *",
+            "0 0 * foo *",
+        ] {
+            assert!(Cron::parse(cron).is_err(), "{}", cron);
+        }
+        assert_eq!(
+            Cron::parse("0 0 30 2 *")
+                .unwrap()
+                .next_after(at("2023-01-01 00:00")),
+            None
+        );
+    }
+}
@@ -1,144 +1,897 @@
-use std::net::SocketAddr;
-use std::os::unix::prelude::AsRawFd;
-use std::path::PathBuf;
-use std::time::SystemTime;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::prelude::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
 
-use axum::http::{HeaderMap, HeaderValue, Request};
-use nix::errno::Errno;
-use regex::Regex;
-use tokio::io::{AsyncReadExt, AsyncWriteExt, BufWriter};
-use tokio::net::{TcpListener, TcpStream};
+use async_trait::async_trait;
+use axum::http::{header, HeaderMap, HeaderValue, Request, StatusCode};
+use nix::sys::socket::{setsockopt, sockopt};
+use once_cell::sync::{Lazy, OnceCell};
+use percent_encoding::{percent_decode_str, percent_encode, NON_ALPHANUMERIC};
+use serde::Deserialize;
+use tokio::io::Interest;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UnixStream};
+use tracing::Instrument;
 
-static CHUNK_SIZE: i64 = 1_048_576;
+use crate::{
+    auth::{self, AuthUser},
+    config::{Config, ListenAddr},
+    edges,
+    events::{self, Event},
+    files,
+    listen::{self, Listener},
+    playback,
+    restrictions::Restrictions,
+    sonarr,
+    storage::{self, Unavailable},
+    store, stream_stats,
+    transcode::{self, AudioCodec, MediaInfo, Mode},
+    users::{self, Device},
+};
 
-fn parse_request(buf: &[u8]) -> Option<Request<()>> {
-    let string = String::from_utf8(buf.to_vec()).unwrap();
-    let mut request = Request::builder();
-    let mut complete = false;
+/// The smallest chunks are after a seek with `adaptive_chunks` on, so the
+/// player gets going quickly.
+const MIN_ADAPTIVE_CHUNK_SIZE: i64 = 128 * 1024;
+/// Chunks grow up to this many times the configured size while reading on.
+const MAX_ADAPTIVE_CHUNK_FACTOR: i64 = 4;
+/// Requests with longer headers are turned away.
+const MAX_HEADER_SIZE: usize = 16 * 1024;
+/// How long a client gets to send its request.
+const HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long unread data from a client is drained after responding.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+/// How often a file that's still being copied in is checked for more.
+const GROWTH_POLL: Duration = Duration::from_secs(1);
+/// How much faster than its bitrate a stream is sent after the burst,
+/// enough for the player to stay ahead through busier scenes.
+const PACE_FACTOR: f64 = 1.5;
+/// Bitrates are forgotten once this many files were probed.
+const MAX_BITRATES: usize = 1000;
 
-    for raw_line in string.lines() {
-        let line = raw_line.replace('\0', "");
+/// Bitrates ffprobe read, in bits per second, by file and its size so a
+/// replaced file is probed again. `None` when it couldn't tell.
+type Bitrates = HashMap<(PathBuf, u64), Option<u64>>;
 
-        if line.contains("HTTP/1.1") {
-            let mut parts = line.split(' ');
-            let method = parts.next().unwrap();
-            let uri = parts.next().unwrap();
+static BITRATES: Lazy<Mutex<Bitrates>> = Lazy::new(Default::default);
 
-            request = request.method(method).uri(uri);
-            continue;
+/// Where the streaming server ended up listening, which differs from the
+/// configured addresses when a port is 0 or systemd handed over sockets.
+static LISTENING: OnceCell<Vec<ListenAddr>> = OnceCell::new();
+
+/// A client's connection, over TCP or a Unix socket.
+#[async_trait]
+pub trait Connection: AsyncRead + AsyncWrite + AsRawFd + Unpin + Send + Sync {
+    /// Hands up to `count` bytes of `file` from `offset` to the kernel to
+    /// send, once the socket has room for them.
+    async fn send_file(&self, file: RawFd, offset: &mut i64, count: usize) -> io::Result<usize>;
+}
+
+macro_rules! connection {
+    ($stream:ty) => {
+        #[async_trait]
+        impl Connection for $stream {
+            async fn send_file(
+                &self,
+                file: RawFd,
+                offset: &mut i64,
+                count: usize,
+            ) -> io::Result<usize> {
+                loop {
+                    self.writable().await?;
+                    let sent = self.try_io(Interest::WRITABLE, || {
+                        nix::sys::sendfile::sendfile(self.as_raw_fd(), file, Some(offset), count)
+                            .map_err(io::Error::from)
+                    });
+                    match sent {
+                        // it was readied by something else, wait for room again
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                        sent => return sent,
+                    }
+                }
+            }
+        }
+    };
+}
+
+connection!(TcpStream);
+connection!(UnixStream);
+
+/// How sending a response ended.
+#[derive(PartialEq, Eq)]
+enum Ended {
+    Completed,
+    /// The client closed the connection first, like when a player stops
+    /// or seeks.
+    Left,
+    Failed,
+}
+
+/// The name in a `Host` header without its port, IPv6 literals in
+/// brackets.
+fn host_name(host: &str) -> String {
+    if let Ok(ip) = host.parse::<Ipv6Addr>() {
+        return format!("[{}]", ip);
+    }
+
+    match host.rsplit_once(':') {
+        Some((name, port))
+            if port.chars().all(|c| c.is_ascii_digit())
+                && (!name.contains(':') || name.ends_with(']')) =>
+        {
+            name.into()
         }
+        _ => host.into(),
+    }
+}
+
+/// Where the client reached the API, with the port swapped for ours on
+/// the address family the client used when there's a choice. When
+/// streaming is only on a Unix socket it's behind the same reverse proxy
+/// as the API, which passes `/stream` on to it.
+fn stream_origin(headers: &HeaderMap, config: &Config) -> String {
+    let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+    let host = header(header::HOST.as_str()).unwrap_or("localhost");
+    let name = host_name(host);
+
+    let tcp = LISTENING
+        .get()
+        .unwrap_or(&config.stream_addr)
+        .iter()
+        .filter_map(|addr| match addr {
+            ListenAddr::Tcp(addr) => Some(addr),
+            ListenAddr::Unix(_) => None,
+        })
+        .collect::<Vec<_>>();
+    let ipv6 = name.starts_with('[');
+    let port = tcp
+        .iter()
+        .find(|addr| addr.is_ipv6() == ipv6)
+        .or_else(|| tcp.first())
+        .map(|addr| addr.port());
+
+    match port {
+        Some(port) => format!("http://{}:{}", name, port),
+        None => format!(
+            "{}://{}",
+            header("X-Forwarded-Proto").unwrap_or("http"),
+            host
+        ),
+    }
+}
+
+fn content_type(path: &Path) -> HeaderValue {
+    match mime_guess::from_path(path).first_raw() {
+        // browsers refuse to play e.g. video/x-matroska in a <video> tag but are
+        // happy to try when told it's webm, which is what most of the library is
+        Some(mime) if mime.starts_with("video/") => HeaderValue::from_static("video/webm"),
+        Some(mime) => HeaderValue::from_static(mime),
+        None => HeaderValue::from_static("application/octet-stream"),
+    }
+}
+
+/// Local paths of episode files by id, remembered as their urls are
+/// handed out when the library isn't synced.
+static EPISODE_FILES: Lazy<RwLock<HashMap<i32, PathBuf>>> = Lazy::new(Default::default);
 
-        if line.contains(':') {
-            let mut parts = line.split(": ");
-            let key = parts.next().unwrap();
-            let value = parts.next().unwrap();
+/// Adds the access token the request came with to `url` when it's needed,
+/// as players can't set headers.
+fn with_token(mut url: String, headers: &HeaderMap, config: &Config) -> String {
+    let token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if let (true, Some(token)) = (config.require_auth, token) {
+        url.push(if url.contains('?') { '&' } else { '?' });
+        url.push_str("token=");
+        url.push_str(&urlencoding::encode(token));
+    }
+
+    url
+}
+
+/// Url streaming the file an upstream service knows as `remote_path`.
+pub fn watch_url(headers: &HeaderMap, config: &Config, remote_path: &str) -> String {
+    let path = config.local_path(Path::new(remote_path));
+    let target = format!(
+        "/stream?file={}",
+        percent_encode(path.as_os_str().as_bytes(), NON_ALPHANUMERIC)
+    );
+    if let Some(url) = edges::url(&path, &target) {
+        return url;
+    }
+
+    let url = format!("{}{}", stream_origin(headers, config), target);
+
+    with_token(url, headers, config)
+}
+
+/// Url streaming the episode file Sonarr knows by `id`, without telling
+/// the client where it is on disk.
+pub fn episode_url(headers: &HeaderMap, config: &Config, id: i32, remote_path: &str) -> String {
+    let path = config.local_path(Path::new(remote_path));
+    let target = format!("/stream/{}", id);
+    if let Some(url) = edges::url(&path, &target) {
+        return url;
+    }
+    if store::library().is_none() {
+        EPISODE_FILES.write().unwrap().insert(id, path);
+    }
+
+    let url = format!("{}{}", stream_origin(headers, config), target);
 
-            let maybe_valid_header = HeaderValue::from_str(value);
-            if let Ok(valid_header) = maybe_valid_header {
-                request = request.header(key, valid_header);
+    with_token(url, headers, config)
+}
+
+#[derive(Deserialize)]
+struct EpisodeFile {
+    path: String,
+}
+
+/// Local path of the episode file Sonarr knows by `id`.
+async fn episode_file(config: &Config, id: i32) -> Option<PathBuf> {
+    let remote_path = match store::library() {
+        Some(library) => library
+            .episode_file(id)
+            .and_then(|file| file.get("path")?.as_str().map(String::from))?,
+        None => {
+            if let Some(path) = EPISODE_FILES.read().unwrap().get(&id) {
+                return Some(path.clone());
             }
-            continue;
+
+            let body = sonarr::get(&format!("/episodefile/{}", id)).await.ok()?;
+            serde_json::from_str::<EpisodeFile>(&body).ok()?.path
         }
+    };
 
-        if line.is_empty() {
-            complete = true;
+    let path = config.local_path(Path::new(&remote_path));
+    if store::library().is_none() {
+        EPISODE_FILES.write().unwrap().insert(id, path.clone());
+    }
+
+    Some(path)
+}
+
+/// What Sonarr read from the episode file it knows by `id`.
+async fn media_info(id: i32) -> Option<MediaInfo> {
+    let file = match store::library() {
+        Some(library) => library.episode_file(id)?.clone(),
+        None => {
+            let body = sonarr::get(&format!("/episodefile/{}", id)).await.ok()?;
+            serde_json::from_str(&body).ok()?
+        }
+    };
+
+    MediaInfo::deserialize(file.get("mediaInfo")?).ok()
+}
+
+/// The local path a request is for, from `/stream/<episode file id>` or
+/// `?file=<path>`.
+async fn requested_file(config: &Config, req: &Request<()>) -> Result<PathBuf, HttpError> {
+    match req.uri().path().strip_prefix("/stream/") {
+        Some(id) => {
+            let id = id.parse().map_err(|_| {
+                HttpError::new(StatusCode::BAD_REQUEST, format!("{:?} is not an id", id))
+            })?;
+
+            episode_file(config, id).await.ok_or_else(|| {
+                HttpError::new(
+                    StatusCode::NOT_FOUND,
+                    format!("There's no episode file {}", id),
+                )
+            })
+        }
+        None => {
+            let path = query_param(req, "file")
+                .map(|path| PathBuf::from(OsString::from_vec(path)))
+                .ok_or_else(|| {
+                    HttpError::new(
+                        StatusCode::BAD_REQUEST,
+                        "Ask for /stream/<episode file id> or ?file=<path>",
+                    )
+                })?;
+            // checked before looking, so it can't tell what exists elsewhere
+            if !storage::in_roots(config, &path) {
+                return Err(not_in_roots());
+            }
+
+            Ok(path)
         }
     }
+}
 
-    if !complete {
+/// The version of the file the request asks for, `?audioOnly=true` for
+/// just the audio as opus or, with `&audioCodec=aac`, AAC. Remuxing is
+/// picked for it rather than asked for.
+fn requested_mode(req: &Request<()>) -> Result<Option<Mode>, &'static str> {
+    if query_param(req, "audioOnly").as_deref() != Some(b"true") {
+        return Ok(None);
+    }
+
+    match query_param(req, "audioCodec") {
+        None => Ok(Some(Mode::AudioOnly(AudioCodec::Opus))),
+        Some(codec) => AudioCodec::parse(&codec)
+            .map(|codec| Some(Mode::AudioOnly(codec)))
+            .ok_or("The audio codec is opus or aac"),
+    }
+}
+
+/// Remuxing, for episodes Sonarr read h264 and AAC from in another
+/// container, unless the request has `?remux=false`.
+async fn negotiate(req: &Request<()>, filename: &Path) -> Option<Mode> {
+    if query_param(req, "remux").as_deref() == Some(b"false") {
         return None;
     }
+    let id = req.uri().path().strip_prefix("/stream/")?.parse().ok()?;
 
-    Some(request.body(()).unwrap())
+    transcode::negotiate(filename, &media_info(id).await?)
 }
 
-async fn get_request_from_stream(socket: &mut TcpStream) -> Request<()> {
-    let mut req = None;
-    let mut buf = vec![0; 1024];
-    let mut writer = BufWriter::new(&mut buf);
+fn not_in_roots() -> HttpError {
+    HttpError::new(
+        StatusCode::FORBIDDEN,
+        "Only files in the media roots can be streamed by path",
+    )
+}
 
-    for _ in 1..5 {
-        let mut temp_buf = vec![0; 1024];
-        socket.read_buf(&mut temp_buf).await.unwrap();
-        writer.write_all(&temp_buf).await.unwrap();
+/// Decodes a query string component to the bytes it stands for, `+`
+/// being a space as in forms.
+fn decode_component(component: &str) -> Vec<u8> {
+    percent_decode_str(&component.replace('+', " ")).collect()
+}
 
-        let maybe_req = parse_request(writer.buffer());
+/// The decoded value of `name` in the query string. Players sometimes add
+/// a parameter that's already there, the first one that isn't empty is
+/// used then. Paths don't have to be UTF-8, so neither are values.
+fn query_param(req: &Request<()>, name: &str) -> Option<Vec<u8>> {
+    req.uri()
+        .query()?
+        .split('&')
+        .map(|pair| pair.split_once('=').unwrap_or((pair, "")))
+        .map(|(key, value)| (decode_component(key), decode_component(value)))
+        .find(|(key, value)| key == name.as_bytes() && !value.is_empty())
+        .map(|(_, value)| value)
+}
 
-        if let Some(inner) = maybe_req {
-            req = Some(inner);
-            break;
+/// Percent-encodes what players leave unescaped in the request target but
+/// urls can't contain, like non-ASCII file names or `|`.
+fn escape_target(target: &[u8]) -> String {
+    let mut escaped = String::with_capacity(target.len());
+    for byte in target {
+        match byte {
+            b'!'..=b'~' if !b"\"<>\\^`{|}".contains(byte) => escaped.push(*byte as char),
+            _ => escaped.push_str(&format!("%{:02X}", byte)),
         }
     }
 
-    if req.is_none() {
-        panic!("Could not parse request");
+    escaped
+}
+
+/// Parses the request line and headers, `head` ending before the blank
+/// line. The target can be a path or an absolute url, as some players send
+/// those. Headers with values that aren't valid are left out.
+fn parse_request(head: &[u8]) -> Option<Request<()>> {
+    let mut lines = head
+        .split(|byte| *byte == b'\n')
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line));
+
+    // the target is left as bytes, players send file names as they are
+    let mut parts = lines.next()?.split(|byte| *byte == b' ');
+    let (method, uri, version) = (parts.next()?, parts.next()?, parts.next()?);
+    if !version.starts_with(b"HTTP/1.") || parts.next().is_some() {
+        return None;
     }
 
-    req.unwrap()
+    let mut request = Request::builder().method(method).uri(escape_target(uri));
+    for line in lines {
+        let colon = line.iter().position(|byte| *byte == b':')?;
+        let name = std::str::from_utf8(&line[..colon]).ok()?;
+        let value = line[colon + 1..].trim_ascii();
+
+        if let Ok(value) = HeaderValue::from_bytes(value) {
+            request = request.header(name, value);
+        }
+    }
+
+    request.body(()).ok()
 }
 
-pub async fn server() {
-    let addr = "0.0.0.0:3001".parse::<SocketAddr>().unwrap();
+/// Why a request couldn't be read.
+enum ReadError {
+    /// The client went away before sending it all.
+    Closed,
+    TimedOut,
+    TooLarge,
+    Invalid,
+}
 
-    let listener = TcpListener::bind(&addr).await.unwrap();
-    tracing::debug!("Listening on: http://{}", addr);
+/// Reads up to the blank line ending the headers, leaving it out.
+async fn read_head(socket: &mut impl Connection) -> Result<Vec<u8>, ReadError> {
+    let mut head = Vec::with_capacity(1024);
+    let mut buf = [0; 1024];
 
     loop {
-        let (mut stream, addr) = listener.accept().await.unwrap();
+        let read = socket.read(&mut buf).await.map_err(|_| ReadError::Closed)?;
+        if read == 0 {
+            return Err(ReadError::Closed);
+        }
 
-        tokio::spawn(async move {
-            process(&mut stream, addr).await;
-        });
+        // the blank line can be split over two reads
+        let from = head.len().saturating_sub(3);
+        head.extend_from_slice(&buf[..read]);
+        if let Some(end) = head[from..].windows(4).position(|w| w == b"\r\n\r\n") {
+            head.truncate(from + end);
+            return Ok(head);
+        }
+
+        if head.len() > MAX_HEADER_SIZE {
+            return Err(ReadError::TooLarge);
+        }
     }
 }
 
-pub async fn process(stream: &mut TcpStream, addr: SocketAddr) {
-    let req = get_request_from_stream(stream).await;
-    tracing::debug!("{:?} Parsed request", addr);
+async fn get_request_from_stream(socket: &mut impl Connection) -> Result<Request<()>, ReadError> {
+    let head = tokio::time::timeout(HEADER_TIMEOUT, read_head(socket))
+        .await
+        .map_err(|_| ReadError::TimedOut)??;
 
-    let mut range = "bytes=0-";
-    let maybe_range_header = req
-        .headers()
-        .iter()
-        .find(|(name, _)| name == &axum::http::header::RANGE);
-    if let Some((_, value)) = maybe_range_header {
-        range = value.to_str().unwrap();
+    parse_request(&head).ok_or(ReadError::Invalid)
+}
+
+/// A request answered with `status` and `message` instead of the file.
+struct HttpError {
+    status: StatusCode,
+    message: String,
+    headers: HeaderMap,
+}
+
+impl HttpError {
+    fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        HttpError {
+            status,
+            message: message.into(),
+            headers: HeaderMap::new(),
+        }
     }
 
-    tracing::debug!("{:?} Has range: {:?}", addr, range);
+    fn header(mut self, name: header::HeaderName, value: HeaderValue) -> Self {
+        self.headers.insert(name, value);
+        self
+    }
+}
+
+impl From<ReadError> for HttpError {
+    fn from(e: ReadError) -> Self {
+        match e {
+            // nobody is listening, but it has to be something
+            ReadError::Closed => HttpError::new(StatusCode::BAD_REQUEST, "Incomplete request"),
+            ReadError::TimedOut => {
+                HttpError::new(StatusCode::REQUEST_TIMEOUT, "The request took too long")
+            }
+            ReadError::TooLarge => HttpError::new(
+                StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+                format!("Headers can't be over {} bytes", MAX_HEADER_SIZE),
+            ),
+            ReadError::Invalid => HttpError::new(StatusCode::BAD_REQUEST, "Malformed request"),
+        }
+    }
+}
+
+/// Sends `error` as the whole response.
+async fn reject(stream: &mut impl Connection, error: HttpError) {
+    let mut response = format!(
+        "HTTP/1.1 {} {}\r\n",
+        error.status.as_u16(),
+        error.status.canonical_reason().unwrap_or_default()
+    );
+    for (name, value) in &error.headers {
+        response.push_str(&format!(
+            "{}: {}\r\n",
+            name,
+            value.to_str().unwrap_or_default()
+        ));
+    }
+    response.push_str(&format!(
+        "Content-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        error.message.len(),
+        error.message
+    ));
+
+    let _ = stream.write_all(response.as_bytes()).await;
+    close(stream).await;
+}
+
+/// Ends the response and waits a moment for the client to stop sending,
+/// as closing with unread data resets the connection and can cut off what
+/// was sent last.
+async fn close(stream: &mut impl Connection) {
+    let _ = stream.shutdown().await;
+
+    let mut buf = [0; 4096];
+    let _ = tokio::time::timeout(CLOSE_TIMEOUT, async {
+        while matches!(stream.read(&mut buf).await, Ok(read) if read > 0) {}
+    })
+    .await;
+}
 
-    let path_encoded = req.uri().to_string().replace("/?file=", "");
-    let path_decoded = urlencoding::decode(path_encoded.as_str()).unwrap();
-    let filename = PathBuf::from(path_decoded.to_string());
+/// Sleeps until sending `sent` bytes since `started` no longer exceeds `rate` bytes per second.
+pub async fn throttle(rate: u64, sent: u64, started: Instant) {
+    let due = Duration::from_secs_f64(sent as f64 / rate as f64);
+    let elapsed = started.elapsed();
+
+    if due > elapsed {
+        tokio::time::sleep(due - elapsed).await;
+    }
+}
+
+#[derive(Deserialize)]
+struct Probe {
+    format: ProbeFormat,
+}
+
+#[derive(Deserialize)]
+struct ProbeFormat {
+    bit_rate: Option<String>,
+}
+
+/// The overall bitrate of the file at `path`, `len` bytes long, in bits
+/// per second.
+async fn bitrate(path: PathBuf, len: u64) -> Option<u64> {
+    let key = (path, len);
+    if let Some(bitrate) = BITRATES.lock().unwrap().get(&key) {
+        return *bitrate;
+    }
+
+    let ffprobe = crate::config::get().ffprobe_path.clone();
+    let output = tokio::process::Command::new(&ffprobe)
+        .args(["-v", "quiet", "-print_format", "json"])
+        .args(["-show_entries", "format=bit_rate"])
+        .arg(&key.0)
+        .output()
+        .await;
+    let bitrate = match output {
+        Ok(output) => serde_json::from_slice::<Probe>(&output.stdout)
+            .ok()
+            .and_then(|probe| probe.format.bit_rate?.parse().ok())
+            .filter(|bitrate| *bitrate > 0),
+        Err(e) => {
+            tracing::warn!("{:?} can't be run to pace streams: {}", ffprobe, e);
+            None
+        }
+    };
+    if bitrate.is_none() {
+        tracing::debug!("No bitrate for {:?}, it's streamed without pacing", key.0);
+    }
+
+    let mut bitrates = BITRATES.lock().unwrap();
+    if bitrates.len() >= MAX_BITRATES {
+        bitrates.clear();
+    }
+    bitrates.insert(key, bitrate);
+
+    bitrate
+}
+
+/// Applies the configured socket options to a streaming connection.
+fn tune(stream: &TcpStream, config: &Config) -> nix::Result<()> {
+    let fd = stream.as_raw_fd();
+
+    if config.tcp_nodelay {
+        setsockopt(fd, sockopt::TcpNoDelay, &true)?;
+    }
+    if let Some(size) = config.send_buffer_size {
+        setsockopt(fd, sockopt::SndBuf, &(size as usize))?;
+    }
+    if let Some(idle) = config.tcp_keepalive {
+        let secs = idle.as_secs().min(u32::MAX as u64) as u32;
+        setsockopt(fd, sockopt::KeepAlive, &true)?;
+        setsockopt(fd, sockopt::TcpKeepIdle, &secs)?;
+        setsockopt(fd, sockopt::TcpKeepInterval, &secs)?;
+    }
+
+    Ok(())
+}
+
+/// Peers on a Unix socket are on this host, usually a reverse proxy.
+const UNIX_PEER: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+
+pub async fn server(listeners: Vec<Listener>) {
+    let _ = LISTENING.set(listeners.iter().filter_map(Listener::addr).collect());
+
+    listen::serve_each(listeners, serve).await;
+}
+
+async fn serve(listener: Listener) {
+    tracing::debug!("Streaming on {}", listener.describe());
+
+    match listener {
+        Listener::Tcp(listener) => loop {
+            let (mut stream, addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    tracing::warn!("Can't accept a connection: {}", e);
+                    continue;
+                }
+            };
+            if let Err(e) = tune(&stream, &crate::config::get()) {
+                tracing::warn!("{:?} Can't set socket options: {}", addr, e);
+            }
+
+            tokio::spawn(async move {
+                process(&mut stream, addr)
+                    .instrument(tracing::info_span!("stream", %addr))
+                    .await;
+            });
+        },
+        Listener::Unix(listener, _file) => loop {
+            let mut stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    tracing::warn!("Can't accept a connection: {}", e);
+                    continue;
+                }
+            };
+
+            tokio::spawn(async move {
+                process(&mut stream, UNIX_PEER)
+                    .instrument(tracing::info_span!("stream", addr = "unix"))
+                    .await;
+            });
+        },
+    }
+}
+
+/// What's needed to send a file once the request checks out.
+struct Opened {
+    filename: PathBuf,
+    /// A version of the file made for the request, when it asked for one.
+    transcoded: Option<(Mode, PathBuf)>,
+    file: tokio::fs::File,
+    len: u64,
+    /// How long the file itself is, which plays are kept against.
+    source_len: u64,
+    /// Inclusive, `None` for the whole file.
+    range: Option<(u64, u64)>,
+    /// Still being copied in, so sent as it grows.
+    growing: bool,
+    user: Option<AuthUser>,
+    device: Option<Device>,
+}
+
+/// Checks who's asking for which file and opens it.
+async fn open(req: &Request<()>, addr: SocketAddr) -> Result<Opened, HttpError> {
+    let config = crate::config::get();
+    // signed by the primary this is an edge for, which checked who's asking
+    let vouched = req
+        .uri()
+        .path_and_query()
+        .is_some_and(|target| edges::vouched(target.as_str()));
+    let user = match query_param(req, "token").and_then(|token| String::from_utf8(token).ok()) {
+        Some(token) => auth::verify(&token).await,
+        None => None,
+    };
+    if config.require_auth && user.is_none() && !vouched {
+        return Err(HttpError::new(
+            StatusCode::UNAUTHORIZED,
+            "A valid ?token= is required",
+        ));
+    }
+
+    let filename = requested_file(&config, req).await?;
+    let mode = match requested_mode(req) {
+        Ok(Some(mode)) => Some(mode),
+        Ok(None) => negotiate(req, &filename).await,
+        Err(e) => return Err(HttpError::new(StatusCode::BAD_REQUEST, e)),
+    };
+    let device = user.as_ref().and_then(|user| {
+        let id = user.device.as_ref()?;
+        users::device(&user.name, id)
+    });
+
+    let restrictions = Restrictions::for_user(&config, user.as_ref());
+    if !vouched && !restrictions.allows_file(&config, &filename).await {
+        return Err(HttpError::new(
+            StatusCode::FORBIDDEN,
+            "You're not allowed this file",
+        ));
+    }
 
     tracing::debug!("{:?} Opening file: {:?}", addr, filename);
 
-    let file = tokio::fs::OpenOptions::new()
-        .read(true)
-        .write(false)
-        .open(&filename)
-        .await
-        .unwrap();
-    tracing::debug!("{:?} Opened file {:?}", addr, filename);
-    let metadata = file.metadata().await.unwrap();
-    let mut start_index;
-    let mut end_index = metadata.len() as i64;
+    let (resolved, metadata) = storage::resolve(&config, &filename).await.map_err(|e| {
+        let (status, message) = match e {
+            Unavailable::Missing => (StatusCode::NOT_FOUND, "The file doesn't exist".into()),
+            Unavailable::Offline(e) => {
+                tracing::warn!("{:?} Storage offline: {}", addr, e);
+                (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    format!("Storage offline: {}", e),
+                )
+            }
+            Unavailable::Unreadable(e) => (
+                match e.kind() {
+                    io::ErrorKind::PermissionDenied => StatusCode::FORBIDDEN,
+                    _ => StatusCode::INTERNAL_SERVER_ERROR,
+                },
+                format!("The file can't be read: {}", e),
+            ),
+        };
+        tracing::debug!("{:?} Can't open {:?}: {}", addr, filename, message);
+        HttpError::new(status, message)
+    })?;
+    if resolved != filename {
+        tracing::debug!("{:?} {:?} resolves to {:?}", addr, filename, resolved);
+    }
+    // episode files are wherever Sonarr says, paths asked for have to stay
+    // in the roots once symlinks are followed
+    let by_id = req.uri().path().starts_with("/stream/");
+    if !by_id && !storage::resolved_in_roots(&config, &resolved).await {
+        tracing::debug!("{:?} {:?} is outside the media roots", addr, resolved);
+        return Err(not_in_roots());
+    }
 
-    let captures = Regex::new(r"bytes=(\d+)-(\d+)?")
-        .unwrap()
-        .captures(range)
-        .unwrap();
-    let start = captures.get(1).unwrap().as_str();
-    start_index = start.parse::<i64>().unwrap();
+    // a file that's still growing is sent as it is, there's no end to make
+    // a version of yet
+    let transcoded = match mode.filter(|_| !files::is_growing(&metadata)) {
+        Some(mode) => {
+            let path = transcode::prepare(&resolved, mode).await.map_err(|e| {
+                HttpError::new(
+                    e.status_code(),
+                    format!("The {} version can't be made", mode.content_type()),
+                )
+            })?;
+            Some((mode, path))
+        }
+        None => None,
+    };
+    let served = transcoded.as_ref().map_or(&resolved, |(_, path)| path);
 
-    if let Some(end) = captures.get(2) {
-        end_index = end.as_str().parse::<i64>().unwrap();
+    let opened = tokio::fs::File::open(served)
+        .instrument(tracing::info_span!("open_file", path = ?served))
+        .await;
+    let file = opened.map_err(|e| {
+        let status = match e.kind() {
+            io::ErrorKind::NotFound => StatusCode::NOT_FOUND,
+            io::ErrorKind::PermissionDenied => StatusCode::FORBIDDEN,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        tracing::debug!("{:?} Can't open {:?}: {}", addr, served, e);
+        HttpError::new(status, format!("The file can't be opened: {}", e))
+    })?;
+    tracing::debug!("{:?} Opened file {:?}", addr, served);
+
+    let source_len = metadata.len();
+    let len = match &transcoded {
+        Some(_) => file.metadata().await.map_or(0, |metadata| metadata.len()),
+        None => source_len,
+    };
+
+    // its length isn't known yet, so it can only be sent from the start
+    if files::is_growing(&metadata) {
+        tracing::debug!("{:?} {:?} is still growing", addr, filename);
+        return Ok(Opened {
+            filename,
+            transcoded,
+            file,
+            len,
+            source_len,
+            range: None,
+            growing: true,
+            user,
+            device,
+        });
     }
 
-    stream
-        .write_all(b"HTTP/1.1 206 Partial Content\r\n".as_slice())
-        .await
-        .unwrap();
+    let range = files::byte_range(req.headers().get(header::RANGE), len).map_err(|()| {
+        HttpError::new(
+            StatusCode::RANGE_NOT_SATISFIABLE,
+            format!("The file is {} bytes", len),
+        )
+        .header(
+            header::CONTENT_RANGE,
+            HeaderValue::from_str(&format!("bytes */{}", len)).unwrap(),
+        )
+    })?;
+    tracing::debug!("{:?} Has range: {:?}", addr, range);
+
+    Ok(Opened {
+        filename,
+        transcoded,
+        file,
+        len,
+        source_len,
+        range,
+        growing: false,
+        user,
+        device,
+    })
+}
+
+/// Sends a file that's still being copied in, chunked as its length isn't
+/// known, until it stops growing. Returns how many bytes were sent and
+/// whether that was all of it.
+async fn send_growing(
+    stream: &mut impl Connection,
+    file: &mut tokio::fs::File,
+    addr: SocketAddr,
+) -> (u64, bool) {
+    let mut buf = vec![0; crate::config::get().stream_chunk_size as usize];
+    let mut sent = 0;
+    let started = Instant::now();
+
+    loop {
+        let read = match file.read(&mut buf).await {
+            Ok(read) => read,
+            Err(e) => {
+                tracing::debug!("{:?} Reading stopped after {} bytes: {}", addr, sent, e);
+                return (sent, false);
+            }
+        };
+        if read == 0 {
+            let growing = file
+                .metadata()
+                .await
+                .is_ok_and(|metadata| files::is_growing(&metadata));
+            if !growing {
+                break;
+            }
+            tokio::time::sleep(GROWTH_POLL).await;
+            continue;
+        }
+
+        let chunk = [format!("{:x}\r\n", read).as_bytes(), &buf[..read], b"\r\n"].concat();
+        if let Err(e) = stream.write_all(&chunk).await {
+            tracing::debug!("{:?} Sending stopped: {}", addr, e);
+            return (sent, false);
+        }
+        sent += read as u64;
+
+        if let Some(rate) = crate::config::get().max_stream_rate {
+            throttle(rate, sent, started).await;
+        }
+    }
+
+    (sent, stream.write_all(b"0\r\n\r\n").await.is_ok())
+}
+
+pub async fn process(stream: &mut impl Connection, addr: SocketAddr) {
+    let opened = match get_request_from_stream(stream).await {
+        Ok(req) => {
+            tracing::debug!("{:?} Parsed request", addr);
+            open(&req, addr).await
+        }
+        Err(ReadError::Closed) => return,
+        Err(e) => Err(e.into()),
+    };
+    let Opened {
+        filename,
+        transcoded,
+        mut file,
+        len,
+        source_len,
+        range,
+        growing,
+        user,
+        device,
+    } = match opened {
+        Ok(opened) => opened,
+        Err(e) => {
+            tracing::debug!("{:?} Rejected with {}: {}", addr, e.status, e.message);
+            return reject(stream, e).await;
+        }
+    };
+    let _streaming = stream_stats::Streaming::start();
+    let viewer = user.as_ref().map(|user| user.name.as_str());
+
+    let (status, first_byte, end_index) = match range {
+        Some((start, end)) => ("206 Partial Content", start, end + 1),
+        None => ("200 OK", 0, len),
+    };
 
     let mut headers = HeaderMap::new();
     headers.append("Server", HeaderValue::from_static("centarr"));
@@ -146,73 +899,200 @@ pub async fn process(stream: &mut TcpStream, addr: SocketAddr) {
         "Date",
         HeaderValue::from_str(httpdate::fmt_http_date(SystemTime::now()).as_str()).unwrap(),
     );
-    headers.append("Accept-Ranges", HeaderValue::from_static("bytes"));
-    headers.append("Content-Type", HeaderValue::from_static("video/webm"));
     headers.append(
-        "Content-Range",
-        HeaderValue::from_str(
-            format!("bytes {}-{}/{}", start_index, end_index, metadata.len()).as_str(),
-        )
-        .unwrap(),
+        "Accept-Ranges",
+        HeaderValue::from_static(if growing { "none" } else { "bytes" }),
     );
-    if let Some(header) = req.headers().get("Connection") {
-        if header.to_str().unwrap().to_lowercase() == "keep-alive" {
-            headers.append("Connection", HeaderValue::from_static("close"));
+    match &transcoded {
+        Some((mode, _)) => {
+            headers.append(
+                "Content-Type",
+                HeaderValue::from_static(mode.content_type()),
+            );
+            if let Some(bitrate) = mode.bitrate() {
+                headers.append("X-Estimated-Bitrate", HeaderValue::from(bitrate));
+            }
+        }
+        None => {
+            headers.append("Content-Type", content_type(&filename));
         }
     }
-    headers.append(
-        "Content-Length",
-        HeaderValue::from_str((end_index - start_index).to_string().as_str()).unwrap(),
-    );
+    if range.is_some() {
+        headers.append(
+            "Content-Range",
+            HeaderValue::from_str(&format!("bytes {}-{}/{}", first_byte, end_index - 1, len))
+                .unwrap(),
+        );
+    }
+    // every response is the last on its connection
+    headers.append("Connection", HeaderValue::from_static("close"));
+    if growing {
+        headers.append("Transfer-Encoding", HeaderValue::from_static("chunked"));
+    } else {
+        headers.append("Content-Length", HeaderValue::from(end_index - first_byte));
+    }
 
-    for (name, value) in headers {
-        let bytes = format!("{}: {}\r\n", name.unwrap(), value.to_str().unwrap());
-        stream.write_all(bytes.as_bytes()).await.unwrap();
+    let mut head = format!("HTTP/1.1 {}\r\n", status);
+    for (name, value) in &headers {
+        head.push_str(&format!("{}: {}\r\n", name, value.to_str().unwrap()));
+    }
+    head.push_str("\r\n");
+    if let Err(e) = stream.write_all(head.as_bytes()).await {
+        tracing::debug!("{:?} Client left before the headers were sent: {}", addr, e);
+        return;
     }
 
-    stream.write_all(b"\r\n").await.unwrap();
+    tracing::debug!("{:?} Starting from {} to {}", addr, first_byte, end_index);
 
-    tracing::debug!("{:?} Starting from {} to {}", addr, start_index, end_index);
+    if first_byte == 0 && playback::is_new_play(viewer, &filename) {
+        events::publish(Event::PlaybackStarted {
+            path: filename.to_string_lossy().into(),
+            client: addr.ip().to_string(),
+            device: device.as_ref().map(|device| device.name.clone()),
+        });
+        playback::record(viewer, &filename, 0, source_len);
+        if let (Some(user), Some(device)) = (&user, &device) {
+            users::touch_device(&user.name, &device.id).await;
+        }
+    }
 
-    let mut completed = false;
+    if growing {
+        let (sent, completed) = send_growing(stream, &mut file, addr).await;
+        let len = file
+            .metadata()
+            .await
+            .map_or(sent, |metadata| metadata.len());
+        playback::record(viewer, &filename, sent, len);
+        if completed {
+            tracing::debug!("{:?} Sent everything, {} bytes", addr, sent);
+        }
+
+        close(stream).await;
+        return;
+    }
+
+    let start = stream_stats::started(addr.ip(), &filename, first_byte).await;
+    let config = crate::config::get();
+    let configured_chunk_size = config.stream_chunk_size as i64;
+    let max_chunk_size = match config.adaptive_chunks {
+        true => configured_chunk_size * MAX_ADAPTIVE_CHUNK_FACTOR,
+        false => configured_chunk_size,
+    };
+    let mut chunk_size = match (config.adaptive_chunks, start) {
+        (true, stream_stats::Start::Seek) => MIN_ADAPTIVE_CHUNK_SIZE.min(configured_chunk_size),
+        _ => configured_chunk_size,
+    };
+    // the bitrate is read while the burst goes out, so it's there when pacing starts
+    let burst = config
+        .stream_burst
+        .filter(|burst| *burst < end_index - first_byte);
+    let served = transcoded.map_or_else(|| filename.clone(), |(_, path)| path);
+    let mut bitrate = burst.map(|_| tokio::spawn(bitrate(served, len)));
+    drop(config);
+    // bytes per second after the burst and since when
+    let mut pace = None;
+
+    let mut start_index = first_byte as i64;
+    let end_index = end_index as i64;
     let mut bytes_read: i64 = start_index;
-    let stream_fd = stream.as_raw_fd();
     let file_fd = file.as_raw_fd();
 
-    loop {
-        let mut offset = start_index;
-        let chunk_size = std::cmp::min(CHUNK_SIZE, end_index - bytes_read);
-        let result = tokio::spawn(async move {
-            nix::sys::sendfile::sendfile(stream_fd, file_fd, Some(&mut offset), chunk_size as usize)
-        });
-
-        let res = result.await.unwrap();
-        if let Ok(bytes) = res {
+    let span = tracing::info_span!("sendfile", start = start_index, end = end_index);
+    let started = Instant::now();
+    let ended = async {
+        loop {
+            let mut offset = start_index;
+            let max_rate = crate::config::get().max_stream_rate;
+            let rate = match (max_rate, pace) {
+                (Some(max_rate), Some((pace, _))) => Some(max_rate.min(pace)),
+                (max_rate, pace) => max_rate.or(pace.map(|(pace, _)| pace)),
+            };
+            let count = std::cmp::min(
+                rate.map_or(chunk_size, |rate| chunk_size.min(rate as i64)),
+                end_index - bytes_read,
+            );
+            let bytes = match stream.send_file(file_fd, &mut offset, count as usize).await {
+                Ok(0) => return Ended::Completed,
+                Ok(bytes) => bytes,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::BrokenPipe | io::ErrorKind::ConnectionReset
+                    ) =>
+                {
+                    return Ended::Left
+                }
+                Err(e) => {
+                    tracing::debug!("{:?} Sending stopped: {}", addr, e);
+                    return Ended::Failed;
+                }
+            };
             tracing::debug!("{:?} Start index: {}", addr, start_index);
             tracing::debug!("{:?} Read bytes: {}", addr, bytes);
 
-            if bytes == 0 {
-                completed = true;
-                break;
-            }
             bytes_read += bytes as i64;
-            start_index = bytes_read
-        }
+            start_index = bytes_read;
+            // the client is reading on, so bigger chunks take fewer trips
+            chunk_size = (chunk_size * 2).min(max_chunk_size);
 
-        if let Err(e) = res {
-            // tracing::debug!("{:?} Error: {:?}", addr, e);
-            if e != Errno::EAGAIN {
-                break;
+            if let Some(rate) = max_rate {
+                throttle(rate, bytes_read as u64 - first_byte, started).await;
+            }
+
+            let sent = bytes_read as u64 - first_byte;
+            match (&mut bitrate, pace, burst) {
+                (Some(probing), None, Some(burst)) if sent >= burst => {
+                    pace = probing.await.ok().flatten().map(|bitrate| {
+                        let pace = (bitrate as f64 / 8.0 * PACE_FACTOR) as u64;
+                        tracing::debug!("{:?} Pacing at {} bytes per second", addr, pace);
+                        (pace, (Instant::now(), sent))
+                    });
+                    bitrate = None;
+                }
+                (_, Some((pace, (since, at))), _) => throttle(pace, sent - at, since).await,
+                _ => {}
             }
         }
     }
+    .instrument(span)
+    .await;
+
+    stream_stats::finished(
+        addr.ip(),
+        &filename,
+        first_byte,
+        bytes_read as u64,
+        ended == Ended::Left,
+    )
+    .await;
+    // as far into the file itself as into the version sent
+    let position = (bytes_read as u128 * source_len as u128 / len.max(1) as u128) as u64;
+    playback::record(viewer, &filename, position, source_len);
+    if !playback::is_watched(viewer, first_byte, len)
+        && playback::is_watched(viewer, bytes_read as u64, len)
+        && !playback::is_reported(viewer, &filename)
+    {
+        events::publish(Event::PlaybackFinished {
+            path: filename.to_string_lossy().into(),
+            client: addr.ip().to_string(),
+            device: device.map(|device| device.name),
+        });
+    }
 
-    if completed {
-        tracing::debug!("{:?} waiting for socket to end", addr);
-        let mut buffer = Vec::new();
-        stream.read_to_end(&mut buffer).await.unwrap();
+    match ended {
+        Ended::Completed => tracing::debug!("{:?} Sent everything", addr),
+        // there's nobody left to wait for
+        Ended::Left => {
+            tracing::debug!(
+                "{:?} Client left after {} bytes",
+                addr,
+                bytes_read as u64 - first_byte
+            );
+            return;
+        }
+        Ended::Failed => {}
     }
 
-    stream.flush().await.unwrap();
+    close(stream).await;
     tracing::debug!("{:?} Closing stream", addr);
 }
@@ -0,0 +1,699 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Display;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use axum::{http::Request, middleware::Next, response::Response};
+use once_cell::sync::Lazy;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    cassettes::{self, Mode},
+    config::{self, Config, UpstreamConfig},
+    errors::ApiError,
+    library::{Image, Item, Kind, MediaFile, MediaProvider, Tag},
+    store,
+    upstream::Upstream,
+};
+
+pub static UPSTREAM: Lazy<Upstream> = Lazy::new(|| Upstream::new("sonarr"));
+
+/// How often Sonarr's version is checked again, as it may be upgraded.
+const DETECT_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// How soon detecting is tried again when Sonarr couldn't be reached.
+const DETECT_RETRY: Duration = Duration::from_secs(30);
+/// How long Sonarr's tags are reused for while not syncing.
+const TAGS_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// Which of Sonarr's APIs it's spoken to with.
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ApiVersion {
+    /// `/api`, Sonarr v2.
+    Legacy,
+    /// `/api/v3`, Sonarr v3 and v4.
+    V3,
+}
+
+/// What Sonarr at the configured url turned out to be.
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Detected {
+    pub version: String,
+    pub api: ApiVersion,
+    /// The configured url this was found at.
+    #[serde(skip)]
+    configured_url: String,
+    /// Where the API is, which paths are appended to.
+    #[serde(skip)]
+    api_url: String,
+}
+
+static DETECTED: Lazy<RwLock<Option<Detected>>> = Lazy::new(Default::default);
+/// Tag labels by id.
+type Tags = BTreeMap<i32, String>;
+
+/// Tags are looked up for every show, and hardly ever change.
+static TAGS: Lazy<RwLock<Option<(Instant, Tags)>>> = Lazy::new(Default::default);
+
+/// How many of each kind of item Sonarr sent were left out as they
+/// couldn't be read, since startup.
+static SKIPPED: Lazy<Mutex<BTreeMap<&'static str, u64>>> = Lazy::new(Default::default);
+/// The items that were, so each is only warned about once.
+static WARNED: Lazy<Mutex<BTreeSet<(&'static str, i64)>>> = Lazy::new(Default::default);
+
+#[derive(Deserialize)]
+struct SystemStatus {
+    version: String,
+}
+
+/// Asks Sonarr for its version under both API prefixes, the url being
+/// configured with either of them or neither.
+pub async fn detect(config: &Config) -> Result<Detected, ApiError> {
+    let url = &config.sonarr.url;
+    let root = url
+        .strip_suffix("/api/v3")
+        .or_else(|| url.strip_suffix("/api"))
+        .unwrap_or(url);
+
+    let mut last_error = None;
+    for (api, api_url) in [
+        (ApiVersion::V3, format!("{}/api/v3", root)),
+        (ApiVersion::Legacy, format!("{}/api", root)),
+    ] {
+        let upstream = UpstreamConfig {
+            url: api_url.clone(),
+            ..config.sonarr.clone()
+        };
+        match UPSTREAM.fetch(&upstream, "/system/status").await {
+            Ok(body) => match serde_json::from_str::<SystemStatus>(&body) {
+                Ok(status) => {
+                    return Ok(Detected {
+                        version: status.version,
+                        api,
+                        configured_url: url.clone(),
+                        api_url,
+                    })
+                }
+                Err(e) => last_error = Some(ApiError::empty(502, Some(e.to_string()))),
+            },
+            Err(e) => last_error = Some(e),
+        }
+    }
+
+    Err(last_error.unwrap())
+}
+
+/// What was last detected of the configured Sonarr, if it was reached.
+pub fn detected() -> Option<Detected> {
+    let url = &config::get().sonarr.url;
+
+    DETECTED
+        .read()
+        .unwrap()
+        .clone()
+        .filter(|detected| detected.configured_url == *url)
+}
+
+/// Detects Sonarr and talks to it accordingly from then on, for when
+/// [`watch_version`] isn't running.
+pub async fn connect(config: &Config) -> Result<Detected, ApiError> {
+    let found = detect(config).await?;
+    *DETECTED.write().unwrap() = Some(found.clone());
+
+    Ok(found)
+}
+
+/// Keeps what's known of Sonarr's version current, checking again
+/// sooner while it can't be reached or the configured url changed.
+pub async fn watch_version() {
+    loop {
+        let config = config::get();
+        let wait = match detect(&config).await {
+            Ok(found) => {
+                if detected().map(|known| known.version) != Some(found.version.clone()) {
+                    tracing::info!("Sonarr {} speaks the {:?} API", found.version, found.api);
+                }
+                *DETECTED.write().unwrap() = Some(found);
+                DETECT_INTERVAL
+            }
+            Err(e) => {
+                tracing::warn!("Can't tell which Sonarr {} is: {:?}", config.sonarr.url, e);
+                DETECT_RETRY
+            }
+        };
+        drop(config);
+
+        let started = Instant::now();
+        while started.elapsed() < wait {
+            tokio::time::sleep(DETECT_RETRY).await;
+            if detected().is_none() {
+                break;
+            }
+        }
+    }
+}
+
+/// The Sonarr connection, pointing at its API as detected. The configured
+/// url is used as is until Sonarr was reached.
+pub fn api(config: &Config) -> UpstreamConfig {
+    match detected() {
+        Some(detected) => UpstreamConfig {
+            url: detected.api_url,
+            ..config.sonarr.clone()
+        },
+        None => config.sonarr.clone(),
+    }
+}
+
+/// Adds what v3 leaves out of episodes to `body`, the response to `path`,
+/// so it reads like v2's. Episodes there only have their file when asked
+/// for one at a time.
+async fn adapt(client: &Client, path: &str, body: String) -> Result<String, ApiError> {
+    let is_v3 = matches!(detected(), Some(detected) if detected.api == ApiVersion::V3);
+    let series_id = path
+        .strip_prefix("/episode?seriesId=")
+        .and_then(|id| id.parse::<i32>().ok());
+    let series_id = match series_id {
+        Some(id) if is_v3 => id,
+        _ => return Ok(body),
+    };
+
+    let invalid = |e: serde_json::Error| ApiError::empty(500, Some(e.to_string()));
+    let mut episodes = serde_json::from_str::<Vec<Value>>(&body).map_err(invalid)?;
+    let without_file = |episode: &Value| {
+        episode["episodeFileId"].as_i64().unwrap_or_default() > 0
+            && episode.get("episodeFile").is_none()
+    };
+    if !episodes.iter().any(without_file) {
+        return Ok(body);
+    }
+
+    let files = client
+        .get(&format!("/episodefile?seriesId={}", series_id))
+        .await?;
+    let files = serde_json::from_str::<Vec<Value>>(&files)
+        .map_err(invalid)?
+        .into_iter()
+        .filter_map(|file| Some((file["id"].as_i64()?, file)))
+        .collect::<BTreeMap<_, _>>();
+
+    for episode in episodes.iter_mut().filter(|episode| without_file(episode)) {
+        let id = episode["episodeFileId"].as_i64().unwrap_or_default();
+        if let (Some(file), Some(episode)) = (files.get(&id), episode.as_object_mut()) {
+            episode.insert("episodeFile".into(), file.clone());
+        }
+    }
+
+    serde_json::to_string(&episodes).map_err(invalid)
+}
+
+/// Everything centarr asks of Sonarr, so handlers can be run against a
+/// stand-in instead.
+#[async_trait]
+pub trait SonarrApi: Send + Sync {
+    /// GETs `path`, reusing a recent response when there is one.
+    async fn get(&self, path: &str) -> Result<String, ApiError>;
+
+    /// GETs `path`, skipping the cache and failing unless Sonarr answers
+    /// with a 2xx.
+    async fn fetch(&self, path: &str) -> Result<String, ApiError>;
+
+    /// GETs `path`, which waits on indexers, with the search timeout.
+    async fn search(&self, path: &str) -> Result<String, ApiError>;
+
+    /// POSTs `body` as JSON to `path`.
+    async fn post(&self, path: &str, body: &Value) -> Result<String, ApiError>;
+
+    /// DELETEs `path`.
+    async fn delete(&self, path: &str) -> Result<String, ApiError>;
+
+    /// The artwork file `name` of series `id`, like `poster.jpg`.
+    async fn media_cover(&self, id: i32, name: &str) -> Result<Vec<u8>, ApiError>;
+}
+
+/// The Sonarr handlers talk to.
+pub type Client = Arc<dyn SonarrApi>;
+
+/// The configured Sonarr, over HTTP.
+pub struct Http;
+
+#[async_trait]
+impl SonarrApi for Http {
+    async fn get(&self, path: &str) -> Result<String, ApiError> {
+        let config = config::get();
+        UPSTREAM.get(&api(&config), config.cache_ttl, path).await
+    }
+
+    async fn fetch(&self, path: &str) -> Result<String, ApiError> {
+        UPSTREAM.fetch(&api(&config::get()), path).await
+    }
+
+    async fn search(&self, path: &str) -> Result<String, ApiError> {
+        UPSTREAM.search(&api(&config::get()), path).await
+    }
+
+    async fn post(&self, path: &str, body: &Value) -> Result<String, ApiError> {
+        UPSTREAM.post(&api(&config::get()), path, body).await
+    }
+
+    async fn delete(&self, path: &str) -> Result<String, ApiError> {
+        UPSTREAM.delete(&api(&config::get()), path).await
+    }
+
+    async fn media_cover(&self, id: i32, name: &str) -> Result<Vec<u8>, ApiError> {
+        if cassettes::mode() == Some(Mode::Replay) {
+            return Err(ApiError::empty(
+                503,
+                Some(format!("Artwork isn't recorded, not fetching {}", name)),
+            ));
+        }
+
+        let config = config::get();
+        let res = UPSTREAM
+            .request(
+                &api(&config),
+                reqwest::Method::GET,
+                &format!("/mediacover/{}/{}", id, name),
+            )
+            .send()
+            .await
+            .map_err(|e| ApiError::empty(502, Some(e.to_string())))?;
+
+        match res.status() {
+            status if status.is_success() => res
+                .bytes()
+                .await
+                .map(|bytes| bytes.to_vec())
+                .map_err(|e| ApiError::empty(502, Some(e.to_string()))),
+            reqwest::StatusCode::NOT_FOUND => Err(ApiError::new(
+                404,
+                format!("Sonarr has no {} for show {}", name, id),
+            )),
+            status => Err(ApiError::empty(
+                502,
+                Some(format!("Sonarr answered {} for {}", status, name)),
+            )),
+        }
+    }
+}
+
+static HTTP: Lazy<Client> = Lazy::new(|| Arc::new(Http));
+
+tokio::task_local! {
+    /// The Sonarr of the request being handled, see [`inject`].
+    static CLIENT: Client;
+}
+
+/// The Sonarr to talk to, the one put in the request's extensions while
+/// handling one and the configured one otherwise, like in background jobs.
+pub fn client() -> Client {
+    CLIENT
+        .try_with(Client::clone)
+        .unwrap_or_else(|_| HTTP.clone())
+}
+
+/// Middleware that has the request handled with the [`Client`] in its
+/// extensions, so whatever the handler calls uses it too.
+pub async fn inject<B>(req: Request<B>, next: Next<B>) -> Response {
+    match req.extensions().get::<Client>().cloned() {
+        Some(client) => CLIENT.scope(client, next.run(req)).await,
+        None => next.run(req).await,
+    }
+}
+
+/// A Sonarr answering from canned responses, for testing handlers.
+#[cfg(test)]
+#[derive(Default)]
+pub struct Mock {
+    responses: std::collections::HashMap<String, Result<String, ApiError>>,
+}
+
+#[cfg(test)]
+impl Mock {
+    /// Answers `path` with `body`.
+    pub fn with(mut self, path: &str, body: &str) -> Self {
+        self.responses.insert(path.into(), Ok(body.into()));
+        self
+    }
+
+    /// Fails `path` with `error`.
+    pub fn failing(mut self, path: &str, error: ApiError) -> Self {
+        self.responses.insert(path.into(), Err(error));
+        self
+    }
+
+    /// Runs `f` with this as the Sonarr it talks to, like [`inject`] does
+    /// for requests.
+    pub async fn scope<F: std::future::Future>(self, f: F) -> F::Output {
+        CLIENT.scope(Arc::new(self), f).await
+    }
+
+    fn answer(&self, path: &str) -> Result<String, ApiError> {
+        self.responses.get(path).cloned().unwrap_or_else(|| {
+            Err(ApiError::new(
+                404,
+                format!("Nothing is canned for {}", path),
+            ))
+        })
+    }
+}
+
+#[cfg(test)]
+#[async_trait]
+impl SonarrApi for Mock {
+    async fn get(&self, path: &str) -> Result<String, ApiError> {
+        self.answer(path)
+    }
+
+    async fn fetch(&self, path: &str) -> Result<String, ApiError> {
+        self.answer(path)
+    }
+
+    async fn search(&self, path: &str) -> Result<String, ApiError> {
+        self.answer(path)
+    }
+
+    async fn post(&self, path: &str, _: &Value) -> Result<String, ApiError> {
+        self.answer(path)
+    }
+
+    async fn delete(&self, path: &str) -> Result<String, ApiError> {
+        self.answer(path)
+    }
+
+    async fn media_cover(&self, id: i32, name: &str) -> Result<Vec<u8>, ApiError> {
+        self.answer(&format!("/mediacover/{}/{}", id, name))
+            .map(String::into_bytes)
+    }
+}
+
+pub async fn get(path: &str) -> Result<String, ApiError> {
+    let client = client();
+    let body = client.get(path).await?;
+
+    adapt(&client, path, body).await
+}
+
+/// Like [`get`], skipping the cache and failing unless Sonarr answers
+/// with a 2xx.
+pub async fn fetch(path: &str) -> Result<String, ApiError> {
+    let client = client();
+    let body = client.fetch(path).await?;
+
+    adapt(&client, path, body).await
+}
+
+/// GETs `path` from Sonarr when it searches indexers for it, like
+/// `/release`, giving it as long as searches get.
+pub async fn search(path: &str) -> Result<String, ApiError> {
+    client().search(path).await
+}
+
+/// POSTs `body` to `path`, passing along Sonarr's status and message when
+/// it rejects it.
+pub async fn post<T: Serialize>(path: &str, body: &T) -> Result<String, ApiError> {
+    let body = serde_json::to_value(body).map_err(|e| ApiError::empty(500, Some(e.to_string())))?;
+
+    client().post(path, &body).await
+}
+
+/// DELETEs `path` in Sonarr, passing along its status and message when it
+/// refuses.
+pub async fn delete(path: &str) -> Result<String, ApiError> {
+    client().delete(path).await
+}
+
+/// Every series by id, from the synced library or Sonarr.
+pub async fn series_by_id() -> Result<BTreeMap<i64, Value>, ApiError> {
+    let series = match store::library() {
+        Some(library) => library.series.values().cloned().collect(),
+        None => serde_json::from_str::<Vec<Value>>(&get("/series").await?)
+            .map_err(|e| ApiError::empty(500, Some(e.to_string())))?,
+    };
+
+    Ok(series
+        .into_iter()
+        .filter_map(|series| Some((series["id"].as_i64()?, series)))
+        .collect())
+}
+
+/// Episode `id`, from the synced library or Sonarr.
+pub async fn episode(id: i32) -> Result<Option<Value>, ApiError> {
+    let is_it = |episode: &&Value| episode["id"].as_i64() == Some(id.into());
+
+    match store::library() {
+        Some(library) => Ok(library.episodes.values().flatten().find(is_it).cloned()),
+        None => match get(&format!("/episode/{}", id)).await {
+            Ok(body) => serde_json::from_str(&body)
+                .map(Some)
+                .map_err(|e| ApiError::empty(500, Some(e.to_string()))),
+            Err(e) if e.status_code() == reqwest::StatusCode::NOT_FOUND => Ok(None),
+            Err(e) => Err(e),
+        },
+    }
+}
+
+/// The episodes of series `id`, from the synced library or Sonarr.
+pub async fn episodes(id: i32) -> Result<Vec<Value>, ApiError> {
+    match store::library() {
+        Some(library) => Ok(library.episodes.get(&id).cloned().unwrap_or_default()),
+        None => serde_json::from_str(&get(&format!("/episode?seriesId={}", id)).await?)
+            .map_err(|e| ApiError::empty(500, Some(e.to_string()))),
+    }
+}
+
+/// The artwork file `name` of series `id`, like `poster.jpg`, straight
+/// from Sonarr.
+pub async fn media_cover(id: i32, name: &str) -> Result<Vec<u8>, ApiError> {
+    client().media_cover(id, name).await
+}
+
+/// Sonarr's series for the library.
+pub struct Provider;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Series {
+    id: i32,
+    title: String,
+    year: Option<i32>,
+    overview: Option<String>,
+    #[serde(default)]
+    images: Vec<Image>,
+    added: Option<String>,
+    path: Option<String>,
+    #[serde(default)]
+    tags: Vec<i32>,
+}
+
+impl Series {
+    fn into_item(self, labels: &BTreeMap<i32, String>) -> Item {
+        Item {
+            kind: Kind::Show,
+            id: self.id,
+            title: self.title,
+            year: self.year.filter(|year| *year > 0),
+            overview: self.overview,
+            images: self.images,
+            added: self.added,
+            files: None,
+            path: self.path,
+            tags: self
+                .tags
+                .iter()
+                .filter_map(|id| labels.get(id).cloned())
+                .collect(),
+        }
+    }
+}
+
+/// [`tags`], but empty when no tags are restricted, as nothing needs them
+/// then.
+pub async fn tag_labels(config: &Config) -> Result<BTreeMap<i32, String>, ApiError> {
+    if config.restricted_tags.is_empty() {
+        return Ok(BTreeMap::new());
+    }
+
+    tags().await
+}
+
+/// Sonarr's tag labels by id, lowercased.
+pub async fn tags() -> Result<BTreeMap<i32, String>, ApiError> {
+    let cached = TAGS
+        .read()
+        .unwrap()
+        .as_ref()
+        .filter(|(fetched_at, _)| fetched_at.elapsed() < TAGS_TTL)
+        .map(|(_, tags)| tags.clone());
+
+    let tags = match (store::library(), cached) {
+        (Some(library), _) => library.tags.clone(),
+        (None, Some(tags)) => return Ok(tags),
+        (None, None) => get_json::<Vec<Tag>>("/tag")
+            .await?
+            .into_iter()
+            .map(|tag| (tag.id, tag.label))
+            .collect(),
+    };
+
+    let tags = tags
+        .into_iter()
+        .map(|(id, label)| (id, label.to_lowercase()))
+        .collect::<BTreeMap<_, _>>();
+    if store::library().is_none() {
+        *TAGS.write().unwrap() = Some((Instant::now(), tags.clone()));
+    }
+
+    Ok(tags)
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct EpisodeFile {
+    id: i32,
+    season_number: i32,
+    path: String,
+    size: i64,
+    date_added: Option<String>,
+}
+
+async fn get_json<T: DeserializeOwned>(path: &str) -> Result<T, ApiError> {
+    let body = get(path).await?;
+
+    serde_json::from_str(&body).map_err(|e| ApiError::empty(500, Some(e.to_string())))
+}
+
+/// Reads each of `values` with `parse`. Ones that can't be read, like
+/// after a Sonarr update changed their shape, are left out and logged, so
+/// they don't fail the whole listing, unless `skip_unreadable` is off.
+/// `kind` names them, like `episode`.
+pub fn parse_each<'a, T, E: Display>(
+    kind: &'static str,
+    values: impl IntoIterator<Item = &'a Value>,
+    parse: impl Fn(&'a Value) -> Result<T, E>,
+) -> Result<Vec<T>, ApiError> {
+    let skip = config::get().skip_unreadable;
+    let mut parsed = Vec::new();
+
+    for value in values {
+        match parse(value) {
+            Ok(item) => parsed.push(item),
+            Err(e) if skip => {
+                let id = value["id"].as_i64().unwrap_or_default();
+                *SKIPPED.lock().unwrap().entry(kind).or_default() += 1;
+                if WARNED.lock().unwrap().insert((kind, id)) {
+                    tracing::warn!(
+                        "Leaving out {} {} Sonarr sent, it can't be read: {}",
+                        kind,
+                        id,
+                        e
+                    );
+                } else {
+                    tracing::debug!("Leaving out {} {}: {}", kind, id, e);
+                }
+            }
+            Err(e) => {
+                return Err(ApiError::empty(
+                    500,
+                    Some(format!("Can't read {} {}: {}", kind, value["id"], e)),
+                ))
+            }
+        }
+    }
+
+    Ok(parsed)
+}
+
+/// How many items of each kind were left out by [`parse_each`].
+pub fn skipped() -> BTreeMap<&'static str, u64> {
+    SKIPPED.lock().unwrap().clone()
+}
+
+fn from_values<'a, T: DeserializeOwned>(
+    kind: &'static str,
+    values: impl IntoIterator<Item = &'a Value>,
+) -> Result<Vec<T>, ApiError> {
+    parse_each(kind, values, T::deserialize)
+}
+
+/// Like [`get_json`] for lists, reading them with [`from_values`].
+async fn get_each<T: DeserializeOwned>(kind: &'static str, path: &str) -> Result<Vec<T>, ApiError> {
+    from_values(kind, &get_json::<Vec<Value>>(path).await?)
+}
+
+#[async_trait]
+impl MediaProvider for Provider {
+    fn kind(&self) -> Kind {
+        Kind::Show
+    }
+
+    fn is_configured(&self, _: &Config) -> bool {
+        true
+    }
+
+    async fn list(&self, config: &Config) -> Result<Vec<Item>, ApiError> {
+        let series = match store::library() {
+            Some(library) => from_values("show", library.series.values())?,
+            None => get_each::<Series>("show", "/series").await?,
+        };
+        let labels = tag_labels(config).await?;
+
+        Ok(series
+            .into_iter()
+            .map(|series| series.into_item(&labels))
+            .collect())
+    }
+
+    async fn get(&self, config: &Config, id: i32) -> Result<Item, ApiError> {
+        let series = match store::library() {
+            Some(library) => {
+                let series = library
+                    .series
+                    .get(&id)
+                    .ok_or_else(|| ApiError::new(404, format!("There's no show {}", id)))?;
+                Series::deserialize(series)
+                    .map_err(|e| ApiError::empty(500, Some(e.to_string())))?
+            }
+            None => get_json::<Series>(&format!("/series/{}", id)).await?,
+        };
+
+        Ok(series.into_item(&tag_labels(config).await?))
+    }
+
+    async fn files(&self, _: &Config, id: i32) -> Result<Vec<MediaFile>, ApiError> {
+        let mut files = match store::library() {
+            Some(library) => from_values(
+                "episode file",
+                library
+                    .episodes
+                    .get(&id)
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|episode| episode.get("episodeFile")),
+            )?,
+            None => {
+                get_each::<EpisodeFile>("episode file", &format!("/episodefile?seriesId={}", id))
+                    .await?
+            }
+        };
+        files.sort_by(|a, b| (a.season_number, &a.path).cmp(&(b.season_number, &b.path)));
+        // multi-episode files show up once per episode
+        files.dedup_by_key(|file| file.id);
+
+        Ok(files
+            .into_iter()
+            .map(|file| MediaFile {
+                id: file.id,
+                path: file.path,
+                size: file.size,
+                date_added: file.date_added,
+                season_number: Some(file.season_number),
+                watch_url: None,
+                position: None,
+            })
+            .collect())
+    }
+}
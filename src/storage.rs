@@ -0,0 +1,228 @@
+use std::fs::Metadata;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+
+use nix::libc;
+use serde::Serialize;
+
+use crate::config::Config;
+
+/// How long a stat gets before the storage counts as offline, as hard NFS
+/// mounts hang instead of failing when the server is gone.
+const STAT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Why a media file can't be streamed.
+pub enum Unavailable {
+    /// It's not there, but its storage is.
+    Missing,
+    /// The mount it's on is gone or not answering.
+    Offline(String),
+    Unreadable(io::Error),
+}
+
+/// Errors of filesystems whose server went away, rather than of the file.
+fn is_offline_error(e: &io::Error) -> bool {
+    matches!(
+        e.raw_os_error(),
+        Some(
+            libc::ESTALE
+                | libc::EIO
+                | libc::ENOTCONN
+                | libc::EHOSTDOWN
+                | libc::EHOSTUNREACH
+                | libc::ETIMEDOUT
+                | libc::ENODEV
+        )
+    )
+}
+
+/// Runs filesystem calls off the runtime, giving up after [`STAT_TIMEOUT`].
+async fn blocking<T: Send + 'static>(
+    path: &Path,
+    f: impl FnOnce() -> io::Result<T> + Send + 'static,
+) -> Result<T, Unavailable> {
+    let call = tokio::task::spawn_blocking(f);
+    match tokio::time::timeout(STAT_TIMEOUT, call).await {
+        Ok(Ok(Ok(value))) => Ok(value),
+        Ok(Ok(Err(e))) if is_offline_error(&e) => Err(Unavailable::Offline(format!(
+            "{:?} can't be reached: {}",
+            path, e
+        ))),
+        Ok(Ok(Err(e))) if e.kind() == io::ErrorKind::NotFound => Err(Unavailable::Missing),
+        Ok(Ok(Err(e))) => Err(Unavailable::Unreadable(e)),
+        Ok(Err(e)) => Err(Unavailable::Unreadable(io::Error::other(e))),
+        Err(_) => Err(Unavailable::Offline(format!(
+            "{:?} didn't answer within {:?}",
+            path, STAT_TIMEOUT
+        ))),
+    }
+}
+
+/// Folders media is kept in, the media roots and where path mappings
+/// point to, which are usually mounts.
+pub fn roots(config: &Config) -> Vec<PathBuf> {
+    let mut roots = config
+        .media_roots
+        .iter()
+        .chain(config.path_mappings.iter().map(|mapping| &mapping.local))
+        .cloned()
+        .collect::<Vec<_>>();
+    roots.sort();
+    roots.dedup();
+
+    roots
+}
+
+/// Whether `path` is a file `?file=` may stream: in one of the [`roots`]
+/// and not in the data dir, going by the path as it's written.
+pub fn in_roots(config: &Config, path: &Path) -> bool {
+    path.is_absolute()
+        && !path
+            .components()
+            .any(|component| component == Component::ParentDir)
+        && !path.starts_with(&config.data_dir)
+        && roots(config).iter().any(|root| path.starts_with(root))
+}
+
+/// Like [`in_roots`], for a path with symlinks followed, against the roots
+/// and data dir with theirs followed too, so links can't lead out of them.
+pub async fn resolved_in_roots(config: &Config, resolved: &Path) -> bool {
+    let real = |path: &Path| {
+        let owned = path.to_path_buf();
+        async move {
+            blocking(&owned.clone(), move || std::fs::canonicalize(&owned))
+                .await
+                .ok()
+        }
+    };
+
+    let data_dir = real(&config.data_dir)
+        .await
+        .unwrap_or_else(|| config.data_dir.clone());
+    if resolved.starts_with(&data_dir) {
+        return false;
+    }
+    for root in roots(config) {
+        if real(&root)
+            .await
+            .is_some_and(|root| resolved.starts_with(root))
+        {
+            return true;
+        }
+    }
+
+    false
+}
+
+/// Whether `root` is there and has something in it. A mount point that
+/// isn't mounted is usually left an empty folder.
+async fn check_root(root: &Path) -> Result<(), Unavailable> {
+    let path = root.to_path_buf();
+    let empty = blocking(root, move || Ok(std::fs::read_dir(path)?.next().is_none())).await;
+
+    match empty {
+        Ok(false) => Ok(()),
+        Ok(true) | Err(Unavailable::Missing) => Err(Unavailable::Offline(format!(
+            "{:?} is empty or missing, it's probably not mounted",
+            root
+        ))),
+        Err(e) => Err(e),
+    }
+}
+
+/// Where `path` really is with symlinks followed, and its metadata. Tells
+/// a missing file apart from one whose storage is offline. Hardlinks need
+/// no resolving, they're the same file under another name.
+pub async fn resolve(config: &Config, path: &Path) -> Result<(PathBuf, Metadata), Unavailable> {
+    let owned = path.to_path_buf();
+    let resolved = blocking(path, move || {
+        let real = std::fs::canonicalize(owned)?;
+        let metadata = std::fs::metadata(&real)?;
+        Ok((real, metadata))
+    })
+    .await;
+
+    match resolved {
+        Err(Unavailable::Missing) => {
+            for root in roots(config).iter().filter(|root| path.starts_with(root)) {
+                check_root(root).await?;
+            }
+            Err(Unavailable::Missing)
+        }
+        resolved => resolved,
+    }
+}
+
+/// Stats many files at once for a response, not waiting on storage that's
+/// found to be offline for each of them.
+pub struct Preflight {
+    offline: Vec<PathBuf>,
+}
+
+impl Preflight {
+    pub async fn new(config: &Config) -> Self {
+        let offline = check_roots(config)
+            .await
+            .into_iter()
+            .filter(|root| !root.online)
+            .map(|root| root.path)
+            .collect();
+
+        Self { offline }
+    }
+
+    /// The file's metadata, `None` when it's not there, not a file or its
+    /// storage is offline.
+    pub async fn stat(&self, path: &Path) -> Option<Metadata> {
+        if self.offline.iter().any(|root| path.starts_with(root)) {
+            return None;
+        }
+
+        let owned = path.to_path_buf();
+        blocking(path, move || std::fs::metadata(owned))
+            .await
+            .ok()
+            .filter(Metadata::is_file)
+    }
+}
+
+#[derive(Serialize)]
+pub struct RootStatus {
+    pub path: PathBuf,
+    pub online: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Whether each storage root can be reached, checked all at once so one
+/// hanging mount doesn't hold up the others.
+pub async fn check_roots(config: &Config) -> Vec<RootStatus> {
+    let checks = roots(config)
+        .into_iter()
+        .map(|root| {
+            tokio::spawn(async move {
+                let error = match check_root(&root).await {
+                    Ok(()) => None,
+                    Err(Unavailable::Offline(e)) => Some(e),
+                    Err(Unavailable::Missing) => Some("missing".into()),
+                    Err(Unavailable::Unreadable(e)) => Some(e.to_string()),
+                };
+                RootStatus {
+                    path: root,
+                    online: error.is_none(),
+                    error,
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let mut statuses = Vec::with_capacity(checks.len());
+    for check in checks {
+        if let Ok(status) = check.await {
+            statuses.push(status);
+        }
+    }
+
+    statuses
+}
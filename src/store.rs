@@ -0,0 +1,783 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use once_cell::sync::Lazy;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::migrate::{Migrate, Migrator};
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePool, SqlitePoolOptions};
+use sqlx::Row;
+use tokio::sync::mpsc;
+
+use crate::{config, migrations, postgres};
+
+/// The SQLite database in the data dir.
+pub const DATABASE_FILE: &str = "centarr.db";
+
+pub const SERIES: &str = "series";
+pub const EPISODES: &str = "episodes";
+pub const TAGS: &str = "tags";
+pub const MISSING_FILES: &str = "missing_files";
+/// When the library was last synced, by what kind of sync.
+pub const SYNCS: &str = "syncs";
+
+/// Every table, each a row per thing by its key with the thing as JSON.
+/// Sets, like the missing files, only have keys.
+pub const TABLES: &[&str] = &[
+    SERIES,
+    EPISODES,
+    TAGS,
+    MISSING_FILES,
+    SYNCS,
+    "users",
+    "plays",
+    "lists",
+    "markers",
+    "images",
+    "analyzed_files",
+    "jobs",
+];
+
+/// How long to wait for more changes after one, before loading them.
+const CHANGES_SETTLE: Duration = Duration::from_secs(1);
+/// How long to wait before listening for changes again after it failed.
+const CHANGES_RETRY: Duration = Duration::from_secs(5);
+
+static LIBRARY: Lazy<RwLock<Option<Arc<Library>>>> = Lazy::new(Default::default);
+/// The tables other centarrs change, as they're heard about.
+static CHANGES: Lazy<tokio::sync::Mutex<mpsc::UnboundedReceiver<&'static str>>> = Lazy::new(|| {
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        loop {
+            match BACKEND.changed().await {
+                Ok(tables) => {
+                    if tables.into_iter().any(|table| tx.send(table).is_err()) {
+                        return;
+                    }
+                }
+                Err(e) => {
+                    tracing::warn!("Can't hear about changes other centarrs make: {}", e);
+                    tokio::time::sleep(CHANGES_RETRY).await;
+                }
+            }
+        }
+    });
+
+    tokio::sync::Mutex::new(rx)
+});
+static UPDATING: Lazy<tokio::sync::Mutex<()>> = Lazy::new(Default::default);
+
+/// Sonarr's series and episodes as of the last sync, kept as Sonarr sent
+/// them so the API can serve them without asking Sonarr again.
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Library {
+    pub series: BTreeMap<i32, Value>,
+    /// Episodes by series id.
+    pub episodes: BTreeMap<i32, Vec<Value>>,
+    /// Unix timestamp of the last complete sync.
+    pub synced_at: Option<u64>,
+    /// Unix timestamp of the last sync of any kind, the next delta sync
+    /// asks Sonarr for what happened since.
+    #[serde(default)]
+    pub updated_at: Option<u64>,
+    /// Local paths of episode files that disappeared from disk since
+    /// Sonarr last told us about them.
+    #[serde(default)]
+    pub missing_files: BTreeSet<PathBuf>,
+    /// Sonarr's tag labels by id.
+    #[serde(default)]
+    pub tags: BTreeMap<i32, String>,
+    /// Where each episode file is in `episodes` by its id, as the series id
+    /// and index, made again whenever the library is loaded or updated.
+    #[serde(skip)]
+    episode_files: BTreeMap<i32, (i32, usize)>,
+}
+
+impl Library {
+    /// The episode file Sonarr knows by `id`. Multi-episode files are the
+    /// first episode's.
+    pub fn episode_file(&self, id: i32) -> Option<&Value> {
+        let (series_id, index) = self.episode_files.get(&id)?;
+
+        self.episodes
+            .get(series_id)?
+            .get(*index)?
+            .get("episodeFile")
+    }
+
+    fn index_episode_files(&mut self) {
+        self.episode_files.clear();
+        for (series_id, episodes) in &self.episodes {
+            for (index, episode) in episodes.iter().enumerate() {
+                if let Some(id) = episode["episodeFile"]["id"].as_i64() {
+                    self.episode_files
+                        .entry(id as i32)
+                        .or_insert((*series_id, index));
+                }
+            }
+        }
+    }
+}
+
+/// The rows of some of the [`TABLES`], as their keys and values.
+pub type Tables = BTreeMap<&'static str, Vec<(String, String)>>;
+
+/// A row of one of the [`TABLES`] written, or deleted without a value.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    pub table: &'static str,
+    pub key: String,
+    pub value: Option<String>,
+}
+
+/// What rows are keyed by.
+pub trait Key: Ord + Clone {
+    fn to_key(&self) -> String;
+
+    fn from_key(key: &str) -> Option<Self>;
+}
+
+macro_rules! parsed_key {
+    ($($ty:ty),*) => {$(
+        impl Key for $ty {
+            fn to_key(&self) -> String {
+                self.to_string()
+            }
+
+            fn from_key(key: &str) -> Option<Self> {
+                <$ty>::from_str(key).ok()
+            }
+        }
+    )*};
+}
+
+parsed_key!(String, i32, u32, u64);
+
+impl Key for PathBuf {
+    fn to_key(&self) -> String {
+        self.to_string_lossy().into_owned()
+    }
+
+    fn from_key(key: &str) -> Option<Self> {
+        Some(PathBuf::from(key))
+    }
+}
+
+/// Where the tables are.
+#[async_trait]
+pub trait Backend: Send + Sync {
+    /// The migrations that make the tables.
+    fn migrator(&self) -> &'static Migrator;
+
+    /// A connection of its own to migrate the tables over.
+    async fn migration_connection(&self) -> io::Result<Box<dyn Migrate + Send>>;
+
+    /// Copies every table as it is at one moment into a new SQLite
+    /// database at `path`.
+    async fn snapshot(&self, path: &Path) -> io::Result<()>;
+
+    /// Every row of `table`, as its key and value.
+    async fn rows(&self, table: &str) -> io::Result<Vec<(String, String)>>;
+
+    /// Makes all of `changes`, or none of them when one fails.
+    async fn apply(&self, changes: &[Change]) -> io::Result<()>;
+
+    /// Waits for other centarrs sharing the database to change tables,
+    /// answering with which. Only one centarr uses SQLite, so it never
+    /// answers.
+    async fn changed(&self) -> io::Result<Vec<&'static str>> {
+        std::future::pending().await
+    }
+}
+
+/// SQLite, in the data dir.
+struct Sqlite {
+    pool: SqlitePool,
+}
+
+impl Sqlite {
+    fn new() -> Self {
+        let options = SqliteConnectOptions::new()
+            .filename(config::get().data_dir.join(DATABASE_FILE))
+            .create_if_missing(true)
+            .journal_mode(SqliteJournalMode::Wal)
+            .busy_timeout(Duration::from_secs(10));
+
+        Sqlite {
+            pool: SqlitePoolOptions::new().connect_lazy_with(options),
+        }
+    }
+}
+
+#[async_trait]
+impl Backend for Sqlite {
+    fn migrator(&self) -> &'static Migrator {
+        &migrations::SQLITE
+    }
+
+    async fn migration_connection(&self) -> io::Result<Box<dyn Migrate + Send>> {
+        tokio::fs::create_dir_all(&config::get().data_dir).await?;
+        let connection = self.pool.acquire().await.map_err(io::Error::other)?;
+
+        Ok(Box::new(connection.detach()))
+    }
+
+    async fn snapshot(&self, path: &Path) -> io::Result<()> {
+        sqlx::query("VACUUM INTO ?1")
+            .bind(path.to_string_lossy())
+            .execute(&self.pool)
+            .await
+            .map_err(io::Error::other)?;
+
+        Ok(())
+    }
+
+    async fn rows(&self, table: &str) -> io::Result<Vec<(String, String)>> {
+        let rows = sqlx::query(&format!("SELECT key, value FROM {} ORDER BY key", table))
+            .fetch_all(&self.pool)
+            .await
+            .map_err(io::Error::other)?;
+
+        Ok(rows
+            .iter()
+            .map(|row| (row.get("key"), row.get("value")))
+            .collect())
+    }
+
+    async fn apply(&self, changes: &[Change]) -> io::Result<()> {
+        let mut transaction = self.pool.begin().await.map_err(io::Error::other)?;
+        for change in changes {
+            let sql = match &change.value {
+                Some(_) => format!(
+                    "INSERT INTO {} (key, value) VALUES (?1, ?2)
+                     ON CONFLICT (key) DO UPDATE SET value = excluded.value",
+                    change.table
+                ),
+                None => format!("DELETE FROM {} WHERE key = ?1", change.table),
+            };
+            let mut query = sqlx::query(&sql).bind(&change.key);
+            if let Some(value) = &change.value {
+                query = query.bind(value);
+            }
+            query
+                .execute(&mut *transaction)
+                .await
+                .map_err(io::Error::other)?;
+        }
+
+        transaction.commit().await.map_err(io::Error::other)
+    }
+}
+
+/// The database when there's one, SQLite in the data dir otherwise.
+/// Picked once, a changed `database_url` takes a restart.
+static BACKEND: Lazy<Box<dyn Backend>> = Lazy::new(|| {
+    let options = config::get().database_url.as_deref().map(postgres::options);
+
+    match options {
+        Some(Ok(options)) => Box::new(postgres::Database::new(options)),
+        _ => Box::new(Sqlite::new()),
+    }
+});
+
+/// Migrates the tables to the latest version, before anything is loaded
+/// from them.
+pub async fn open() -> Result<(), String> {
+    let describe = match &config::get().database_url {
+        Some(url) => format!(
+            "PostgreSQL at {}",
+            postgres::describe(&postgres::options(url)?)
+        ),
+        None => format!("{:?}", config::get().data_dir.join(DATABASE_FILE)),
+    };
+    migrations::run()
+        .await
+        .map_err(|e| format!("Can't use the database {}: {}", describe, e))?;
+    tracing::info!("Keeping state in {}", describe);
+
+    Ok(())
+}
+
+/// Every row of `table` that can be read, by key.
+pub async fn rows<K: Key, V: DeserializeOwned>(table: &'static str) -> BTreeMap<K, V> {
+    match try_rows(table).await {
+        Ok(rows) => rows,
+        Err(e) => {
+            tracing::warn!("Can't read the {}: {}", table, e);
+            BTreeMap::new()
+        }
+    }
+}
+
+/// Like [`rows`], failing when the table can't be read at all.
+pub async fn try_rows<K: Key, V: DeserializeOwned>(
+    table: &'static str,
+) -> io::Result<BTreeMap<K, V>> {
+    let rows = BACKEND.rows(table).await?;
+
+    Ok(rows
+        .into_iter()
+        .filter_map(|(key, value)| {
+            let parsed = K::from_key(&key).zip(serde_json::from_str(&value).ok());
+            if parsed.is_none() {
+                tracing::warn!("Ignoring {} {:?}, it's not valid", table, key);
+            }
+            parsed
+        })
+        .collect())
+}
+
+/// The keys of `table`, for tables that are sets.
+pub async fn keys<K: Key>(table: &'static str) -> BTreeSet<K> {
+    rows::<K, Value>(table).await.into_keys().collect()
+}
+
+/// Writes `value` to row `key` of `table`.
+pub fn put<K: Key, V: Serialize>(table: &'static str, key: &K, value: &V) -> Change {
+    Change {
+        table,
+        key: key.to_key(),
+        // a value that can't be serialized is a bug, and is left as null
+        value: Some(serde_json::to_string(value).unwrap_or_else(|_| "null".into())),
+    }
+}
+
+/// Deletes row `key` of `table`.
+pub fn delete<K: Key>(table: &'static str, key: &K) -> Change {
+    Change {
+        table,
+        key: key.to_key(),
+        value: None,
+    }
+}
+
+/// The changes to `table` to go from `before` to `after`.
+pub fn diff<K: Key, V: Serialize + PartialEq>(
+    table: &'static str,
+    before: &BTreeMap<K, V>,
+    after: &BTreeMap<K, V>,
+) -> Vec<Change> {
+    let deleted = before
+        .keys()
+        .filter(|key| !after.contains_key(key))
+        .map(|key| delete(table, key));
+    let written = after
+        .iter()
+        .filter(|(key, value)| before.get(key) != Some(value))
+        .map(|(key, value)| put(table, key, value));
+
+    deleted.chain(written).collect()
+}
+
+/// Like [`diff`], for tables that are sets.
+pub fn diff_keys<K: Key>(
+    table: &'static str,
+    before: &BTreeSet<K>,
+    after: &BTreeSet<K>,
+) -> Vec<Change> {
+    let deleted = before.difference(after).map(|key| delete(table, key));
+    let added = after
+        .difference(before)
+        .map(|key| put(table, key, &Value::Null));
+
+    deleted.chain(added).collect()
+}
+
+/// Makes `changes` in one transaction, all of them or, when that fails,
+/// none.
+pub async fn apply(changes: &[Change]) -> io::Result<()> {
+    match changes.is_empty() {
+        true => Ok(()),
+        false => BACKEND.apply(changes).await,
+    }
+}
+
+/// The tables other centarrs sharing the database changed. Waits for the
+/// first change and then a moment longer, so a burst of them is loaded
+/// once.
+pub async fn changed() -> BTreeSet<&'static str> {
+    let mut changes = CHANGES.lock().await;
+    let mut changed = BTreeSet::new();
+    match changes.recv().await {
+        Some(table) => changed.insert(table),
+        None => std::future::pending().await,
+    };
+
+    let more = async {
+        while let Some(table) = changes.recv().await {
+            changed.insert(table);
+        }
+    };
+    let _ = tokio::time::timeout(CHANGES_SETTLE, more).await;
+
+    changed
+}
+
+/// The migrations that make the tables.
+pub fn migrator() -> &'static Migrator {
+    BACKEND.migrator()
+}
+
+/// A connection of its own to migrate the tables over.
+pub async fn migration_connection() -> io::Result<Box<dyn Migrate + Send>> {
+    BACKEND.migration_connection().await
+}
+
+/// Replaces every row of each of `tables` with the ones given, all in one
+/// transaction, for restoring backups.
+pub async fn replace(tables: &Tables) -> io::Result<()> {
+    let mut changes = Vec::new();
+    for (table, rows) in tables {
+        let before = BACKEND.rows(table).await?;
+        changes.extend(before.into_iter().map(|(key, _)| delete(table, &key)));
+        changes.extend(rows.iter().map(|(key, value)| Change {
+            table,
+            key: key.clone(),
+            value: Some(value.clone()),
+        }));
+    }
+
+    BACKEND.apply(&changes).await
+}
+
+fn snapshot_options(path: &Path) -> SqliteConnectOptions {
+    SqliteConnectOptions::new()
+        .filename(path)
+        .busy_timeout(Duration::from_secs(10))
+}
+
+/// Copies the tables as they are at one moment into a new SQLite database
+/// at `path`, without the rows of the `left_out` ones.
+pub async fn snapshot(path: &Path, left_out: &[&str]) -> io::Result<()> {
+    BACKEND.snapshot(path).await?;
+
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect_with(snapshot_options(path))
+        .await
+        .map_err(io::Error::other)?;
+    let mut cleared = Ok(());
+    for table in left_out {
+        cleared = sqlx::query(&format!("DELETE FROM {}", table))
+            .execute(&pool)
+            .await
+            .map(|_| ());
+        if cleared.is_err() {
+            break;
+        }
+    }
+    if cleared.is_ok() {
+        cleared = sqlx::query("VACUUM").execute(&pool).await.map(|_| ());
+    }
+    pool.close().await;
+
+    cleared.map_err(io::Error::other)
+}
+
+/// Writes `tables` into a new SQLite database at `path`, for a snapshot
+/// of tables kept elsewhere.
+pub async fn write_snapshot(
+    path: &Path,
+    tables: &[(&str, Vec<(String, String)>)],
+) -> io::Result<()> {
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect_with(snapshot_options(path).create_if_missing(true))
+        .await
+        .map_err(io::Error::other)?;
+    let written = async {
+        migrations::SQLITE.run(&pool).await?;
+        let mut transaction = pool.begin().await?;
+        for (table, rows) in tables {
+            let sql = format!("INSERT INTO {} (key, value) VALUES (?1, ?2)", table);
+            for (key, value) in rows {
+                sqlx::query(&sql)
+                    .bind(key)
+                    .bind(value)
+                    .execute(&mut *transaction)
+                    .await?;
+            }
+        }
+        transaction.commit().await?;
+        Ok::<_, sqlx::Error>(())
+    }
+    .await;
+    pool.close().await;
+
+    written.map_err(io::Error::other)
+}
+
+/// The version of the tables in the snapshot at `path`, and the rows of
+/// those of them there are.
+pub async fn read_snapshot(path: &Path) -> io::Result<(i64, Tables)> {
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect_with(snapshot_options(path).read_only(true))
+        .await
+        .map_err(io::Error::other)?;
+    let read = async {
+        let version = sqlx::query("SELECT max(version) AS version FROM _sqlx_migrations")
+            .fetch_one(&pool)
+            .await?
+            .get::<Option<i64>, _>("version")
+            .unwrap_or(0);
+        let mut tables = BTreeMap::new();
+        for table in TABLES {
+            let rows = sqlx::query(&format!("SELECT key, value FROM {}", table))
+                .fetch_all(&pool)
+                .await;
+            match rows {
+                Ok(rows) => {
+                    let rows = rows
+                        .iter()
+                        .map(|row| (row.get("key"), row.get("value")))
+                        .collect();
+                    tables.insert(*table, rows);
+                }
+                // from before the table was made
+                Err(sqlx::Error::Database(e)) if e.message().contains("no such table") => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok((version, tables))
+    }
+    .await;
+    pool.close().await;
+
+    read.map_err(io::Error::other)
+}
+
+/// Reads `name` in the data dir, `None` when it doesn't exist yet or isn't
+/// usable. For what's kept beside what it describes, like thumbnail
+/// manifests, the rest of the state is in the tables.
+pub async fn read<T: DeserializeOwned>(name: &str) -> Option<T> {
+    let contents = match tokio::fs::read(config::get().data_dir.join(name)).await {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+        Err(e) => {
+            tracing::warn!("Can't read {}: {}", name, e);
+            return None;
+        }
+    };
+
+    serde_json::from_slice(&contents)
+        .map_err(|e| tracing::warn!("Ignoring {}, it's not valid: {}", name, e))
+        .ok()
+}
+
+/// Writes `value` to `name` in the data dir. Callers make sure writes to
+/// the same file don't overlap.
+pub async fn write<T: Serialize>(name: &str, value: &T) -> io::Result<()> {
+    let path = config::get().data_dir.join(name);
+    let temp = path.with_extension("tmp");
+
+    if let Some(dir) = path.parent() {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+    tokio::fs::write(&temp, serde_json::to_vec(value)?).await?;
+    // a crash halfway through writing shouldn't lose what was there before
+    tokio::fs::rename(&temp, &path).await
+}
+
+/// Loads what the previous run synced, so there's something to serve
+/// before the first sync finishes or when Sonarr is down.
+pub async fn load() {
+    let mut library = Library {
+        series: rows(SERIES).await,
+        tags: rows(TAGS).await,
+        missing_files: keys(MISSING_FILES).await,
+        ..Default::default()
+    };
+    for episode in rows::<i32, Value>(EPISODES).await.into_values() {
+        let series_id = episode["seriesId"].as_i64().unwrap_or_default() as i32;
+        library.episodes.entry(series_id).or_default().push(episode);
+    }
+    let syncs = rows::<String, u64>(SYNCS).await;
+    library.synced_at = syncs.get("full").copied();
+    library.updated_at = syncs.get("any").copied();
+
+    if library.series.is_empty() && library.updated_at.is_none() {
+        return;
+    }
+    tracing::debug!("Loaded {} series", library.series.len());
+    library.index_episode_files();
+    *LIBRARY.write().unwrap() = Some(Arc::new(library));
+}
+
+/// The synced library, `None` when syncing is off or nothing has been
+/// synced yet, in which case Sonarr should be asked directly.
+pub fn library() -> Option<Arc<Library>> {
+    if config::get().sync_interval.is_zero() {
+        return None;
+    }
+
+    LIBRARY.read().unwrap().clone()
+}
+
+/// Episodes by their id.
+fn episodes_by_id(library: &Library) -> BTreeMap<i32, &Value> {
+    library
+        .episodes
+        .values()
+        .flatten()
+        .map(|episode| (episode["id"].as_i64().unwrap_or_default() as i32, episode))
+        .collect()
+}
+
+/// The rows that changed between two versions of the library.
+fn diff_library(before: &Library, after: &Library) -> Vec<Change> {
+    let syncs = |library: &Library| {
+        [("full", library.synced_at), ("any", library.updated_at)]
+            .into_iter()
+            .filter_map(|(kind, at)| Some((kind.to_string(), at?)))
+            .collect::<BTreeMap<_, _>>()
+    };
+
+    let mut changes = diff(SERIES, &before.series, &after.series);
+    changes.extend(diff(
+        EPISODES,
+        &episodes_by_id(before),
+        &episodes_by_id(after),
+    ));
+    changes.extend(diff(TAGS, &before.tags, &after.tags));
+    changes.extend(diff_keys(
+        MISSING_FILES,
+        &before.missing_files,
+        &after.missing_files,
+    ));
+    changes.extend(diff(SYNCS, &syncs(before), &syncs(after)));
+
+    changes
+}
+
+/// Applies `change` to the library and writes the rows that changed. The
+/// in-memory copy is updated even when writing fails.
+pub async fn update(change: impl FnOnce(&mut Library)) {
+    // keeps an older library from being written after a newer one
+    let _updating = UPDATING.lock().await;
+
+    let (before, after) = {
+        let mut current = LIBRARY.write().unwrap();
+        let before = current.clone().unwrap_or_default();
+        let mut library = (*before).clone();
+        change(&mut library);
+        library.index_episode_files();
+
+        let library = Arc::new(library);
+        *current = Some(library.clone());
+        (before, library)
+    };
+
+    if let Err(e) = apply(&diff_library(&before, &after)).await {
+        tracing::warn!("Failed to save the library: {}", e);
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn only_changed_rows_are_written() {
+        let before = BTreeMap::from([(1, "a"), (2, "b"), (3, "c")]);
+        let after = BTreeMap::from([(1, "a"), (2, "B"), (4, "d")]);
+
+        assert_eq!(
+            diff("lists", &before, &after),
+            [
+                delete("lists", &3),
+                put("lists", &2, &"B"),
+                put("lists", &4, &"d"),
+            ]
+        );
+        assert!(diff("lists", &after, &after).is_empty());
+    }
+
+    #[test]
+    fn the_library_is_a_row_per_series_and_episode() {
+        let episode = |id: i32, title: &str| json!({"id": id, "seriesId": 1, "title": title});
+        let before = Library {
+            series: BTreeMap::from([(1, json!({"id": 1}))]),
+            episodes: BTreeMap::from([(1, vec![episode(10, "a"), episode(11, "b")])]),
+            synced_at: Some(100),
+            updated_at: Some(100),
+            ..Default::default()
+        };
+        let mut after = before.clone();
+        after
+            .episodes
+            .insert(1, vec![episode(10, "a"), episode(12, "c")]);
+        after.missing_files.insert(PathBuf::from("/tv/a.mkv"));
+        after.updated_at = Some(200);
+
+        assert_eq!(
+            diff_library(&before, &after),
+            [
+                delete(EPISODES, &11),
+                put(EPISODES, &12, &episode(12, "c")),
+                put(MISSING_FILES, &PathBuf::from("/tv/a.mkv"), &Value::Null),
+                put(SYNCS, &"any".to_string(), &200),
+            ]
+        );
+    }
+
+    #[test]
+    fn episode_files_are_found_by_id() {
+        let episode = |id: i32, file: Value| json!({"id": id, "episodeFile": file});
+        let mut library = Library {
+            episodes: BTreeMap::from([
+                (
+                    1,
+                    vec![episode(10, Value::Null), episode(11, json!({"id": 21}))],
+                ),
+                (2, vec![episode(12, json!({"id": 22, "path": "/tv/b.mkv"}))]),
+            ]),
+            ..Default::default()
+        };
+        library.index_episode_files();
+
+        assert_eq!(library.episode_file(21), Some(&json!({"id": 21})));
+        assert_eq!(library.episode_file(22).unwrap()["path"], "/tv/b.mkv");
+        assert_eq!(library.episode_file(23), None);
+
+        library.episodes.remove(&2);
+        library.index_episode_files();
+        assert_eq!(library.episode_file(22), None);
+    }
+
+    /// Held by tests opening the tables, so none of them finds them at
+    /// another version.
+    pub static OPENING: Lazy<tokio::sync::Mutex<()>> = Lazy::new(Default::default);
+
+    #[tokio::test]
+    async fn changes_are_made_all_together_or_not_at_all() {
+        config::init_for_tests();
+        let _opening = OPENING.lock().await;
+        open().await.unwrap();
+        let key = "store-test".to_string();
+
+        let failing = [
+            put("markers", &key, &1),
+            Change {
+                table: "no_such_table",
+                key: key.clone(),
+                value: None,
+            },
+        ];
+        assert!(apply(&failing).await.is_err());
+        assert!(!rows::<String, u32>("markers").await.contains_key(&key));
+
+        apply(&[put("markers", &key, &2)]).await.unwrap();
+        assert_eq!(rows::<String, u32>("markers").await.get(&key), Some(&2));
+        apply(&[delete("markers", &key)]).await.unwrap();
+        assert!(!rows::<String, u32>("markers").await.contains_key(&key));
+    }
+}
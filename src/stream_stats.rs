@@ -0,0 +1,224 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+use crate::redis;
+
+/// How long after a stream a request for the same file by the same client
+/// still counts as the same session.
+const SESSION_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+/// Requests starting this close to where the last one ended are reading on,
+/// players overlap them a little.
+const SEQUENTIAL_SLACK: u64 = 1_048_576;
+/// Upper bounds of the buckets seek distances and request sizes are
+/// counted in.
+const BUCKETS: [u64; 5] = [
+    1_048_576,
+    10 * 1_048_576,
+    100 * 1_048_576,
+    1_073_741_824,
+    u64::MAX,
+];
+
+/// Where a request starts, compared to what the client read before.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Start {
+    Beginning,
+    Sequential,
+    Seek,
+}
+
+#[derive(Default)]
+struct Stats {
+    requests: u64,
+    from_beginning: u64,
+    sequential: u64,
+    forward_seeks: u64,
+    backward_seeks: u64,
+    seek_distances: [u64; BUCKETS.len()],
+    request_sizes: [u64; BUCKETS.len()],
+    bytes_sent: u64,
+    /// Requests the client left before they were sent in full.
+    aborted: u64,
+    /// Where each client's last request for a file ended, and when,
+    /// unless they're shared over Redis.
+    sessions: HashMap<(IpAddr, PathBuf), (u64, Instant)>,
+}
+
+static STATS: Lazy<Mutex<Stats>> = Lazy::new(Default::default);
+/// Responses being sent right now.
+static STREAMING: AtomicUsize = AtomicUsize::new(0);
+
+/// Counts a response as being sent for as long as it's held.
+pub struct Streaming(());
+
+impl Streaming {
+    pub fn start() -> Self {
+        STREAMING.fetch_add(1, Ordering::Relaxed);
+        Self(())
+    }
+}
+
+impl Drop for Streaming {
+    fn drop(&mut self) {
+        STREAMING.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// How many responses are being sent right now.
+pub fn streaming() -> usize {
+    STREAMING.load(Ordering::Relaxed)
+}
+
+fn bucket(value: u64) -> usize {
+    BUCKETS
+        .iter()
+        .position(|bound| value <= *bound)
+        .unwrap_or(BUCKETS.len() - 1)
+}
+
+fn session_key(client: IpAddr, path: &Path) -> String {
+    format!("session:{}:{}", client, path.to_string_lossy())
+}
+
+/// Where `client`'s last request for `path` ended, when it was recent
+/// enough to be the same session.
+async fn previous_end(client: IpAddr, path: &Path) -> Option<u64> {
+    if let Some(redis) = redis::shared() {
+        return match redis.get(&session_key(client, path)).await {
+            Ok(end) => end.and_then(|end| String::from_utf8_lossy(&end).parse().ok()),
+            Err(e) => {
+                tracing::warn!("Can't get the streaming session: {}", e);
+                None
+            }
+        };
+    }
+
+    let mut stats = STATS.lock().unwrap();
+    stats
+        .sessions
+        .retain(|_, (_, at)| at.elapsed() < SESSION_TIMEOUT);
+    stats
+        .sessions
+        .get(&(client, path.to_path_buf()))
+        .map(|(end, _)| *end)
+}
+
+/// Counts a request for `path` from `first_byte`, telling whether it's a
+/// seek or reading on from the client's previous request.
+pub async fn started(client: IpAddr, path: &Path, first_byte: u64) -> Start {
+    let previous = previous_end(client, path).await;
+    let mut stats = STATS.lock().unwrap();
+    stats.requests += 1;
+
+    match previous {
+        _ if first_byte == 0 => {
+            stats.from_beginning += 1;
+            Start::Beginning
+        }
+        Some(end) if first_byte.abs_diff(end) <= SEQUENTIAL_SLACK => {
+            stats.sequential += 1;
+            Start::Sequential
+        }
+        previous => {
+            let end = previous.unwrap_or(0);
+            if first_byte > end {
+                stats.forward_seeks += 1;
+            } else {
+                stats.backward_seeks += 1;
+            }
+            stats.seek_distances[bucket(first_byte.abs_diff(end))] += 1;
+            Start::Seek
+        }
+    }
+}
+
+/// Counts what was sent of a request, up to where it ended, and whether
+/// the client left before it was sent in full.
+pub async fn finished(client: IpAddr, path: &Path, first_byte: u64, end: u64, aborted: bool) {
+    {
+        let mut stats = STATS.lock().unwrap();
+        let sent = end.saturating_sub(first_byte);
+        stats.bytes_sent += sent;
+        stats.aborted += u64::from(aborted);
+        stats.request_sizes[bucket(sent)] += 1;
+        if redis::shared().is_none() {
+            stats
+                .sessions
+                .insert((client, path.to_path_buf()), (end, Instant::now()));
+        }
+    }
+
+    if let Some(redis) = redis::shared() {
+        let key = session_key(client, path);
+        if let Err(e) = redis.set(&key, &end.to_string(), SESSION_TIMEOUT).await {
+            tracing::warn!("Can't share the streaming session: {}", e);
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Bucket {
+    /// Bytes, `None` for the last bucket.
+    up_to: Option<u64>,
+    count: u64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Summary {
+    requests: u64,
+    from_beginning: u64,
+    sequential: u64,
+    forward_seeks: u64,
+    backward_seeks: u64,
+    seek_distances: Vec<Bucket>,
+    request_sizes: Vec<Bucket>,
+    bytes_sent: u64,
+    average_bytes_per_request: u64,
+    aborted: u64,
+    active_sessions: usize,
+    streaming: usize,
+}
+
+fn buckets(counts: &[u64]) -> Vec<Bucket> {
+    BUCKETS
+        .iter()
+        .zip(counts)
+        .map(|(bound, count)| Bucket {
+            up_to: (*bound != u64::MAX).then_some(*bound),
+            count: *count,
+        })
+        .collect()
+}
+
+/// How streams started and how much they sent since centarr started.
+pub fn summary() -> Summary {
+    let stats = STATS.lock().unwrap();
+
+    Summary {
+        requests: stats.requests,
+        from_beginning: stats.from_beginning,
+        sequential: stats.sequential,
+        forward_seeks: stats.forward_seeks,
+        backward_seeks: stats.backward_seeks,
+        seek_distances: buckets(&stats.seek_distances),
+        request_sizes: buckets(&stats.request_sizes),
+        bytes_sent: stats.bytes_sent,
+        average_bytes_per_request: stats.bytes_sent.checked_div(stats.requests).unwrap_or(0),
+        aborted: stats.aborted,
+        active_sessions: stats
+            .sessions
+            .values()
+            .filter(|(_, at)| at.elapsed() < SESSION_TIMEOUT)
+            .count(),
+        streaming: streaming(),
+    }
+}
@@ -0,0 +1,506 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use axum::{extract::Query, http::StatusCode, routing::post, Json, Router};
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::Value;
+use tokio::{
+    select,
+    sync::{Mutex as AsyncMutex, Notify},
+};
+
+use crate::{
+    admin, config,
+    errors::ApiError,
+    events::{self, Event},
+    library, sonarr, store,
+};
+
+/// How long to wait before trying again after a failed sync.
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+/// History is asked for from a bit before the previous sync, so events
+/// recorded while it was running aren't missed.
+const HISTORY_OVERLAP: u64 = 5 * 60;
+const HISTORY_PAGE_SIZE: u32 = 250;
+/// How often to check whether syncing got turned on while it's off.
+const DISABLED_POLL: Duration = Duration::from_secs(60);
+
+/// Wakes the sync task up early, e.g. for a webhook.
+static WAKE: Lazy<Notify> = Lazy::new(Notify::new);
+/// Series to refresh on the next wake up.
+static PENDING: Lazy<Mutex<BTreeSet<i32>>> = Lazy::new(Default::default);
+/// Whether the next wake up should sync everything.
+static FULL_SYNC_REQUESTED: AtomicBool = AtomicBool::new(false);
+/// Held while syncing, so a scheduled full sync doesn't run alongside
+/// the sync task's own.
+static SYNCING: Lazy<AsyncMutex<()>> = Lazy::new(Default::default);
+
+pub fn router() -> Router {
+    Router::new().route("/webhooks/sonarr", post(sonarr_webhook))
+}
+
+#[derive(Deserialize)]
+struct WebhookQuery {
+    token: Option<String>,
+}
+
+/// The parts of Sonarr's webhook payload we care about.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct WebhookEvent {
+    event_type: String,
+    series: Option<WebhookSeries>,
+    #[serde(default)]
+    episodes: Vec<WebhookEpisode>,
+}
+
+#[derive(Deserialize)]
+struct WebhookSeries {
+    id: i32,
+    title: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct WebhookEpisode {
+    season_number: i32,
+    episode_number: i32,
+    title: String,
+}
+
+/// Receives Sonarr's "Connect > Webhook" notifications and refreshes the
+/// series they're about right away.
+async fn sonarr_webhook(
+    Query(query): Query<WebhookQuery>,
+    Json(event): Json<WebhookEvent>,
+) -> Result<StatusCode, ApiError> {
+    let config = config::get();
+    if config.sync_interval.is_zero() {
+        return Err(ApiError::new(404, "Syncing is turned off".into()));
+    }
+
+    if let Some(token) = config.webhook_token.clone() {
+        let authorized = query
+            .token
+            .filter(|given| admin::constant_time_eq(given.as_bytes(), token.as_bytes()))
+            .is_some();
+
+        if !authorized {
+            return Err(ApiError::empty(401, None));
+        }
+    }
+
+    if event.event_type == "Test" {
+        return Ok(StatusCode::NO_CONTENT);
+    }
+
+    match event.series {
+        Some(series) => {
+            tracing::debug!("Sonarr sent {} for series {}", event.event_type, series.id);
+            refresh(series.id);
+
+            if event.event_type == "Download" {
+                events::publish(Event::EpisodeImported {
+                    series_id: series.id,
+                    series_title: series.title,
+                    episodes: event
+                        .episodes
+                        .iter()
+                        .map(|episode| {
+                            format!(
+                                "S{:02}E{:02} - {}",
+                                episode.season_number, episode.episode_number, episode.title
+                            )
+                        })
+                        .collect(),
+                });
+            }
+        }
+        // no idea what changed, so check everything
+        None => {
+            FULL_SYNC_REQUESTED.store(true, Ordering::Relaxed);
+            WAKE.notify_one();
+        }
+    }
+
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// Syncs series `id` as soon as possible.
+pub fn refresh(id: i32) {
+    PENDING.lock().unwrap().insert(id);
+    WAKE.notify_one();
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+async fn fetch_json<T: DeserializeOwned>(path: &str) -> Result<T, ApiError> {
+    let body = sonarr::fetch(path).await?;
+
+    serde_json::from_str(&body).map_err(|e| ApiError::empty(500, Some(e.to_string())))
+}
+
+/// Lets subscribers know which series changed, if any did.
+fn library_updated(series_ids: BTreeSet<i32>) {
+    if !series_ids.is_empty() {
+        events::publish(Event::LibraryUpdated {
+            series_ids: series_ids.into_iter().collect(),
+        });
+    }
+}
+
+fn series_id(series: &Value) -> Option<i32> {
+    series["id"].as_i64().map(|id| id as i32)
+}
+
+/// Mirrors every series and its episodes into the store.
+/// Sonarr's tag labels by id.
+async fn fetch_tags() -> Result<BTreeMap<i32, String>, ApiError> {
+    let tags = fetch_json::<Vec<library::Tag>>("/tag").await?;
+
+    Ok(tags.into_iter().map(|tag| (tag.id, tag.label)).collect())
+}
+
+async fn full_sync() -> Result<(), ApiError> {
+    let started = Instant::now();
+    let started_at = now();
+    let series = fetch_json::<Vec<Value>>("/series").await?;
+    let tags = fetch_tags().await?;
+    let mut episodes = BTreeMap::new();
+
+    for id in series.iter().filter_map(series_id) {
+        let path = format!("/episode?seriesId={}", id);
+        episodes.insert(id, fetch_json::<Vec<Value>>(&path).await?);
+    }
+
+    let config = config::get();
+    let known = episodes
+        .values()
+        .flatten()
+        .filter_map(|episode| episode["episodeFile"]["path"].as_str())
+        .map(|path| config.local_path(Path::new(path)))
+        .collect::<BTreeSet<_>>();
+
+    let stored = store::library().unwrap_or_default();
+    let mut changed = stored
+        .series
+        .keys()
+        .filter(|id| !episodes.contains_key(id))
+        .copied()
+        .collect::<BTreeSet<_>>();
+    for series in &series {
+        if let Some(id) = series_id(series) {
+            if stored.series.get(&id) != Some(series)
+                || stored.episodes.get(&id) != episodes.get(&id)
+            {
+                changed.insert(id);
+            }
+        }
+    }
+
+    let count = series.len();
+    store::update(|library| {
+        library.series = series
+            .into_iter()
+            .filter_map(|series| Some((series_id(&series)?, series)))
+            .collect();
+        library.episodes = episodes;
+        library.tags = tags;
+        // files Sonarr has since dropped or that are back don't need tracking
+        library
+            .missing_files
+            .retain(|file| known.contains(file) && !file.exists());
+        library.synced_at = Some(started_at);
+        library.updated_at = Some(started_at);
+    })
+    .await;
+
+    tracing::info!("Synced {} series in {:?}", count, started.elapsed());
+    library_updated(changed);
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct HistoryPage {
+    records: Vec<HistoryRecord>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct HistoryRecord {
+    series_id: i32,
+    date: DateTime<Utc>,
+}
+
+/// Series with anything in Sonarr's history since `since`, newest first
+/// until the records get older than that.
+async fn changed_since(since: DateTime<Utc>) -> Result<BTreeSet<i32>, ApiError> {
+    let mut changed = BTreeSet::new();
+
+    for page in 1.. {
+        // sortDir is what v2 understands, sortDirection is v3's
+        let path = format!(
+            "/history?page={}&pageSize={}&sortKey=date&sortDir=desc&sortDirection=descending",
+            page, HISTORY_PAGE_SIZE
+        );
+        let records = fetch_json::<HistoryPage>(&path).await?.records;
+        let count = records.len();
+
+        for record in records {
+            if record.date < since {
+                return Ok(changed);
+            }
+            changed.insert(record.series_id);
+        }
+
+        if count < HISTORY_PAGE_SIZE as usize {
+            break;
+        }
+    }
+
+    Ok(changed)
+}
+
+/// Which of the listed `series` to refetch the episodes of, the ones in
+/// `changed` Sonarr still has and the ones that differ from `stored`, and
+/// which of the `stored` ones Sonarr no longer has.
+fn delta(
+    stored: &store::Library,
+    series: &[Value],
+    mut changed: BTreeSet<i32>,
+) -> (BTreeSet<i32>, BTreeSet<i32>) {
+    for series in series {
+        if let Some(id) = series_id(series) {
+            // statistics like the file count and size on disk change on imports
+            if stored.series.get(&id) != Some(series) {
+                changed.insert(id);
+            }
+        }
+    }
+
+    let listed = series.iter().filter_map(series_id).collect::<BTreeSet<_>>();
+    changed.retain(|id| listed.contains(id));
+    let removed = stored
+        .series
+        .keys()
+        .filter(|id| !listed.contains(id))
+        .copied()
+        .collect();
+
+    (changed, removed)
+}
+
+/// Refetches the series list, and episodes only for series that were
+/// added, changed, show up in the history since the last sync or are
+/// `pending`.
+async fn delta_sync(updated_at: u64, pending: BTreeSet<i32>) -> Result<(), ApiError> {
+    let started = Instant::now();
+    let started_at = now();
+    let since = DateTime::from_timestamp(updated_at.saturating_sub(HISTORY_OVERLAP) as i64, 0)
+        .unwrap_or_default();
+
+    let mut changed = changed_since(since).await?;
+    changed.extend(pending);
+    let series = fetch_json::<Vec<Value>>("/series").await?;
+    let tags = fetch_tags().await?;
+    let stored = store::library().unwrap_or_default();
+    let (changed, removed) = delta(&stored, &series, changed);
+    let listed = series.iter().filter_map(series_id).collect::<BTreeSet<_>>();
+
+    let mut episodes = BTreeMap::new();
+    for id in &changed {
+        let path = format!("/episode?seriesId={}", id);
+        episodes.insert(*id, fetch_json::<Vec<Value>>(&path).await?);
+    }
+
+    store::update(|library| {
+        library.series = series
+            .into_iter()
+            .filter_map(|series| Some((series_id(&series)?, series)))
+            .collect();
+        library.episodes.retain(|id, _| listed.contains(id));
+        library.episodes.extend(episodes);
+        library.tags = tags;
+        library.updated_at = Some(started_at);
+    })
+    .await;
+
+    tracing::info!(
+        "Synced {} changed series in {:?}",
+        changed.len(),
+        started.elapsed()
+    );
+    library_updated(changed.into_iter().chain(removed).collect());
+    Ok(())
+}
+
+/// Refreshes one series, dropping it when Sonarr no longer has it.
+async fn sync_series(id: i32) -> Result<(), ApiError> {
+    let series = match fetch_json::<Value>(&format!("/series/{}", id)).await {
+        Ok(series) => Some(series),
+        Err(e) if e.status_code() == StatusCode::NOT_FOUND => None,
+        Err(e) => return Err(e),
+    };
+    let episodes = match &series {
+        Some(_) => Some(fetch_json::<Vec<Value>>(&format!("/episode?seriesId={}", id)).await?),
+        None => None,
+    };
+
+    store::update(|library| match (series, episodes) {
+        (Some(series), Some(episodes)) => {
+            library.series.insert(id, series);
+            library.episodes.insert(id, episodes);
+        }
+        _ => {
+            library.series.remove(&id);
+            library.episodes.remove(&id);
+        }
+    })
+    .await;
+
+    tracing::debug!("Synced series {}", id);
+    library_updated(BTreeSet::from([id]));
+    Ok(())
+}
+
+/// Refetches everything, once whatever sync is running is done. Delta
+/// syncs can miss changes that don't show up in Sonarr's history, so the
+/// scheduler runs this daily.
+pub async fn full() -> Result<(), ApiError> {
+    let _syncing = SYNCING.lock().await;
+
+    full_sync().await
+}
+
+/// Keeps the store in sync with Sonarr, with a delta sync every
+/// `sync_interval`, a full one the first time or when asked for, and per
+/// series whenever a webhook comes in.
+pub async fn run() {
+    loop {
+        let interval = config::get().sync_interval;
+        if interval.is_zero() {
+            select! {
+                _ = tokio::time::sleep(DISABLED_POLL) => {},
+                _ = WAKE.notified() => {},
+            }
+            continue;
+        }
+
+        let syncing = SYNCING.lock().await;
+        let pending = std::mem::take(&mut *PENDING.lock().unwrap());
+        let library = store::library().unwrap_or_default();
+        // timestamps are kept in the store, so a restart picks up where
+        // the previous run left off instead of refetching everything
+        let since = |at: Option<u64>| Duration::from_secs(now().saturating_sub(at.unwrap_or(0)));
+
+        let result =
+            if library.synced_at.is_none() || FULL_SYNC_REQUESTED.swap(false, Ordering::Relaxed) {
+                full_sync().await
+            } else if since(library.updated_at) >= interval {
+                delta_sync(library.updated_at.unwrap_or_default(), pending.clone()).await
+            } else {
+                let mut result = Ok(());
+                for id in &pending {
+                    result = result.and(sync_series(*id).await);
+                }
+                result
+            };
+
+        let wait = match result {
+            Ok(()) => {
+                let updated_at = store::library().and_then(|library| library.updated_at);
+                interval.saturating_sub(since(updated_at))
+            }
+            Err(e) => {
+                tracing::warn!("Sync failed, serving what was synced before: {:?}", e);
+                PENDING.lock().unwrap().extend(pending);
+                RETRY_DELAY
+            }
+        };
+        drop(syncing);
+
+        select! {
+            _ = tokio::time::sleep(wait) => {},
+            _ = WAKE.notified() => {},
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn history_path(page: u32) -> String {
+        format!(
+            "/history?page={}&pageSize={}&sortKey=date&sortDir=desc&sortDirection=descending",
+            page, HISTORY_PAGE_SIZE
+        )
+    }
+
+    fn history(records: &[(i32, &str)]) -> String {
+        let records = records
+            .iter()
+            .map(|(series_id, date)| json!({ "seriesId": series_id, "date": date }))
+            .collect::<Vec<_>>();
+
+        json!({ "records": records }).to_string()
+    }
+
+    #[tokio::test]
+    async fn history_is_read_up_to_the_last_sync() {
+        let since = "2020-01-01T12:00:00Z".parse().unwrap();
+        let full = [(1, "2020-01-02T00:00:00Z"), (2, "2020-01-01T13:00:00Z")]
+            .repeat(HISTORY_PAGE_SIZE as usize / 2);
+        // the third page would fail, the older record ends it before that
+        let sonarr = sonarr::Mock::default()
+            .with(&history_path(1), &history(&full))
+            .with(
+                &history_path(2),
+                &history(&[
+                    (3, "2020-01-01T12:00:00Z"),
+                    (4, "2020-01-01T11:59:59Z"),
+                    (5, "2020-01-02T00:00:00Z"),
+                ]),
+            );
+        let changed = sonarr.scope(changed_since(since)).await.unwrap();
+        assert_eq!(changed, BTreeSet::from([1, 2, 3]));
+
+        // nor is there a next page after a short one
+        let sonarr = sonarr::Mock::default()
+            .with(&history_path(1), &history(&[(6, "2020-01-02T00:00:00Z")]));
+        let changed = sonarr.scope(changed_since(since)).await.unwrap();
+        assert_eq!(changed, BTreeSet::from([6]));
+    }
+
+    #[test]
+    fn changed_series_are_refetched_and_removed_ones_dropped() {
+        let series = |id: i32, episodes: i32| json!({ "id": id, "episodeFileCount": episodes });
+        let mut stored = store::Library::default();
+        stored.series = [series(1, 10), series(2, 10), series(3, 10)]
+            .into_iter()
+            .map(|series| (series_id(&series).unwrap(), series))
+            .collect();
+        let listed = [series(1, 10), series(2, 11), series(4, 0)];
+
+        // 1 is pending, 9 has history but was removed since
+        let (changed, removed) = delta(&stored, &listed, BTreeSet::from([1, 9]));
+        assert_eq!(changed, BTreeSet::from([1, 2, 4]));
+        assert_eq!(removed, BTreeSet::from([3]));
+
+        let (changed, removed) = delta(&stored, &listed[..1], BTreeSet::new());
+        assert!(changed.is_empty());
+        assert_eq!(removed, BTreeSet::from([2, 3]));
+    }
+}
@@ -0,0 +1,126 @@
+//! Socket activation and service notifications for running under systemd,
+//! as in sd_listen_fds(3) and sd_notify(3). Without the variables systemd
+//! sets for them, none of this does anything.
+
+use std::env;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::time::Duration;
+
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+
+use crate::listen::{self, Listener};
+
+/// The first fd systemd passes sockets from.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Sockets systemd opened for the servers.
+#[derive(Default)]
+pub struct Inherited {
+    pub api: Vec<Listener>,
+    pub stream: Vec<Listener>,
+}
+
+/// Whether variables systemd sets for a process are meant for us rather
+/// than the process that started us.
+fn for_us(pid_var: &str) -> bool {
+    env::var(pid_var)
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .is_some_and(|pid| pid == std::process::id())
+}
+
+/// Takes the sockets systemd passed, those named `api` or `stream` with
+/// `FileDescriptorName=` going to that server, any number of them, and
+/// unnamed ones to the API first and the streaming server after.
+pub fn listeners() -> Result<Inherited, String> {
+    let mut inherited = Inherited::default();
+    let count = env::var("LISTEN_FDS")
+        .ok()
+        .filter(|_| for_us("LISTEN_PID"))
+        .and_then(|count| count.parse::<RawFd>().ok())
+        .unwrap_or(0);
+    let names = env::var("LISTEN_FDNAMES").unwrap_or_default();
+    // ffmpeg and friends shouldn't think they're activated too
+    for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        env::remove_var(name);
+    }
+
+    let mut fds = (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .zip(names.split(':').chain(std::iter::repeat("")))
+        .collect::<Vec<_>>();
+    // named sockets first, so unnamed ones don't take their place
+    fds.sort_by_key(|(_, name)| !matches!(*name, "api" | "stream"));
+
+    for (fd, name) in fds {
+        fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))
+            .map_err(|e| format!("Can't take over fd {} from systemd: {}", fd, e))?;
+
+        let (server, listeners) = match name {
+            "api" => ("API", &mut inherited.api),
+            "stream" => ("streaming server", &mut inherited.stream),
+            _ if inherited.api.is_empty() => ("API", &mut inherited.api),
+            _ if inherited.stream.is_empty() => ("streaming server", &mut inherited.stream),
+            _ => {
+                return Err(format!(
+                    "Can't tell which server fd {} from systemd is for, name it api or stream",
+                    fd
+                ))
+            }
+        };
+
+        tracing::info!(
+            "Using the socket systemd passed as fd {} for the {}",
+            fd,
+            server
+        );
+        listeners.push(listen::from_fd(fd)?);
+    }
+
+    Ok(inherited)
+}
+
+/// Tells systemd about a change in state, like `READY=1` once centarr
+/// listens.
+pub fn notify(state: &str) {
+    let path = match env::var_os("NOTIFY_SOCKET") {
+        Some(path) => path,
+        None => return,
+    };
+
+    let addr = match path.as_bytes().strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name),
+        None => SocketAddr::from_pathname(&path),
+    };
+    let sent = UnixDatagram::unbound().and_then(|socket| {
+        socket.send_to_addr(state.as_bytes(), &addr?)?;
+        Ok(())
+    });
+
+    if let Err(e) = sent {
+        tracing::warn!("Can't notify systemd of {:?}: {}", state, e);
+    }
+}
+
+/// Lets systemd know centarr is still alive, twice as often as its
+/// `WatchdogSec=` asks for. Waits forever without a watchdog.
+pub async fn watchdog() {
+    let timeout = env::var("WATCHDOG_USEC")
+        .ok()
+        .filter(|_| env::var("WATCHDOG_PID").is_err() || for_us("WATCHDOG_PID"))
+        .and_then(|usec| usec.parse::<u64>().ok())
+        .filter(|usec| *usec > 0);
+
+    let timeout = match timeout {
+        Some(usec) => Duration::from_micros(usec),
+        None => return std::future::pending().await,
+    };
+
+    let mut ticks = tokio::time::interval(timeout / 2);
+    loop {
+        ticks.tick().await;
+        notify("WATCHDOG=1");
+    }
+}
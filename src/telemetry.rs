@@ -0,0 +1,105 @@
+//! Log setup and optional OTLP/HTTP export of our `tracing` spans.
+//!
+//! Set `OTEL_EXPORTER_OTLP_ENDPOINT` (e.g. `http://tempo:4318`) to enable it,
+//! `OTEL_SERVICE_NAME` overrides the reported service name.
+
+use std::collections::HashMap;
+use std::env;
+use std::sync::Mutex;
+
+use once_cell::sync::{Lazy, OnceCell};
+use opentelemetry::global;
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::SpanExporter;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{SdkTracer, SdkTracerProvider};
+use opentelemetry_sdk::Resource;
+use tracing::Subscriber;
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{reload, EnvFilter, Registry};
+
+static LOG_FILTER: OnceCell<reload::Handle<EnvFilter, Registry>> = OnceCell::new();
+static LOG_LEVEL: Lazy<Mutex<String>> = Lazy::new(Mutex::default);
+static TRACER_PROVIDER: OnceCell<SdkTracerProvider> = OnceCell::new();
+
+/// Installs the global subscriber, logging at `log_level` (an `EnvFilter`
+/// directive like `RUST_LOG`).
+pub fn init(log_level: &str) {
+    let (filter, handle) = reload::Layer::new(EnvFilter::new(log_level));
+    *LOG_LEVEL.lock().unwrap() = log_level.to_string();
+    LOG_FILTER
+        .set(handle)
+        .expect("telemetry should only be initialized once");
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(tracing_subscriber::fmt::layer())
+        .with(layer())
+        .init();
+}
+
+/// Swaps the log filter of the running subscriber.
+pub fn set_log_level(log_level: &str) -> Result<(), String> {
+    let filter = EnvFilter::try_new(log_level).map_err(|e| e.to_string())?;
+
+    if let Some(handle) = LOG_FILTER.get() {
+        handle.reload(filter).map_err(|e| e.to_string())?;
+        *LOG_LEVEL.lock().unwrap() = log_level.to_string();
+    }
+
+    Ok(())
+}
+
+/// The filter directive currently in use.
+pub fn log_level() -> String {
+    LOG_LEVEL.lock().unwrap().clone()
+}
+
+/// Builds the exporting layer when an OTLP endpoint is configured. The
+/// exporter reads `OTEL_EXPORTER_OTLP_ENDPOINT` and the rest of the
+/// `OTEL_EXPORTER_OTLP_*` variables itself.
+fn layer<S>() -> Option<OpenTelemetryLayer<S, SdkTracer>>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    env::var("OTEL_EXPORTER_OTLP_ENDPOINT").ok()?;
+    let service_name = env::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| "centarr".into());
+
+    let exporter = match SpanExporter::builder().with_http().build() {
+        Ok(exporter) => exporter,
+        Err(e) => {
+            eprintln!("can't export spans: {}", e);
+            return None;
+        }
+    };
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name(service_name).build())
+        .build();
+    let tracer = provider.tracer("centarr");
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    let _ = TRACER_PROVIDER.set(provider);
+
+    Some(tracing_opentelemetry::layer().with_tracer(tracer))
+}
+
+/// Headers that carry the current span on to upstream requests, W3C
+/// `traceparent` and `tracestate`, empty unless spans are exported.
+pub fn propagation_headers() -> HashMap<String, String> {
+    let context = tracing::Span::current().context();
+    let mut headers = HashMap::new();
+    global::get_text_map_propagator(|propagator| propagator.inject_context(&context, &mut headers));
+    headers
+}
+
+/// Sends off the spans that are still waiting to be exported.
+pub fn shutdown() {
+    if let Some(provider) = TRACER_PROVIDER.get() {
+        if let Err(e) = provider.shutdown() {
+            eprintln!("failed to export the last spans: {}", e);
+        }
+    }
+}
@@ -0,0 +1,259 @@
+use axum::http::{header, HeaderMap};
+use serde::{Deserialize, Serialize};
+
+/// The ISO 639-1 codes of the languages Sonarr knows, by its names for
+/// them.
+const LANGUAGES: &[(&str, &str)] = &[
+    ("ar", "Arabic"),
+    ("bg", "Bulgarian"),
+    ("bs", "Bosnian"),
+    ("ca", "Catalan"),
+    ("cs", "Czech"),
+    ("da", "Danish"),
+    ("de", "German"),
+    ("el", "Greek"),
+    ("en", "English"),
+    ("es", "Spanish"),
+    ("et", "Estonian"),
+    ("fa", "Persian"),
+    ("fi", "Finnish"),
+    ("fr", "French"),
+    ("he", "Hebrew"),
+    ("hi", "Hindi"),
+    ("hr", "Croatian"),
+    ("hu", "Hungarian"),
+    ("id", "Indonesian"),
+    ("is", "Icelandic"),
+    ("it", "Italian"),
+    ("ja", "Japanese"),
+    ("ko", "Korean"),
+    ("lt", "Lithuanian"),
+    ("lv", "Latvian"),
+    ("mk", "Macedonian"),
+    ("ml", "Malayalam"),
+    ("nb", "Norwegian"),
+    ("nl", "Dutch"),
+    ("nn", "Norwegian"),
+    ("no", "Norwegian"),
+    ("pl", "Polish"),
+    ("pt", "Portuguese"),
+    ("ro", "Romanian"),
+    ("ru", "Russian"),
+    ("sk", "Slovak"),
+    ("sl", "Slovenian"),
+    ("sr", "Serbian"),
+    ("sv", "Swedish"),
+    ("ta", "Tamil"),
+    ("th", "Thai"),
+    ("tr", "Turkish"),
+    ("uk", "Ukrainian"),
+    ("vi", "Vietnamese"),
+    ("zh", "Chinese"),
+];
+
+/// ISO 639-2 codes, both the bibliographic and the terminology one where
+/// they differ, of the languages in [`LANGUAGES`].
+const THREE_LETTER_CODES: &[(&str, &str)] = &[
+    ("ara", "Arabic"),
+    ("bul", "Bulgarian"),
+    ("bos", "Bosnian"),
+    ("cat", "Catalan"),
+    ("cze", "Czech"),
+    ("ces", "Czech"),
+    ("dan", "Danish"),
+    ("ger", "German"),
+    ("deu", "German"),
+    ("gre", "Greek"),
+    ("ell", "Greek"),
+    ("eng", "English"),
+    ("spa", "Spanish"),
+    ("est", "Estonian"),
+    ("per", "Persian"),
+    ("fas", "Persian"),
+    ("fin", "Finnish"),
+    ("fre", "French"),
+    ("fra", "French"),
+    ("heb", "Hebrew"),
+    ("hin", "Hindi"),
+    ("hrv", "Croatian"),
+    ("hun", "Hungarian"),
+    ("ind", "Indonesian"),
+    ("ice", "Icelandic"),
+    ("isl", "Icelandic"),
+    ("ita", "Italian"),
+    ("jpn", "Japanese"),
+    ("kor", "Korean"),
+    ("lit", "Lithuanian"),
+    ("lav", "Latvian"),
+    ("mac", "Macedonian"),
+    ("mkd", "Macedonian"),
+    ("mal", "Malayalam"),
+    ("nob", "Norwegian"),
+    ("nno", "Norwegian"),
+    ("nor", "Norwegian"),
+    ("dut", "Dutch"),
+    ("nld", "Dutch"),
+    ("pol", "Polish"),
+    ("por", "Portuguese"),
+    ("rum", "Romanian"),
+    ("ron", "Romanian"),
+    ("rus", "Russian"),
+    ("slo", "Slovak"),
+    ("slk", "Slovak"),
+    ("slv", "Slovenian"),
+    ("srp", "Serbian"),
+    ("swe", "Swedish"),
+    ("tam", "Tamil"),
+    ("tha", "Thai"),
+    ("tur", "Turkish"),
+    ("ukr", "Ukrainian"),
+    ("vie", "Vietnamese"),
+    ("chi", "Chinese"),
+    ("zho", "Chinese"),
+];
+
+/// Sonarr's name for `language`, which is a two or three letter code like
+/// `en` or `eng`, or already a name like `English`.
+pub fn language_name(language: &str) -> Option<&'static str> {
+    let language = language.trim().to_ascii_lowercase();
+
+    LANGUAGES
+        .iter()
+        .chain(THREE_LETTER_CODES)
+        .find(|(code, name)| *code == language || name.to_ascii_lowercase() == language)
+        .map(|(_, name)| *name)
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Language {
+    pub name: String,
+}
+
+/// Another title a show goes by, like a translation or a scene name.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AlternateTitle {
+    pub title: String,
+    /// When it's only used for one season.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub season_number: Option<i32>,
+    /// Sonarr doesn't tell for most titles.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub language: Option<Language>,
+}
+
+/// The names Sonarr has for the languages in `Accept-Language`, most
+/// preferred first.
+pub fn preferred_languages(headers: &HeaderMap) -> Vec<&'static str> {
+    let mut ranges = headers
+        .get_all(header::ACCEPT_LANGUAGE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|range| {
+            let mut parts = range.split(';').map(str::trim);
+            let tag = parts.next()?.to_ascii_lowercase();
+            let quality = parts
+                .find_map(|param| param.strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.parse::<f32>().ok())?;
+            Some((tag, quality))
+        })
+        .filter(|(_, quality)| *quality > 0.0)
+        .collect::<Vec<_>>();
+    // stable, so equally preferred ones stay in the order they were sent
+    ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    let mut languages = Vec::new();
+    for (tag, _) in ranges {
+        let primary = tag.split('-').next().unwrap_or_default();
+        let name = LANGUAGES
+            .iter()
+            .find(|(code, _)| *code == primary)
+            .map(|(_, name)| *name);
+        if let Some(name) = name.filter(|name| !languages.contains(name)) {
+            languages.push(name);
+        }
+    }
+
+    languages
+}
+
+/// The alternate title in the most preferred of `languages`, if there is
+/// one for the whole show. `None` when English is preferred more, or as
+/// much, as Sonarr's own titles are TVDB's English ones whatever language
+/// the show was made in.
+pub fn localized(alternate_titles: &[AlternateTitle], languages: &[&str]) -> Option<String> {
+    for language in languages {
+        if *language == "English" {
+            return None;
+        }
+
+        let title = alternate_titles.iter().find(|title| {
+            title.season_number.is_none()
+                && title
+                    .language
+                    .as_ref()
+                    .is_some_and(|title| title.name == *language)
+        });
+        if let Some(title) = title {
+            return Some(title.title.clone());
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    fn accepting(value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT_LANGUAGE, HeaderValue::from_static(value));
+        headers
+    }
+
+    fn title(title: &str, language: Option<&str>, season_number: Option<i32>) -> AlternateTitle {
+        AlternateTitle {
+            title: title.into(),
+            season_number,
+            language: language.map(|name| Language { name: name.into() }),
+        }
+    }
+
+    #[test]
+    fn languages_go_by_quality_then_order() {
+        let headers = accepting("en;q=0.5, de-AT, fr-CA;q=0.8, nl;q=0, xx, de");
+        assert_eq!(
+            preferred_languages(&headers),
+            ["German", "French", "English"]
+        );
+        assert_eq!(preferred_languages(&accepting("ja, *;q=0.1")), ["Japanese"]);
+        assert!(preferred_languages(&HeaderMap::new()).is_empty());
+    }
+
+    #[test]
+    fn titles_are_localized_unless_english_comes_first() {
+        let titles = [
+            title("Shingeki no Kyojin", Some("Japanese"), None),
+            title("Angriff auf Titan", Some("German"), None),
+            title("Angriff auf Titan: Staffel 2", Some("German"), Some(2)),
+            title("Attack on Titan (US)", None, None),
+        ];
+
+        // the show is Japanese, but its title in Sonarr is English
+        assert_eq!(
+            localized(&titles, &["Japanese", "English"]).as_deref(),
+            Some("Shingeki no Kyojin")
+        );
+        assert_eq!(
+            localized(&titles, &["French", "German"]).as_deref(),
+            Some("Angriff auf Titan")
+        );
+        assert_eq!(localized(&titles, &["English", "German"]), None);
+        assert_eq!(localized(&titles, &["French"]), None);
+        assert_eq!(localized(&[], &["German"]), None);
+    }
+}
@@ -0,0 +1,534 @@
+//! Scrobbling what players report playing to the Trakt accounts users
+//! linked. Linking goes through Trakt's device flow: `POST /users/me/trakt`
+//! hands out a code to enter at trakt.tv/activate, and centarr keeps asking
+//! Trakt whether it was until it runs out.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use axum::{http::StatusCode, routing::get, Json, Router};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+
+use crate::{
+    auth::AuthUser,
+    config::{self, TraktConfig},
+    errors::ApiError,
+    sonarr, upstream,
+    users::{self, TraktTokens},
+};
+
+const API_VERSION: &str = "2";
+/// Access tokens are refreshed when they run out sooner than this.
+const REFRESH_BEFORE: Duration = Duration::from_secs(24 * 60 * 60);
+/// What Trakt wants as the redirect uri of apps logging in with codes.
+const NO_REDIRECT: &str = "urn:ietf:wg:oauth:2.0:oob";
+
+/// The device codes of users who were handed a code and haven't entered
+/// it yet.
+static LINKING: Lazy<Mutex<HashMap<String, String>>> = Lazy::new(Default::default);
+/// Held while tokens are refreshed, as each refresh token only works once.
+static REFRESHING: Lazy<tokio::sync::Mutex<()>> = Lazy::new(Default::default);
+/// Scrobbles waiting to be sent, one at a time so they arrive in order.
+static QUEUE: Lazy<mpsc::UnboundedSender<Scrobble>> = Lazy::new(|| {
+    let (queue, mut queued) = mpsc::unbounded_channel::<Scrobble>();
+    tokio::spawn(async move {
+        while let Some(scrobble) = queued.recv().await {
+            scrobble.send().await;
+        }
+    });
+    queue
+});
+
+pub fn router() -> Router {
+    Router::new().route("/users/me/trakt", get(status).post(link).delete(unlink))
+}
+
+fn trakt() -> Result<TraktConfig, ApiError> {
+    config::get()
+        .trakt
+        .clone()
+        .ok_or_else(|| ApiError::new(404, "Trakt is not configured".into()))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Posts `body` to `path` of the Trakt API, as the user `token` is of when
+/// it's given.
+async fn post(
+    trakt: &TraktConfig,
+    path: &str,
+    token: Option<&str>,
+    body: &Value,
+) -> reqwest::Result<reqwest::Response> {
+    let mut request = upstream::client()
+        .post(format!("{}{}", trakt.url, path))
+        .header("trakt-api-version", API_VERSION)
+        .header("trakt-api-key", &trakt.client_id)
+        .timeout(config::get().upstream_timeout)
+        .json(body);
+    if let Some(token) = token {
+        request = request.bearer_auth(token);
+    }
+
+    request.send().await
+}
+
+#[derive(Deserialize)]
+struct DeviceCode {
+    device_code: String,
+    user_code: String,
+    verification_url: String,
+    /// In seconds.
+    expires_in: u64,
+    /// Seconds to wait between asking whether the code was entered.
+    interval: u64,
+}
+
+#[derive(Deserialize)]
+struct Token {
+    access_token: String,
+    refresh_token: String,
+    /// In seconds from `created_at`.
+    expires_in: u64,
+    /// Unix timestamp.
+    created_at: u64,
+}
+
+impl From<Token> for TraktTokens {
+    fn from(token: Token) -> Self {
+        Self {
+            access_token: token.access_token,
+            refresh_token: token.refresh_token,
+            expires_at: token.created_at + token.expires_in,
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Status {
+    linked: bool,
+    /// Whether they were handed a code they haven't entered yet.
+    linking: bool,
+}
+
+async fn status(user: AuthUser) -> Json<Status> {
+    Json(Status {
+        linked: users::trakt(&user.name).is_some(),
+        linking: LINKING.lock().unwrap().contains_key(&user.name),
+    })
+}
+
+/// A code to enter at Trakt.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Linking {
+    user_code: String,
+    verification_url: String,
+    /// In seconds.
+    expires_in: u64,
+}
+
+/// Hands out a code to link their Trakt account with, and waits for them
+/// to enter it in the background.
+async fn link(user: AuthUser) -> Result<Json<Linking>, ApiError> {
+    let trakt = trakt()?;
+    let failed = |e: reqwest::Error| ApiError::empty(502, Some(format!("Trakt failed: {}", e)));
+    let code = post(
+        &trakt,
+        "/oauth/device/code",
+        None,
+        &json!({ "client_id": trakt.client_id }),
+    )
+    .await
+    .and_then(|res| res.error_for_status())
+    .map_err(failed)?
+    .json::<DeviceCode>()
+    .await
+    .map_err(failed)?;
+
+    LINKING
+        .lock()
+        .unwrap()
+        .insert(user.name.clone(), code.device_code.clone());
+    let linking = Linking {
+        user_code: code.user_code.clone(),
+        verification_url: code.verification_url.clone(),
+        expires_in: code.expires_in,
+    };
+    tokio::spawn(wait_for_code(trakt, user.name, code));
+
+    Ok(Json(linking))
+}
+
+/// Asks Trakt whether `name` entered their code until they did, it ran out
+/// or they asked for another.
+async fn wait_for_code(trakt: TraktConfig, name: String, code: DeviceCode) {
+    let deadline = Instant::now() + Duration::from_secs(code.expires_in);
+    let mut interval = Duration::from_secs(code.interval.max(1));
+    let body = json!({
+        "code": code.device_code,
+        "client_id": trakt.client_id,
+        "client_secret": trakt.client_secret,
+    });
+    let still_ours = || LINKING.lock().unwrap().get(&name) == Some(&code.device_code);
+
+    while Instant::now() < deadline {
+        tokio::time::sleep(interval).await;
+        if !still_ours() {
+            return;
+        }
+
+        let res = match post(&trakt, "/oauth/device/token", None, &body).await {
+            Ok(res) => res,
+            Err(e) => {
+                tracing::debug!(
+                    "Can't ask Trakt whether {} linked their account: {}",
+                    name,
+                    e
+                );
+                continue;
+            }
+        };
+        match res.status() {
+            StatusCode::OK => {
+                match res.json::<Token>().await {
+                    Ok(token) => {
+                        if users::set_trakt(&name, Some(token.into())).await {
+                            tracing::info!("{} linked their Trakt account", name);
+                        }
+                    }
+                    Err(e) => tracing::warn!("Trakt sent {} unreadable tokens: {}", name, e),
+                }
+                break;
+            }
+            // not entered yet
+            StatusCode::BAD_REQUEST => {}
+            StatusCode::TOO_MANY_REQUESTS => interval += Duration::from_secs(1),
+            // used, expired or denied
+            status => {
+                tracing::debug!("Trakt didn't link {}'s account: {}", name, status);
+                break;
+            }
+        }
+    }
+
+    if still_ours() {
+        LINKING.lock().unwrap().remove(&name);
+    }
+}
+
+/// Forgets their Trakt account, revoking its token.
+async fn unlink(user: AuthUser) -> StatusCode {
+    LINKING.lock().unwrap().remove(&user.name);
+    let tokens = users::trakt(&user.name);
+    users::set_trakt(&user.name, None).await;
+
+    if let (Some(tokens), Some(trakt)) = (tokens, config::get().trakt.clone()) {
+        let body = json!({
+            "token": tokens.access_token,
+            "client_id": trakt.client_id,
+            "client_secret": trakt.client_secret,
+        });
+        if let Err(e) = post(&trakt, "/oauth/revoke", None, &body).await {
+            tracing::debug!("Can't revoke {}'s Trakt token: {}", user.name, e);
+        }
+    }
+
+    StatusCode::NO_CONTENT
+}
+
+/// `name`'s access token, refreshed first when it's about to run out.
+/// They're unlinked when Trakt no longer takes their refresh token.
+async fn access_token(trakt: &TraktConfig, name: &str) -> Option<String> {
+    let _refreshing = REFRESHING.lock().await;
+    let tokens = users::trakt(name)?;
+    if tokens.expires_at > now() + REFRESH_BEFORE.as_secs() {
+        return Some(tokens.access_token);
+    }
+
+    let body = json!({
+        "refresh_token": tokens.refresh_token,
+        "client_id": trakt.client_id,
+        "client_secret": trakt.client_secret,
+        "redirect_uri": NO_REDIRECT,
+        "grant_type": "refresh_token",
+    });
+    let res = post(trakt, "/oauth/token", None, &body).await;
+    match res {
+        Ok(res) if res.status().is_success() => match res.json::<Token>().await {
+            Ok(token) => {
+                let tokens = TraktTokens::from(token);
+                users::set_trakt(name, Some(tokens.clone())).await;
+                return Some(tokens.access_token);
+            }
+            Err(e) => tracing::warn!("Trakt sent {} unreadable tokens: {}", name, e),
+        },
+        Ok(res) if matches!(res.status().as_u16(), 400 | 401) => {
+            tracing::warn!(
+                "Trakt refused {}'s refresh token, they have to link their account again",
+                name
+            );
+            users::set_trakt(name, None).await;
+            return None;
+        }
+        Ok(res) => tracing::warn!("Can't refresh {}'s Trakt token: {}", name, res.status()),
+        Err(e) => tracing::warn!("Can't refresh {}'s Trakt token: {}", name, e),
+    }
+
+    // it'll be tried again next time
+    (tokens.expires_at > now()).then_some(tokens.access_token)
+}
+
+/// What a player is doing with an episode, as far as Trakt cares.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Start,
+    Pause,
+    Stop,
+}
+
+impl Action {
+    fn path(self) -> &'static str {
+        match self {
+            Self::Start => "/scrobble/start",
+            Self::Pause => "/scrobble/pause",
+            Self::Stop => "/scrobble/stop",
+        }
+    }
+}
+
+struct Scrobble {
+    trakt: TraktConfig,
+    user: String,
+    action: Action,
+    episode_id: i32,
+    /// Percent watched.
+    progress: f64,
+}
+
+/// Tells Trakt `user` is at `progress` percent of episode `episode_id`,
+/// when they linked their account.
+pub fn scrobble(user: &str, action: Action, episode_id: i32, progress: f64) {
+    let trakt = match config::get().trakt.clone() {
+        Some(trakt) if users::trakt(user).is_some() => trakt,
+        _ => return,
+    };
+
+    let _ = QUEUE.send(Scrobble {
+        trakt,
+        user: user.to_string(),
+        action,
+        episode_id,
+        progress: progress.clamp(0.0, 100.0),
+    });
+}
+
+/// How Trakt is told which episode it is, by its TVDB id or else by its
+/// show's and its number.
+fn episode_ids(episode: &Value, series: Option<&Value>) -> Option<Value> {
+    if let Some(tvdb) = episode["tvdbId"].as_i64().filter(|id| *id > 0) {
+        return Some(json!({ "episode": { "ids": { "tvdb": tvdb } } }));
+    }
+
+    let show = series?["tvdbId"].as_i64().filter(|id| *id > 0)?;
+    Some(json!({
+        "show": { "ids": { "tvdb": show } },
+        "episode": {
+            "season": episode["seasonNumber"].as_i64()?,
+            "number": episode["episodeNumber"].as_i64()?,
+        },
+    }))
+}
+
+impl Scrobble {
+    async fn body(&self) -> Option<Value> {
+        let episode = sonarr::episode(self.episode_id).await.ok()??;
+        let mut body = match episode_ids(&episode, None) {
+            Some(body) => body,
+            None => {
+                let series = sonarr::series_by_id().await.ok()?;
+                let series = episode["seriesId"].as_i64().and_then(|id| series.get(&id));
+                episode_ids(&episode, series)?
+            }
+        };
+        body["progress"] = json!(self.progress);
+        body["app_version"] = json!(env!("CARGO_PKG_VERSION"));
+
+        Some(body)
+    }
+
+    async fn send(self) {
+        let body = match self.body().await {
+            Some(body) => body,
+            None => {
+                tracing::debug!("Episode {} can't be found on Trakt", self.episode_id);
+                return;
+            }
+        };
+        let token = match access_token(&self.trakt, &self.user).await {
+            Some(token) => token,
+            None => return,
+        };
+
+        match post(&self.trakt, self.action.path(), Some(&token), &body).await {
+            Ok(res) if res.status().is_success() => tracing::debug!(
+                "Scrobbled {:?} of episode {} for {}",
+                self.action,
+                self.episode_id,
+                self.user
+            ),
+            // it was scrobbled as watched in the last hour already
+            Ok(res) if res.status() == StatusCode::CONFLICT => {}
+            Ok(res) => tracing::warn!(
+                "Trakt refused scrobbling episode {} for {}: {}",
+                self.episode_id,
+                self.user,
+                res.status()
+            ),
+            Err(e) => tracing::warn!(
+                "Can't scrobble episode {} for {}: {}",
+                self.episode_id,
+                self.user,
+                e
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+
+    use axum::{extract::Path, http::HeaderMap, routing};
+
+    use super::*;
+
+    /// Requests a fake Trakt got, by path, with their bodies and bearer
+    /// tokens.
+    type Received = Arc<Mutex<Vec<(String, Value, Option<String>)>>>;
+
+    /// A Trakt that hands out tokens for refresh token `valid` only.
+    async fn fake_trakt() -> (TraktConfig, Received) {
+        let received = Received::default();
+        let logged = received.clone();
+        let app = Router::new().route(
+            "/*path",
+            routing::post(
+                move |Path(path): Path<String>, headers: HeaderMap, Json(body): Json<Value>| {
+                    let logged = logged.clone();
+                    async move {
+                        let token = headers
+                            .get("authorization")
+                            .and_then(|value| value.to_str().ok())
+                            .map(String::from);
+                        let path = format!("/{}", path.trim_start_matches('/'));
+                        logged
+                            .lock()
+                            .unwrap()
+                            .push((path.clone(), body.clone(), token));
+                        match path.as_str() {
+                            "/oauth/token" if body["refresh_token"] == "valid" => (
+                                StatusCode::OK,
+                                Json(json!({
+                                    "access_token": "fresh",
+                                    "refresh_token": "next",
+                                    "expires_in": 7_776_000,
+                                    "created_at": now(),
+                                })),
+                            ),
+                            "/oauth/token" => (StatusCode::UNAUTHORIZED, Json(json!({}))),
+                            _ => (StatusCode::CREATED, Json(json!({}))),
+                        }
+                    }
+                },
+            ),
+        );
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr: SocketAddr = listener.local_addr().unwrap();
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+
+        let trakt = TraktConfig {
+            client_id: "id".into(),
+            client_secret: "secret".into(),
+            url: format!("http://{}", addr),
+        };
+        (trakt, received)
+    }
+
+    #[test]
+    fn episodes_are_told_apart_by_tvdb_ids() {
+        let episode = json!({ "tvdbId": 7, "seasonNumber": 1, "episodeNumber": 2 });
+        assert_eq!(
+            episode_ids(&episode, None),
+            Some(json!({ "episode": { "ids": { "tvdb": 7 } } }))
+        );
+
+        let episode = json!({ "tvdbId": 0, "seasonNumber": 1, "episodeNumber": 2 });
+        assert_eq!(episode_ids(&episode, None), None);
+        assert_eq!(
+            episode_ids(&episode, Some(&json!({ "tvdbId": 3 }))),
+            Some(json!({
+                "show": { "ids": { "tvdb": 3 } },
+                "episode": { "season": 1, "number": 2 },
+            }))
+        );
+    }
+
+    #[tokio::test]
+    async fn tokens_are_refreshed_before_they_run_out() {
+        config::init_for_tests();
+        let (trakt, received) = fake_trakt().await;
+        users::set_password("trakt-test", "secret").await;
+
+        let tokens = |access: &str, refresh: &str, expires_at| TraktTokens {
+            access_token: access.into(),
+            refresh_token: refresh.into(),
+            expires_at,
+        };
+        users::set_trakt("trakt-test", Some(tokens("current", "valid", now() + 3600))).await;
+        assert_eq!(access_token(&trakt, "trakt-test").await.unwrap(), "fresh");
+        let refreshed = users::trakt("trakt-test").unwrap();
+        assert_eq!(refreshed.refresh_token, "next");
+        assert!(refreshed.expires_at > now() + REFRESH_BEFORE.as_secs());
+        assert_eq!(access_token(&trakt, "trakt-test").await.unwrap(), "fresh");
+        assert_eq!(received.lock().unwrap().len(), 1);
+
+        // a refresh token Trakt doesn't take anymore unlinks them
+        users::set_trakt("trakt-test", Some(tokens("current", "used", now() + 3600))).await;
+        assert!(access_token(&trakt, "trakt-test").await.is_none());
+        assert!(users::trakt("trakt-test").is_none());
+
+        // scrobbles go with the current one
+        users::set_trakt(
+            "trakt-test",
+            Some(tokens("current", "valid", now() + 86400 * 30)),
+        )
+        .await;
+        let token = access_token(&trakt, "trakt-test").await.unwrap();
+        let body = json!({ "episode": { "ids": { "tvdb": 7 } }, "progress": 50.0 });
+        let res = post(&trakt, Action::Pause.path(), Some(&token), &body)
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let (path, sent, token) = received.lock().unwrap().pop().unwrap();
+        assert_eq!(path, "/scrobble/pause");
+        assert_eq!(sent, body);
+        assert_eq!(token.as_deref(), Some("Bearer current"));
+        users::remove("trakt-test").await;
+    }
+}
@@ -0,0 +1,248 @@
+//! Versions of media files made with ffmpeg for players that can't, or
+//! shouldn't, take the file as it is, like just the audio for listening on
+//! little bandwidth or the same streams in mp4 for browsers. Streamed from
+//! `streams/` in the data dir like any other file once made, and made again
+//! when the file changes.
+
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use ring::digest;
+use serde::Deserialize;
+
+use crate::{config, errors::ApiError, extras, users};
+
+/// Versions not made or streamed since are removed as others are made.
+const KEEP_FOR: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// What's made of a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Only its first audio stream, in stereo.
+    AudioOnly(AudioCodec),
+    /// Its video and first audio stream as they are, in fragmented mp4.
+    Remux,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioCodec {
+    Opus,
+    Aac,
+}
+
+impl AudioCodec {
+    /// `opus` or `aac`.
+    pub fn parse(name: &[u8]) -> Option<Self> {
+        match name {
+            b"opus" => Some(AudioCodec::Opus),
+            b"aac" => Some(AudioCodec::Aac),
+            _ => None,
+        }
+    }
+}
+
+impl Mode {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Mode::AudioOnly(AudioCodec::Opus) => "audio/webm",
+            Mode::AudioOnly(AudioCodec::Aac) => "audio/mp4",
+            Mode::Remux => "video/mp4",
+        }
+    }
+
+    /// Bits per second it's made at, what players should expect to need.
+    pub fn bitrate(self) -> Option<u64> {
+        match self {
+            Mode::AudioOnly(AudioCodec::Opus) => Some(96_000),
+            Mode::AudioOnly(AudioCodec::Aac) => Some(128_000),
+            // as much as the file itself
+            Mode::Remux => None,
+        }
+    }
+
+    /// What it's called in file names and logs.
+    fn name(self) -> &'static str {
+        match self {
+            Mode::AudioOnly(AudioCodec::Opus) => "audio-opus",
+            Mode::AudioOnly(AudioCodec::Aac) => "audio-aac",
+            Mode::Remux => "remux",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            Mode::AudioOnly(AudioCodec::Opus) => "webm",
+            Mode::AudioOnly(AudioCodec::Aac) => "m4a",
+            Mode::Remux => "mp4",
+        }
+    }
+
+    /// What ffmpeg is told to make of `source`, up to the output file.
+    fn args(self, source: &Path) -> Vec<String> {
+        let mut args = vec!["-i".into(), source.to_string_lossy().into_owned()];
+        let bitrate = format!("{}k", self.bitrate().unwrap_or_default() / 1000);
+        let audio_only = ["-map", "0:a:0", "-vn", "-sn", "-ac", "2", "-c:a"];
+        match self {
+            Mode::AudioOnly(AudioCodec::Opus) => args.extend(
+                audio_only
+                    .into_iter()
+                    .chain(["libopus", "-b:a", &bitrate, "-f", "webm"])
+                    .map(String::from),
+            ),
+            Mode::AudioOnly(AudioCodec::Aac) => args.extend(
+                audio_only
+                    .into_iter()
+                    .chain(["aac", "-b:a", &bitrate])
+                    .chain(["-movflags", "+faststart", "-f", "mp4"])
+                    .map(String::from),
+            ),
+            // players can start on the fragments before the end is read
+            Mode::Remux => args.extend(
+                ["-map", "0:v:0", "-map", "0:a:0?", "-sn", "-c", "copy"]
+                    .into_iter()
+                    .chain(["-movflags", "+frag_keyframe+empty_moov+default_base_moof"])
+                    .chain(["-f", "mp4"])
+                    .map(String::from),
+            ),
+        }
+
+        args
+    }
+}
+
+/// What Sonarr read from a file, enough to tell whether browsers can play
+/// its streams.
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct MediaInfo {
+    /// Like `x264` or `h264`.
+    video_codec: Option<String>,
+    video_bit_depth: Option<u32>,
+    /// Like `AAC` or `EAC3`.
+    audio_codec: Option<String>,
+}
+
+/// Remux when `path` isn't mp4 already but its streams are h264 in 8 bits
+/// and AAC, which browsers play in mp4, taking a copy instead of an encode.
+pub fn negotiate(path: &Path, media_info: &MediaInfo) -> Option<Mode> {
+    let is_mp4 = path
+        .extension()
+        .is_some_and(|extension| matches!(extension.to_str(), Some("mp4" | "m4v")));
+    let is = |codec: &Option<String>, names: &[&str]| {
+        codec
+            .as_deref()
+            .is_some_and(|codec| names.contains(&codec.to_ascii_lowercase().as_str()))
+    };
+    let h264 = is(&media_info.video_codec, &["h264", "x264", "avc"])
+        && media_info.video_bit_depth.is_none_or(|depth| depth <= 8);
+    let aac = is(&media_info.audio_codec, &["aac", "he-aac", "aac lc"]);
+
+    (!is_mp4 && h264 && aac).then_some(Mode::Remux)
+}
+
+/// Where the `mode` version of `source` is kept, named after its path.
+fn cached(source: &Path, mode: Mode) -> PathBuf {
+    let hash = digest::digest(&digest::SHA256, source.as_os_str().as_bytes());
+    let name = format!(
+        "{}.{}.{}",
+        &users::hex(hash.as_ref())[..32],
+        mode.name(),
+        mode.extension()
+    );
+
+    config::get().data_dir.join("streams").join(name)
+}
+
+/// Removes the versions that weren't made or streamed for a day.
+async fn prune(folder: &Path) {
+    let mut entries = match tokio::fs::read_dir(folder).await {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let path = entry.path();
+        let stale = extras::modified(&path)
+            .await
+            .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+            .is_some_and(|age| age > KEEP_FOR);
+        if stale {
+            tracing::debug!("Removing {:?}, it wasn't streamed for a day", path);
+            let _ = tokio::fs::remove_file(&path).await;
+        }
+    }
+}
+
+/// The `mode` version of `source`, made first when there's none or the
+/// file changed since. Made one at a time, like previews.
+pub async fn prepare(source: &Path, mode: Mode) -> Result<PathBuf, ApiError> {
+    let path = cached(source, mode);
+
+    if !extras::is_fresh(&path, Some(source)).await {
+        let _generating = extras::GENERATING.lock().await;
+
+        if !extras::is_fresh(&path, Some(source)).await {
+            if let Some(folder) = path.parent() {
+                prune(folder).await;
+            }
+            tracing::info!("Making the {} version of {:?}", mode.name(), source);
+            extras::ffmpeg(&mode.args(source), &path).await?;
+        }
+    }
+
+    // streaming it counts as using it, so it isn't pruned while it's played
+    let touched = std::fs::File::options()
+        .write(true)
+        .open(&path)
+        .and_then(|file| file.set_modified(SystemTime::now()));
+    if let Err(e) = touched {
+        tracing::debug!("Can't touch {:?}: {}", path, e);
+    }
+
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_h264_and_aac_are_remuxed() {
+        let info = |video: &str, depth: u32, audio: &str| MediaInfo {
+            video_codec: Some(video.into()),
+            video_bit_depth: Some(depth),
+            audio_codec: Some(audio.into()),
+        };
+        let mkv = Path::new("/tv/Show/S01E01.mkv");
+
+        assert_eq!(negotiate(mkv, &info("x264", 8, "AAC")), Some(Mode::Remux));
+        assert_eq!(
+            negotiate(mkv, &info("h264", 8, "HE-AAC")),
+            Some(Mode::Remux)
+        );
+        // browsers can't decode 10 bit h264, nor these
+        assert_eq!(negotiate(mkv, &info("x264", 10, "AAC")), None);
+        assert_eq!(negotiate(mkv, &info("x265", 8, "AAC")), None);
+        assert_eq!(negotiate(mkv, &info("x264", 8, "EAC3")), None);
+        assert_eq!(negotiate(mkv, &MediaInfo::default()), None);
+        // already playable as it is
+        let mp4 = Path::new("/tv/Show/S01E01.mp4");
+        assert_eq!(negotiate(mp4, &info("x264", 8, "AAC")), None);
+    }
+
+    #[test]
+    fn remuxes_copy_the_streams() {
+        let args = Mode::Remux.args(Path::new("/tv/a.mkv")).join(" ");
+        assert!(args.contains("-c copy"), "{}", args);
+        assert!(args.contains("frag_keyframe"), "{}", args);
+
+        let args = Mode::AudioOnly(AudioCodec::Opus)
+            .args(Path::new("/tv/a.mkv"))
+            .join(" ");
+        assert!(
+            args.contains("-vn -sn -ac 2 -c:a libopus -b:a 96k"),
+            "{}",
+            args
+        );
+    }
+}
@@ -0,0 +1,321 @@
+use std::path::{Path as FilePath, PathBuf};
+use std::time::Instant;
+
+use axum::{extract::Path, response::Response, routing::get, Json, Router};
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+
+use crate::{
+    config::{self, Config},
+    errors::ApiError,
+    extras,
+    jobs::{self, Kind},
+    markers,
+    restrictions::Restrictions,
+    store,
+};
+
+/// Seconds between thumbnails.
+const INTERVAL: u32 = 10;
+/// Thumbnails per row and column of a sheet.
+const TILES: u32 = 10;
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/episodes/:id/trickplay", get(get_manifest))
+        .route("/episodes/:id/trickplay/:width/:sheet", get(get_sheet))
+}
+
+/// Which thumbnail sheets were made of an episode. Thumbnail `n` is at
+/// `n * interval` ms, in sheet `n / (tileWidth * tileHeight)`, filling
+/// its rows from the top left.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Manifest {
+    episode_id: i32,
+    resolutions: Vec<Resolution>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Resolution {
+    width: u32,
+    height: u32,
+    interval: u32,
+    tile_width: u32,
+    tile_height: u32,
+    thumbnail_count: u32,
+    sheet_count: u32,
+}
+
+fn folder(episode_id: i32) -> PathBuf {
+    config::get()
+        .data_dir
+        .join("trickplay")
+        .join(episode_id.to_string())
+}
+
+fn manifest_name(episode_id: i32) -> String {
+    format!("trickplay/{}/manifest.json", episode_id)
+}
+
+#[derive(Deserialize)]
+struct Probe {
+    #[serde(default)]
+    streams: Vec<ProbeStream>,
+    format: ProbeFormat,
+}
+
+#[derive(Deserialize)]
+struct ProbeStream {
+    width: u32,
+    height: u32,
+}
+
+#[derive(Deserialize)]
+struct ProbeFormat {
+    duration: String,
+}
+
+/// The size of the file's video and how many seconds it lasts.
+async fn probe(path: &FilePath) -> Result<(u32, u32, f64), String> {
+    let ffprobe = config::get().ffprobe_path.clone();
+    let output = Command::new(&ffprobe)
+        .args([
+            "-v",
+            "quiet",
+            "-print_format",
+            "json",
+            "-select_streams",
+            "v:0",
+        ])
+        .args(["-show_entries", "stream=width,height:format=duration"])
+        .arg(path)
+        .output()
+        .await
+        .map_err(|e| format!("{:?} can't be run: {}", ffprobe, e))?;
+
+    let probe = serde_json::from_slice::<Probe>(&output.stdout)
+        .map_err(|e| format!("ffprobe exited with {}: {}", output.status, e))?;
+    let stream = probe.streams.first().ok_or("there's no video")?;
+    let duration = probe.format.duration.parse::<f64>().unwrap_or_default();
+    if stream.width == 0 || stream.height == 0 || duration <= 0.0 {
+        return Err("the video has no size or length".into());
+    }
+
+    Ok((stream.width, stream.height, duration))
+}
+
+/// Makes the thumbnail sheets of one width, into a temporary folder that
+/// replaces the old sheets when they're all made.
+async fn make_sheets(
+    source: &FilePath,
+    folder: &FilePath,
+    width: u32,
+    height: u32,
+) -> Result<(), String> {
+    let done = folder.join(width.to_string());
+    let temp = folder.join(format!("{}.tmp", width));
+    let _ = tokio::fs::remove_dir_all(&temp).await;
+    tokio::fs::create_dir_all(&temp)
+        .await
+        .map_err(|e| format!("{:?} can't be made: {}", temp, e))?;
+
+    let ffmpeg = config::get().ffmpeg_path.clone();
+    let output = Command::new(&ffmpeg)
+        .args(["-hide_banner", "-loglevel", "error", "-y"])
+        // only decoding keyframes is many times faster and close enough
+        .args(["-skip_frame", "nokey", "-i"])
+        .arg(source)
+        .args(["-an", "-sn", "-vf"])
+        .arg(format!(
+            "fps=1/{},scale={}:{},tile={}x{}",
+            INTERVAL, width, height, TILES, TILES
+        ))
+        .args(["-q:v", "5", "-start_number", "0"])
+        .arg(temp.join("%d.jpg"))
+        .output()
+        .await
+        .map_err(|e| format!("{:?} can't be run: {}", ffmpeg, e))?;
+
+    if !output.status.success() {
+        let _ = tokio::fs::remove_dir_all(&temp).await;
+        return Err(format!(
+            "ffmpeg exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    let _ = tokio::fs::remove_dir_all(&done).await;
+    tokio::fs::rename(&temp, &done)
+        .await
+        .map_err(|e| format!("{:?} can't be moved: {}", temp, e))
+}
+
+/// Makes the episode's thumbnail sheets in every configured width.
+async fn generate(config: &Config, episode_id: i32, source: &FilePath) -> Result<(), String> {
+    let (video_width, video_height, duration) = probe(source).await?;
+    let folder = folder(episode_id);
+    let thumbnail_count = (duration / INTERVAL as f64).ceil() as u32;
+
+    let mut resolutions = Vec::new();
+    for width in &config.trickplay_widths {
+        // even, as the encoder wants it, and at least 2 pixels
+        let height =
+            ((*width as f64 * video_height as f64 / video_width as f64 / 2.0).round() as u32 * 2)
+                .max(2);
+
+        make_sheets(source, &folder, *width, height).await?;
+        resolutions.push(Resolution {
+            width: *width,
+            height,
+            interval: INTERVAL * 1000,
+            tile_width: TILES,
+            tile_height: TILES,
+            thumbnail_count,
+            sheet_count: thumbnail_count.div_ceil(TILES * TILES),
+        });
+    }
+
+    let manifest = Manifest {
+        episode_id,
+        resolutions,
+    };
+    store::write(&manifest_name(episode_id), &manifest)
+        .await
+        .map_err(|e| format!("The manifest can't be saved: {}", e))
+}
+
+/// Whether the episode has sheets in every configured width, made after
+/// its file last changed.
+async fn is_done(config: &Config, episode_id: i32, source: &FilePath) -> bool {
+    let manifest = match store::read::<Manifest>(&manifest_name(episode_id)).await {
+        Some(manifest) => manifest,
+        None => return false,
+    };
+
+    let widths = manifest
+        .resolutions
+        .iter()
+        .map(|resolution| resolution.width)
+        .collect::<Vec<_>>();
+
+    widths == config.trickplay_widths
+        && extras::is_fresh(
+            &config.data_dir.join(manifest_name(episode_id)),
+            Some(source),
+        )
+        .await
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Episode {
+    id: i32,
+    episode_file: Option<EpisodeFile>,
+}
+
+#[derive(Deserialize)]
+struct EpisodeFile {
+    path: String,
+}
+
+/// Queues a job for every synced episode without thumbnail sheets,
+/// returning how many were queued.
+pub async fn scan(config: &Config) -> Result<usize, String> {
+    let library = match store::library() {
+        Some(library) => library,
+        None => return Ok(0),
+    };
+    // there's no point going through every episode when ffmpeg can't be run
+    if let Err(e) = Command::new(&config.ffmpeg_path)
+        .arg("-version")
+        .output()
+        .await
+    {
+        return Err(format!("{:?} can't be run: {}", config.ffmpeg_path, e));
+    }
+
+    let mut missing = Vec::new();
+    let episodes = library
+        .episodes
+        .values()
+        .flatten()
+        .filter_map(|episode| Episode::deserialize(episode).ok())
+        .filter_map(|episode| {
+            let path = config.local_path(FilePath::new(&episode.episode_file?.path));
+            Some((episode.id, path))
+        })
+        .filter(|(_, path)| !library.missing_files.contains(path));
+    for (episode_id, path) in episodes {
+        if !is_done(config, episode_id, &path).await {
+            missing.push(Kind::Thumbnails { episode_id, path });
+        }
+    }
+
+    Ok(jobs::enqueue_all(missing).await)
+}
+
+/// Makes the thumbnail sheets of one episode, unless it has them. Run by
+/// the job workers.
+pub async fn make(episode_id: i32, path: &FilePath) -> Result<(), String> {
+    let config = config::get();
+    if is_done(&config, episode_id, path).await {
+        return Ok(());
+    }
+
+    let started = Instant::now();
+    generate(&config, episode_id, path).await?;
+    tracing::debug!(
+        "Made thumbnails of episode {} in {:?}",
+        episode_id,
+        started.elapsed()
+    );
+    Ok(())
+}
+
+/// 404s episodes whose show is hidden from whoever is asking.
+async fn check_allowed(id: i32, restrictions: &Restrictions) -> Result<(), ApiError> {
+    let path = markers::episode_path(id).await?;
+
+    if restrictions.allows_file(&config::get(), &path).await {
+        Ok(())
+    } else {
+        Err(ApiError::new(404, format!("There's no episode {}", id)))
+    }
+}
+
+async fn get_manifest(
+    Path(id): Path<i32>,
+    restrictions: Restrictions,
+) -> Result<Json<Manifest>, ApiError> {
+    check_allowed(id, &restrictions).await?;
+
+    store::read::<Manifest>(&manifest_name(id))
+        .await
+        .map(Json)
+        .ok_or_else(|| ApiError::new(404, format!("Episode {} has no thumbnails yet", id)))
+}
+
+/// A sheet of thumbnails, `:sheet` being `<index>.jpg`.
+async fn get_sheet(
+    Path((id, width, sheet)): Path<(i32, u32, String)>,
+    restrictions: Restrictions,
+) -> Result<Response, ApiError> {
+    let index = sheet
+        .strip_suffix(".jpg")
+        .and_then(|index| index.parse::<u32>().ok())
+        .ok_or_else(|| ApiError::new(404, format!("There's no sheet {}", sheet)))?;
+
+    check_allowed(id, &restrictions).await?;
+
+    let path = folder(id)
+        .join(width.to_string())
+        .join(format!("{}.jpg", index));
+    if extras::modified(&path).await.is_none() {
+        return Err(ApiError::new(404, format!("There's no sheet {}", sheet)));
+    }
+
+    extras::serve(&path, "image/jpeg").await
+}
@@ -0,0 +1,392 @@
+use std::collections::HashMap;
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+use reqwest::{Certificate, Client, Identity, Method, RequestBuilder, StatusCode};
+use serde::Serialize;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::Instrument;
+
+use crate::{
+    cache::Cache,
+    cassettes::{self, Mode},
+    circuit_breaker::CircuitBreaker,
+    config::{self, UpstreamConfig, UpstreamTlsConfig},
+    errors::ApiError,
+    events::{self, Event},
+    request_id, telemetry,
+};
+
+/// The upstreams' names, which their limits in `upstream_limits` go by.
+pub const NAMES: [&str; 5] = ["sonarr", "radarr", "lidarr", "readarr", "prowlarr"];
+
+/// Calls to all upstreams together, up to `upstream_concurrency` at a time.
+static ALL: Lazy<Limiter> = Lazy::new(Default::default);
+
+/// Lets a number of calls through at a time, the others waiting their turn
+/// in the order they came. Made again with the new limit when it changes,
+/// calls already let through keep going.
+#[derive(Default)]
+struct Limiter(Mutex<Option<(usize, Arc<Semaphore>)>>);
+
+impl Limiter {
+    /// Waits for a turn, right away without a `limit`.
+    async fn acquire(&self, limit: Option<usize>) -> Option<OwnedSemaphorePermit> {
+        let limit = limit?;
+        let semaphore = {
+            let mut current = self.0.lock().unwrap();
+            match &*current {
+                Some((made_with, semaphore)) if *made_with == limit => semaphore.clone(),
+                _ => {
+                    let semaphore = Arc::new(Semaphore::new(limit));
+                    *current = Some((limit, semaphore.clone()));
+                    semaphore
+                }
+            }
+        };
+
+        semaphore.acquire_owned().await.ok()
+    }
+}
+
+/// The client shared by upstream calls, with the TLS settings it was made
+/// with so it's made again when they're changed.
+static CLIENT: Lazy<Mutex<Option<(UpstreamTlsConfig, Client)>>> = Lazy::new(Default::default);
+
+/// A client connecting to upstreams as `tls` says.
+pub fn build_client(tls: &UpstreamTlsConfig) -> Result<Client, String> {
+    let read = |path| fs::read(path).map_err(|e| format!("can't read {:?}: {}", path, e));
+
+    let mut builder = Client::builder().danger_accept_invalid_certs(tls.accept_invalid_certs);
+    if let Some(path) = &tls.ca_cert {
+        let cert = Certificate::from_pem(&read(path)?)
+            .map_err(|e| format!("{:?} is not a PEM certificate: {}", path, e))?;
+        builder = builder.add_root_certificate(cert);
+    }
+    if let Some(path) = &tls.client_cert {
+        let mut pem = read(path)?;
+        if let Some(key) = &tls.client_key {
+            pem.push(b'\n');
+            pem.extend(read(key)?);
+        }
+        let identity = Identity::from_pem(&pem)
+            .map_err(|e| format!("{:?} is not a PEM certificate and key: {}", path, e))?;
+        builder = builder.identity(identity);
+    }
+
+    builder.build().map_err(|e| e.to_string())
+}
+
+/// The shared upstream client for the current config. Settings that
+/// stopped working since the config was loaded fall back to the defaults.
+pub fn client() -> Client {
+    let tls = config::get().upstream_tls.clone();
+    let mut client = CLIENT.lock().unwrap();
+
+    match &*client {
+        Some((made_with, client)) if *made_with == tls => client.clone(),
+        _ => {
+            let made = build_client(&tls).unwrap_or_else(|e| {
+                tracing::warn!("Using the default upstream TLS settings: {}", e);
+                Client::new()
+            });
+            *client = Some((tls, made.clone()));
+            made
+        }
+    }
+}
+
+/// A GET on its way upstream, with the response once it's there.
+type Flight = Arc<tokio::sync::OnceCell<Result<(StatusCode, String), ApiError>>>;
+
+/// One of the *arr services we proxy, with its own response cache and
+/// circuit breaker.
+pub struct Upstream {
+    pub name: &'static str,
+    pub cache: Cache,
+    pub breaker: CircuitBreaker,
+    limiter: Limiter,
+    /// GETs on their way by url, which identical ones wait on instead of
+    /// sending their own.
+    in_flight: Mutex<HashMap<String, Flight>>,
+    /// Whether calls wait on indexers and get the search timeout.
+    searches: bool,
+}
+
+impl Upstream {
+    pub fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            cache: Cache::new(name),
+            breaker: CircuitBreaker::new(5, Duration::from_secs(30)),
+            limiter: Limiter::default(),
+            in_flight: Mutex::default(),
+            searches: false,
+        }
+    }
+
+    /// An upstream searching indexers, like Prowlarr.
+    pub fn searching(name: &'static str) -> Self {
+        Self {
+            searches: true,
+            ..Self::new(name)
+        }
+    }
+
+    pub fn request(&self, config: &UpstreamConfig, method: Method, path: &str) -> RequestBuilder {
+        let settings = config::get();
+        let timeout = if self.searches {
+            settings.search_timeout
+        } else {
+            settings.upstream_timeout
+        };
+
+        let mut builder = client()
+            .request(method, format!("{}{}", config.url, path))
+            .header("X-Api-Key", &config.api_key)
+            .timeout(timeout);
+
+        if let Some(id) = request_id::current() {
+            builder = builder.header(&request_id::X_REQUEST_ID, id);
+        }
+        for (name, value) in telemetry::propagation_headers() {
+            builder = builder.header(name, value);
+        }
+
+        builder
+    }
+
+    /// Sends `builder` unless the circuit breaker is open, recording the
+    /// outcome. Only transport errors and 5xx responses count as failures.
+    /// With `--mock`, responses are saved, or answered with instead.
+    async fn send(
+        &self,
+        builder: RequestBuilder,
+        path: &str,
+    ) -> Result<(StatusCode, String), ApiError> {
+        let request = builder
+            .build()
+            .map_err(|e| ApiError::empty(500, Some(e.to_string())))?;
+        let method = request.method().to_string();
+        let body = request
+            .body()
+            .and_then(|body| body.as_bytes())
+            .map(<[u8]>::to_vec);
+        if cassettes::mode() == Some(Mode::Replay) {
+            return cassettes::replay(self.name, &method, path, body.as_deref());
+        }
+
+        if !self.breaker.allow() {
+            return Err(ApiError::empty(
+                503,
+                Some(format!(
+                    "Not calling {} for {}, it keeps failing",
+                    self.name, path
+                )),
+            ));
+        }
+
+        // its own limit first, so calls waiting on a busy service don't
+        // hold up the others
+        let (own_limit, limit) = {
+            let config = config::get();
+            (
+                config.upstream_limits.get(self.name).copied(),
+                config.upstream_concurrency,
+            )
+        };
+        let waiting = Instant::now();
+        let _turn = (
+            self.limiter.acquire(own_limit).await,
+            ALL.acquire(limit).await,
+        );
+        if waiting.elapsed() > Duration::from_millis(100) {
+            tracing::debug!(
+                "Waited {:?} for a turn to call {} for {}",
+                waiting.elapsed(),
+                self.name,
+                path
+            );
+        }
+
+        let span = tracing::info_span!(
+            "upstream",
+            service = self.name,
+            path,
+            status = tracing::field::Empty
+        );
+
+        let result = async {
+            let res = client().execute(request).await.map_err(|e| {
+                let status = if e.is_timeout() { 504 } else { 500 };
+                ApiError::empty(status, Some(e.to_string()))
+            })?;
+            tracing::Span::current().record("status", res.status().as_u16());
+
+            let status = res.status();
+            let body = res
+                .text()
+                .await
+                .map_err(|e| ApiError::empty(500, Some(e.to_string())))?;
+
+            Ok((status, body))
+        }
+        .instrument(span)
+        .await;
+
+        match &result {
+            Ok((status, _)) if !status.is_server_error() => self.breaker.record_success(),
+            _ => {
+                if self.breaker.record_failure() {
+                    events::publish(Event::UpstreamUnreachable {
+                        service: self.name.into(),
+                    });
+                }
+            }
+        }
+        if let Ok(response) = &result {
+            cassettes::record(self.name, &method, path, body.as_deref(), response).await;
+        }
+
+        result
+    }
+
+    /// GETs `path`, sharing the response with the identical GETs made while
+    /// it's on its way, like when many clients load the same page at once.
+    async fn get_once(
+        &self,
+        config: &UpstreamConfig,
+        path: &str,
+    ) -> Result<(StatusCode, String), ApiError> {
+        let url = format!("{}{}", config.url, path);
+        let flight = self
+            .in_flight
+            .lock()
+            .unwrap()
+            .entry(url.clone())
+            .or_default()
+            .clone();
+
+        // whoever waits next sends it when the one sending it gives up
+        let result = flight
+            .get_or_init(|| self.send(self.request(config, Method::GET, path), path))
+            .await
+            .clone();
+
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if in_flight
+            .get(&url)
+            .is_some_and(|current| Arc::ptr_eq(current, &flight))
+        {
+            in_flight.remove(&url);
+        }
+
+        result
+    }
+
+    /// GETs `path`, reusing a cached body when there is one and failing
+    /// fast while the circuit breaker is open.
+    pub async fn get(
+        &self,
+        config: &UpstreamConfig,
+        cache_ttl: Duration,
+        path: &str,
+    ) -> Result<String, ApiError> {
+        if let Some(body) = self.cache.get(path).await {
+            return Ok(body);
+        }
+
+        let (status, body) = self.get_once(config, path).await?;
+
+        if status.is_success() && !cache_ttl.is_zero() {
+            self.cache
+                .insert(path.to_string(), body.clone(), cache_ttl)
+                .await;
+        }
+
+        Ok(body)
+    }
+
+    /// GETs `path` from the service itself, skipping the cache, and fails
+    /// unless it answers with a 2xx.
+    pub async fn fetch(&self, config: &UpstreamConfig, path: &str) -> Result<String, ApiError> {
+        let (status, body) = self.get_once(config, path).await?;
+
+        if !status.is_success() {
+            return Err(ApiError::new(status.as_u16(), body));
+        }
+
+        Ok(body)
+    }
+
+    /// GETs `path` when the upstream waits on indexers for it, like
+    /// Sonarr's release search, with the search timeout and skipping the
+    /// cache. Fails unless the upstream answers with a 2xx.
+    pub async fn search(&self, config: &UpstreamConfig, path: &str) -> Result<String, ApiError> {
+        let builder = self
+            .request(config, Method::GET, path)
+            .timeout(config::get().search_timeout);
+        let (status, body) = self.send(builder, path).await?;
+
+        if !status.is_success() {
+            return Err(ApiError::new(status.as_u16(), body));
+        }
+
+        Ok(body)
+    }
+
+    /// POSTs `body` as JSON to `path`, passing along the upstream's status
+    /// and message when it rejects it.
+    pub async fn post<T: Serialize>(
+        &self,
+        config: &UpstreamConfig,
+        path: &str,
+        body: &T,
+    ) -> Result<String, ApiError> {
+        let builder = self.request(config, Method::POST, path).json(body);
+        let (status, body) = self.send(builder, path).await?;
+
+        if !status.is_success() {
+            return Err(ApiError::new(status.as_u16(), body));
+        }
+
+        Ok(body)
+    }
+
+    /// DELETEs `path`, passing along the upstream's status and message when
+    /// it refuses.
+    pub async fn delete(&self, config: &UpstreamConfig, path: &str) -> Result<String, ApiError> {
+        let builder = self.request(config, Method::DELETE, path);
+        let (status, body) = self.send(builder, path).await?;
+
+        if !status.is_success() {
+            return Err(ApiError::new(status.as_u16(), body));
+        }
+
+        Ok(body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn calls_over_the_limit_wait_their_turn() {
+        let limiter = Limiter::default();
+        let first = limiter.acquire(Some(2)).await;
+        let _second = limiter.acquire(Some(2)).await;
+
+        let third = limiter.acquire(Some(2));
+        tokio::pin!(third);
+        assert!(tokio::time::timeout(Duration::from_millis(50), &mut third)
+            .await
+            .is_err());
+
+        drop(first);
+        assert!(third.await.is_some());
+        assert!(limiter.acquire(None).await.is_none());
+    }
+}
@@ -0,0 +1,501 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::num::NonZeroU32;
+use std::sync::RwLock;
+use std::time::{Duration, SystemTime};
+
+use once_cell::sync::Lazy;
+use ring::{digest, pbkdf2, rand::SecureRandom, rand::SystemRandom};
+use serde::{Deserialize, Serialize};
+
+use crate::store;
+
+const USERS_TABLE: &str = "users";
+const PBKDF2_ITERATIONS: u32 = 100_000;
+const REFRESH_TOKEN_LIFETIME: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+static USERS: Lazy<RwLock<Users>> = Lazy::new(Default::default);
+static UPDATING: Lazy<tokio::sync::Mutex<()>> = Lazy::new(Default::default);
+
+#[derive(Default)]
+struct Users {
+    users: BTreeMap<String, User>,
+}
+
+/// What someone may do, in order of privilege.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Browses and streams the library.
+    #[default]
+    Viewer,
+    /// Also searches indexers, grabs releases, changes the library and uses
+    /// the admin API.
+    Admin,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct User {
+    pub name: String,
+    #[serde(default)]
+    pub role: Role,
+    /// Lowercased restricted tags this user may see anyway.
+    #[serde(default)]
+    pub allowed_tags: BTreeSet<String>,
+    /// `pbkdf2-sha256$<iterations>$<salt>$<hash>`, hex encoded.
+    password_hash: String,
+    #[serde(default)]
+    refresh_tokens: Vec<RefreshToken>,
+    /// Clients they logged in on, registered on their first login.
+    #[serde(default)]
+    pub devices: Vec<Device>,
+    #[serde(default)]
+    pub preferences: Preferences,
+    /// Their linked Trakt account.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trakt: Option<TraktTokens>,
+}
+
+/// How someone likes to watch, set by themselves.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Preferences {
+    /// Percent of an episode past which it counts as watched for them,
+    /// `watched_threshold` unless set.
+    pub watched_threshold: Option<u64>,
+    /// Like `eng`, most preferred first, for play queues to pick streams by.
+    pub audio_languages: Vec<String>,
+    pub subtitle_languages: Vec<String>,
+    /// Whether play queues go on to the next episode by themselves.
+    pub autoplay_next: bool,
+}
+
+impl Default for Preferences {
+    fn default() -> Self {
+        Self {
+            watched_threshold: None,
+            audio_languages: Vec::new(),
+            subtitle_languages: Vec::new(),
+            autoplay_next: true,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+struct RefreshToken {
+    /// Only a hash is kept, so a leaked users file can't be used to log in.
+    hash: String,
+    expires_at: u64,
+    /// Id of the device it was handed to.
+    #[serde(default)]
+    device: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Device {
+    pub id: String,
+    /// Like `Living Room TV`, as the client calls itself.
+    pub name: String,
+    /// Like `tv` or `browser`, as the client says.
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub registered_at: u64,
+    pub last_seen: u64,
+}
+
+/// What Trakt handed out when someone linked their account.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TraktTokens {
+    pub access_token: String,
+    pub refresh_token: String,
+    /// Unix timestamp.
+    pub expires_at: u64,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn unhex(hex: &str) -> Option<Vec<u8>> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Random bytes from the OS, hex encoded.
+pub fn random_hex(len: usize) -> String {
+    let mut bytes = vec![0; len];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .expect("the OS should provide random bytes");
+    hex(&bytes)
+}
+
+fn sha256(token: &str) -> String {
+    hex(digest::digest(&digest::SHA256, token.as_bytes()).as_ref())
+}
+
+fn hash_password(password: &str) -> String {
+    let salt = random_hex(16);
+    let mut hash = [0; digest::SHA256_OUTPUT_LEN];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        NonZeroU32::new(PBKDF2_ITERATIONS).unwrap(),
+        salt.as_bytes(),
+        password.as_bytes(),
+        &mut hash,
+    );
+
+    format!(
+        "pbkdf2-sha256${}${}${}",
+        PBKDF2_ITERATIONS,
+        salt,
+        hex(&hash)
+    )
+}
+
+fn verify_hash(password_hash: &str, password: &str) -> bool {
+    let mut parts = password_hash.split('$');
+    let (iterations, salt, hash) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some("pbkdf2-sha256"), Some(iterations), Some(salt), Some(hash)) => {
+            (iterations, salt, hash)
+        }
+        _ => return false,
+    };
+    let (iterations, hash) = match (
+        iterations.parse().ok().and_then(NonZeroU32::new),
+        unhex(hash),
+    ) {
+        (Some(iterations), Some(hash)) => (iterations, hash),
+        _ => return false,
+    };
+
+    pbkdf2::verify(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        iterations,
+        salt.as_bytes(),
+        password.as_bytes(),
+        &hash,
+    )
+    .is_ok()
+}
+
+/// Loads the users saved by the previous run.
+pub async fn load() {
+    let users = store::rows::<String, User>(USERS_TABLE).await;
+    tracing::debug!("Loaded {} users", users.len());
+    *USERS.write().unwrap() = Users { users };
+}
+
+/// Applies `change` and saves the users it changed, returning what
+/// `change` did.
+async fn update<T>(change: impl FnOnce(&mut Users) -> T) -> T {
+    let _updating = UPDATING.lock().await;
+    // another centarr sharing the database may have changed them since
+    if let Ok(users) = store::try_rows(USERS_TABLE).await {
+        USERS.write().unwrap().users = users;
+    }
+
+    let (result, changes) = {
+        let mut users = USERS.write().unwrap();
+        let before = users.users.clone();
+        let result = change(&mut users);
+        (result, store::diff(USERS_TABLE, &before, &users.users))
+    };
+
+    if let Err(e) = store::apply(&changes).await {
+        tracing::warn!("Failed to save users: {}", e);
+    }
+
+    result
+}
+
+pub fn all() -> Vec<User> {
+    USERS.read().unwrap().users.values().cloned().collect()
+}
+
+pub fn exists(name: &str) -> bool {
+    USERS.read().unwrap().users.contains_key(name)
+}
+
+pub fn role(name: &str) -> Option<Role> {
+    USERS.read().unwrap().users.get(name).map(|user| user.role)
+}
+
+pub fn allowed_tags(name: &str) -> BTreeSet<String> {
+    USERS
+        .read()
+        .unwrap()
+        .users
+        .get(name)
+        .map(|user| user.allowed_tags.clone())
+        .unwrap_or_default()
+}
+
+/// `name`'s preferences, the defaults for who isn't a user.
+pub fn preferences(name: &str) -> Preferences {
+    USERS
+        .read()
+        .unwrap()
+        .users
+        .get(name)
+        .map(|user| user.preferences.clone())
+        .unwrap_or_default()
+}
+
+/// Changes `name`'s preferences, false when there's no such user.
+pub async fn set_preferences(name: &str, preferences: Preferences) -> bool {
+    update(|users| match users.users.get_mut(name) {
+        Some(user) => {
+            user.preferences = preferences;
+            true
+        }
+        None => false,
+    })
+    .await
+}
+
+/// `name`'s Trakt tokens, when they linked their account.
+pub fn trakt(name: &str) -> Option<TraktTokens> {
+    USERS
+        .read()
+        .unwrap()
+        .users
+        .get(name)
+        .and_then(|user| user.trakt.clone())
+}
+
+/// Links `name`'s Trakt account, or unlinks it with `None`, false when
+/// there's no such user.
+pub async fn set_trakt(name: &str, tokens: Option<TraktTokens>) -> bool {
+    update(|users| match users.users.get_mut(name) {
+        Some(user) => {
+            user.trakt = tokens;
+            true
+        }
+        None => false,
+    })
+    .await
+}
+
+/// Changes which restricted tags `name` may see, false when there's no
+/// such user.
+pub async fn set_allowed_tags(name: &str, tags: BTreeSet<String>) -> bool {
+    update(|users| match users.users.get_mut(name) {
+        Some(user) => {
+            user.allowed_tags = tags;
+            true
+        }
+        None => false,
+    })
+    .await
+}
+
+/// Changes what `name` may do, false when there's no such user.
+pub async fn set_role(name: &str, role: Role) -> bool {
+    update(|users| match users.users.get_mut(name) {
+        Some(user) => {
+            user.role = role;
+            true
+        }
+        None => false,
+    })
+    .await
+}
+
+/// Adds a user, or changes their password when they already exist.
+pub async fn set_password(name: &str, password: &str) {
+    let password_hash = hash_password(password);
+
+    update(|users| {
+        let user = users.users.entry(name.to_string()).or_insert_with(|| User {
+            name: name.to_string(),
+            role: Role::default(),
+            allowed_tags: BTreeSet::new(),
+            password_hash: String::new(),
+            refresh_tokens: Vec::new(),
+            devices: Vec::new(),
+            preferences: Preferences::default(),
+            trakt: None,
+        });
+        user.password_hash = password_hash;
+        // a new password logs out everywhere
+        user.refresh_tokens.clear();
+    })
+    .await
+}
+
+/// Adds a user without a password, for people logging in through OIDC.
+pub async fn ensure(name: &str) {
+    if exists(name) {
+        return;
+    }
+
+    update(|users| {
+        users.users.entry(name.to_string()).or_insert_with(|| User {
+            name: name.to_string(),
+            role: Role::default(),
+            allowed_tags: BTreeSet::new(),
+            // matches no password
+            password_hash: String::new(),
+            refresh_tokens: Vec::new(),
+            devices: Vec::new(),
+            preferences: Preferences::default(),
+            trakt: None,
+        });
+    })
+    .await
+}
+
+pub async fn remove(name: &str) -> bool {
+    update(|users| users.users.remove(name).is_some()).await
+}
+
+/// The user, when `password` is theirs.
+pub fn authenticate(name: &str, password: &str) -> Option<User> {
+    let user = USERS.read().unwrap().users.get(name).cloned();
+
+    match user {
+        Some(user) if verify_hash(&user.password_hash, password) => Some(user),
+        Some(_) => None,
+        None => {
+            // take as long as a wrong password would, so usernames can't be probed
+            verify_hash(&hash_password(""), password);
+            None
+        }
+    }
+}
+
+/// A new refresh token for `name` on `device`, valid for a month.
+pub async fn issue_refresh_token(name: &str, device: Option<&str>) -> String {
+    let token = random_hex(32);
+    let hash = sha256(&token);
+    let now = now();
+
+    update(|users| {
+        if let Some(user) = users.users.get_mut(name) {
+            user.refresh_tokens.retain(|token| token.expires_at > now);
+            user.refresh_tokens.push(RefreshToken {
+                hash,
+                expires_at: now + REFRESH_TOKEN_LIFETIME.as_secs(),
+                device: device.map(String::from),
+            });
+        }
+    })
+    .await;
+
+    token
+}
+
+/// Revokes `token`, returning who it belonged to and their device when it
+/// was still valid.
+pub async fn take_refresh_token(token: &str) -> Option<(String, Option<String>)> {
+    let hash = sha256(token);
+    let now = now();
+
+    update(|users| {
+        users.users.values_mut().find_map(|user| {
+            let index = user
+                .refresh_tokens
+                .iter()
+                .position(|token| token.hash == hash)?;
+            let token = user.refresh_tokens.remove(index);
+
+            (token.expires_at > now).then(|| (user.name.clone(), token.device))
+        })
+    })
+    .await
+}
+
+/// Registers a device for `name`, or updates the one with `id` when they
+/// already have it, returning its id.
+pub async fn register_device(
+    name: &str,
+    id: Option<&str>,
+    device_name: &str,
+    kind: &str,
+) -> String {
+    let now = now();
+
+    update(|users| {
+        let user = match users.users.get_mut(name) {
+            Some(user) => user,
+            None => return String::new(),
+        };
+
+        if let Some(device) =
+            id.and_then(|id| user.devices.iter_mut().find(|device| device.id == id))
+        {
+            device.name = device_name.to_string();
+            device.kind = kind.to_string();
+            device.last_seen = now;
+            return device.id.clone();
+        }
+
+        let device = Device {
+            id: random_hex(16),
+            name: device_name.to_string(),
+            kind: kind.to_string(),
+            registered_at: now,
+            last_seen: now,
+        };
+        let id = device.id.clone();
+        user.devices.push(device);
+        id
+    })
+    .await
+}
+
+pub fn device(name: &str, id: &str) -> Option<Device> {
+    USERS
+        .read()
+        .unwrap()
+        .users
+        .get(name)?
+        .devices
+        .iter()
+        .find(|device| device.id == id)
+        .cloned()
+}
+
+/// Notes that `name` was just seen using device `id`.
+pub async fn touch_device(name: &str, id: &str) {
+    let now = now();
+
+    update(|users| {
+        let device = users
+            .users
+            .get_mut(name)
+            .and_then(|user| user.devices.iter_mut().find(|device| device.id == id));
+        if let Some(device) = device {
+            device.last_seen = now;
+        }
+    })
+    .await
+}
+
+/// Forgets device `id`, logging it out. False when nobody has it.
+pub async fn revoke_device(id: &str) -> bool {
+    update(|users| {
+        users.users.values_mut().any(|user| {
+            let before = user.devices.len();
+            user.devices.retain(|device| device.id != id);
+            user.refresh_tokens
+                .retain(|token| token.device.as_deref() != Some(id));
+
+            user.devices.len() != before
+        })
+    })
+    .await
+}
@@ -0,0 +1,126 @@
+//! What Sonarr will still upgrade: monitored episodes whose file is below
+//! the cutoff of the show's quality profile.
+
+use std::collections::BTreeMap;
+
+use axum::{extract::Query, routing::get, Json, Router};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{errors::ApiError, restrictions::Restrictions, sonarr, store};
+
+const DEFAULT_PAGE_SIZE: u32 = 20;
+const MAX_PAGE_SIZE: u32 = 100;
+/// Enough to have every episode below the cutoff in one page when counting
+/// them by show.
+const ALL: u32 = 100_000;
+
+pub fn router() -> Router {
+    Router::new().route("/cutoff-unmet", get(cutoff_unmet))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CutoffQuery {
+    #[serde(default = "first_page")]
+    page: u32,
+    #[serde(default = "default_page_size")]
+    page_size: u32,
+}
+
+fn first_page() -> u32 {
+    1
+}
+
+fn default_page_size() -> u32 {
+    DEFAULT_PAGE_SIZE
+}
+
+/// A page of Sonarr's cutoff unmet list.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Page {
+    page: u32,
+    page_size: u32,
+    total_records: u64,
+    /// Episodes with their `series` and `episodeFile`, most recently aired
+    /// first.
+    records: Vec<Value>,
+}
+
+fn path(page: u32, page_size: u32, include_series: bool) -> String {
+    // v2 sorts by sortDir, v3 by sortDirection
+    format!(
+        "/wanted/cutoff?page={}&pageSize={}&sortKey=airDateUtc&sortDir=desc\
+         &sortDirection=descending&includeSeries={}&includeEpisodeFile=true",
+        page, page_size, include_series
+    )
+}
+
+/// Episodes Sonarr will upgrade once a better release shows up, a page at
+/// a time. Restricted shows' episodes are left out of the page, but still
+/// counted in `totalRecords`.
+async fn cutoff_unmet(
+    Query(query): Query<CutoffQuery>,
+    restrictions: Restrictions,
+) -> Result<Json<Page>, ApiError> {
+    if query.page == 0 {
+        return Err(ApiError::new(400, "page starts at 1".into()));
+    }
+    if !(1..=MAX_PAGE_SIZE).contains(&query.page_size) {
+        return Err(ApiError::new(
+            400,
+            format!("pageSize should be between 1 and {}", MAX_PAGE_SIZE),
+        ));
+    }
+
+    let body = sonarr::get(&path(query.page, query.page_size, true)).await?;
+    let mut page = serde_json::from_str::<Page>(&body)
+        .map_err(|e| ApiError::empty(500, Some(e.to_string())))?;
+
+    let labels = sonarr::tags().await?;
+    page.records.retain(|episode| {
+        let tags = episode["series"]["tags"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|id| labels.get(&(id.as_i64()? as i32)))
+            .collect::<Vec<_>>();
+        restrictions.allows(tags)
+    });
+
+    Ok(Json(page))
+}
+
+/// How many monitored episodes of each show Sonarr will still upgrade, by
+/// show id, from the synced library or Sonarr's cutoff unmet list.
+pub async fn counts() -> Result<BTreeMap<i32, u32>, ApiError> {
+    let mut counts = BTreeMap::new();
+    let count = |episode: &Value| {
+        if let Some(id) = episode["seriesId"].as_i64() {
+            *counts.entry(id as i32).or_default() += 1;
+        }
+    };
+
+    match store::library() {
+        Some(library) => library
+            .episodes
+            .values()
+            .flatten()
+            .filter(|episode| {
+                episode["monitored"].as_bool() == Some(true)
+                    && episode["episodeFile"]["qualityCutoffNotMet"].as_bool() == Some(true)
+            })
+            .for_each(count),
+        None => {
+            let body = sonarr::get(&path(1, ALL, false)).await?;
+            serde_json::from_str::<Page>(&body)
+                .map_err(|e| ApiError::empty(500, Some(e.to_string())))?
+                .records
+                .iter()
+                .for_each(count);
+        }
+    }
+
+    Ok(counts)
+}
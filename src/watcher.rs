@@ -0,0 +1,177 @@
+use std::path::{Path, PathBuf};
+
+use notify::event::{AccessKind, AccessMode, EventKind, ModifyKind, RenameMode};
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+use tokio::sync::mpsc;
+
+use crate::{config, store, sync};
+
+/// How many events wait for the store before the watcher holds back.
+const BACKLOG: usize = 1024;
+
+/// Passes what happened to files when Sonarr imports, upgrades or deletes
+/// them on to [`present`] and [`gone`].
+async fn handle(event: Event) {
+    if event.need_rescan() {
+        return recheck_missing().await;
+    }
+
+    match event.kind {
+        EventKind::Create(_)
+        | EventKind::Access(AccessKind::Close(AccessMode::Write))
+        | EventKind::Modify(ModifyKind::Name(RenameMode::To)) => {
+            // a folder moved in from elsewhere arrives with its files already in it
+            for path in &event.paths {
+                present(path).await;
+            }
+        }
+        EventKind::Remove(_) | EventKind::Modify(ModifyKind::Name(RenameMode::From)) => {
+            for path in &event.paths {
+                gone(path).await;
+            }
+        }
+        // watchers that can't tell which end of a rename they saw
+        EventKind::Modify(ModifyKind::Name(RenameMode::Any)) => {
+            for path in &event.paths {
+                if path.exists() {
+                    present(path).await;
+                } else {
+                    gone(path).await;
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Where the episode files Sonarr knows about are on disk.
+fn episode_files() -> Vec<PathBuf> {
+    let config = config::get();
+    let library = match store::library() {
+        Some(library) => library,
+        None => return Vec::new(),
+    };
+
+    library
+        .episodes
+        .values()
+        .flatten()
+        .filter_map(|episode| episode["episodeFile"]["path"].as_str())
+        .map(|path| config.local_path(Path::new(path)))
+        .collect()
+}
+
+/// Something showed up at `path`, when it's not a file Sonarr told us about
+/// yet the series folder it's in is synced again to pick it up.
+async fn present(path: &Path) {
+    let known = episode_files();
+
+    if known.iter().any(|file| file.starts_with(path)) {
+        let missing = store::library()
+            .filter(|library| {
+                library
+                    .missing_files
+                    .iter()
+                    .any(|file| file.starts_with(path))
+            })
+            .is_some();
+
+        if missing {
+            tracing::debug!("{:?} is back", path);
+            store::update(|library| library.missing_files.retain(|file| !file.starts_with(path)))
+                .await;
+        }
+        return;
+    }
+
+    let config = config::get();
+    let series = store::library().and_then(|library| {
+        library.series.iter().find_map(|(id, series)| {
+            let folder = config.local_path(Path::new(series["path"].as_str()?));
+            path.starts_with(folder).then_some(*id)
+        })
+    });
+
+    if let Some(id) = series {
+        tracing::debug!("{:?} appeared, syncing series {}", path, id);
+        sync::refresh(id);
+    }
+}
+
+/// Something at `path` was deleted or moved away, any episode files there
+/// are marked as missing until they come back or Sonarr forgets them.
+async fn gone(path: &Path) {
+    let missing = episode_files()
+        .into_iter()
+        .filter(|file| file.starts_with(path))
+        .collect::<Vec<_>>();
+
+    if !missing.is_empty() {
+        tracing::debug!(
+            "{:?} is gone, marking {} files missing",
+            path,
+            missing.len()
+        );
+        store::update(|library| library.missing_files.extend(missing)).await;
+    }
+}
+
+/// Whatever went missing while we weren't looking may be back by now.
+async fn recheck_missing() {
+    let found = store::library()
+        .map(|library| library.missing_files.iter().any(|file| file.exists()))
+        .unwrap_or(false);
+    if found {
+        store::update(|library| library.missing_files.retain(|file| !file.exists())).await;
+    }
+}
+
+/// Watches the media roots for files appearing and disappearing, keeping
+/// the store up to date without waiting for Sonarr to notice.
+pub async fn watch() {
+    let roots = config::get().media_roots.clone();
+    if roots.is_empty() {
+        return std::future::pending().await;
+    }
+
+    let (events, mut received) = mpsc::channel(BACKLOG);
+    let watcher = RecommendedWatcher::new(
+        move |event| {
+            let _ = events.blocking_send(event);
+        },
+        notify::Config::default(),
+    );
+    let mut watcher = match watcher {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            tracing::error!("Can't watch the media roots: {}", e);
+            return std::future::pending().await;
+        }
+    };
+
+    for root in &roots {
+        match watcher.watch(root, RecursiveMode::Recursive) {
+            Ok(()) => tracing::debug!("Watching {:?} for changes", root),
+            Err(notify::Error {
+                kind: notify::ErrorKind::MaxFilesWatch,
+                ..
+            }) => tracing::warn!(
+                "Ran out of inotify watches at {:?}, raise fs.inotify.max_user_watches",
+                root
+            ),
+            Err(e) => tracing::warn!("Can't watch {:?}: {}", root, e),
+        }
+    }
+
+    recheck_missing().await;
+
+    while let Some(event) = received.recv().await {
+        match event {
+            Ok(event) => handle(event).await,
+            Err(e) => tracing::warn!("Failed to watch the media roots: {}", e),
+        }
+    }
+
+    tracing::error!("Stopped watching the media roots");
+    std::future::pending().await
+}
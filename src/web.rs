@@ -0,0 +1,59 @@
+use std::path::PathBuf;
+
+use axum::routing::{get_service, MethodRouter};
+use tower_http::services::{ServeDir, ServeFile};
+
+/// The web UI to serve next to the API: files from `CENTARR_WEB_ROOT` when
+/// set, otherwise the embedded UI if the `webui` feature is enabled.
+pub fn ui() -> Option<MethodRouter> {
+    if let Some(root) = crate::config::get().web_root.clone() {
+        tracing::debug!("Serving web ui from {:?}", root);
+        return Some(serve(root));
+    }
+
+    #[cfg(feature = "webui")]
+    return Some(embedded());
+
+    #[cfg(not(feature = "webui"))]
+    None
+}
+
+/// Serves the files in `root`, answering every path that isn't a file with
+/// `index.html` so the UI's client side router can take over. Files that
+/// can't be read are a 500.
+fn serve(root: PathBuf) -> MethodRouter {
+    let index = ServeFile::new(root.join("index.html"));
+
+    get_service(ServeDir::new(root).fallback(index))
+}
+
+/// The minimal web UI from `web/`, compiled into the binary.
+#[cfg(feature = "webui")]
+#[derive(rust_embed::RustEmbed)]
+#[folder = "web/"]
+struct Assets;
+
+/// Serves [`Assets`] by path, `index.html` for anything else.
+#[cfg(feature = "webui")]
+fn embedded() -> MethodRouter {
+    use axum::{
+        http::{header, Uri},
+        response::IntoResponse,
+        routing::get,
+    };
+
+    get(|uri: Uri| async move {
+        let path = uri.path().trim_start_matches('/');
+        let (path, asset) = match Assets::get(path) {
+            Some(asset) => (path, asset),
+            None => ("index.html", Assets::get("index.html").unwrap()),
+        };
+        let content_type = mime_guess::from_path(path).first_or_octet_stream();
+
+        (
+            [(header::CONTENT_TYPE, content_type.to_string())],
+            asset.data,
+        )
+            .into_response()
+    })
+}
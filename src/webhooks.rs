@@ -0,0 +1,126 @@
+use std::time::{Duration, SystemTime};
+
+use reqwest::header;
+use ring::hmac;
+use serde_json::json;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::{
+    config::{self, WebhookConfig},
+    events,
+};
+
+const MAX_ATTEMPTS: u32 = 5;
+/// Doubled after every failed attempt.
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(1);
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// `sha256=<hex>` of the body, keyed with the webhook's secret, so the
+/// receiver can check it came from us.
+fn signature(secret: &str, body: &[u8]) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    format!("sha256={}", hex(hmac::sign(&key, body).as_ref()))
+}
+
+/// POSTs `body` to the webhook, retrying with backoff while it fails in a
+/// way that might go away.
+async fn deliver(client: &reqwest::Client, webhook: &WebhookConfig, kind: &str, body: Vec<u8>) {
+    let mut delay = FIRST_RETRY_DELAY;
+
+    for attempt in 1..=MAX_ATTEMPTS {
+        let mut request = client
+            .post(&webhook.url)
+            .timeout(TIMEOUT)
+            .header(header::CONTENT_TYPE, "application/json")
+            .header("X-Centarr-Event", kind);
+        if let Some(secret) = &webhook.secret {
+            request = request.header("X-Centarr-Signature", signature(secret, &body));
+        }
+
+        let error = match request.body(body.clone()).send().await {
+            Ok(res) if res.status().is_success() => return,
+            Ok(res)
+                if res.status().is_client_error()
+                    && res.status() != reqwest::StatusCode::TOO_MANY_REQUESTS =>
+            {
+                tracing::warn!(
+                    "Webhook {} rejected {} with {}, not retrying",
+                    webhook.url,
+                    kind,
+                    res.status()
+                );
+                return;
+            }
+            Ok(res) => res.status().to_string(),
+            Err(e) => e.to_string(),
+        };
+
+        if attempt == MAX_ATTEMPTS {
+            tracing::warn!(
+                "Giving up on sending {} to {} after {} attempts: {}",
+                kind,
+                webhook.url,
+                attempt,
+                error
+            );
+            return;
+        }
+
+        tracing::debug!(
+            "Sending {} to {} failed, retrying in {:?}: {}",
+            kind,
+            webhook.url,
+            delay,
+            error
+        );
+        tokio::time::sleep(delay).await;
+        delay *= 2;
+    }
+}
+
+/// POSTs every event to the webhooks configured for it.
+pub async fn run() {
+    let client = reqwest::Client::new();
+    let mut events = events::subscribe();
+
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(missed)) => {
+                tracing::warn!("Dropped {} webhook deliveries, too many at once", missed);
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+
+        let config = config::get();
+        let wanted = config.webhooks.iter().filter(|webhook| {
+            webhook.events.is_empty() || webhook.events.iter().any(|kind| kind == event.kind())
+        });
+
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let body = json!({
+            "event": event.kind(),
+            "timestamp": timestamp,
+            "data": &event,
+        })
+        .to_string()
+        .into_bytes();
+
+        for webhook in wanted {
+            let client = client.clone();
+            let webhook = webhook.clone();
+            let kind = event.kind();
+            let body = body.clone();
+
+            tokio::spawn(async move { deliver(&client, &webhook, kind, body).await });
+        }
+    }
+}
@@ -0,0 +1,258 @@
+//! Zip archives, of whole seasons written as they're sent so files of any
+//! size can be bundled without a temporary copy, and of backups, made and
+//! read in memory. Media doesn't compress, so seasons are only stored.
+
+use std::io::{self, Cursor, Read, Write};
+use std::path::PathBuf;
+use std::time::{Instant, SystemTime};
+
+use async_zip::base::write::ZipFileWriter;
+use async_zip::{Compression, ZipDateTime, ZipEntryBuilder};
+use axum::body::Body;
+use chrono::{DateTime, Datelike, Timelike, Utc};
+use futures::{AsyncWriteExt, StreamExt};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, DuplexStream};
+use tokio_util::io::ReaderStream;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+use crate::sendfile;
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// async_zip makes every streamed entry ZIP64, as seasons easily go over
+/// 4 GB, which makes the headers a fixed size apart from the name: the
+/// local header with the sizes in its extra field, the data descriptor,
+/// the central directory header with the offset too, and the ZIP64 end
+/// records before the usual one.
+const LOCAL_HEADER_LEN: u64 = 30 + 20;
+const DATA_DESCRIPTOR_LEN: u64 = 16;
+const CENTRAL_HEADER_LEN: u64 = 46 + 28;
+const END_LEN: u64 = 56 + 20 + 22;
+
+/// A file to put in the archive as `name`.
+pub struct Entry {
+    pub name: String,
+    pub path: PathBuf,
+    pub size: u64,
+    pub modified: SystemTime,
+}
+
+/// How long the archive of `entries` is, known before it's written.
+pub fn len(entries: &[Entry]) -> u64 {
+    entries
+        .iter()
+        .map(|entry| {
+            LOCAL_HEADER_LEN
+                + DATA_DESCRIPTOR_LEN
+                + CENTRAL_HEADER_LEN
+                + 2 * entry.name.len() as u64
+                + entry.size
+        })
+        .sum::<u64>()
+        + END_LEN
+}
+
+/// Why an archive wasn't finished.
+enum Stopped {
+    ClientLeft,
+    Failed(String),
+}
+
+impl From<async_zip::error::ZipError> for Stopped {
+    fn from(_: async_zip::error::ZipError) -> Self {
+        Stopped::ClientLeft
+    }
+}
+
+impl From<io::Error> for Stopped {
+    fn from(_: io::Error) -> Self {
+        Stopped::ClientLeft
+    }
+}
+
+/// Writes the archive of `entries` to `sink`, at no more than `max_rate`
+/// bytes of the files per second.
+async fn write(
+    entries: &[Entry],
+    sink: DuplexStream,
+    max_rate: Option<u64>,
+) -> Result<(), Stopped> {
+    let mut writer = ZipFileWriter::with_tokio(sink);
+    let started = Instant::now();
+    let mut sent = 0;
+
+    for entry in entries {
+        let mut file = File::open(&entry.path)
+            .await
+            .map_err(|e| Stopped::Failed(format!("{:?} can't be read: {}", entry.path, e)))?;
+        let modified = DateTime::<Utc>::from(entry.modified);
+        let header = ZipEntryBuilder::new(entry.name.clone().into(), Compression::Stored)
+            .last_modification_date(ZipDateTime::from_chrono(&modified));
+        let mut stream = writer.write_entry_stream(header).await?;
+
+        let mut left = entry.size;
+        let mut buf = vec![0; CHUNK_SIZE];
+        while left > 0 {
+            let len = CHUNK_SIZE.min(left as usize);
+            let read = file
+                .read(&mut buf[..len])
+                .await
+                .map_err(|e| Stopped::Failed(format!("Reading {:?} failed: {}", entry.path, e)))?;
+            if read == 0 {
+                return Err(Stopped::Failed(format!(
+                    "{:?} got shorter while sending it",
+                    entry.path
+                )));
+            }
+            stream.write_all(&buf[..read]).await?;
+            left -= read as u64;
+            sent += read as u64;
+
+            if let Some(rate) = max_rate {
+                sendfile::throttle(rate, sent, started).await;
+            }
+        }
+        stream.close().await?;
+    }
+
+    let mut sink = writer.close().await?;
+    futures::AsyncWriteExt::close(&mut sink).await?;
+    Ok(())
+}
+
+/// The archive of `entries`, written as it's sent, at no more than
+/// `max_rate` bytes per second. Every entry has to stay the size it was
+/// given as, the archive is cut short otherwise.
+pub fn body(entries: Vec<Entry>, max_rate: Option<u64>) -> Body {
+    let (sink, archive) = tokio::io::duplex(CHUNK_SIZE);
+
+    let written = tokio::spawn(async move {
+        match write(&entries, sink, max_rate).await {
+            Ok(()) => Ok(()),
+            Err(Stopped::ClientLeft) => {
+                tracing::debug!("Client left the zip download");
+                Ok(())
+            }
+            Err(Stopped::Failed(e)) => {
+                tracing::warn!("Zip of {} files stopped: {}", entries.len(), e);
+                Err(io::Error::other(e))
+            }
+        }
+    });
+    // a failed archive ends in an error rather than like a finished one
+    let failed = futures::stream::once(async move {
+        written
+            .await
+            .unwrap_or_else(|e| Err(io::Error::other(e)))
+            .err()
+    })
+    .filter_map(|failed| async move { failed.map(Err) });
+
+    Body::wrap_stream(ReaderStream::new(archive).chain(failed))
+}
+
+/// An archive of `files`, by name, deflated in memory for when they're
+/// small and have to be read all at once anyway.
+pub fn archive(files: &[(String, Vec<u8>)], modified: SystemTime) -> io::Result<Vec<u8>> {
+    let modified = DateTime::<Utc>::from(modified);
+    let modified = zip::DateTime::from_date_and_time(
+        modified.year().clamp(1980, 2107) as u16,
+        modified.month() as u8,
+        modified.day() as u8,
+        modified.hour() as u8,
+        modified.minute() as u8,
+        modified.second() as u8,
+    )
+    .unwrap_or_default();
+    let options = SimpleFileOptions::default()
+        .compression_method(CompressionMethod::Deflated)
+        .last_modified_time(modified)
+        .large_file(false);
+
+    let mut archive = ZipWriter::new(Cursor::new(Vec::new()));
+    for (name, contents) in files {
+        archive.start_file(name.as_str(), options)?;
+        archive.write_all(contents)?;
+    }
+
+    Ok(archive.finish()?.into_inner())
+}
+
+/// The files in an archive, with their names, however they're compressed.
+pub fn read(archive: &[u8]) -> Result<Vec<(String, Vec<u8>)>, String> {
+    let mut archive = ZipArchive::new(Cursor::new(archive)).map_err(|e| e.to_string())?;
+
+    (0..archive.len())
+        .map(|index| {
+            let mut file = archive.by_index(index).map_err(|e| e.to_string())?;
+            let name = file.name().map_err(|e| e.to_string())?.into_owned();
+            let mut contents = Vec::with_capacity(file.size() as usize);
+            file.read_to_end(&mut contents)
+                .map_err(|e| format!("{} is corrupt: {}", name, e))?;
+            Ok((name, contents))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn archives_read_back_what_was_written() {
+        let files = vec![
+            (
+                "data/users.json".to_string(),
+                b"{\"users\": {}}".repeat(100),
+            ),
+            ("empty".to_string(), Vec::new()),
+        ];
+        let mut archive = archive(&files, SystemTime::now()).unwrap();
+        assert_eq!(read(&archive).unwrap(), files);
+
+        // flip a byte at the end of the first file's deflated contents
+        let at = archive
+            .windows(4)
+            .position(|four| four == b"PK\x03\x04")
+            .unwrap();
+        let second = archive[at + 4..]
+            .windows(4)
+            .position(|four| four == b"PK\x03\x04")
+            .unwrap();
+        archive[at + second] ^= 0xff;
+        assert!(read(&archive)
+            .unwrap_err()
+            .starts_with("data/users.json is corrupt"));
+        assert!(read(b"not a zip").is_err());
+    }
+
+    #[tokio::test]
+    async fn seasons_are_as_long_as_announced() {
+        let dir = std::env::temp_dir().join(format!("centarr-zip-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let entries = [("S01E01.mkv", 1000), ("S01E02 – Ünïcode.mkv", 0)]
+            .into_iter()
+            .map(|(name, size)| {
+                let path = dir.join(name);
+                std::fs::write(&path, vec![7; size]).unwrap();
+                Entry {
+                    name: name.into(),
+                    path,
+                    size: size as u64,
+                    modified: SystemTime::now(),
+                }
+            })
+            .collect::<Vec<_>>();
+        let expected = len(&entries);
+
+        let body = hyper::body::to_bytes(body(entries, None)).await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(body.len() as u64, expected);
+
+        let files = read(&body).unwrap();
+        assert_eq!(files[0], ("S01E01.mkv".to_string(), vec![7; 1000]));
+        assert_eq!(files[1].0, "S01E02 – Ünïcode.mkv");
+    }
+}
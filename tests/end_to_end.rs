@@ -0,0 +1,341 @@
+//! Runs centarr against a fake Sonarr and a folder of media, from listing
+//! shows to streaming an episode, like a client would.
+
+use std::io::{BufRead, BufReader};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use reqwest::{header, StatusCode};
+use serde_json::{json, Value};
+use wiremock::matchers::{method, path, query_param};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+struct Server {
+    child: Child,
+    api: SocketAddr,
+    dir: PathBuf,
+    // keeps Sonarr up for as long as centarr
+    _sonarr: MockServer,
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+impl Server {
+    fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.api, path)
+    }
+}
+
+/// Sonarr v3 with one show, its first episode downloaded to `file`.
+async fn sonarr(file: &str) -> MockServer {
+    let sonarr = MockServer::start().await;
+    let series = json!({
+        "id": 1,
+        "title": "Faked",
+        "images": [],
+        "tags": [],
+        "episodeCount": 2,
+        "episodeFileCount": 1
+    });
+    let episode = |id: i32, file: Value| {
+        json!({
+            "id": id,
+            "seriesId": 1,
+            "episodeFileId": file["id"].as_i64().unwrap_or_default(),
+            "seasonNumber": 1,
+            "episodeNumber": id,
+            "title": format!("Episode {}", id),
+            "airDateUtc": "2020-01-01T00:00:00Z",
+            "episodeFile": file,
+            "hasFile": !file.is_null(),
+            "monitored": true,
+            "unverifiedSceneNumbering": false
+        })
+    };
+    let episodes = json!([
+        episode(
+            1,
+            json!({
+                "id": 11,
+                "seriesId": 1,
+                "seasonNumber": 1,
+                "relativePath": "Season 1/Faked - S01E01.mkv",
+                "path": file,
+                "size": 100,
+                "dateAdded": "2020-01-01T00:00:00Z",
+                "originalFilePath": "",
+                "qualityCutoffNotMet": false
+            })
+        ),
+        episode(2, Value::Null),
+    ]);
+
+    for (at, body) in [
+        ("/api/v3/system/status", json!({ "version": "3.0.10.1567" })),
+        ("/api/v3/series", json!([series])),
+        ("/api/v3/series/1", series),
+        ("/api/v3/tag", json!([])),
+    ] {
+        Mock::given(method("GET"))
+            .and(path(at))
+            .respond_with(ResponseTemplate::new(200).set_body_json(body))
+            .mount(&sonarr)
+            .await;
+    }
+    Mock::given(method("GET"))
+        .and(path("/api/v3/episode"))
+        .and(query_param("seriesId", "1"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(episodes))
+        .mount(&sonarr)
+        .await;
+
+    sonarr
+}
+
+/// Where centarr says `server` is listening in `line`, if it does.
+fn listening(line: &str, server: &str) -> Option<SocketAddr> {
+    let (_, rest) = line.split_once(&format!("{} http://", server))?;
+    rest.split(|c: char| c.is_whitespace() || c.is_control())
+        .next()?
+        .parse()
+        .ok()
+}
+
+/// Starts centarr on free ports with a fake Sonarr and waits until it has
+/// found out which Sonarr that is.
+/// Lets the tests in to `/admin`.
+const ADMIN_TOKEN: &str = "admin";
+
+async fn start(name: &str) -> Server {
+    start_on(name, "127.0.0.1:0").await
+}
+
+/// Like [`start`], with both servers on `addr`.
+async fn start_on(name: &str, addr: &str) -> Server {
+    let dir = std::env::temp_dir().join(format!("centarr-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(dir.join("media")).unwrap();
+    std::fs::write(dir.join("config.json"), "{}").unwrap();
+    let file = dir.join("media").join("Faked - S01E01.mkv");
+    std::fs::write(&file, (0..100u8).collect::<Vec<_>>()).unwrap();
+    // files written to in the last few seconds are taken for ones still
+    // being imported
+    std::fs::File::options()
+        .write(true)
+        .open(&file)
+        .unwrap()
+        .set_modified(SystemTime::now() - Duration::from_secs(60 * 60))
+        .unwrap();
+
+    let sonarr = sonarr(file.to_str().unwrap()).await;
+    let mut child = Command::new(env!("CARGO_BIN_EXE_centarr"))
+        .env_clear()
+        .env("SONARR_URL", sonarr.uri())
+        .env("SONARR_API_KEY", "test")
+        .env("CENTARR_CONFIG", dir.join("config.json"))
+        .env("CENTARR_DATA_DIR", dir.join("data"))
+        .env("CENTARR_SYNC_INTERVAL", "0")
+        .env("CENTARR_PREFETCH_IMAGES", "false")
+        .env("CENTARR_MDNS", "false")
+        .env("CENTARR_ADMIN_TOKEN", ADMIN_TOKEN)
+        .env("CENTARR_API_ADDR", addr)
+        .env("CENTARR_STREAM_ADDR", addr)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    // keeps reading the log so centarr never blocks on writing it
+    let (tx, rx) = mpsc::channel();
+    let stdout = child.stdout.take().unwrap();
+    thread::spawn(move || {
+        for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+            if let Some(addr) = listening(&line, "API listening on") {
+                let _ = tx.send(addr);
+            }
+        }
+    });
+    let api = rx.recv_timeout(Duration::from_secs(10));
+    let server = Server {
+        child,
+        api: api.expect("the server didn't start"),
+        dir,
+        _sonarr: sonarr,
+    };
+
+    let started = Instant::now();
+    loop {
+        let ready = reqwest::get(server.url("/readyz")).await;
+        if matches!(ready, Ok(res) if res.status() == StatusCode::OK) {
+            break;
+        }
+        assert!(
+            started.elapsed() < Duration::from_secs(10),
+            "Sonarr wasn't detected"
+        );
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    server
+}
+
+async fn get_json(server: &Server, path: &str) -> Value {
+    let res = reqwest::get(server.url(path)).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK, "{}", path);
+
+    res.json().await.unwrap()
+}
+
+#[tokio::test]
+async fn shows_come_from_sonarr() {
+    let server = start("shows").await;
+
+    let shows = get_json(&server, "/shows").await;
+    assert_eq!(shows[0]["title"], "Faked");
+    assert_eq!(shows[0]["percentOfEpisodes"], 50.0);
+}
+
+#[tokio::test]
+async fn shows_have_their_episodes() {
+    let server = start("show").await;
+
+    let show = get_json(&server, "/shows/1").await;
+    assert_eq!(show["title"], "Faked");
+    let episodes = show["episodes"].as_array().unwrap();
+    assert_eq!(episodes.len(), 2);
+    assert_eq!(episodes[0]["isAired"], true);
+    assert!(episodes[0]["episodeFile"]["watchUrl"].is_string());
+    assert_eq!(episodes[0]["episodeFile"]["fileAvailable"], true);
+    assert_eq!(episodes[0]["episodeFile"]["fileSizeOnDisk"], 100);
+    assert!(episodes[1]["episodeFile"].is_null());
+}
+
+#[tokio::test]
+async fn episodes_stream_in_ranges() {
+    let server = start("stream").await;
+
+    let show = get_json(&server, "/shows/1").await;
+    let watch_url = show["episodes"][0]["episodeFile"]["watchUrl"]
+        .as_str()
+        .unwrap();
+    let res = reqwest::Client::new()
+        .get(watch_url)
+        .header(header::RANGE, "bytes=10-19")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(res.headers()[header::CONTENT_RANGE], "bytes 10-19/100");
+    assert_eq!(
+        res.bytes().await.unwrap().as_ref(),
+        (10..20u8).collect::<Vec<_>>()
+    );
+}
+
+#[tokio::test]
+async fn dual_stack_servers_hand_out_ipv6_urls_to_ipv6_clients() {
+    let mut server = start_on("ipv6", "[::]:0").await;
+    server.api.set_ip(Ipv6Addr::LOCALHOST.into());
+
+    let show = get_json(&server, "/shows/1").await;
+    let watch_url = show["episodes"][0]["episodeFile"]["watchUrl"]
+        .as_str()
+        .unwrap();
+    assert!(watch_url.starts_with("http://[::1]:"), "{}", watch_url);
+    let res = reqwest::get(watch_url).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+
+    // IPv4 clients get through too
+    server.api.set_ip(Ipv4Addr::LOCALHOST.into());
+    let show = get_json(&server, "/shows/1").await;
+    let watch_url = show["episodes"][0]["episodeFile"]["watchUrl"]
+        .as_str()
+        .unwrap();
+    assert!(watch_url.starts_with("http://127.0.0.1:"), "{}", watch_url);
+}
+
+#[tokio::test]
+async fn watch_history_is_imported_and_exported() {
+    let server = start("import").await;
+
+    let plex = "Series Title,Season,Episode,Title,View Count,Last Viewed at\r\n\
+                Faked,1,1,Episode 1,1,1672653600\r\n\
+                Elsewhere,1,1,Pilot,3,1672653600\r\n";
+    let imported: Value = reqwest::Client::new()
+        .post(server.url("/import"))
+        .header(header::CONTENT_TYPE, "text/csv")
+        .body(plex)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(imported["imported"], 1);
+    assert_eq!(imported["unmatched"], json!(["Elsewhere S01E01"]));
+
+    let exported = get_json(&server, "/export").await;
+    assert_eq!(exported[0]["watchState"], "watched");
+    assert_eq!(exported[0]["lastPlayed"], "2023-01-02T10:00:00Z");
+}
+
+#[tokio::test]
+async fn backups_are_restored() {
+    let server = start("backup").await;
+    let client = reqwest::Client::new();
+    let add_user = |name: &str| {
+        client
+            .put(server.url(&format!("/admin/users/{}", name)))
+            .bearer_auth(ADMIN_TOKEN)
+            .json(&json!({ "password": "hunter22" }))
+            .send()
+    };
+
+    add_user("bob").await.unwrap().error_for_status().unwrap();
+    let res = client
+        .post(server.url("/admin/backup"))
+        .bearer_auth(ADMIN_TOKEN)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let backup = res.bytes().await.unwrap();
+    add_user("carol").await.unwrap().error_for_status().unwrap();
+
+    let restored: Value = client
+        .post(server.url("/admin/restore?config=false"))
+        .bearer_auth(ADMIN_TOKEN)
+        .body(backup)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert!(restored["files"]
+        .as_array()
+        .unwrap()
+        .contains(&json!("users")));
+    let users: Value = client
+        .get(server.url("/admin/users"))
+        .bearer_auth(ADMIN_TOKEN)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(users.as_array().unwrap().len(), 1);
+    assert_eq!(users[0]["name"], "bob");
+}
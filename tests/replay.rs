@@ -0,0 +1,126 @@
+//! Runs the API on responses recorded from Sonarr, as `--mock replay`
+//! does for developing without one.
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+struct Server {
+    child: Child,
+    addr: SocketAddr,
+    dir: PathBuf,
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn free_addr() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+}
+
+fn cassette(dir: &Path, name: &str, path: &str, status: u16, body: &str) {
+    let cassette = format!(
+        r#"{{ "method": "GET", "path": {:?}, "status": {}, "body": {:?} }}"#,
+        path, status, body
+    );
+    std::fs::write(dir.join(name), cassette).unwrap();
+}
+
+/// Starts centarr replaying a Sonarr with one show, which isn't running.
+fn start(name: &str) -> Server {
+    let dir = std::env::temp_dir().join(format!("centarr-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let sonarr = dir.join("cassettes").join("sonarr");
+    std::fs::create_dir_all(&sonarr).unwrap();
+    std::fs::write(dir.join("config.json"), "{}").unwrap();
+
+    cassette(
+        &sonarr,
+        "status.json",
+        "/system/status",
+        200,
+        r#"{"version": "3.0.10.1567"}"#,
+    );
+    cassette(
+        &sonarr,
+        "series.json",
+        "/series",
+        200,
+        r#"[{"id": 1, "title": "Recorded", "images": [], "tags": []}]"#,
+    );
+    cassette(&sonarr, "tags.json", "/tag", 200, "[]");
+
+    let addr = free_addr();
+    let child = Command::new(env!("CARGO_BIN_EXE_centarr"))
+        .args(["serve", "--mock", "replay", "--cassettes"])
+        .arg(dir.join("cassettes"))
+        .env_clear()
+        .env("SONARR_URL", "http://127.0.0.1:1")
+        .env("SONARR_API_KEY", "test")
+        .env("CENTARR_CONFIG", dir.join("config.json"))
+        .env("CENTARR_DATA_DIR", dir.join("data"))
+        .env("CENTARR_SYNC_INTERVAL", "0")
+        .env("CENTARR_API_ADDR", addr.to_string())
+        .env("CENTARR_STREAM_ADDR", free_addr().to_string())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let server = Server { child, addr, dir };
+
+    let started = Instant::now();
+    while TcpStream::connect(addr).is_err() {
+        assert!(
+            started.elapsed() < Duration::from_secs(10),
+            "the server didn't start"
+        );
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    server
+}
+
+fn get(server: &Server, target: &str) -> String {
+    let mut stream = TcpStream::connect(server.addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    write!(
+        stream,
+        "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        target
+    )
+    .unwrap();
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+    String::from_utf8_lossy(&response).into_owned()
+}
+
+#[test]
+fn shows_come_from_recorded_responses() {
+    let server = start("replay");
+
+    let response = get(&server, "/shows");
+    assert!(response.starts_with("HTTP/1.1 200 "), "{}", response);
+    assert!(response.contains(r#""title":"Recorded""#), "{}", response);
+}
+
+#[test]
+fn unrecorded_requests_are_unavailable() {
+    let server = start("unrecorded");
+
+    let response = get(&server, "/shows/2");
+    assert!(response.starts_with("HTTP/1.1 503 "), "{}", response);
+}
@@ -0,0 +1,351 @@
+//! Sends requests to the streaming server as players and misbehaving
+//! clients do, checking every one gets a proper response.
+
+use std::ffi::OsStr;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+struct Server {
+    child: Child,
+    addr: SocketAddr,
+    dir: PathBuf,
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn free_addr() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+}
+
+/// Writes a file last modified `age` ago, files written to in the last few
+/// seconds being taken for ones still being imported.
+fn write_aged(path: &Path, contents: &[u8], age: Duration) {
+    std::fs::write(path, contents).unwrap();
+    std::fs::File::options()
+        .write(true)
+        .open(path)
+        .unwrap()
+        .set_modified(SystemTime::now() - age)
+        .unwrap();
+}
+
+fn write_media(path: &Path, contents: &[u8]) {
+    write_aged(path, contents, Duration::from_secs(60 * 60));
+}
+
+/// Starts centarr with an unreachable Sonarr and waits for its streaming
+/// server to accept connections.
+fn start(name: &str) -> Server {
+    let dir = std::env::temp_dir().join(format!("centarr-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("config.json"), "{}").unwrap();
+    write_media(&dir.join("video.mkv"), &(0..100u8).collect::<Vec<_>>());
+    write_media(&dir.join("my vidéo.mkv"), &[0; 50]);
+    write_media(
+        &dir.join(OsStr::from_bytes(b"caf\xe9 \xf0\x9f\x8e\xac.mkv")),
+        &[0; 25],
+    );
+    std::os::unix::fs::symlink(dir.join("video.mkv"), dir.join("linked.mkv")).unwrap();
+    // a mount point with nothing mounted on it
+    std::fs::create_dir_all(dir.join("unmounted")).unwrap();
+    // writes how it was run to the file it's told to make, and nothing
+    // when asked what it's built with
+    let ffmpeg = dir.join("ffmpeg");
+    std::fs::write(
+        &ffmpeg,
+        "#!/bin/sh\nfor last; do :; done\ncase $last in /*) printf '%s' \"$*\" > \"$last\";; esac\n",
+    )
+    .unwrap();
+    std::fs::set_permissions(&ffmpeg, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let addr = free_addr();
+    let child = Command::new(env!("CARGO_BIN_EXE_centarr"))
+        .env_clear()
+        .env("SONARR_URL", "http://127.0.0.1:1")
+        .env("SONARR_API_KEY", "test")
+        .env("CENTARR_CONFIG", dir.join("config.json"))
+        .env("CENTARR_DATA_DIR", dir.join("data"))
+        // the default signing key would be in a media root
+        .env("CENTARR_JWT_SECRET", "test")
+        .env("CENTARR_SYNC_INTERVAL", "0")
+        .env("FFMPEG_PATH", &ffmpeg)
+        .env(
+            "CENTARR_MEDIA_ROOTS",
+            format!("{},{}", dir.join("unmounted").display(), dir.display()),
+        )
+        .env("CENTARR_API_ADDR", free_addr().to_string())
+        .env("CENTARR_STREAM_ADDR", addr.to_string())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let server = Server { child, addr, dir };
+
+    let started = Instant::now();
+    while TcpStream::connect(addr).is_err() {
+        assert!(
+            started.elapsed() < Duration::from_secs(10),
+            "the server didn't start"
+        );
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    server
+}
+
+/// Sends `request` as is and reads the response until the server closes
+/// the connection.
+fn send(server: &Server, request: &[u8]) -> String {
+    let mut stream = TcpStream::connect(server.addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    stream.write_all(request).unwrap();
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+    String::from_utf8_lossy(&response).into_owned()
+}
+
+fn get(server: &Server, target: &str, headers: &str) -> String {
+    send(
+        server,
+        format!(
+            "GET {} HTTP/1.1\r\nHost: localhost\r\n{}\r\n",
+            target, headers
+        )
+        .as_bytes(),
+    )
+}
+
+fn video(server: &Server) -> String {
+    format!("/?file={}", server.dir.join("video.mkv").display())
+}
+
+#[test]
+fn malformed_requests_are_bad_requests() {
+    let server = start("malformed");
+
+    let response = send(&server, b"this is not http\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 400 "), "{}", response);
+    assert!(response.contains("Malformed request"), "{}", response);
+
+    let response = get(&server, "/", "");
+    assert!(response.starts_with("HTTP/1.1 400 "), "{}", response);
+
+    let response = get(&server, "/stream/abc", "");
+    assert!(response.starts_with("HTTP/1.1 400 "), "{}", response);
+}
+
+#[test]
+fn oversized_headers_are_refused() {
+    let server = start("oversized");
+
+    let header = format!("X-Padding: {}\r\n", "a".repeat(32 * 1024));
+    let response = get(&server, &video(&server), &header);
+    assert!(response.starts_with("HTTP/1.1 431 "), "{}", response);
+}
+
+#[test]
+fn missing_files_are_not_found() {
+    let server = start("missing");
+
+    let missing = format!("/?file={}/exist.mkv", server.dir.display());
+    let response = get(&server, &missing, "");
+    assert!(response.starts_with("HTTP/1.1 404 "), "{}", response);
+    assert!(response.contains("Content-Length: "), "{}", response);
+}
+
+#[test]
+fn symlinks_are_followed_and_offline_storage_is_unavailable() {
+    let server = start("storage");
+
+    let linked = format!("/?file={}", server.dir.join("linked.mkv").display());
+    let response = get(&server, &linked, "");
+    assert!(response.starts_with("HTTP/1.1 200 "), "{}", response);
+    assert!(response.contains("content-length: 100"), "{}", response);
+
+    let unmounted = format!("/?file={}/show.mkv", server.dir.join("unmounted").display());
+    let response = get(&server, &unmounted, "");
+    assert!(response.starts_with("HTTP/1.1 503 "), "{}", response);
+    assert!(response.contains("Storage offline"), "{}", response);
+}
+
+#[test]
+fn files_outside_the_media_roots_are_forbidden() {
+    let server = start("outside");
+    std::fs::create_dir_all(server.dir.join("data")).unwrap();
+    std::fs::write(server.dir.join("data/users.json"), "{}").unwrap();
+    let outside = std::env::temp_dir().join(format!("centarr-secret-{}", std::process::id()));
+    std::fs::write(&outside, "secret").unwrap();
+    std::os::unix::fs::symlink(&outside, server.dir.join("escape.mkv")).unwrap();
+
+    for target in [
+        format!("/?file={}", outside.display()),
+        "/?file=/does/not/exist.mkv".to_string(),
+        format!("/?file={}/data/users.json", server.dir.display()),
+        format!("/?file={}/../{}", server.dir.display(), outside.display()),
+        format!("/?file={}", server.dir.join("escape.mkv").display()),
+        "/?file=video.mkv".to_string(),
+    ] {
+        let response = get(&server, &target, "");
+        assert!(
+            response.starts_with("HTTP/1.1 403 "),
+            "{}: {}",
+            target,
+            response
+        );
+    }
+    let _ = std::fs::remove_file(outside);
+}
+
+#[test]
+fn ranges_past_the_end_are_unsatisfiable() {
+    let server = start("unsatisfiable");
+
+    let response = get(&server, &video(&server), "Range: bytes=500-\r\n");
+    assert!(response.starts_with("HTTP/1.1 416 "), "{}", response);
+    assert!(
+        response.contains("content-range: bytes */100"),
+        "{}",
+        response
+    );
+}
+
+#[test]
+fn files_are_sent_whole_or_in_ranges() {
+    let server = start("ranges");
+
+    let response = get(&server, &video(&server), "");
+    assert!(response.starts_with("HTTP/1.1 200 "), "{}", response);
+    assert!(response.contains("content-length: 100"), "{}", response);
+
+    let response = get(&server, &video(&server), "Range: bytes=10-19\r\n");
+    assert!(response.starts_with("HTTP/1.1 206 "), "{}", response);
+    assert!(
+        response.contains("content-range: bytes 10-19/100"),
+        "{}",
+        response
+    );
+    assert!(response.contains("content-length: 10"), "{}", response);
+}
+
+#[test]
+fn growing_files_are_sent_chunked_until_they_stop() {
+    let server = start("growing");
+    // stops counting as growing a couple of seconds in
+    let path = server.dir.join("importing.mkv");
+    write_aged(&path, &[b'x'; 100], Duration::from_secs(8));
+
+    let target = format!("/?file={}", path.display());
+    let response = get(&server, &target, "Range: bytes=10-19\r\n");
+    assert!(response.starts_with("HTTP/1.1 200 "), "{}", response);
+    assert!(
+        response.contains("transfer-encoding: chunked"),
+        "{}",
+        response
+    );
+    assert!(!response.contains("content-length"), "{}", response);
+    assert!(
+        response.ends_with(&format!("\r\n\r\n64\r\n{}\r\n0\r\n\r\n", "x".repeat(100))),
+        "{}",
+        response
+    );
+}
+
+#[test]
+fn request_targets_are_decoded() {
+    let server = start("targets");
+    let dir = server.dir.display().to_string();
+
+    let absolute = format!(
+        "http://{}/?file={}",
+        server.addr,
+        urlencode(&format!("{}/video.mkv", dir))
+    );
+    let response = get(&server, &absolute, "");
+    assert!(response.starts_with("HTTP/1.1 200 "), "{}", response);
+    assert!(response.contains("content-length: 100"), "{}", response);
+
+    let plus = format!("/?file=&file={}/my+vid%C3%A9o.mkv&file=x", dir);
+    let response = get(&server, &plus, "");
+    assert!(response.starts_with("HTTP/1.1 200 "), "{}", response);
+    assert!(response.contains("content-length: 50"), "{}", response);
+
+    let raw = format!("/?file={}/my%20vidéo.mkv", dir);
+    let response = get(&server, &raw, "");
+    assert!(response.starts_with("HTTP/1.1 200 "), "{}", response);
+}
+
+#[test]
+fn file_names_do_not_have_to_be_utf8() {
+    let server = start("bytes");
+    let dir = server.dir.display().to_string();
+
+    let encoded = format!("/?file={}/caf%E9%20%F0%9F%8E%AC.mkv", dir);
+    let response = get(&server, &encoded, "");
+    assert!(response.starts_with("HTTP/1.1 200 "), "{}", response);
+    assert!(response.contains("content-length: 25"), "{}", response);
+
+    let mut raw = format!("GET /?file={}/caf", dir).into_bytes();
+    raw.extend_from_slice(b"\xe9%20\xf0\x9f\x8e\xac.mkv HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    let response = send(&server, &raw);
+    assert!(response.starts_with("HTTP/1.1 200 "), "{}", response);
+}
+
+#[test]
+fn audio_only_streams_are_made_with_ffmpeg() {
+    let server = start("audio");
+
+    let target = format!("{}&audioOnly=true", video(&server));
+    let response = get(&server, &target, "");
+    assert!(response.starts_with("HTTP/1.1 200 "), "{}", response);
+    assert!(
+        response.contains("content-type: audio/webm"),
+        "{}",
+        response
+    );
+    assert!(
+        response.contains("x-estimated-bitrate: 96000"),
+        "{}",
+        response
+    );
+    assert!(response.contains("-vn"), "{}", response);
+    assert!(response.contains("-c:a libopus"), "{}", response);
+
+    let response = get(&server, &format!("{}&audioCodec=aac", target), "");
+    assert!(response.starts_with("HTTP/1.1 200 "), "{}", response);
+    assert!(response.contains("content-type: audio/mp4"), "{}", response);
+    assert!(response.contains("-c:a aac"), "{}", response);
+
+    let response = get(&server, &format!("{}&audioCodec=flac", target), "");
+    assert!(response.starts_with("HTTP/1.1 400 "), "{}", response);
+}
+
+fn urlencode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
@@ -0,0 +1,208 @@
+const app = document.getElementById("app");
+const logoutButton = document.getElementById("logout");
+
+function el(tag, attrs = {}, ...children) {
+  const node = document.createElement(tag);
+  Object.assign(node, attrs);
+  node.append(...children);
+  return node;
+}
+
+// thrown when the server wants an access token and we have none that works
+class LoginRequired extends Error {}
+
+function authorized(options = {}) {
+  const token = localStorage.getItem("accessToken");
+  const headers = { ...options.headers };
+  if (token) {
+    headers.Authorization = `Bearer ${token}`;
+  }
+  return { ...options, headers };
+}
+
+function saveTokens(tokens) {
+  localStorage.setItem("accessToken", tokens.accessToken);
+  localStorage.setItem("refreshToken", tokens.refreshToken);
+  if (tokens.deviceId) {
+    localStorage.setItem("deviceId", tokens.deviceId);
+  }
+  logoutButton.hidden = false;
+}
+
+function forgetTokens() {
+  localStorage.removeItem("accessToken");
+  localStorage.removeItem("refreshToken");
+  logoutButton.hidden = true;
+}
+
+async function post(path, body) {
+  return fetch(path, {
+    method: "POST",
+    headers: { "Content-Type": "application/json" },
+    body: JSON.stringify(body),
+  });
+}
+
+// a refresh token only works once, so requests failing together share one refresh
+let refreshing = null;
+
+function refresh() {
+  const refreshToken = localStorage.getItem("refreshToken");
+  if (!refreshToken) {
+    return Promise.resolve(false);
+  }
+  refreshing =
+    refreshing ||
+    post("/auth/refresh", { refreshToken })
+      .then(async (res) => {
+        if (!res.ok) {
+          forgetTokens();
+          return false;
+        }
+        saveTokens(await res.json());
+        return true;
+      })
+      .finally(() => {
+        refreshing = null;
+      });
+  return refreshing;
+}
+
+async function api(path) {
+  let res = await fetch(path, authorized());
+  if (res.status === 401 && (await refresh())) {
+    res = await fetch(path, authorized());
+  }
+  if (res.status === 401) {
+    throw new LoginRequired();
+  }
+  if (!res.ok) {
+    throw new Error(`${path} responded with ${res.status}`);
+  }
+  return res.json();
+}
+
+function login() {
+  const username = el("input", { name: "username", autocomplete: "username", required: true });
+  const password = el("input", {
+    name: "password",
+    type: "password",
+    autocomplete: "current-password",
+    required: true,
+  });
+  const error = el("p", { className: "error" });
+  const form = el(
+    "form",
+    { className: "login" },
+    el("label", {}, "Username", username),
+    el("label", {}, "Password", password),
+    error,
+    el("button", { type: "submit", textContent: "Log in" })
+  );
+  form.onsubmit = async (event) => {
+    event.preventDefault();
+    const device = { name: "centarr web", type: "browser" };
+    if (localStorage.getItem("deviceId")) {
+      device.id = localStorage.getItem("deviceId");
+    }
+    const res = await post("/auth/login", {
+      username: username.value,
+      password: password.value,
+      device,
+    });
+    if (!res.ok) {
+      error.textContent = (await res.text()) || `Logging in failed with ${res.status}`;
+      return;
+    }
+    saveTokens(await res.json());
+    route();
+  };
+
+  app.replaceChildren(form);
+  username.focus();
+}
+
+async function logout() {
+  const refreshToken = localStorage.getItem("refreshToken");
+  if (refreshToken) {
+    await post("/auth/logout", { refreshToken }).catch(() => {});
+  }
+  forgetTokens();
+  route();
+}
+
+function poster(show) {
+  const image = show.images.find((image) => image.coverType === "poster");
+  return image ? image.remoteUrl : "";
+}
+
+async function library() {
+  const shows = await api("/shows");
+  shows.sort((a, b) => a.title.localeCompare(b.title));
+
+  app.replaceChildren(
+    el(
+      "div",
+      { className: "grid" },
+      ...shows.map((show) =>
+        el(
+          "a",
+          { href: `#/show/${show.id}` },
+          el("img", { src: poster(show), alt: show.title, loading: "lazy" }),
+          el("div", {}, show.title)
+        )
+      )
+    )
+  );
+}
+
+async function show(id) {
+  const show = await api(`/shows/${id}`);
+  const player = el("video", { controls: true, hidden: true });
+  const episodes = (show.episodes || []).sort(
+    (a, b) => a.seasonNumber - b.seasonNumber || a.episodeNumber - b.episodeNumber
+  );
+
+  app.replaceChildren(
+    el("h1", {}, show.title),
+    player,
+    el(
+      "ul",
+      { className: "episodes" },
+      ...episodes.map((episode) => {
+        const label = `S${episode.seasonNumber}E${episode.episodeNumber} - ${episode.title}`;
+        const file = episode.episodeFile;
+        const play = el("button", { textContent: "Play", disabled: !(file && file.watchUrl) });
+        play.onclick = () => {
+          player.src = file.watchUrl;
+          player.hidden = false;
+          player.play();
+          window.scrollTo({ top: 0 });
+        };
+        return el("li", {}, el("span", {}, label), play);
+      })
+    )
+  );
+}
+
+async function route() {
+  const match = location.hash.match(/^#\/show\/(\d+)/);
+  try {
+    if (match) {
+      await show(match[1]);
+    } else {
+      await library();
+    }
+  } catch (e) {
+    if (e instanceof LoginRequired) {
+      login();
+    } else {
+      app.replaceChildren(el("p", {}, e.message));
+    }
+  }
+}
+
+logoutButton.hidden = !localStorage.getItem("refreshToken");
+logoutButton.onclick = logout;
+window.addEventListener("hashchange", route);
+route();